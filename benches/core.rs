@@ -0,0 +1,132 @@
+//
+// Benchmarks of the core paths, run with: cargo bench
+//
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use fuser::FileType;
+
+use path_tag_fs::block_cache::BlockCache;
+use path_tag_fs::{MountMode, PathTagFs, BLOCK_SIZE, INO_ROOT};
+
+
+// a single index block limits files to this many data blocks
+const MAX_FILE_BLOCKS:usize = BLOCK_SIZE/8 - 2;
+
+
+fn make_fs(path: &str, size: u64) -> PathTagFs {
+    let _ = std::fs::remove_file(path);
+    let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+    fs.mkfs(INO_ROOT, size).unwrap();
+    fs
+}
+
+
+fn bench_allocation(c: &mut Criterion) {
+    let path = "/tmp/ptfs_bench_alloc";
+    let size = 16384;
+
+    let mut group = c.benchmark_group("allocation");
+    group.throughput(Throughput::Elements(size - 3));
+    group.bench_function("allocate_all_blocks", |b| {
+        b.iter_batched(
+            || {
+                let mut cache = BlockCache::new(path, MountMode::ReadWrite).unwrap();
+                cache.size_filesystem(size).unwrap();
+                cache.take_block(0).unwrap();
+                cache.take_block(1).unwrap();
+                cache.take_block(2).unwrap();
+                cache
+            },
+            |mut cache| {
+                while cache.allocate_block().is_ok() {}
+                cache
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+
+fn bench_find_child(c: &mut Criterion) {
+    let mut fs = make_fs("/tmp/ptfs_bench_dir", 4096);
+    let dir = fs.mkdir(INO_ROOT, &"big".to_string()).unwrap().ino;
+    let count = 1000;
+
+    for i in 0..count {
+        fs.mknod(dir, &format!("file_{:05}", i), FileType::RegularFile).unwrap();
+    }
+
+    let mut group = c.benchmark_group("directory");
+    group.bench_function("find_child_first", |b| {
+        b.iter(|| fs.find_child(dir, &"file_00000".to_string()).unwrap())
+    });
+    group.bench_function("find_child_last", |b| {
+        b.iter(|| fs.find_child(dir, &format!("file_{:05}", count - 1)).unwrap())
+    });
+    group.bench_function("find_child_missing", |b| {
+        b.iter(|| fs.find_child(dir, &"missing".to_string()).unwrap())
+    });
+    group.bench_function("list_children", |b| {
+        b.iter(|| fs.list_children(dir).unwrap())
+    });
+    group.finish();
+}
+
+
+fn bench_file_io(c: &mut Criterion) {
+    let mut fs = make_fs("/tmp/ptfs_bench_io", 65536);
+    let data: Vec<u8> = (0..MAX_FILE_BLOCKS * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+
+    let mut group = c.benchmark_group("file_io");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+
+    // every write allocates fresh blocks, so the image is recreated when full
+    group.bench_function("sequential_write", |b| {
+        b.iter_batched(
+            || make_fs("/tmp/ptfs_bench_write", 4096),
+            |mut fs| {
+                let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+                fs.write(ino, None, 0, &data).unwrap();
+                fs
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    fs.write(ino, None, 0, &data).unwrap();
+    group.bench_function("sequential_read", |b| {
+        b.iter(|| fs.read_file(ino, 0, data.len() as u64).unwrap())
+    });
+    group.finish();
+}
+
+
+fn bench_cache(c: &mut Criterion) {
+    let mut fs = make_fs("/tmp/ptfs_bench_cache", 256);
+
+    let mut group = c.benchmark_group("cache");
+    group.bench_function("retrieve_cached_entry", |b| {
+        b.iter(|| fs.retrieve_entry_block(INO_ROOT).unwrap().attr.size)
+    });
+    group.bench_function("retrieve_uncached_entry", |b| {
+        b.iter_batched(
+            || {
+                let mut fs = PathTagFs::new("/tmp/ptfs_bench_cache", MountMode::ReadOnly).unwrap();
+                fs.open(INO_ROOT, true).unwrap();
+                fs
+            },
+            |mut fs| {
+                fs.getattr(INO_ROOT).unwrap();
+                fs
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_allocation, bench_find_child, bench_file_io, bench_cache);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+// Feeds arbitrary block contents to the index block parser, run with:
+//   cargo fuzz run parse_index_block
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use path_tag_fs::block_io::parse_index_block;
+use path_tag_fs::BLOCK_SIZE;
+
+fuzz_target!(|data: &[u8]| {
+    // the raw input exercises the length check
+    let _ = parse_index_block(data, 0);
+
+    let mut block = [0u8; BLOCK_SIZE];
+    let len = std::cmp::min(data.len(), BLOCK_SIZE);
+    block[..len].copy_from_slice(&data[..len]);
+    let _ = parse_index_block(&block, 0);
+});
@@ -0,0 +1,63 @@
+/*
+ * C interface to path_tag_fs images, build with: cargo build --features ffi
+ *
+ * Mirrors src/ptfs_ffi.rs, it can be regenerated with:
+ *   cbindgen --config cbindgen.toml --output include/ptfs.h
+ *
+ * All functions return 0 or a positive count on success, and a negated
+ * errno value on failure.
+ *
+ * ptfs_create() and ptfs_open() return NULL on failure instead, and the
+ * negated errno through their err argument.
+ *
+ * Strings must be NUL terminated UTF-8. Handles come from ptfs_create()
+ * or ptfs_open() and must not be used after ptfs_close().
+ */
+
+#ifndef PTFS_H
+#define PTFS_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+typedef struct PtfsHandle PtfsHandle;
+
+/* called once per query result, name is only valid during the call */
+typedef void (*PtfsQueryCallback)(uint64_t ino, const char *name, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Formats a new image of size blocks and opens it for writing.
+   Returns NULL on failure, with the negated errno in err if not NULL. */
+PtfsHandle *ptfs_create(const char *image, uint64_t size, int *err);
+
+/* Opens an existing image. Returns NULL on failure, with the negated
+   errno in err if not NULL. */
+PtfsHandle *ptfs_open(const char *image, int read_only, int *err);
+
+/* Writes pending changes and frees the handle, also if writing fails. */
+int ptfs_close(PtfsHandle *handle);
+
+/* Copies up to len bytes of the file into buf and returns the file size,
+   which may be larger than len. */
+int64_t ptfs_read_file(PtfsHandle *handle, const char *path, uint8_t *buf, size_t len);
+
+/* Replaces the contents of the file, creates it if needed. */
+int ptfs_write_file(PtfsHandle *handle, const char *path, const uint8_t *data, size_t len);
+
+int ptfs_tag(PtfsHandle *handle, const char *path, const char *tag);
+
+int ptfs_untag(PtfsHandle *handle, const char *path, const char *tag);
+
+/* Calls callback for each file that carries all count tags and returns
+   the number of files found. */
+int ptfs_query(PtfsHandle *handle, const char *const *tags, size_t count,
+               PtfsQueryCallback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PTFS_H */
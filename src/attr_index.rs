@@ -0,0 +1,171 @@
+//
+// Sorted indexes over the size, the modification time and the rating of
+// the files, so queries comparing them look at the matching files only
+// instead of walking all of /Pathes. They are kept on the image, but
+// trusted after a clean unmount only, otherwise they are built again from
+// the tree.
+//
+
+use std::collections::{BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+use crate::error::PtfsError;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let mut index = AttrIndex::default();
+        index.update(5, 100, 1000, 0);
+        index.update(6, 3000, 2000, 4);
+        index.update(7, 100, 3000, 5);
+
+        assert_eq!(index.range(IndexedAttr::Size, 100..=100), BTreeSet::from([5, 7]));
+        assert_eq!(index.range(IndexedAttr::Size, 101..=u64::MAX), BTreeSet::from([6]));
+        assert_eq!(index.range(IndexedAttr::Mtime, 0..=2000), BTreeSet::from([5, 6]));
+        // a query can ask for a reversed range, it matches nothing
+        let (low, high) = (10, 5);
+        assert_eq!(index.range(IndexedAttr::Mtime, low..=high), BTreeSet::new());
+        assert_eq!(index.range(IndexedAttr::Rating, 4..=5), BTreeSet::from([6, 7]));
+
+        // a file is in the index once, with its last values
+        index.update(5, 4000, 1000, 0);
+        assert_eq!(index.range(IndexedAttr::Size, 0..=1000), BTreeSet::from([7]));
+        index.remove(7);
+        assert_eq!(index.range(IndexedAttr::Mtime, 0..=u64::MAX), BTreeSet::from([5, 6]));
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut index = AttrIndex::default();
+        index.update(5, 100, 1000, 0);
+        index.update(6, 3000, 2000, 3);
+        index.partial = true;
+        assert_eq!(AttrIndex::decode(&index.encode()).unwrap(), index);
+        assert_eq!(AttrIndex::decode(&AttrIndex::default().encode()).unwrap(), AttrIndex::default());
+
+        assert!(AttrIndex::decode(&[]).is_err());
+        assert!(AttrIndex::decode(&index.encode()[..4]).is_err());
+        assert!(AttrIndex::decode(&[8]).is_err());
+
+        // indexes from before the ratings have three words per file
+        let old = AttrIndex::decode(&[0, 5, 100, 1000]).unwrap();
+        assert_eq!(old.range(IndexedAttr::Size, 100..=100), BTreeSet::from([5]));
+        assert_eq!(old.range(IndexedAttr::Rating, 0..=0), BTreeSet::from([5]));
+    }
+}
+
+
+// the index keeps mtimes as seconds since 1970, unrated files have 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexedAttr {
+    Size,
+    Mtime,
+    Rating,
+}
+
+
+// flags of the first encoded word
+const PARTIAL:u64 = 1;
+const RATINGS:u64 = 2;
+
+
+#[derive(Default, Debug, PartialEq)]
+pub struct AttrIndex {
+    // pairs of value and inode, sorted by value
+    sizes: BTreeSet<(u64, u64)>,
+    mtimes: BTreeSet<(u64, u64)>,
+    ratings: BTreeSet<(u64, u64)>,
+
+    // the size, mtime and rating of each inode, to find its pairs again
+    files: HashMap<u64, (u64, u64, u64)>,
+
+    // some files are not in the index, their attributes come from the host
+    pub partial: bool,
+}
+
+
+impl AttrIndex {
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+
+    pub fn update(&mut self, ino: u64, size: u64, mtime: u64, rating: u64) {
+        self.remove(ino);
+        self.sizes.insert((size, ino));
+        self.mtimes.insert((mtime, ino));
+        self.ratings.insert((rating, ino));
+        self.files.insert(ino, (size, mtime, rating));
+    }
+
+
+    pub fn remove(&mut self, ino: u64) {
+        if let Some((size, mtime, rating)) = self.files.remove(&ino) {
+            self.sizes.remove(&(size, ino));
+            self.mtimes.remove(&(mtime, ino));
+            self.ratings.remove(&(rating, ino));
+        }
+    }
+
+
+    // the inodes with a value in range, both ends included
+    pub fn range(&self, attr: IndexedAttr, range: RangeInclusive<u64>) -> BTreeSet<u64> {
+        let (low, high) = range.into_inner();
+        if low > high {
+            return BTreeSet::new();
+        }
+
+        let pairs = match attr {
+            IndexedAttr::Size => &self.sizes,
+            IndexedAttr::Mtime => &self.mtimes,
+            IndexedAttr::Rating => &self.ratings,
+        };
+
+        pairs.range((low, 0)..=(high, u64::MAX)).map(|(_, ino)| *ino).collect()
+    }
+
+
+    // a flags word, then inode, size, mtime and rating of each file
+    pub fn encode(&self) -> Vec<u64> {
+        let mut words = Vec::with_capacity(1 + 4 * self.files.len());
+        words.push(RATINGS | if self.partial {PARTIAL} else {0});
+
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort();
+        for (ino, (size, mtime, rating)) in files {
+            words.extend_from_slice(&[*ino, *size, *mtime, *rating]);
+        }
+        words
+    }
+
+
+    // without the RATINGS flag there are three words per file
+    pub fn decode(words: &[u64]) -> Result<AttrIndex, PtfsError> {
+        let (flags, files) = match words.split_first() {
+            Some((flags, files)) if flags & !(PARTIAL | RATINGS) == 0 => (flags, files),
+            _ => return Err(PtfsError::Corrupt("attribute index is damaged".to_string())),
+        };
+
+        let record = if flags & RATINGS != 0 {4} else {3};
+        if files.len() % record != 0 {
+            return Err(PtfsError::Corrupt("attribute index is damaged".to_string()));
+        }
+
+        let mut index = AttrIndex {partial: flags & PARTIAL != 0, ..Default::default()};
+        for file in files.chunks_exact(record) {
+            index.update(file[0], file[1], file[2], file.get(3).copied().unwrap_or(0));
+        }
+        Ok(index)
+    }
+}
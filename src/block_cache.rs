@@ -2,329 +2,2358 @@
 // A write through cache for file system blocks
 //
 
-use std::collections::HashMap;
-use std::io::Error;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::time::Duration;
+use fuser::FileType;
+use log::{debug, warn};
 
-use crate::{block_io::BlockIo, path_tag_fs::BLOCK_SIZE, nodes::{AnyBlock, DataBlock, DirectoryBlock, EntryBlock, IndexBlock}};
+use crate::diagnostics::Diagnostics;
+use crate::error::PtfsError;
+use crate::stats::CacheStats;
+use crate::{block_io::{crc32, to_u32, to_u64, BlockIo}, path_tag_fs::{AllocPolicy, MountMode, SyncMode, BLOCK_SIZE, INO_ROOT}, nodes::{AnyBlock, DataBlock, DirectoryBlock, EntryBlock, IndexBlock, MAX_NAME_LENGTH}};
 
 const FSINFO_BLOCK:u64 = 2;
 
+// Version of the on-disk format. Images of older versions must be
+// upgraded before they can be mounted read-write, upgrade() lists what
+// changed with each version.
+pub const FORMAT_VERSION:u32 = 11;
+
+// the first version whose directory blocks all count their entries
+const COUNTED_DIRECTORIES:u32 = 11;
+
+const FSINFO_MAGIC:&[u8] = "PTFSInfo".as_bytes();
+
+// fsinfo block layout
+const FSINFO_VERSION:usize = 8;
+const FSINFO_BITMAP_COUNT:usize = 12;
+const FSINFO_STATE:usize = 16;
+const FSINFO_EPOCH:usize = 24;
+const FSINFO_INODE_TABLE:usize = 32;
+const FSINFO_NEXT_INO:usize = 40;
+const FSINFO_RESERVED:usize = 48;
+const FSINFO_FREE_LIST:usize = 56;
+const FSINFO_MOUNT_COUNT:usize = 64;
+const FSINFO_ATTR_INDEX:usize = 72;
+const FSINFO_BAD_BLOCKS:usize = 80;
+const FSINFO_JOURNAL:usize = 88;
+
+// CRC-32 of the block, taken with this field set to zero
+const FSINFO_CHECKSUM:usize = 68;
+
+// fsinfo block layout of version 0
+const LEGACY_BITMAP_COUNT:usize = 4;
+const LEGACY_STATE:usize = 5;
+const LEGACY_EPOCH:usize = 8;
+
+// read-write mounts after which the image is checked before it is used
+pub const MAX_MOUNTS_WITHOUT_CHECK:u32 = 20;
+
+// a file system is marked dirty while it is mounted read-write
+const STATE_CLEAN:u8 = 0;
+const STATE_DIRTY:u8 = 1;
+
+// Inodes live in the entry block of the same number, unless they were
+// relocated. Inodes created on a block that still carries the number of
+// a relocated inode get a number from this range instead.
+const FIRST_REMAPPED_INO:u64 = 1 << 40;
+
+// Each bitmap block covers one group of blocks and has a free counter,
+// so allocation can skip full groups without looking at their bitmaps.
+const GROUP_SIZE:usize = BLOCK_SIZE * 8;
+
+// inode table blocks are index blocks holding pairs of inode and block number
+const INODE_TABLE_PAIRS:usize = (BLOCK_SIZE/8 - 1) / 2;
+
+// Released metadata blocks stay marked as used and are chained into a free
+// list, so creating files after deleting others doesn't scan the bitmap.
+// Each listed block starts with this magic and the number of the next one.
+const FREE_MAGIC:&[u8] = "PTFSFree".as_bytes();
+const FREE_LIST_LIMIT:usize = 1024;
+
+// In the writeback and async modes, this many dirty blocks trigger a flush
+// by default. The write that hits the limit waits for it, which throttles
+// writers and bounds what a crash can lose.
+const DIRTY_LIMIT:usize = 256;
+
+// the attribute index and the change journal are chains of index blocks,
+// the first word of the first block tells how many words follow
+const CHAIN_WORDS:usize = BLOCK_SIZE/8 - 1;
+
+// the change journal keeps this share of the image for all mounts, so
+// writing it needs no blocks of a full image, see reserve_journal()
+const JOURNAL_SHARE:u64 = 32;
+const JOURNAL_MAX_BLOCKS:u64 = 128;
+
+// A block that fails this often is marked bad. Bad blocks stay marked as
+// used and are listed in a chain of index blocks, so they are never
+// allocated again, not even after a check rebuilt the bitmap.
+const BAD_BLOCK_FAILURES:u32 = 3;
+const BAD_BLOCKS_PER_BLOCK:usize = BLOCK_SIZE/8 - 1;
+
+// memory of a cached block, decoded blocks are larger than on disk
+pub const CACHED_BLOCK_MEMORY:usize = 2 * BLOCK_SIZE;
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::nodes::{ENTRY_SIZE, MAX_ENTRIES};
+    use crate::path_tag_fs::PathTagFs;
+
+    #[test]
+    fn test_bit_set() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_arena", MountMode::ReadWrite).unwrap();
+        storage.bitmap.push(DataBlock::new());
+        storage.bitmap.push(DataBlock::new());
+        storage.take_block(0).unwrap();
+        storage.take_block(7).unwrap();
+        storage.take_block(8).unwrap();
+        storage.take_block(17).unwrap();
+
+        // second block
+        storage.take_block(16384).unwrap();
+        storage.take_block(16391).unwrap();
+        storage.take_block(16392).unwrap();
+        
+        assert!(storage.get_bitmap_bit(16391));
+        assert!(!storage.get_bitmap_bit(16390));
+        
+        // beyond the bitmap
+        assert!(storage.take_block(40000).is_err());
+    }
+
+    #[test]
+    fn test_full_filesystem() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_full", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(8).unwrap();
+        
+        // reserved, root and fsinfo block, bitmap block 3 is taken already
+        for i in 0..3 {
+            storage.take_block(i).unwrap();
+        }
+        
+        for _i in 4..8 {
+            assert!(storage.allocate_block().is_ok());
+        }
+        
+        assert!(matches!(storage.allocate_block(), Err(PtfsError::NoSpace)));
+    }
+
+    #[test]
+    fn test_reserved_blocks() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_reserved", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        for i in 0..3 {
+            storage.take_block(i).unwrap();
+        }
+        assert_eq!(storage.free_blocks(), 12);
+        
+        storage.set_reserved_blocks(4);
+        for _i in 0..8 {
+            assert!(storage.allocate_block().is_ok());
+        }
+        assert!(matches!(storage.allocate_block(), Err(PtfsError::NoSpace)));
+        
+        // metadata and privileged callers get the rest
+        let bno = storage.allocate_metadata_block().unwrap();
+        storage.set_privileged(true);
+        assert!(storage.allocate_block().is_ok());
+        storage.set_privileged(false);
+        assert_eq!(storage.free_blocks(), 2);
+
+        storage.release_block(bno).unwrap();
+        storage.release_block(bno).unwrap();
+        assert_eq!(storage.free_blocks(), 3);
+        storage.close().unwrap();
+        
+        let mut storage = BlockCache::new("/tmp/ptfs_test_reserved", MountMode::ReadOnly).unwrap();
+        storage.open(false).unwrap();
+        assert_eq!(storage.reserved_blocks(), 4);
+        assert_eq!(storage.free_blocks(), 3);
+    }
+
+    #[test]
+    fn test_free_list() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_free_list", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(64).unwrap();
+        for i in 0..3 {
+            storage.take_block(i).unwrap();
+        }
+
+        let inodes: Vec<u64> = (0..4).map(|_| storage.allocate_inode().unwrap().0).collect();
+        let free = storage.free_blocks();
+        storage.release_inode(inodes[1]).unwrap();
+        storage.release_inode(inodes[2]).unwrap();
+
+        // listed blocks stay marked as used
+        assert_eq!(storage.free_blocks(), free);
+        assert_eq!(storage.free_list_blocks(), 2);
+        assert!(storage.is_block_used(inodes[1]));
+        storage.close().unwrap();
+
+        let mut storage = BlockCache::new("/tmp/ptfs_test_free_list", MountMode::ReadWrite).unwrap();
+        storage.open(false).unwrap();
+        assert_eq!(storage.free_list, vec![inodes[1], inodes[2]]);
+
+        // the last released block comes back first, then the bitmap is used again
+        assert_eq!(storage.allocate_inode().unwrap().1, inodes[2]);
+        assert_eq!(storage.allocate_metadata_block().unwrap(), inodes[1]);
+        assert_eq!(storage.free_list_blocks(), 0);
+        assert_eq!(storage.allocate_inode().unwrap().1, inodes[3] + 1);
+
+        // a reused head that was not written back ends the list
+        storage.release_inode(inodes[0]).unwrap();
+        storage.flush().unwrap();
+        storage.storage.write_data_block(&DataBlock::new(), inodes[0]).unwrap();
+        storage.close().unwrap();
+        drop(storage);
+        let mut storage = BlockCache::new("/tmp/ptfs_test_free_list", MountMode::ReadOnly).unwrap();
+        storage.open(false).unwrap();
+        assert_eq!(storage.free_list_blocks(), 0);
+    }
+
+    #[test]
+    fn test_bitmap_written_with_blocks() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_bitmap_sync", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        let bno = storage.allocate_block().unwrap();
+        storage.write_block(AnyBlock::DataBlock(DataBlock::new()), bno).unwrap();
+        
+        // not flushed, but the allocation is on disk already
+        let mut reader = BlockCache::new("/tmp/ptfs_test_bitmap_sync", MountMode::ReadOnly).unwrap();
+        reader.open(false).unwrap();
+        assert!(reader.get_bitmap_bit(bno as usize));
+        assert!(storage.dirty_bitmap.is_empty());
+    }
+
+    #[test]
+    fn test_writeback_mode() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_writeback", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(DIRTY_LIMIT as u64 + 64).unwrap();
+        storage.set_sync_mode(SyncMode::Writeback);
+
+        let mut db = DataBlock::new();
+        db.data[0] = 42;
+        let bno = storage.allocate_block().unwrap();
+        storage.write_block(AnyBlock::DataBlock(db), bno).unwrap();
+        assert_eq!(storage.dirty_blocks(), 1);
+
+        // nothing reached the image yet
+        let mut reader = BlockCache::new("/tmp/ptfs_test_writeback", MountMode::ReadOnly).unwrap();
+        assert_eq!(reader.storage.read_data_block(bno).unwrap().data[0], 0);
+
+        storage.flush().unwrap();
+        assert_eq!(storage.dirty_blocks(), 0);
+        assert_eq!(reader.storage.read_data_block(bno).unwrap().data[0], 42);
+
+        // a full cache is written without being asked
+        for _ in 0..DIRTY_LIMIT {
+            let bno = storage.allocate_block().unwrap();
+            storage.write_block(AnyBlock::DataBlock(DataBlock::new()), bno).unwrap();
+        }
+        assert_eq!(storage.dirty_blocks(), 0);
+        assert_eq!(storage.stats().throttled, 1);
+
+        storage.set_dirty_limit(8);
+        for n in 1..=20 {
+            let bno = storage.allocate_block().unwrap();
+            storage.write_block(AnyBlock::DataBlock(DataBlock::new()), bno).unwrap();
+            assert_eq!(storage.dirty_blocks(), n % 8);
+        }
+        assert_eq!((storage.stats().throttled, storage.stats().dirty_limit), (3, 8));
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_cache_stats", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+
+        storage.retrieve_data_block(5).unwrap();
+        storage.retrieve_data_block(5).unwrap();
+        storage.retrieve_data_block(6).unwrap();
+        let stats = storage.stats();
+        assert_eq!((stats.hits, stats.misses, stats.cached), (1, 2, 2));
+
+        storage.drop_blocks().unwrap();
+        let stats = storage.stats();
+        assert_eq!((stats.evictions, stats.cached, stats.dirty), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_max_blocks() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_max_blocks", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(64).unwrap();
+        storage.set_max_blocks(Some(16));
+
+        // changed in place, the eviction writes it
+        storage.retrieve_data_block(40).unwrap().data[0] = 42;
+
+        for bno in 41..60 {
+            storage.retrieve_data_block(bno).unwrap();
+            storage.retrieve_data_block(40).unwrap();
+            assert!(storage.stats().cached <= 16);
+        }
+
+        // the block used all the time stays
+        assert!(storage.blocks.contains_key(&40));
+        assert!(storage.stats().evictions > 0);
+
+        for bno in 0..20 {
+            storage.retrieve_data_block(bno).unwrap();
+        }
+        assert!(!storage.blocks.contains_key(&40));
+        assert_eq!(storage.retrieve_data_block(40).unwrap().data[0], 42);
+    }
+
+    #[test]
+    fn test_alloc_policy() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_alloc_policy", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(64).unwrap();
+        for i in 0..3 {
+            storage.take_block(i).unwrap();
+        }
+        while storage.allocate_block().unwrap() < 20 {}
+        storage.release_block(5).unwrap();
+        storage.release_block(12).unwrap();
+        storage.release_block(13).unwrap();
+        
+        // the goal is ignored by default
+        assert_eq!(storage.allocate_block_near(10).unwrap(), 5);
+        
+        storage.set_alloc_policy(AllocPolicy::NearParent);
+        assert_eq!(storage.allocate_block_near(10).unwrap(), 12);
+        assert_eq!(storage.allocate_metadata_block_near(10).unwrap(), 13);
+        assert_eq!(storage.allocate_block_near(10).unwrap(), 21);
+        
+        // wraps around at the end
+        storage.release_block(7).unwrap();
+        for bno in 60..64 {
+            assert_eq!(storage.allocate_block_near(60).unwrap(), bno);
+        }
+        assert_eq!(storage.allocate_block_near(60).unwrap(), 7);
+        assert_eq!(storage.allocate_block_near(60).unwrap(), 22);
+    }
+
+    #[test]
+    fn test_block_groups() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_groups", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(40000).unwrap();
+
+        // the last group is partial, the bitmap blocks sit in the first one
+        assert_eq!(storage.group_free_blocks(), &[GROUP_SIZE as u32 - 3, GROUP_SIZE as u32, 40000 - 2 * GROUP_SIZE as u32]);
+
+        for bit_no in 0..GROUP_SIZE {
+            storage.take_block(bit_no).unwrap();
+        }
+        assert_eq!(storage.group_free_blocks()[0], 0);
+        assert_eq!(storage.allocate_block().unwrap(), GROUP_SIZE as u64);
+
+        storage.set_alloc_policy(AllocPolicy::NearParent);
+        assert_eq!(storage.allocate_block_near(39999).unwrap(), 39999);
+        assert_eq!(storage.allocate_block_near(39999).unwrap(), GROUP_SIZE as u64 + 1);
+
+        storage.release_block(100).unwrap();
+        assert_eq!(storage.group_free_blocks()[0], 1);
+        assert_eq!(storage.allocate_block_near(0).unwrap(), 100);
+        storage.close().unwrap();
+
+        // counters are set up again from the bitmap
+        let counts = storage.group_free_blocks().to_vec();
+        let mut storage = BlockCache::new("/tmp/ptfs_test_groups", MountMode::ReadOnly).unwrap();
+        storage.open(false).unwrap();
+        assert_eq!(storage.group_free_blocks(), &counts[..]);
+        assert_eq!(storage.free_blocks(), counts.iter().map(|free| *free as u64).sum::<u64>());
+    }
+
+    #[test]
+    fn test_rescue_never_writes() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_rescue", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        
+        let mut rescue = BlockCache::new("/tmp/ptfs_test_rescue", MountMode::Rescue).unwrap();
+        assert!(rescue.open(false).is_ok());
+        assert!(rescue.write_block(AnyBlock::DataBlock(DataBlock::new()), 10).is_err());
+        
+        // blocks past the end of the image are treated as damaged
+        assert!(rescue.retrieve_data_block(1000).is_err());
+        assert!(rescue.retrieve_entry_block(10).is_err());
+    }
+
+    #[test]
+    fn test_upgrade_legacy_image() {
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_upgrade", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 16).unwrap();
+        fs.destroy().unwrap();
+        {
+            let mut storage = BlockCache::new("/tmp/ptfs_test_upgrade", MountMode::ReadWrite).unwrap();
+
+            // rewrite the fsinfo block the way unversioned images had it
+            let mut legacy = DataBlock::new();
+            legacy.data[LEGACY_BITMAP_COUNT] = 1;
+            storage.storage.write_data_block(&legacy, FSINFO_BLOCK).unwrap();
+        }
+        
+        let mut old = BlockCache::new("/tmp/ptfs_test_upgrade", MountMode::ReadWrite).unwrap();
+        assert!(old.open(false).is_err());
+        drop(old);
+        
+        let mut upgrader = BlockCache::new("/tmp/ptfs_test_upgrade", MountMode::ReadWrite).unwrap();
+        assert_eq!(upgrader.upgrade().unwrap(), (0, FORMAT_VERSION));
+        drop(upgrader);
+
+        let mut storage = BlockCache::new("/tmp/ptfs_test_upgrade", MountMode::ReadWrite).unwrap();
+        assert!(storage.open(false).is_ok());
+        assert_eq!(storage.bitmap.len(), 1);
+    }
+
+    // turns the entry of ino in the root directory into one without count
+    // under a new name, like images before version 2 have it
+    fn make_old_root(path: &str, ino: u64, name: &str) {
+        let mut storage = BlockCache::new(path, MountMode::ReadWrite).unwrap();
+        let mut fsinfo = FsInfo::from_block(&storage.storage.read_data_block(FSINFO_BLOCK).unwrap());
+        fsinfo.version = COUNTED_DIRECTORIES - 1;
+        storage.storage.write_data_block(&fsinfo.to_block(), FSINFO_BLOCK).unwrap();
+
+        let bno = storage.storage.read_entry_block(INO_ROOT).unwrap().more_data;
+        let mut block = storage.storage.read_data_block(bno).unwrap();
+        let data = &mut block.data;
+        let slot = (0..MAX_ENTRIES).find(|slot| to_u64(&data[slot*ENTRY_SIZE..slot*ENTRY_SIZE+8]) == ino).unwrap();
+        let pos = slot * ENTRY_SIZE + 8;
+        data[pos..pos+ENTRY_SIZE-8].fill(0);
+        data[pos..pos+name.len()].copy_from_slice(name.as_bytes());
+        data[ENTRY_SIZE-1] = 0;
+        data[2*ENTRY_SIZE-MAX_ENTRIES..2*ENTRY_SIZE].fill(0);
+        storage.storage.write_data_block(&block, bno).unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_directory_blocks() {
+        let path = "/tmp/ptfs_test_upgrade_directories";
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.destroy().unwrap();
+
+        // names that don't fit the counted layout are refused, nothing changes
+        let long = "x".repeat(MAX_NAME_LENGTH + 4);
+        make_old_root(path, ino, &long);
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        assert!(matches!(fs.upgrade(), Err(PtfsError::Refused(_))));
+        drop(fs);
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert!(fs.list_children_names(INO_ROOT).unwrap().contains(&(ino, long)));
+        drop(fs);
+
+        // other blocks without count are rewritten with count and file types
+        make_old_root(path, ino, "renamed");
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        assert_eq!(fs.upgrade().unwrap(), (COUNTED_DIRECTORIES - 1, FORMAT_VERSION));
+        drop(fs);
+
+        let mut storage = BlockCache::new(path, MountMode::ReadOnly).unwrap();
+        let bno = storage.storage.read_entry_block(INO_ROOT).unwrap().more_data;
+        let db = crate::block_io::parse_directory_block(&storage.storage.read_data_block(bno).unwrap().data, bno).unwrap();
+        let entry = db.entries.iter().find(|entry| entry.ino == ino).unwrap();
+        assert_eq!((entry.name.as_str(), entry.kind), ("renamed", Some(FileType::RegularFile)));
+        drop(storage);
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.lookup(INO_ROOT, &"renamed".to_string()).unwrap().ino, ino);
+        fs.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_dirty_image_refused() {
+        {
+            let mut storage = BlockCache::new("/tmp/ptfs_test_dirty", MountMode::ReadWrite).unwrap();
+            storage.size_filesystem(16).unwrap();
+            assert!(storage.open(false).is_ok());
+        }
+        
+        // not closed, so the image is still marked dirty
+        let mut again = BlockCache::new("/tmp/ptfs_test_dirty", MountMode::ReadWrite).unwrap();
+        assert!(again.open(false).is_err());
+        drop(again);
+
+        let mut rescue = BlockCache::new("/tmp/ptfs_test_dirty", MountMode::Rescue).unwrap();
+        assert!(rescue.open(false).is_ok());
+    }
+
+    #[test]
+    fn test_fsinfo_checksum() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_checksum", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        drop(storage);
+
+        let mut storage = BlockCache::new("/tmp/ptfs_test_checksum", MountMode::ReadWrite).unwrap();
+        let mut db = storage.storage.read_data_block(FSINFO_BLOCK).unwrap();
+        assert!(FsInfo::checksum_ok(&db));
+        db.data[FSINFO_RESERVED] ^= 1;
+        storage.storage.write_data_block(&db, FSINFO_BLOCK).unwrap();
+        assert!(storage.open(false).is_err());
+        drop(storage);
+
+        let mut rescue = BlockCache::new("/tmp/ptfs_test_checksum", MountMode::Rescue).unwrap();
+        assert!(rescue.open(false).is_ok());
+    }
+
+    #[test]
+    fn test_mount_count() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_mount_count", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        storage.close().unwrap();
+        drop(storage);
+
+        for count in 1..=MAX_MOUNTS_WITHOUT_CHECK + 1 {
+            let mut storage = BlockCache::new("/tmp/ptfs_test_mount_count", MountMode::ReadWrite).unwrap();
+            storage.open(false).unwrap();
+            assert_eq!(storage.needs_check(), count > MAX_MOUNTS_WITHOUT_CHECK);
+            if storage.needs_check() {
+                storage.reset_mount_count();
+            }
+            storage.close().unwrap();
+        }
+
+        // read-only mounts don't count
+        let mut storage = BlockCache::new("/tmp/ptfs_test_mount_count", MountMode::ReadOnly).unwrap();
+        storage.open(false).unwrap();
+        assert_eq!(storage.read_fsinfo().unwrap().mount_count, 0);
+        assert!(!storage.needs_check());
+    }
+
+    #[test]
+    fn test_bitmap_outside_image() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_bitmap_bounds", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        storage.bitmap.resize_with(20, DataBlock::new);
+        storage.close().unwrap();
+        drop(storage);
+
+        let mut storage = BlockCache::new("/tmp/ptfs_test_bitmap_bounds", MountMode::ReadOnly).unwrap();
+        assert!(storage.open(false).is_err());
+    }
+
+    #[test]
+    fn test_double_mount_refused() {
+        let mut storage = BlockCache::new("/tmp/ptfs_test_lock", MountMode::ReadWrite).unwrap();
+        storage.size_filesystem(16).unwrap();
+        assert!(storage.open(false).is_ok());
+        
+        let mut second = BlockCache::new("/tmp/ptfs_test_lock", MountMode::ReadOnly).unwrap();
+        assert!(second.open(false).is_err());
+        
+        storage.close().unwrap();
+        drop(storage);
+        
+        // readers may share the image
+        let mut reader1 = BlockCache::new("/tmp/ptfs_test_lock", MountMode::ReadOnly).unwrap();
+        let mut reader2 = BlockCache::new("/tmp/ptfs_test_lock", MountMode::ReadOnly).unwrap();
+        assert!(reader1.open(false).is_ok());
+        assert!(reader2.open(false).is_ok());
+    }
+
+    #[test]
+    fn test_shared_reader() {
+        let mut writer = BlockCache::new("/tmp/ptfs_test_shared", MountMode::ReadWrite).unwrap();
+        writer.size_filesystem(16).unwrap();
+        assert!(writer.open(false).is_ok());
+        assert!(writer.set_shared(true).is_err());
+
+        let mut reader = BlockCache::new("/tmp/ptfs_test_shared", MountMode::ReadOnly).unwrap();
+        reader.set_shared(true).unwrap();
+        assert!(reader.open(false).is_ok());
+        assert!(reader.attr_words.is_none());
+        drop(reader);
+
+        // a dirty image without a writer is still refused
+        drop(writer);
+        let mut reader = BlockCache::new("/tmp/ptfs_test_shared", MountMode::ReadOnly).unwrap();
+        reader.set_shared(true).unwrap();
+        assert!(reader.open(false).is_err());
+    }
+
+    #[test]
+    fn test_epoch_mismatch_detected() {
+        let mut first = BlockCache::new("/tmp/ptfs_test_epoch", MountMode::ReadWrite).unwrap();
+        first.size_filesystem(16).unwrap();
+        assert!(first.open(false).is_ok());
+
+        // forced past lock and dirty state, the newer mount owns the image now
+        let mut second = BlockCache::new("/tmp/ptfs_test_epoch", MountMode::ReadWrite).unwrap();
+        assert!(second.open(true).is_ok());
+        
+        assert!(!first.owns_image());
+        assert!(second.owns_image());
+    }
+}
+
+
+pub struct BlockCache {
+    pub bitmap: Vec<DataBlock>,
+
+    // bitmap blocks changed since they were last written
+    dirty_bitmap: BTreeSet<usize>,
+
+    // free blocks of each group
+    group_free: Vec<u32>,
+    
+    // just in memory for now
+    blocks: HashMap<u64, AnyBlock>,
+
+    // cached blocks that were not written yet, always empty in sync mode
+    dirty_blocks: HashSet<u64>,
+    
+    storage: BlockIo, 
+    
+    mode: MountMode,
+    state: u8,
+
+    // read-only next to a writer, see set_shared()
+    shared: bool,
+
+    // nothing is written while frozen, see PathTagFs::freeze()
+    frozen: bool,
+    
+    // incremented on each read-write mount, tells if another instance took over the image
+    epoch: u64,
+
+    // read-write mounts since the last check, including this one
+    mount_count: u32,
+    
+    // size of the file system in blocks
+    block_count: u64,
+
+    // inodes that don't live in the block of their own number
+    inodes: HashMap<u64, u64>,
+
+    // blocks that hold the inode table on disk
+    inode_table: Vec<u64>,
+
+    // released metadata blocks, the last one is the head of the list on disk
+    free_list: Vec<u64>,
+
+    // blocks of the attribute index, and its words if they can be trusted,
+    // see take_attr_index()
+    attr_index: Vec<u64>,
+    attr_words: Option<Vec<u64>>,
+
+    // the same for the change journal, see take_journal()
+    journal: Vec<u64>,
+    journal_words: Option<Vec<u64>>,
+    journal_reserved: usize,
+
+    next_ino: u64,
+
+    // Blocks kept back for directory and other metadata updates, and
+    // for privileged callers, so a full image can still be cleaned up
+    reserved_blocks: u64,
+    free_blocks: u64,
+    privileged: bool,
+
+    policy: AllocPolicy,
+    sync_mode: SyncMode,
+
+    // dirty blocks that make a write flush the cache, see DIRTY_LIMIT
+    dirty_limit: usize,
+
+    // hits, misses and evictions, the rest is filled in by stats()
+    stats: CacheStats,
+
+    // without a limit all blocks stay cached until drop_blocks()
+    max_blocks: Option<usize>,
+
+    // when each cached block was used last, see make_room()
+    last_used: HashMap<u64, u64>,
+    clock: u64,
+
+    // damaged and unreadable blocks met so far
+    diagnostics: Diagnostics,
+
+    // failed reads and writes of each block, the bad ones, the blocks that
+    // list them on disk, and the ones marked since take_new_bad_blocks()
+    failures: HashMap<u64, u32>,
+    bad_blocks: BTreeSet<u64>,
+    bad_block_list: Vec<u64>,
+    new_bad_blocks: Vec<u64>,
+}
+
+
+// what points to a block, see relocate_block()
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlockOwner {
+    // the entry block of the inode, through the inode table
+    Inode(u64),
+
+    // the first block of the directory or index chain of the inode
+    ChainHead(u64),
+
+    // the next block after this directory or index block
+    DirectoryNext(u64),
+    IndexNext(u64),
+
+    // a data block listed in this index block
+    IndexSlot(u64, usize),
+}
+
+
+// contents of the fsinfo block
+pub struct FsInfo {
+    pub version: u32,
+    pub bitmap_count: u64,
+    pub state: u8,
+    pub epoch: u64,
+    pub inode_table: u64,
+    pub next_ino: u64,
+    pub reserved_blocks: u64,
+    pub free_list: u64,
+
+    // read-write mounts since the last check
+    pub mount_count: u32,
+
+    pub attr_index: u64,
+    pub bad_blocks: u64,
+    pub journal: u64,
+}
+
+
+impl FsInfo {
+
+    pub fn is_clean(&self) -> bool {
+        self.state == STATE_CLEAN
+    }
+
+
+    fn from_block(db: &DataBlock) -> FsInfo {
+        let data = &db.data;
+        
+        if &data[0..8] == FSINFO_MAGIC {
+            FsInfo {
+                version: to_u32(&data[FSINFO_VERSION..FSINFO_VERSION+4]),
+                bitmap_count: to_u32(&data[FSINFO_BITMAP_COUNT..FSINFO_BITMAP_COUNT+4]) as u64,
+                state: data[FSINFO_STATE],
+                epoch: to_u64(&data[FSINFO_EPOCH..FSINFO_EPOCH+8]),
+                inode_table: to_u64(&data[FSINFO_INODE_TABLE..FSINFO_INODE_TABLE+8]),
+                next_ino: to_u64(&data[FSINFO_NEXT_INO..FSINFO_NEXT_INO+8]),
+                reserved_blocks: to_u64(&data[FSINFO_RESERVED..FSINFO_RESERVED+8]),
+                free_list: to_u64(&data[FSINFO_FREE_LIST..FSINFO_FREE_LIST+8]),
+                mount_count: to_u32(&data[FSINFO_MOUNT_COUNT..FSINFO_MOUNT_COUNT+4]),
+                attr_index: to_u64(&data[FSINFO_ATTR_INDEX..FSINFO_ATTR_INDEX+8]),
+                bad_blocks: to_u64(&data[FSINFO_BAD_BLOCKS..FSINFO_BAD_BLOCKS+8]),
+                journal: to_u64(&data[FSINFO_JOURNAL..FSINFO_JOURNAL+8]),
+            }
+        }
+        else {
+            FsInfo {
+                version: 0,
+                bitmap_count: data[LEGACY_BITMAP_COUNT] as u64,
+                state: data[LEGACY_STATE],
+                epoch: to_u64(&data[LEGACY_EPOCH..LEGACY_EPOCH+8]),
+                inode_table: 0,
+                next_ino: 0,
+                reserved_blocks: 0,
+                free_list: 0,
+                mount_count: 0,
+                attr_index: 0,
+                bad_blocks: 0,
+                journal: 0,
+            }
+        }
+    }
+    
+    
+    // always uses the current layout
+    fn to_block(&self) -> DataBlock {
+        let mut db = DataBlock::new();
+        let data = &mut db.data;
+        
+        data[0..8].copy_from_slice(FSINFO_MAGIC);
+        data[FSINFO_VERSION..FSINFO_VERSION+4].copy_from_slice(&u32::to_le_bytes(self.version));
+        data[FSINFO_BITMAP_COUNT..FSINFO_BITMAP_COUNT+4].copy_from_slice(&u32::to_le_bytes(self.bitmap_count as u32));
+        data[FSINFO_STATE] = self.state;
+        data[FSINFO_EPOCH..FSINFO_EPOCH+8].copy_from_slice(&u64::to_le_bytes(self.epoch));
+        data[FSINFO_INODE_TABLE..FSINFO_INODE_TABLE+8].copy_from_slice(&u64::to_le_bytes(self.inode_table));
+        data[FSINFO_NEXT_INO..FSINFO_NEXT_INO+8].copy_from_slice(&u64::to_le_bytes(self.next_ino));
+        data[FSINFO_RESERVED..FSINFO_RESERVED+8].copy_from_slice(&u64::to_le_bytes(self.reserved_blocks));
+        data[FSINFO_FREE_LIST..FSINFO_FREE_LIST+8].copy_from_slice(&u64::to_le_bytes(self.free_list));
+        data[FSINFO_MOUNT_COUNT..FSINFO_MOUNT_COUNT+4].copy_from_slice(&u32::to_le_bytes(self.mount_count));
+        data[FSINFO_ATTR_INDEX..FSINFO_ATTR_INDEX+8].copy_from_slice(&u64::to_le_bytes(self.attr_index));
+        data[FSINFO_BAD_BLOCKS..FSINFO_BAD_BLOCKS+8].copy_from_slice(&u64::to_le_bytes(self.bad_blocks));
+        data[FSINFO_JOURNAL..FSINFO_JOURNAL+8].copy_from_slice(&u64::to_le_bytes(self.journal));
+
+        let checksum = crc32(data);
+        data[FSINFO_CHECKSUM..FSINFO_CHECKSUM+4].copy_from_slice(&u32::to_le_bytes(checksum));
+        
+        db
+    }
+
+
+    // images before version 7 have no checksum
+    fn checksum_ok(db: &DataBlock) -> bool {
+        let mut data = db.data;
+        let stored = to_u32(&data[FSINFO_CHECKSUM..FSINFO_CHECKSUM+4]);
+        data[FSINFO_CHECKSUM..FSINFO_CHECKSUM+4].fill(0);
+
+        FsInfo::from_block(db).version < 7 || crc32(&data) == stored
+    }
+}
+
+
+impl BlockCache {
+
+
+    pub fn new(backingstore: &str, mode: MountMode) -> Result<BlockCache, PtfsError> {
+        let storage = match mode {
+            MountMode::ReadWrite => BlockIo::new(backingstore)?,
+            MountMode::ReadOnly | MountMode::Rescue => BlockIo::open_read_only(backingstore)?,
+        };
+        
+        let cache = BlockCache {
+            bitmap: Vec::new(),
+            dirty_bitmap: BTreeSet::new(),
+            group_free: Vec::new(),
+            blocks: HashMap::new(),
+            dirty_blocks: HashSet::new(),
+            storage: storage,
+            mode: mode,
+            state: STATE_CLEAN,
+            shared: false,
+            frozen: false,
+            epoch: 0,
+            mount_count: 0,
+            block_count: 0,
+            inodes: HashMap::new(),
+            inode_table: Vec::new(),
+            free_list: Vec::new(),
+            attr_index: Vec::new(),
+            attr_words: None,
+            journal: Vec::new(),
+            journal_words: None,
+            journal_reserved: 0,
+            next_ino: FIRST_REMAPPED_INO,
+            reserved_blocks: 0,
+            free_blocks: 0,
+            privileged: false,
+            policy: AllocPolicy::FirstFree,
+            sync_mode: SyncMode::Sync,
+            dirty_limit: DIRTY_LIMIT,
+            stats: CacheStats::default(),
+            diagnostics: Diagnostics::default(),
+            failures: HashMap::new(),
+            bad_blocks: BTreeSet::new(),
+            bad_block_list: Vec::new(),
+            new_bad_blocks: Vec::new(),
+            max_blocks: None,
+            last_used: HashMap::new(),
+            clock: 0,
+        };
+        
+        
+        Ok(cache)
+    }
+    
+    
+    // force: continue even if the image is locked by another instance
+    //        or was not cleanly unmounted
+    pub fn open(&mut self, force: bool) -> Result<(), PtfsError> {
+
+        let lock_result = self.storage.lock(self.mode == MountMode::ReadWrite);
+
+        // the writer keeps the image dirty while it has it
+        let writer = self.shared && matches!(&lock_result, Err(PtfsError::Io(err)) if err.kind() == ErrorKind::WouldBlock);
+        if writer {
+            debug!("open()  image is in use by a writer, reading it shared");
+        }
+        else if let Err(err) = lock_result {
+            if !force {
+                return Err(PtfsError::Refused(format!("{}, use --read-only or --force", err)));
+            }
+            warn!("open()  warning: {}, continuing because of --force", err);
+        }
+
+        // get fsinfo block
+        let fsinfo_block = self.storage.read_data_block(FSINFO_BLOCK)?;
+        let fsinfo = FsInfo::from_block(&fsinfo_block);
+        let mut bm_size = fsinfo.bitmap_count;
+        let dirty = fsinfo.state != STATE_CLEAN;
+        
+        debug!("open()  on-disk format version {}", fsinfo.version);
+        self.storage.set_old_directories(fsinfo.version < COUNTED_DIRECTORIES);
+        
+        if self.mode != MountMode::Rescue {
+            if fsinfo.version > FORMAT_VERSION {
+                return Err(PtfsError::Refused( 
+                    format!("format version {} is newer than supported version {}", fsinfo.version, FORMAT_VERSION)));
+            }
+            if fsinfo.version < FORMAT_VERSION && self.mode == MountMode::ReadWrite {
+                return Err(PtfsError::Refused( 
+                    format!("format version {} is outdated, run 'path_tag_fs upgrade' first", fsinfo.version)));
+            }
+        }
+        
+        if !FsInfo::checksum_ok(&fsinfo_block) {
+            if self.mode != MountMode::Rescue {
+                return Err(PtfsError::Refused(
+                    "checksum of the fsinfo block is wrong, use --rescue to salvage data".to_string()));
+            }
+            warn!("open()  checksum of the fsinfo block is wrong, continuing in rescue mode");
+        }
+
+        if self.mode == MountMode::Rescue {
+            if dirty {
+                warn!("open()  file system was not cleanly unmounted, continuing in rescue mode");
+            }
+            
+            // a damaged fsinfo block must not make us read past the image
+            let available = self.storage.block_count().saturating_sub(3);
+            bm_size = std::cmp::min(bm_size, available);
+        }
+        else if dirty && !force && !writer {
+            return Err(PtfsError::Refused( 
+                "file system was not cleanly unmounted, use --rescue to salvage data or --force to mount anyway".to_string()));
+        }
+        else if dirty && !writer {
+            warn!("open()  warning: file system was not cleanly unmounted, continuing because of --force");
+        }
+
+        // the bitmap blocks must be inside the image
+        if self.mode != MountMode::Rescue && 3 + bm_size > self.storage.block_count() {
+            return Err(PtfsError::Refused(
+                format!("{} bitmap blocks don't fit into the image, use --rescue to salvage data", bm_size)));
+        }
+        
+        debug!("open()  reading {} bitmap blocks", bm_size);
+        
+        self.bitmap.clear();
+        for i in 0..bm_size {
+            let bmblock = self.storage.read_data_block(3+i)?;
+            self.bitmap.push(bmblock);
+        }
+        
+        self.block_count = self.storage.block_count();
+        self.read_inode_table(fsinfo.inode_table)?;
+        self.next_ino = std::cmp::max(fsinfo.next_ino, FIRST_REMAPPED_INO);
+        self.reserved_blocks = fsinfo.reserved_blocks;
+        self.read_free_list(fsinfo.free_list)?;
+        self.read_attr_index(fsinfo.attr_index, !dirty && self.mode != MountMode::Rescue)?;
+        self.read_journal(fsinfo.journal, !dirty && self.mode != MountMode::Rescue)?;
+        self.read_bad_blocks(fsinfo.bad_blocks)?;
+        self.count_free_blocks();
+        self.mount_count = fsinfo.mount_count;
+
+        if self.mode != MountMode::Rescue {
+            for i in 0..bm_size {
+                if !self.is_block_used(3+i) {
+                    return Err(PtfsError::Refused(
+                        format!("bitmap block {} is marked as free, use --rescue to salvage data", 3+i)));
+                }
+            }
+        }
+        
+        if self.mode == MountMode::ReadWrite {
+            // stays dirty until close()
+            self.state = STATE_DIRTY;
+            self.epoch = fsinfo.epoch + 1;
+            self.mount_count = fsinfo.mount_count.saturating_add(1);
+            self.write_fsinfo()?;
+            self.storage.flush()?;
+            self.storage.sync()?;
+            
+            debug!("open()  mount epoch is {}", self.epoch);
+        }
+        
+        Ok(())
+    }
+    
+    
+    fn write_fsinfo(&mut self) -> Result<usize, PtfsError> {
+        let fsinfo = FsInfo {
+            version: FORMAT_VERSION,
+            bitmap_count: self.bitmap.len() as u64,
+            state: self.state,
+            epoch: self.epoch,
+            inode_table: self.inode_table.first().copied().unwrap_or(0),
+            next_ino: self.next_ino,
+            reserved_blocks: self.reserved_blocks,
+            free_list: self.free_list.last().copied().unwrap_or(0),
+            mount_count: self.mount_count,
+            attr_index: self.attr_index.first().copied().unwrap_or(0),
+            bad_blocks: self.bad_block_list.first().copied().unwrap_or(0),
+            journal: self.journal.first().copied().unwrap_or(0),
+        };
+        
+        self.storage.write_data_block(&fsinfo.to_block(), FSINFO_BLOCK)
+    }
+
+
+    fn read_inode_table(&mut self, first: u64) -> Result<(), PtfsError> {
+        self.inodes.clear();
+        self.inode_table.clear();
+
+        let mut next = first;
+        while next != 0 {
+            if let Err(err) = self.check_readable(next) {
+                // in rescue mode, relocated inodes are lost but the rest is readable
+                warn!("open()  inode table truncated: {}", err);
+                break;
+            }
+
+            let ib = self.storage.read_index_block(next)?;
+            for pair in ib.block.chunks_exact(2) {
+                if pair[0] != 0 {
+                    self.inodes.insert(pair[0], pair[1]);
+                }
+            }
+
+            self.inode_table.push(next);
+            next = ib.next;
+        }
+
+        debug!("open()  {} relocated inodes", self.inodes.len());
+        Ok(())
+    }
+
+
+    // A block that doesn't carry the magic ends the list. This happens if
+    // a listed block was reused but the fsinfo block was not written after,
+    // the rest of the list is lost until a check frees it.
+    fn read_free_list(&mut self, head: u64) -> Result<(), PtfsError> {
+        self.free_list.clear();
+
+        let mut next = head;
+        while next != 0 && self.free_list.len() < FREE_LIST_LIMIT {
+            if self.check_readable(next).is_err() || !self.is_block_used(next) {
+                warn!("open()  free list truncated at block {}", next);
+                break;
+            }
+
+            let db = self.storage.read_data_block(next)?;
+            if &db.data[0..8] != FREE_MAGIC {
+                warn!("open()  free list truncated at block {}", next);
+                break;
+            }
+
+            self.free_list.push(next);
+            next = to_u64(&db.data[8..16]);
+        }
+
+        // the head goes last
+        self.free_list.reverse();
+        debug!("open()  {} blocks in the free list", self.free_list.len());
+        Ok(())
+    }
+
+
+    // The blocks are kept, so the next write can use them again. The words
+    // are left out if the last mount ended without close(), they may be
+    // older than the files then.
+    fn read_attr_index(&mut self, first: u64, trusted: bool) -> Result<(), PtfsError> {
+        let (blocks, words) = self.read_chain(first, trusted, "attribute index")?;
+        self.attr_index = blocks;
+        self.attr_words = words;
+        Ok(())
+    }
+
+
+    // the words of the attribute index as the last clean unmount left them
+    pub fn take_attr_index(&mut self) -> Option<Vec<u64>> {
+        self.attr_words.take()
+    }
+
+
+    // Rewrites the attribute index as a whole. Its blocks go out with the
+    // next flush, like the inode table.
+    pub fn write_attr_index(&mut self, words: &[u64]) -> Result<(), PtfsError> {
+        let mut blocks = std::mem::take(&mut self.attr_index);
+        let result = self.write_chain(&mut blocks, words, 0);
+        self.attr_index = blocks;
+        result
+    }
+
+
+    // frees the attribute index, the next mount builds it from the tree
+    pub fn drop_attr_index(&mut self) -> Result<(), PtfsError> {
+        while let Some(bno) = self.attr_index.pop() {
+            self.release_block(bno)?;
+        }
+        Ok(())
+    }
+
+
+    // like the attribute index, the journal of a mount that ended without
+    // close() may miss records
+    fn read_journal(&mut self, first: u64, trusted: bool) -> Result<(), PtfsError> {
+        let (blocks, words) = self.read_chain(first, trusted, "change journal")?;
+        self.journal = blocks;
+        self.journal_words = words;
+        Ok(())
+    }
+
+
+    pub fn take_journal(&mut self) -> Option<Vec<u64>> {
+        self.journal_words.take()
+    }
+
+
+    // Takes the blocks of the journal for this mount, as many as a full
+    // image has left. Returns how many words of records fit into them.
+    pub fn reserve_journal(&mut self) -> Result<usize, PtfsError> {
+        let wanted = (self.block_count / JOURNAL_SHARE).clamp(1, JOURNAL_MAX_BLOCKS) as usize;
+        while self.journal.len() < wanted {
+            match self.allocate_metadata_block() {
+                Ok(bno) => self.journal.push(bno),
+                Err(PtfsError::NoSpace) => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.journal_reserved = self.journal.len();
+        Ok((self.journal_reserved * CHAIN_WORDS).saturating_sub(1))
+    }
+
+
+    pub fn write_journal(&mut self, words: &[u64]) -> Result<(), PtfsError> {
+        let mut blocks = std::mem::take(&mut self.journal);
+        let result = self.write_chain(&mut blocks, words, self.journal_reserved);
+        self.journal = blocks;
+        result
+    }
+
+
+    // frees the journal, the next mount starts a new one
+    pub fn drop_journal(&mut self) -> Result<(), PtfsError> {
+        self.journal_reserved = 0;
+        while let Some(bno) = self.journal.pop() {
+            self.release_block(bno)?;
+        }
+        Ok(())
+    }
+
+
+    pub fn journal_blocks(&self) -> u64 {
+        self.journal.len() as u64
+    }
+
+
+    // the blocks of a chain of words and the words, if they are trusted
+    fn read_chain(&mut self, first: u64, trusted: bool, what: &str) -> Result<(Vec<u64>, Option<Vec<u64>>), PtfsError> {
+        let mut blocks = Vec::new();
+        let mut words = Vec::new();
+        let mut next = first;
+        while next != 0 && (blocks.len() as u64) < self.block_count {
+            if let Err(err) = self.check_readable(next) {
+                warn!("open()  {} truncated: {}", what, err);
+                return Ok((blocks, None));
+            }
+
+            let ib = self.storage.read_index_block(next)?;
+            words.extend_from_slice(&ib.block);
+            blocks.push(next);
+            next = ib.next;
+        }
+
+        let words = match words.split_first() {
+            Some((count, rest)) if trusted && *count as usize <= rest.len() => Some(rest[..*count as usize].to_vec()),
+            Some(_) if trusted => {
+                warn!("open()  {} is truncated, it is not used", what);
+                None
+            }
+            _ => None,
+        };
+
+        debug!("open()  {} {} blocks", blocks.len(), what);
+        Ok((blocks, words))
+    }
+
+
+    // rewrites the chain in blocks as a whole, it grows and shrinks as needed
+    // but keeps at least the reserved blocks
+    fn write_chain(&mut self, blocks: &mut Vec<u64>, words: &[u64], reserved: usize) -> Result<(), PtfsError> {
+        let needed = (words.len() + 1).div_ceil(CHAIN_WORDS).max(reserved);
+        let mut data = Vec::with_capacity(needed * CHAIN_WORDS);
+        data.push(words.len() as u64);
+        data.extend_from_slice(words);
+
+        while blocks.len() < needed {
+            let bno = self.allocate_metadata_block()?;
+            blocks.push(bno);
+        }
+        while blocks.len() > needed {
+            let bno = blocks.pop().unwrap();
+            self.release_block(bno)?;
+        }
+
+        // reserved blocks are written empty, so the chain runs through them
+        data.resize(needed * CHAIN_WORDS, 0);
+        for (n, chunk) in data.chunks(CHAIN_WORDS).enumerate() {
+            let mut ib = IndexBlock::new();
+            ib.block[..chunk.len()].copy_from_slice(chunk);
+            ib.next = blocks.get(n + 1).copied().unwrap_or(0);
+
+            self.storage.write_block(&AnyBlock::IndexBlock(ib), blocks[n])?;
+        }
+
+        Ok(())
+    }
+
+
+    pub fn attr_index_blocks(&self) -> u64 {
+        self.attr_index.len() as u64
+    }
+
+
+    fn read_bad_blocks(&mut self, first: u64) -> Result<(), PtfsError> {
+        self.bad_blocks.clear();
+        self.bad_block_list.clear();
+
+        let mut next = first;
+        while next != 0 && (self.bad_block_list.len() as u64) < self.block_count {
+            if let Err(err) = self.check_readable(next) {
+                warn!("open()  bad block list truncated: {}", err);
+                break;
+            }
+
+            let ib = self.storage.read_index_block(next)?;
+            self.bad_blocks.extend(ib.block.iter().filter(|bno| **bno != 0));
+            self.bad_block_list.push(next);
+            next = ib.next;
+        }
+
+        debug!("open()  {} bad blocks", self.bad_blocks.len());
+        Ok(())
+    }
+
+
+    // the list only grows, it is rewritten as a whole like the inode table
+    fn write_bad_blocks(&mut self) -> Result<(), PtfsError> {
+        let needed = self.bad_blocks.len().div_ceil(BAD_BLOCKS_PER_BLOCK);
+        if needed == 0 {
+            return Ok(());
+        }
+
+        while self.bad_block_list.len() < needed {
+            let bno = self.allocate_metadata_block()?;
+            self.bad_block_list.push(bno);
+        }
+
+        let bad: Vec<u64> = self.bad_blocks.iter().copied().collect();
+        for (n, chunk) in bad.chunks(BAD_BLOCKS_PER_BLOCK).enumerate() {
+            let mut ib = IndexBlock::new();
+            ib.block[..chunk.len()].copy_from_slice(chunk);
+            ib.next = self.bad_block_list.get(n + 1).copied().unwrap_or(0);
+
+            self.storage.write_block(&AnyBlock::IndexBlock(ib), self.bad_block_list[n])?;
+        }
+
+        Ok(())
+    }
+
+
+    pub fn bad_blocks(&self) -> Vec<u64> {
+        self.bad_blocks.iter().copied().collect()
+    }
+
+
+    // the bad blocks and the blocks listing them
+    pub fn bad_blocks_used(&self) -> u64 {
+        (self.bad_blocks.len() + self.bad_block_list.len()) as u64
+    }
+
+
+    // Marks a block as bad, it is never allocated again. Its content stays
+    // where it is, see PathTagFs::relocate_bad_blocks().
+    pub fn mark_bad(&mut self, bno: u64) -> Result<(), PtfsError> {
+        if bno <= FSINFO_BLOCK || !self.bad_blocks.insert(bno) {
+            return Ok(());
+        }
+
+        self.diagnostics.record("bad block", Some(bno), "it is not used again");
+        self.new_bad_blocks.push(bno);
+        if !self.is_block_used(bno) {
+            self.take_block(bno as usize)?;
+        }
+        Ok(())
+    }
+
+
+    // the blocks marked bad since the last call
+    pub fn take_new_bad_blocks(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.new_bad_blocks)
+    }
+
+
+    // Records a failed read or write of block bno, and marks it bad when
+    // it failed too often. Damaged content is not a failure of the block.
+    fn count_failure(&mut self, bno: u64, err: &PtfsError) {
+        if !matches!(err, PtfsError::Io(_)) {
+            return;
+        }
+
+        let failures = self.failures.entry(bno).or_insert(0);
+        *failures += 1;
+        if *failures >= BAD_BLOCK_FAILURES {
+            self.failures.remove(&bno);
+            if let Err(err) = self.mark_bad(bno) {
+                warn!("cannot mark block {} as bad: {}", bno, err);
+            }
+        }
+    }
+
+
+    // records a failed read of block bno, the error is passed on
+    fn reported<T>(&mut self, kind: &'static str, bno: u64, result: Result<T, PtfsError>) -> Result<T, PtfsError> {
+        if let Err(err) = &result {
+            self.diagnostics.record(kind, Some(bno), &err.to_string());
+            self.count_failure(bno, err);
+        }
+        result
+    }
+
+
+    // the table is rewritten as a whole, growing and shrinking as needed
+    fn write_inode_table(&mut self) -> Result<(), PtfsError> {
+        let needed = self.inodes.len().div_ceil(INODE_TABLE_PAIRS);
+
+        while self.inode_table.len() < needed {
+            let bno = self.allocate_metadata_block()?;
+            self.inode_table.push(bno);
+        }
+        while self.inode_table.len() > needed {
+            let bno = self.inode_table.pop().unwrap();
+            self.release_block(bno)?;
+        }
+
+        let mut pairs: Vec<(&u64, &u64)> = self.inodes.iter().collect();
+        pairs.sort();
+
+        for (n, chunk) in pairs.chunks(INODE_TABLE_PAIRS).enumerate() {
+            let mut ib = IndexBlock::new();
+            for (i, (ino, bno)) in chunk.iter().enumerate() {
+                ib.block[2*i] = **ino;
+                ib.block[2*i + 1] = **bno;
+            }
+            ib.next = self.inode_table.get(n + 1).copied().unwrap_or(0);
+
+            self.storage.write_block(&AnyBlock::IndexBlock(ib), self.inode_table[n])?;
+        }
+
+        Ok(())
+    }
+    
+    
+    // the fsinfo block as it is on disk right now
+    pub fn read_fsinfo(&mut self) -> Result<FsInfo, PtfsError> {
+        Ok(FsInfo::from_block(&self.storage.read_data_block(FSINFO_BLOCK)?))
+    }
+
+
+    pub fn bitmap_blocks(&self) -> u64 {
+        self.bitmap.len() as u64
+    }
+
+
+    pub fn inode_table_blocks(&self) -> u64 {
+        self.inode_table.len() as u64
+    }
+
+
+    pub fn relocated_inodes(&self) -> usize {
+        self.inodes.len()
+    }
+
+
+    // true if the image was mounted read-write too often without a check
+    pub fn needs_check(&self) -> bool {
+        self.mount_count > MAX_MOUNTS_WITHOUT_CHECK
+    }
+
+
+    // the number of this read-write mount, 0 for the other modes
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+
+    // a check found no problems, counting starts again
+    pub fn reset_mount_count(&mut self) {
+        self.mount_count = 0;
+    }
+
+
+    // false if another instance mounted the image after us
+    fn owns_image(&mut self) -> bool {
+        match self.storage.read_data_block(FSINFO_BLOCK) {
+            Ok(db) => FsInfo::from_block(&db).epoch == self.epoch,
+            Err(_) => false,
+        }
+    }
+    
+    
+    // Migrates an unmounted image to the current on-disk format, one 
+    // version step at a time. Returns the old and the new version.
+    pub fn upgrade(&mut self) -> Result<(u32, u32), PtfsError> {
+        self.storage.lock(true)?;
+        
+        let mut fsinfo = FsInfo::from_block(&self.storage.read_data_block(FSINFO_BLOCK)?);
+        let old_version = fsinfo.version;
+        
+        if fsinfo.version > FORMAT_VERSION {
+            return Err(PtfsError::Refused(
+                format!("format version {} is newer than supported version {}", fsinfo.version, FORMAT_VERSION)));
+        }
+        
+        if fsinfo.state != STATE_CLEAN {
+            return Err(PtfsError::Refused(
+                "file system was not cleanly unmounted, cannot upgrade it".to_string()));
+        }
+        
+        while fsinfo.version < FORMAT_VERSION {
+            debug!("upgrade()  migrating format version {} to {}", fsinfo.version, fsinfo.version + 1);
+
+            // 0 -> 1: the unversioned fsinfo block with a single byte bitmap
+            //         count got magic and version, it is rewritten below
+            // 1 -> 2: entry blocks got an extension area, it is empty in older images
+            // 2 -> 3: timestamps got nanosecond precision, entry blocks flag their
+            //         layout and older ones are converted when written again
+            // 3 -> 4: the fsinfo block points to an inode table, older images
+            //         have no relocated inodes and the pointer is zero
+            // 4 -> 5: the fsinfo block holds the number of reserved blocks,
+            //         older images reserve none
+            // 5 -> 6: the fsinfo block points to a free list of metadata
+            //         blocks, it is empty in older images
+            // 6 -> 7: the fsinfo block has a checksum and counts mounts since
+            //         the last check, it is written below with both
+            // 7 -> 8: the fsinfo block points to an attribute index, older
+            //         images have none and it is built at the next mount
+            // 8 -> 9: the fsinfo block points to a list of bad blocks, older
+            //         images have none and the pointer is zero
+            // 9 -> 10: the fsinfo block points to a change journal, older
+            //         images have none and it starts at the next mount
+            // 10 -> 11: directory blocks count their entries and keep their
+            //         file types, which shortens names to MAX_NAME_LENGTH.
+            //         Images from before version 2 and upgrades of them still
+            //         have blocks without count, these are rewritten.
+            if fsinfo.version == COUNTED_DIRECTORIES - 1 {
+                self.count_directory_entries(fsinfo.inode_table)?;
+            }
+            
+            fsinfo.version += 1;
+        }
+        
+        self.storage.write_data_block(&fsinfo.to_block(), FSINFO_BLOCK)?;
+        self.storage.flush()?;
+        self.storage.sync()?;
+        
+        Ok((old_version, fsinfo.version))
+    }
+    
+    
+    // Rewrites all directory blocks with entry counts. Nothing is written if
+    // a name is too long for them, it must be shortened with an older
+    // version first.
+    fn count_directory_entries(&mut self, inode_table: u64) -> Result<(), PtfsError> {
+        self.read_inode_table(inode_table)?;
+        self.storage.set_old_directories(true);
+
+        let mut blocks = Vec::new();
+        let mut seen = HashSet::new();
+        let mut dirs = vec![INO_ROOT];
+
+        while let Some(ino) = dirs.pop() {
+            let eb = self.storage.read_entry_block(self.entry_block_no(ino))?;
+            let mut bno = eb.more_data;
+
+            while bno != 0 && seen.insert(bno) {
+                let mut db = self.storage.read_directory_block(bno)?;
+
+                for entry in &mut db.entries {
+                    if entry.name.len() > MAX_NAME_LENGTH {
+                        return Err(PtfsError::Refused(format!(
+                            "name '{}' in directory block {} is longer than {} bytes, rename it with an older version first",
+                            entry.name, bno, MAX_NAME_LENGTH)));
+                    }
+
+                    // blocks without count have no file types either
+                    if entry.kind.is_none() {
+                        entry.kind = Some(self.storage.read_entry_block(self.entry_block_no(entry.ino))?.attr.kind);
+                    }
+                    if entry.kind == Some(FileType::Directory) {
+                        dirs.push(entry.ino);
+                    }
+                }
+
+                let next = db.next;
+                blocks.push((bno, db));
+                bno = next;
+            }
+        }
+
+        for (bno, db) in blocks {
+            self.storage.write_block(&AnyBlock::DirectoryBlock(db), bno)?;
+        }
+
+        self.storage.set_old_directories(false);
+        debug!("upgrade()  {} directory blocks rewritten", seen.len());
+        Ok(())
+    }
+    
+    
+    // Flush everything and mark the file system as cleanly unmounted. The
+    // clean state is written only after all blocks are on the disk.
+    pub fn close(&mut self) -> Result<(), PtfsError> {
+        self.sync()?;
+
+        if self.mode != MountMode::ReadWrite {
+            return Ok(());
+        }
+
+        self.state = STATE_CLEAN;
+        self.write_fsinfo()?;
+        self.storage.flush()?;
+        self.storage.sync()
+    }
+
+
+    // flushes and waits until the backing store has everything on disk
+    pub fn sync(&mut self) -> Result<(), PtfsError> {
+        self.flush()?;
+
+        if self.mode == MountMode::ReadWrite {
+            self.storage.sync()?;
+        }
+        Ok(())
+    }
+
+
+    // like sync(), but leaves the metadata of the image file alone
+    pub fn sync_data(&mut self) -> Result<(), PtfsError> {
+        self.flush()?;
+
+        if self.mode == MountMode::ReadWrite {
+            self.storage.sync_data()?;
+        }
+        Ok(())
+    }
+        
+
+    pub fn flush(&mut self) -> Result<(), PtfsError> {
+        debug!("flush()");
+        
+        if self.mode != MountMode::ReadWrite {
+            debug!("  {:?} mode, nothing is written", self.mode);
+            return Ok(());
+        }
+
+        if self.frozen {
+            debug!("  frozen, nothing is written");
+            return Ok(());
+        }
+        
+        if self.epoch != 0 && !self.owns_image() {
+            return Err(PtfsError::Refused("image was mounted by another instance, not writing".to_string()));
+        }
+        
+        // may allocate blocks, so they go before the bitmap
+        self.write_inode_table()?;
+        self.write_bad_blocks()?;
+
+        debug!("  writing fsinfo block");
+        self.write_fsinfo()?;
+
+        self.write_bitmap()?;
+        
+        debug!("  writing {} cached blocks", self.blocks.len());
+        let mut failed = None;
+        for (key, v) in &self.blocks {
+            if let Err(err) = self.storage.write_block(v, *key) {
+                failed = Some((*key, err));
+                break;
+            }
+        }
+        if let Some((bno, err)) = failed {
+            self.count_failure(bno, &err);
+            return Err(err);
+        }
+        self.dirty_blocks.clear();
+        
+        self.storage.flush()
+    }
+
+
+    // Writes the given blocks if they wait in the cache, and the bitmap
+    // first so none of them is marked free on the disk. Returns how many
+    // were written. The rest of the cache waits for the next flush().
+    pub fn flush_blocks(&mut self, blocks: &[u64]) -> Result<usize, PtfsError> {
+        if self.mode != MountMode::ReadWrite || self.frozen {
+            return Ok(0);
+        }
+
+        if self.epoch != 0 && !self.owns_image() {
+            return Err(PtfsError::Refused("image was mounted by another instance, not writing".to_string()));
+        }
+
+        let dirty: Vec<u64> = blocks.iter().copied().filter(|bno| self.dirty_blocks.contains(bno)).collect();
+        if !dirty.is_empty() {
+            self.write_bitmap()?;
+        }
+
+        for bno in &dirty {
+            if let Err(err) = self.storage.write_block(&self.blocks[bno], *bno) {
+                self.count_failure(*bno, &err);
+                return Err(err);
+            }
+            self.dirty_blocks.remove(bno);
+        }
+
+        self.storage.flush()?;
+        Ok(dirty.len())
+    }
+
+
+    
+    // Empties the cache, writing it first. Returns the number of blocks
+    // that were cached.
+    pub fn drop_blocks(&mut self) -> Result<usize, PtfsError> {
+        // changes made while frozen are only in the cache
+        if self.frozen {
+            return Err(PtfsError::Refused("file system is frozen".to_string()));
+        }
+        self.flush()?;
+
+        let count = self.blocks.len();
+        self.blocks.clear();
+        self.last_used.clear();
+        self.stats.evictions += count as u64;
+        Ok(count)
+    }
+
+
+    pub fn set_max_blocks(&mut self, max_blocks: Option<usize>) {
+        self.max_blocks = max_blocks;
+    }
+
+
+    // Evicts blocks when the cache is full, an eighth of it at once so the
+    // cache isn't searched for every block. Blocks that are not waiting to
+    // be written go first, then the ones unused for the longest time.
+    fn make_room(&mut self) -> Result<(), PtfsError> {
+        let max = match self.max_blocks {
+            Some(max) if self.blocks.len() >= max => max,
+            _ => return Ok(()),
+        };
+
+        // evicting would write, the cache grows until the thaw instead
+        if self.frozen {
+            return Ok(());
+        }
+
+        let mut victims: Vec<u64> = self.blocks.keys().copied().collect();
+        victims.sort_by_key(|bno| (self.dirty_blocks.contains(bno), self.last_used.get(bno).copied().unwrap_or(0)));
+        victims.truncate(self.blocks.len() + 1 - max + max / 8);
+
+        // callers change cached blocks in place, so all of them are written
+        if self.mode == MountMode::ReadWrite {
+            if self.epoch != 0 && !self.owns_image() {
+                return Err(PtfsError::Refused("image was mounted by another instance, not writing".to_string()));
+            }
+
+            self.write_bitmap()?;
+            for bno in &victims {
+                self.storage.write_block(&self.blocks[bno], *bno)?;
+            }
+        }
+
+        debug!("make_room() evicting {} of {} blocks", victims.len(), self.blocks.len());
+        for bno in &victims {
+            self.blocks.remove(bno);
+            self.dirty_blocks.remove(bno);
+            self.last_used.remove(bno);
+        }
+        self.stats.evictions += victims.len() as u64;
+
+        Ok(())
+    }
+
+
+    fn write_bitmap(&mut self) -> Result<(), PtfsError> {
+        if !self.dirty_bitmap.is_empty() {
+            debug!("  writing {} bitmap blocks", self.dirty_bitmap.len());
+        }
+
+        while let Some(i) = self.dirty_bitmap.pop_first() {
+            self.storage.write_data_block(&self.bitmap[i], 3+i as u64)?;
+        }
+
+        Ok(())
+    }
+
+
+    pub fn size_filesystem(&mut self, size: u64) -> Result<(), PtfsError> {
+        debug!("size_filesystem()  writing {} blocks", size);
+
+        let db = DataBlock::new();
+        for i in 0..size {
+            self.storage.write_data_block(&db, i)?;
+        }
+
+        let bites_per_block = BLOCK_SIZE as u64 * 8;
+        let bm_size = size / bites_per_block + 1;
+        for _i in 0..bm_size {
+            self.bitmap.push(DataBlock::new());
+        }
+        
+        self.block_count = size;
+        self.count_free_blocks();
+        
+        // mark bitmap blocks as taken
+        // block 0 is reserved, block 1 is root inode
+        for i in 0..bm_size {
+            self.take_block((3 + i) as usize)?;
+        }
+
+        self.flush()
+    }
+    
+
+    fn calculate_bit_addr(bit_no: usize) -> (usize, usize, usize) {
+        let bm_block = bit_no / (BLOCK_SIZE * 8);
+        let bm_byte = (bit_no - bm_block * BLOCK_SIZE * 8) / 8;
+        let bm_bit = bit_no % 8;
+
+        (bm_block, bm_byte, bm_bit)        
+    }
+    
+    
+    pub fn take_block(&mut self, bit_no: usize) -> Result<(), PtfsError> {
+        let bit_addr = BlockCache::calculate_bit_addr(bit_no);
+        
+        // println!("Bit {} is found in block {} byte {} bit {}", bit_no, bit_addr.0, bit_addr.1, bit_addr.2);
+    
+        match self.bitmap.get_mut(bit_addr.0) {
+            None => {
+                Err(PtfsError::Corrupt(format!("block {} is not covered by the bitmap", bit_no)))
+            }
+            Some(db) => {
+                if db.data[bit_addr.1] & (1 << bit_addr.2) == 0 {
+                    db.data[bit_addr.1] |= 1 << bit_addr.2;
+                    self.free_blocks = self.free_blocks.saturating_sub(1);
+                    self.dirty_bitmap.insert(bit_addr.0);
+
+                    if let Some(free) = self.group_free.get_mut(bit_addr.0) {
+                        *free = free.saturating_sub(1);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    
+    pub fn is_block_used(&self, bno: u64) -> bool {
+        self.get_bitmap_bit(bno as usize)
+    }
+
+
+    fn get_bitmap_bit(&self, bit_no: usize) -> bool {
+        let bit_addr = BlockCache::calculate_bit_addr(bit_no);
+        
+        // println!("Bit {} is found in block {} byte {} bit {}", bit_no, bit_addr.0, bit_addr.1, bit_addr.2);
+    
+        match self.bitmap.get(bit_addr.0) {
+            // blocks outside of the bitmap can't be used
+            None => true,
+            Some(db) => (db.data[bit_addr.1] & (1 << bit_addr.2)) > 0,
+        }
+    }
+    
+    
+    // first free block at or after start, wraps around at the end
+    fn find_free_block_from(&self, start: usize) -> Option<usize> {
+        let groups = self.group_free.len();
+        let count = self.block_count as usize;
+        let start = if start < count {start} else {0};
+        let first = start / GROUP_SIZE;
+
+        // the group of start is visited twice, from start to its end and
+        // after the wrap from its beginning up to start
+        for n in 0..=groups {
+            let group = (first + n) % groups;
+            if self.group_free[group] == 0 {
+                continue;
+            }
+
+            let group_start = group * GROUP_SIZE;
+            let from = if n == 0 {start} else {group_start};
+            let to = if n == groups {start} else {std::cmp::min(group_start + GROUP_SIZE, count)};
+
+            if let Some(bit_no) = self.find_free_in_range(from, to) {
+                return Some(bit_no);
+            }
+        }
+
+        None
+    }
+
+
+    fn find_free_in_range(&self, from: usize, to: usize) -> Option<usize> {
+        let mut bit_no = from;
+
+        while bit_no < to {
+            let bit_addr = BlockCache::calculate_bit_addr(bit_no);
+            let full = self.bitmap.get(bit_addr.0).is_none_or(|db| db.data[bit_addr.1] == 255);
+
+            if full {
+                // skip the rest of the byte
+                bit_no = (bit_no / 8 + 1) * 8;
+            }
+            else if !self.get_bitmap_bit(bit_no) {
+                return Some(bit_no);
+            }
+            else {
+                bit_no += 1;
+            }
+        }
+
+        None
+    }
+
+
+    pub fn find_free_block(&self) -> Option<usize> {
+        let found = self.find_free_block_from(0);
+
+        if let Some(bit_no) = found {
+            debug!("found free block at {}", bit_no);
+        }
+
+        found
+    }
+    
+    
+    pub fn release_block(&mut self, bno: u64) -> Result<(), PtfsError> {
+        let bit_addr = BlockCache::calculate_bit_addr(bno as usize);
+    
+        match self.bitmap.get_mut(bit_addr.0) {
+            None => {
+                Err(PtfsError::Corrupt(format!("block {} is not covered by the bitmap", bno)))
+            }
+            Some(db) => {
+                // bad blocks stay marked as used
+                if db.data[bit_addr.1] & (1 << bit_addr.2) != 0 && !self.bad_blocks.contains(&bno) {
+                    db.data[bit_addr.1] &= !(1 << bit_addr.2);
+                    self.free_blocks += 1;
+                    self.dirty_bitmap.insert(bit_addr.0);
+
+                    if let Some(free) = self.group_free.get_mut(bit_addr.0) {
+                        *free += 1;
+                    }
+                }
+                self.blocks.remove(&bno);
+                self.dirty_blocks.remove(&bno);
+                Ok(())
+            }
+        }
+    }
+
 
-    #[test]
-    fn test_bit_set() {
-        let mut storage = BlockCache::new("/tmp/ptfs_test_arena");
-        storage.take_block(0);
-        storage.take_block(7);
-        storage.take_block(8);
-        storage.take_block(17);
+    // the block that holds the entry of an inode
+    pub fn entry_block_no(&self, ino: u64) -> u64 {
+        self.inodes.get(&ino).copied().unwrap_or(ino)
+    }
 
-        // second block
-        storage.take_block(8192);
-        storage.take_block(8199);
-        storage.take_block(8200);        
+
+    // Allocates the entry block for a new inode and returns the inode
+    // number and the block number.
+    pub fn allocate_inode(&mut self) -> Result<(u64, u64), PtfsError> {
+        self.allocate_inode_near(0)
     }
-}
 
 
-pub struct BlockCache {
-    pub bitmap: Vec<DataBlock>,
-    
-    // just in memory for now
-    blocks: HashMap<u64, AnyBlock>,
-    
-    storage: BlockIo, 
-}
+    pub fn allocate_inode_near(&mut self, goal: u64) -> Result<(u64, u64), PtfsError> {
+        let bno = match self.pop_free_list() {
+            Some(bno) => bno,
+            None => self.allocate_block_near(goal)?,
+        };
+
+        if !self.inodes.contains_key(&bno) {
+            return Ok((bno, bno));
+        }
 
+        // an inode that was relocated away from here still has this number
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, bno);
 
-impl BlockCache {
+        Ok((ino, bno))
+    }
 
 
-    pub fn new(backingstore: &str) -> BlockCache {
-        let cache = BlockCache {
-            bitmap: Vec::new(),
-            blocks: HashMap::new(),
-            storage: BlockIo::new(backingstore),
-        };
-        
-        
-        cache
+    // frees the entry block of an inode, the blocks it points to are the caller's business
+    pub fn release_inode(&mut self, ino: u64) -> Result<(), PtfsError> {
+        let bno = self.entry_block_no(ino);
+        self.inodes.remove(&ino);
+        self.release_metadata_block(bno)
     }
-    
-    
-    pub fn open(&mut self) {
 
-        // get fsinfo block
-        let fsinfo = self.storage.read_data_block(FSINFO_BLOCK);
-        let bm_size = fsinfo.data[4] as u64;
-        
-        println!("open()  reading {} bitmap blocks", bm_size);
-        
-        for i in 0..bm_size {
-            let bmblock = self.storage.read_data_block(3+i);
-            self.bitmap.push(bmblock);
+
+    // Puts a block on the free list, or back into the bitmap if the list is
+    // long enough. The block is marked on disk right away, the list head
+    // goes into the fsinfo block with the next flush.
+    pub fn release_metadata_block(&mut self, bno: u64) -> Result<(), PtfsError> {
+        if self.free_list.len() >= FREE_LIST_LIMIT || !self.is_block_used(bno) || self.bad_blocks.contains(&bno) {
+            return self.release_block(bno);
         }
+
+        let mut db = DataBlock::new();
+        db.data[0..8].copy_from_slice(FREE_MAGIC);
+        db.data[8..16].copy_from_slice(&u64::to_le_bytes(self.free_list.last().copied().unwrap_or(0)));
+
+        self.blocks.remove(&bno);
+        self.dirty_blocks.remove(&bno);
+        self.storage.write_data_block(&db, bno)?;
+        self.free_list.push(bno);
+
+        Ok(())
     }
-        
 
-    pub fn flush(&mut self) {
-        println!("flush()");
-        
-        println!("  writing fsinfo block");
-        let mut fsinfo = DataBlock::new();
-        fsinfo.data[4] = self.bitmap.len() as u8;
-        self.storage.write_data_block(&fsinfo, FSINFO_BLOCK).unwrap();
 
-        println!("  writing {} bitmap blocks", self.bitmap.len());
-        for i in 0..self.bitmap.len() {
-            let bmblock = &self.bitmap[i as usize];
-            self.storage.write_data_block(bmblock, 3+i as u64).unwrap();
+    pub fn free_list_blocks(&self) -> u64 {
+        self.free_list.len() as u64
+    }
+
+
+    // the head of the free list, skipping bad blocks
+    fn pop_free_list(&mut self) -> Option<u64> {
+        while let Some(bno) = self.free_list.pop() {
+            if !self.bad_blocks.contains(&bno) {
+                return Some(bno);
+            }
         }
-        
-        println!("  writing {} cached blocks", self.blocks.len());
-        let keys = self.blocks.keys();
-        for key in keys {
-            let v = self.blocks.get(key).unwrap();
-            self.storage.write_block(v, *key).unwrap();        
+        None
+    }
+
+
+    pub fn is_cached(&self, bno: u64) -> bool {
+        self.blocks.contains_key(&bno)
+    }
+
+
+    // Moves the entry block of an inode to a newly allocated block, 
+    // the inode number stays the same. Returns the new block number.
+    pub fn relocate_inode(&mut self, ino: u64) -> Result<u64, PtfsError> {
+        self.retrieve_entry_block(ino)?;
+        let new = self.allocate_metadata_block()?;
+        self.move_block(self.entry_block_no(ino), new)?;
+
+        if new == ino {
+            self.inodes.remove(&ino);
         }
-        
-        self.storage.flush();
+        else {
+            self.inodes.insert(ino, new);
+        }
+
+        Ok(new)
     }
 
-    
-    pub fn size_filesystem(&mut self, size: u64) {
-        println!("size_filesystem()  writing {} blocks", size);
 
-        let db = DataBlock::new();
-        for i in 0..size {
-            self.storage.write_data_block(&db, i).unwrap();
+    // Moves block old to the free block new and changes the block that
+    // points to it, the owner. Shrinking, defragmenting and getting off bad
+    // blocks build on this. PathTagFs::relocate_block() finds the owner.
+    pub fn relocate_block(&mut self, old: u64, new: u64, owner: BlockOwner) -> Result<(), PtfsError> {
+        debug!("relocate_block() {} to {}, owner {:?}", old, new, owner);
+
+        if new >= self.block_count || self.is_block_used(new) {
+            return Err(PtfsError::InvalidArgument);
         }
 
-        let bites_per_block = BLOCK_SIZE as u64 * 8;
-        let bm_size = size / bites_per_block + 1;
-        for _i in 0..bm_size {
-            self.bitmap.push(DataBlock::new());
+        // the block must be cached with its type before it moves
+        match owner {
+            BlockOwner::Inode(ino) => {
+                self.retrieve_entry_block(ino)?;
+            }
+            BlockOwner::ChainHead(ino) => {
+                if self.retrieve_entry_block(ino)?.attr.kind == FileType::Directory {
+                    self.retrieve_directory_block(old)?;
+                }
+                else {
+                    self.retrieve_index_block(old)?;
+                }
+            }
+            BlockOwner::DirectoryNext(_) => {
+                self.retrieve_directory_block(old)?;
+            }
+            BlockOwner::IndexNext(_) => {
+                self.retrieve_index_block(old)?;
+            }
+            BlockOwner::IndexSlot(_, _) => {
+                self.retrieve_data_block(old)?;
+            }
         }
-        
-        // mark bitmap blocks as taken
-        // block 0 is reserved, block 1 is root inode
-        for i in 0..bm_size {
-            self.take_block((3 + i) as usize);
+
+        self.take_block(new as usize)?;
+        self.move_block(old, new)?;
+
+        match owner {
+            BlockOwner::Inode(ino) if new == ino => {
+                self.inodes.remove(&ino);
+            }
+            BlockOwner::Inode(ino) => {
+                self.inodes.insert(ino, new);
+            }
+            BlockOwner::ChainHead(ino) => self.retrieve_entry_block(ino)?.more_data = new,
+            BlockOwner::DirectoryNext(bno) => self.retrieve_directory_block(bno)?.next = new,
+            BlockOwner::IndexNext(bno) => self.retrieve_index_block(bno)?.next = new,
+            BlockOwner::IndexSlot(bno, slot) => self.retrieve_index_block(bno)?.block[slot] = new,
         }
 
-        self.flush();        
+        Ok(())
     }
-    
 
-    fn calculate_bit_addr(bit_no: usize) -> (usize, usize, usize) {
-        let bm_block = bit_no / (BLOCK_SIZE * 8);
-        let bm_byte = (bit_no - bm_block * BLOCK_SIZE * 8) / 8;
-        let bm_bit = bit_no % 8;
 
-        (bm_block, bm_byte, bm_bit)        
+    // writes the cached block old to new, which is allocated already, and frees old
+    fn move_block(&mut self, old: u64, new: u64) -> Result<(), PtfsError> {
+        let block = self.blocks.remove(&old).unwrap();
+        self.dirty_blocks.remove(&old);
+        self.last_used.remove(&old);
+        self.write_block(block, new)?;
+        self.release_block(old)
     }
-    
-    
-    pub fn take_block(&mut self, bit_no: usize) {
-        let bit_addr = BlockCache::calculate_bit_addr(bit_no);
-        
-        // println!("Bit {} is found in block {} byte {} bit {}", bit_no, bit_addr.0, bit_addr.1, bit_addr.2);
-    
-        let db = &mut self.bitmap[bit_addr.0];
-        let data = &mut db.data;
-        data[bit_addr.1] |= 1 << bit_addr.2;
+
+
+    // sets up the group counters from the bitmap
+    fn count_free_blocks(&mut self) {
+        self.group_free = (0..self.bitmap.len()).map(|group| self.count_group_free(group)).collect();
+        self.free_blocks = self.group_free.iter().map(|free| *free as u64).sum();
     }
 
-    
-    fn get_bitmap_bit(&self, bit_no: usize) -> bool {
-        let bit_addr = BlockCache::calculate_bit_addr(bit_no);
-        
-        // println!("Bit {} is found in block {} byte {} bit {}", bit_no, bit_addr.0, bit_addr.1, bit_addr.2);
-    
-        let db = &self.bitmap[bit_addr.0];
-        let data = &db.data;
-        
-        (data[bit_addr.1] & (1 << bit_addr.2)) > 0
+
+    fn count_group_free(&self, group: usize) -> u32 {
+        let blocks = std::cmp::min(GROUP_SIZE as u64, self.block_count.saturating_sub((group * GROUP_SIZE) as u64)) as usize;
+        let data = &self.bitmap[group].data;
+
+        let full_bytes = blocks / 8;
+        let mut used: u32 = data[..full_bytes].iter().map(|byte| byte.count_ones()).sum();
+
+        let rest = blocks % 8;
+        if rest > 0 {
+            used += (data[full_bytes] & ((1 << rest) - 1)).count_ones();
+        }
+
+        blocks as u32 - used
     }
-    
-    
-    pub fn find_free_block(&self) -> usize {
-        let bm_blocks = self.bitmap.len();
-        let block = 0;
-           
-        for n in 0..bm_blocks {
-            let db = &self.bitmap[n];
-            let data = &db.data;
-            
-            for b in 0..512 {
-                if data[b] != 255 {
-                    // there are free bits in this byte
-                    let bit_start = n * BLOCK_SIZE * 8 + b * 8;
-                    for bit_no in bit_start..bit_start+8 {
-                        if self.get_bitmap_bit(bit_no) == false {
-                            // this was an free entry
-                            println!("found free block at {}", bit_no);
-                            return bit_no;
-                        }    
-                    }
-                }
-            }
+
+
+    pub fn group_free_blocks(&self) -> &[u32] {
+        &self.group_free
+    }
+
+
+    pub fn free_blocks(&self) -> u64 {
+        self.free_blocks
+    }
+
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+
+    pub fn reserved_blocks(&self) -> u64 {
+        self.reserved_blocks
+    }
+
+
+    pub fn set_reserved_blocks(&mut self, count: u64) {
+        self.reserved_blocks = count;
+    }
+
+
+    // privileged callers may use the reserved blocks
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.privileged = privileged;
+    }
+
+
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.sync_mode
+    }
+
+
+    pub fn set_dirty_limit(&mut self, dirty_limit: usize) {
+        self.dirty_limit = std::cmp::max(dirty_limit, 1);
+    }
+
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.dirty_blocks.len()
+    }
+
+
+    // more blocks would push out others, see make_room()
+    pub fn is_full(&self) -> bool {
+        self.max_blocks.is_some_and(|max| self.blocks.len() >= max)
+    }
+
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            cached: self.blocks.len() as u64,
+            dirty: self.dirty_blocks.len() as u64,
+            degraded: self.storage.is_degraded(),
+            dirty_limit: self.dirty_limit as u64,
+            ..self.stats
         }
-        
-        block
     }
-    
-    
-    pub fn allocate_block(&mut self) -> usize {
-        let n = self.find_free_block();
-        self.take_block(n);
-        n
+
+
+    fn count_lookup(&mut self, bno: u64) {
+        self.clock += 1;
+        self.last_used.insert(bno, self.clock);
+
+        if self.blocks.contains_key(&bno) {
+            self.stats.hits += 1;
+        }
+        else {
+            self.stats.misses += 1;
+        }
+    }
+
+
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
+
+    pub fn diagnostics_mut(&mut self) -> &mut Diagnostics {
+        &mut self.diagnostics
+    }
+
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn inject_faults(&mut self, plan: crate::faults::FaultPlan) {
+        self.storage.inject_faults(plan);
+    }
+
+
+    // the backing store bypasses the page cache of the host
+    pub fn set_direct_io(&mut self, direct: bool) -> Result<(), PtfsError> {
+        self.storage.set_direct(direct)
+    }
+
+
+    pub fn set_io_timeout(&mut self, timeout: Option<Duration>) -> Result<(), PtfsError> {
+        self.storage.set_timeout(timeout)
+    }
+
+
+    pub fn set_frozen(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+
+    // Lets a read-only open() proceed while a writer holds the image. It
+    // reads what the writer has flushed, which may change underneath.
+    pub fn set_shared(&mut self, shared: bool) -> Result<(), PtfsError> {
+        if self.mode != MountMode::ReadOnly {
+            return Err(PtfsError::InvalidArgument);
+        }
+        self.shared = shared;
+        Ok(())
+    }
+
+
+    // for ordinary data, the reserved blocks are left alone
+    pub fn allocate_block(&mut self) -> Result<u64, PtfsError> {
+        self.allocate_block_near(0)
+    }
+
+
+    // goal is a block the new one relates to, like the entry block of its
+    // directory, the allocation policy decides if it matters
+    pub fn allocate_block_near(&mut self, goal: u64) -> Result<u64, PtfsError> {
+        if !self.privileged && self.free_blocks <= self.reserved_blocks {
+            return Err(PtfsError::NoSpace);
+        }
+
+        self.allocate_metadata_block_near(goal)
+    }
+
+
+    // may dig into the reserved blocks
+    pub fn allocate_metadata_block(&mut self) -> Result<u64, PtfsError> {
+        self.allocate_metadata_block_near(0)
+    }
+
+
+    pub fn allocate_metadata_block_near(&mut self, goal: u64) -> Result<u64, PtfsError> {
+        if let Some(bno) = self.pop_free_list() {
+            return Ok(bno);
+        }
+
+        let found = match self.policy {
+            AllocPolicy::FirstFree => self.find_free_block(),
+            AllocPolicy::NearParent => self.find_free_block_from(goal as usize),
+        };
+
+        match found {
+            None => Err(PtfsError::NoSpace),
+            Some(n) => {
+                self.take_block(n)?;
+                Ok(n as u64)
+            }
+        }
     }
 
     
-    pub fn write_block(&mut self, ab: AnyBlock, no: u64) -> Result<usize, Error> {
+    pub fn write_block(&mut self, ab: AnyBlock, no: u64) -> Result<usize, PtfsError> {
+
+        if self.mode != MountMode::ReadWrite {
+            return Err(PtfsError::ReadOnly);
+        }
+
+        if !self.blocks.contains_key(&no) {
+            self.make_room()?;
+        }
+        self.clock += 1;
+        self.last_used.insert(no, self.clock);
+
+        if self.sync_mode != SyncMode::Sync {
+            // flush() writes the bitmap before the blocks
+            self.blocks.insert(no, ab);
+            self.dirty_blocks.insert(no);
+            if self.dirty_blocks.len() >= self.dirty_limit {
+                debug!("write_block() {} dirty blocks, flushing", self.dirty_blocks.len());
+                self.stats.throttled += 1;
+                self.flush()?;
+            }
+            return Ok(BLOCK_SIZE);
+        }
+
+        // a crash must not leave a written block marked as free
+        self.write_bitmap()?;
 
         let result = self.storage.write_block(&ab, no);
         self.blocks.insert(no, ab);
+        if let Err(err) = &result {
+            self.count_failure(no, err);
+        }
         
         return result;
     }
     
     
-    fn check_cache(&mut self, bno: u64) -> bool {
-        let abo = self.blocks.get(&bno);
-        
-        match abo {
-            None => {
-                return false;
-            }
-            Some(_ab) => {
-                return true;
-            }
+    // in rescue mode, block numbers from damaged chains may point anywhere
+    fn check_readable(&self, bno: u64) -> Result<(), PtfsError> {
+        if self.mode == MountMode::Rescue && bno >= self.storage.block_count() {
+            return Err(PtfsError::Corrupt(format!("block {} is outside of the image", bno)));
         }
+        
+        Ok(())
     }
     
     
-    pub fn retrieve_entry_block(&mut self, bno: u64) -> Option<&mut EntryBlock> {
-        println!("retrieve_entry_block() block={}", bno);                
+    pub fn retrieve_entry_block(&mut self, ino: u64) -> Result<&mut EntryBlock, PtfsError> {
+        let bno = self.entry_block_no(ino);
+        debug!("retrieve_entry_block() inode={} block={}", ino, bno);                
+        self.count_lookup(bno);
 
-        let in_cache = self.check_cache(bno);
-        let mut result = None;
-         
-        if in_cache {
-            let ab_opt = self.blocks.get_mut(&bno);
-            
-            match ab_opt {
-                None => {panic!("No entry block");}
-                Some(ab) => {
-                    if let AnyBlock::EntryBlock(eb) = ab {
-                        result = Some(eb);
-                    }
-                }
-            }
-        }
-        else {
-            let eb = self.storage.read_entry_block(bno);
+        if !self.blocks.contains_key(&bno) {
+            let eb = self.check_readable(bno).and_then(|_| self.storage.read_entry_block(bno));
+            let eb = self.reported("entry block", bno, eb)?;
+            self.make_room()?;
             self.blocks.insert(bno, AnyBlock::EntryBlock(eb));
-
-            result = self.retrieve_entry_block(bno);
         }
 
-        result
+        match self.blocks.get_mut(&bno) {
+            Some(AnyBlock::EntryBlock(eb)) => Ok(eb),
+            _ => {
+                self.diagnostics.record("entry block", Some(bno), "cached as another block type");
+                Err(PtfsError::Corrupt(format!("block {} is no entry block", bno)))
+            }
+        }
     }
 
 
-    pub fn retrieve_directory_block(&mut self, bno: u64) -> Option<&mut DirectoryBlock> {
-        println!("retrieve_directory_block() block={}", bno);                
+    pub fn retrieve_directory_block(&mut self, bno: u64) -> Result<&mut DirectoryBlock, PtfsError> {
+        debug!("retrieve_directory_block() block={}", bno);                
+        self.count_lookup(bno);
         
-        let in_cache = self.check_cache(bno);
-        let mut result = None;
-         
-        if in_cache {
-            let ab_opt = self.blocks.get_mut(&bno);
-            
-            match ab_opt {
-                None => {panic!("No directory block");}
-                Some(ab) => {
-                    if let AnyBlock::DirectoryBlock(eb) = ab {
-                        result = Some(eb);
-                    } 
-                    else {
-//                        panic!("{} is no directory block", bno);
-                    }
-                }
-            }
-        }
-        else {
-            println!("  disk read, caching");                
+        if !self.blocks.contains_key(&bno) {
+            debug!("  disk read, caching");                
 
-            let db = self.storage.read_directory_block(bno);
+            let db = self.check_readable(bno).and_then(|_| self.storage.read_directory_block(bno));
+            let db = self.reported("directory block", bno, db)?;
+            self.make_room()?;
             self.blocks.insert(bno, AnyBlock::DirectoryBlock(db));
-
-            result = self.retrieve_directory_block(bno);
         }
 
-        result
+        match self.blocks.get_mut(&bno) {
+            Some(AnyBlock::DirectoryBlock(db)) => Ok(db),
+            _ => {
+                self.diagnostics.record("directory block", Some(bno), "cached as another block type");
+                Err(PtfsError::Corrupt(format!("block {} is no directory block", bno)))
+            }
+        }
     }
 
 
-    pub fn retrieve_index_block(&mut self, bno: u64) -> Option<&mut IndexBlock> {
-        println!("retrieve_index_block() block={}", bno);                
+    pub fn retrieve_index_block(&mut self, bno: u64) -> Result<&mut IndexBlock, PtfsError> {
+        debug!("retrieve_index_block() block={}", bno);                
+        self.count_lookup(bno);
         
-        let in_cache = self.check_cache(bno);
-        let mut result = None;
-         
-        if in_cache {
-            let ab_opt = self.blocks.get_mut(&bno);
-            
-            match ab_opt {
-                None => {}
-                Some(ab) => {
-                    if let AnyBlock::IndexBlock(eb) = ab {
-                        result = Some(eb);
-                    }
-                }
-            }
+        if !self.blocks.contains_key(&bno) {
+            let ib = self.check_readable(bno).and_then(|_| self.storage.read_index_block(bno));
+            let ib = self.reported("index block", bno, ib)?;
+            self.make_room()?;
+            self.blocks.insert(bno, AnyBlock::IndexBlock(ib));
         }
-        else {
-            let db = self.storage.read_index_block(bno);
-            self.blocks.insert(bno, AnyBlock::IndexBlock(db));
 
-            result = self.retrieve_index_block(bno);
+        match self.blocks.get_mut(&bno) {
+            Some(AnyBlock::IndexBlock(ib)) => Ok(ib),
+            _ => {
+                self.diagnostics.record("index block", Some(bno), "cached as another block type");
+                Err(PtfsError::Corrupt(format!("block {} is no index block", bno)))
+            }
         }
-
-        result
     }
 
 
-    pub fn retrieve_data_block(&mut self, bno: u64) -> Option<&mut DataBlock> {
-        println!("retrieve_data_block() block={}", bno);                
+    pub fn retrieve_data_block(&mut self, bno: u64) -> Result<&mut DataBlock, PtfsError> {
+        debug!("retrieve_data_block() block={}", bno);                
+        self.count_lookup(bno);
         
-        let in_cache = self.check_cache(bno);
-        let mut result = None;
-         
-        if in_cache {
-            let ab_opt = self.blocks.get_mut(&bno);
-            
-            match ab_opt {
-                None => {}
-                Some(ab) => {
-                    if let AnyBlock::DataBlock(eb) = ab {
-                        result = Some(eb);
-                    }
-                }
-            }
-        }
-        else {
-            let db = self.storage.read_data_block(bno);
+        if !self.blocks.contains_key(&bno) {
+            let db = self.check_readable(bno).and_then(|_| self.storage.read_data_block(bno));
+            let db = self.reported("data block", bno, db)?;
+            self.make_room()?;
             self.blocks.insert(bno, AnyBlock::DataBlock(db));
+        }
 
-            result = self.retrieve_data_block(bno);
+        match self.blocks.get_mut(&bno) {
+            Some(AnyBlock::DataBlock(db)) => Ok(db),
+            _ => {
+                self.diagnostics.record("data block", Some(bno), "cached as another block type");
+                Err(PtfsError::Corrupt(format!("block {} is no data block", bno)))
+            }
         }
+    }
+}
 
-        result
-   }
-}
\ No newline at end of file
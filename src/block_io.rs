@@ -1,16 +1,190 @@
-use std::{fs::File, io::{Error, Read, Seek, Write}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{fs::File, io::{Error, ErrorKind, Read, Seek, Write}, os::fd::AsRawFd, time::{Duration, SystemTime, UNIX_EPOCH}};
 use fuser::FileType;
+use log::{debug, warn};
 
-use crate::{nodes::{AnyBlock, DataBlock, DirectoryBlock, DirectoryEntry, EntryBlock, IndexBlock, ENTRY_SIZE}, path_tag_fs::BLOCK_SIZE};
+use crate::error::PtfsError;
+use crate::io_worker::IoWorker;
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::faults::{Fault, FaultPlan};
+use crate::{nodes::{AnyBlock, DataBlock, DirectoryBlock, DirectoryEntry, EntryBlock, Extension, IndexBlock, ENTRY_SIZE, MAX_ENTRIES, MAX_NAME_LENGTH}, path_tag_fs::BLOCK_SIZE};
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+
+    fn any_kind() -> impl Strategy<Value = FileType> {
+        prop_oneof![
+            Just(FileType::NamedPipe),
+            Just(FileType::CharDevice),
+            Just(FileType::BlockDevice),
+            Just(FileType::Directory),
+            Just(FileType::RegularFile),
+            Just(FileType::Symlink),
+            Just(FileType::Socket),
+        ]
+    }
+
+    // a range of several ten thousand years around the epoch
+    fn any_time() -> impl Strategy<Value = SystemTime> {
+        (-(1i64 << 40)..(1i64 << 40), 0..1_000_000_000u32).prop_map(|(secs, nanos)| {
+            let base = if secs >= 0 {UNIX_EPOCH + Duration::from_secs(secs as u64)} else {UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())};
+            base + Duration::from_nanos(nanos as u64)
+        })
+    }
+
+    // names are limited in bytes, not in characters, and cannot hold NUL
+    fn any_name() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[^\\x00]{0,60}",
+            "[a-z]{240}",
+        ].prop_filter("name too long", |name| name.len() <= MAX_NAME_LENGTH)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_entry_roundtrip(name in any_name(), kind in any_kind(), is_tag in any::<bool>(),
+                                ino in any::<u64>(), size in any::<u64>(), blocks in any::<u64>(),
+                                times in prop::array::uniform4(any_time()), 
+                                ids in prop::array::uniform6(any::<u32>()), perm in any::<u16>(),
+                                more_data in any::<u64>(),
+                                exts in prop::collection::vec((1u8..=255, prop::collection::vec(any::<u8>(), 0..200)), 0..8)) {
+            let mut b = EntryBlock::new(&name, ino, kind, is_tag);
+            b.more_data = more_data;
+            b.attr.size = size;
+            b.attr.blocks = blocks;
+            b.attr.atime = times[0];
+            b.attr.mtime = times[1];
+            b.attr.ctime = times[2];
+            b.attr.crtime = times[3];
+            b.attr.perm = perm;
+            b.attr.nlink = ids[0];
+            b.attr.uid = ids[1];
+            b.attr.gid = ids[2];
+            b.attr.rdev = ids[3];
+            b.attr.blksize = ids[4];
+            b.attr.flags = ids[5];
+            for (kind, value) in &exts {
+                b.set_extension(*kind, value).unwrap();
+            }
+
+            let eb = parse_entry_block(&encode_entry_block(&b), 0).unwrap();
+
+            prop_assert_eq!(&eb.name, &b.name);
+            prop_assert_eq!(eb.is_tag, b.is_tag);
+            prop_assert_eq!(eb.more_data, b.more_data);
+            prop_assert_eq!(eb.attr, b.attr);
+            prop_assert_eq!(eb.extensions.len(), b.extensions.len());
+            for ext in &b.extensions {
+                prop_assert_eq!(eb.extension(ext.kind), Some(&ext.value[..]));
+            }
+        }
+
+        #[test]
+        fn prop_directory_roundtrip(entries in prop::collection::vec((1..u64::MAX, any_name(), prop::option::of(any_kind())), 0..=MAX_ENTRIES),
+                                    next in any::<u64>()) {
+            let mut b = DirectoryBlock::new();
+            b.next = next;
+            for (ino, name, kind) in &entries {
+                b.entries.push(DirectoryEntry{ino: *ino, name: name.to_string(), kind: *kind});
+            }
+
+            let db = parse_directory_block(&encode_directory_block(&b), 0).unwrap();
+
+            prop_assert_eq!(db.next, next);
+            prop_assert_eq!(db.entries.len(), entries.len());
+            for (entry, (ino, name, kind)) in db.entries.iter().zip(entries.iter()) {
+                prop_assert_eq!(entry.ino, *ino);
+                prop_assert_eq!(&entry.name, name);
+                prop_assert_eq!(entry.kind, *kind);
+            }
+        }
+
+        #[test]
+        fn prop_index_roundtrip(blocks in prop::collection::vec(any::<u64>(), BLOCK_SIZE/8 - 1), next in any::<u64>()) {
+            let mut b = IndexBlock::new();
+            b.block.copy_from_slice(&blocks);
+            b.next = next;
+
+            let ib = parse_index_block(&encode_index_block(&b), 0).unwrap();
+
+            prop_assert_eq!(&ib.block[..], &b.block[..]);
+            prop_assert_eq!(ib.next, next);
+        }
+    }
+
+    #[test]
+    fn test_extensions() {
+        let mut b = EntryBlock::new("link", 3, FileType::Symlink, false);
+        b.set_extension(EXT_SYMLINK_TARGET, b"/somewhere/else").unwrap();
+        b.set_extension(99, b"from the future").unwrap();
+        b.set_extension(EXT_RDEV, &[1, 2]).unwrap();
+        b.set_extension(EXT_RDEV, &[3, 4, 5]).unwrap();
+        assert!(matches!(b.set_extension(0, b""), Err(PtfsError::InvalidArgument)));
+
+        let eb = parse_entry_block(&encode_entry_block(&b), 0).unwrap();
+        assert_eq!(eb.extension(EXT_SYMLINK_TARGET).unwrap(), b"/somewhere/else");
+        assert_eq!(eb.extension(99).unwrap(), b"from the future");
+        assert_eq!(eb.extension(EXT_RDEV).unwrap(), &[3, 4, 5]);
+        assert_eq!(eb.extension(EXT_XATTR), None);
+
+        // the area can be filled exactly
+        let mut b = EntryBlock::new("full", 3, FileType::RegularFile, false);
+        let big = vec![7u8; EXTENSION_SPACE - 2 * EXTENSION_HEADER - 1];
+        b.set_extension(EXT_XATTR, &big).unwrap();
+        assert!(matches!(b.set_extension(EXT_TAG_LIST, &[1, 2]), Err(PtfsError::NoSpace)));
+        b.set_extension(EXT_TAG_LIST, &[1]).unwrap();
+        assert_eq!(b.extension_space(), 0);
+        assert!(b.remove_extension(EXT_TAG_LIST));
+        assert!(!b.remove_extension(EXT_TAG_LIST));
+
+        let eb = parse_entry_block(&encode_entry_block(&b), 0).unwrap();
+        assert_eq!(eb.extension(EXT_XATTR).unwrap(), &big[..]);
+
+        // a record running past the block is damage
+        let mut data = encode_entry_block(&b);
+        data[EXTENSION_START+1..EXTENSION_START+3].copy_from_slice(&(EXTENSION_SPACE as u16).to_le_bytes());
+        assert!(matches!(parse_entry_block(&data, 0), Err(PtfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_time_before_epoch() {
+        let mut b = EntryBlock::new("old", 1, FileType::RegularFile, false);
+        b.attr.mtime = UNIX_EPOCH - Duration::from_nanos(1_500_000_001);
+        b.attr.atime = UNIX_EPOCH - Duration::from_secs(7);
+
+        let eb = parse_entry_block(&encode_entry_block(&b), 0).unwrap();
+        assert_eq!(eb.attr.mtime, b.attr.mtime);
+        assert_eq!(eb.attr.atime, b.attr.atime);
+    }
+
+    #[test]
+    fn test_legacy_times() {
+        let b = EntryBlock::new("old", 1, FileType::RegularFile, false);
+        let mut data = encode_entry_block(&b);
+
+        // entry blocks of older images hold milliseconds
+        data[95] = 0;
+        data[40..48].copy_from_slice(&1_500u64.to_le_bytes());
+        let eb = parse_entry_block(&data, 0).unwrap();
+        assert_eq!(eb.attr.mtime, UNIX_EPOCH + Duration::from_millis(1_500));
+
+        // and 2 links for files with a single name
+        data[68..72].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(parse_entry_block(&data, 0).unwrap().attr.nlink, 1);
+    }
 
     #[test]
     fn test_entry_write_read() {
-        let mut bio = BlockIo::new("/tmp/entry_block");
+        let mut bio = BlockIo::new("/tmp/entry_block").unwrap();
         let b = EntryBlock::new("", 1, FileType::RegularFile, false);
+        let b_times = (b.attr.atime, b.attr.mtime, b.attr.ctime, b.attr.crtime);
         let ab = AnyBlock::EntryBlock(b);
         
         let result = bio.write_block(&ab, 0);
@@ -23,17 +197,13 @@ mod tests {
             
             let eb1 = EntryBlock::new("", 1, FileType::RegularFile, false);
             // now read it back and compare
-            let eb = bio.read_entry_block(0);
+            let eb = bio.read_entry_block(0).unwrap();
             
             assert_eq!(1, eb.attr.ino);
             assert_eq!(eb1.attr.size, eb.attr.size);
             assert_eq!(eb1.attr.blocks, eb.attr.blocks);
 
-// Some nanoseconds difference due to being stored as u64 ...
-//            assert_eq!(eb1.attr.atime, eb.attr.atime);
-//            assert_eq!(eb1.attr.mtime, eb.attr.mtime);
-//            assert_eq!(eb1.attr.ctime, eb.attr.ctime);
-//            assert_eq!(eb1.attr.crtime, eb.attr.crtime);
+            assert_eq!(b_times, (eb.attr.atime, eb.attr.mtime, eb.attr.ctime, eb.attr.crtime));
             assert_eq!(eb1.attr.kind, eb.attr.kind);
             assert_eq!(eb1.attr.perm, eb.attr.perm);
             assert_eq!(eb1.attr.nlink, eb.attr.nlink);
@@ -49,7 +219,7 @@ mod tests {
 
     #[test]
     fn test_data_write() {
-        let mut bio = BlockIo::new("/tmp/dump");
+        let mut bio = BlockIo::new("/tmp/dump").unwrap();
         let b = DataBlock::new();
         let ab = AnyBlock::DataBlock(b);
         
@@ -63,9 +233,42 @@ mod tests {
         }        
     }
 
+    #[test]
+    fn test_direct_io() {
+        let mut bio = BlockIo::new("/tmp/ptfs_test_direct").unwrap();
+        if bio.set_direct(true).is_err() {
+            println!("O_DIRECT is not supported here, skipping");
+            return;
+        }
+        assert!(bio.is_direct());
+
+        let mut db = DataBlock::new();
+        db.data[0] = 1;
+        db.data[BLOCK_SIZE - 1] = 2;
+        bio.write_data_block(&db, 3).unwrap();
+        assert_eq!(bio.block_count(), 4);
+        assert_eq!(bio.read_data_block(3).unwrap().data, db.data);
+
+        // past the end there is nothing to read
+        assert_eq!(bio.read_data_block(4).unwrap().data, [0; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_io_timeout() {
+        let mut bio = BlockIo::new("/tmp/ptfs_test_timeout").unwrap();
+        bio.set_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut db = DataBlock::new();
+        db.data[7] = 7;
+        bio.write_data_block(&db, 2).unwrap();
+        bio.sync().unwrap();
+        assert_eq!(bio.read_data_block(2).unwrap().data, db.data);
+        assert!(!bio.is_degraded());
+    }
+
     #[test]
     fn test_index_write_read() {
-        let mut bio = BlockIo::new("/tmp/dump");
+        let mut bio = BlockIo::new("/tmp/dump").unwrap();
         let mut b = IndexBlock::new();
         b.block[0] = 1;
         b.block[127] = 2000000;
@@ -82,7 +285,7 @@ mod tests {
             assert!(size == BLOCK_SIZE);            
         }
         
-        let ib = bio.read_index_block(0);
+        let ib = bio.read_index_block(0).unwrap();
         
         assert_eq!(ib.block[0], 1);        
         assert_eq!(ib.block[1], 0);        
@@ -90,24 +293,141 @@ mod tests {
         assert_eq!(ib.block[127], 2000000);        
         assert_eq!(ib.next, 2);        
     }
-}
 
+    #[test]
+    fn test_bad_entry_header() {
+        let mut bio = BlockIo::new("/tmp/bad_entry_block").unwrap();
+        let mut b = DataBlock::new();
+        b.data[0..8].copy_from_slice("NotEntry".as_bytes());
+        bio.write_block(&AnyBlock::DataBlock(b), 0).unwrap();
+        
+        assert!(matches!(bio.read_entry_block(0), Err(PtfsError::Corrupt(_))));
+        
+        // past the end of the image
+        assert!(matches!(bio.read_entry_block(100), Err(PtfsError::Corrupt(_))));
+        assert!(matches!(bio.read_entry_block(u64::MAX), Err(PtfsError::Corrupt(_))));
+    }
 
-fn store_time(time: SystemTime, storage: &mut[u8]) {
-    match time.duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => store(n.as_millis() as u64, storage),
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    #[test]
+    fn test_parse_bad_blocks() {
+        assert!(matches!(parse_entry_block(&[0; 10], 0), Err(PtfsError::Corrupt(_))));
+        assert!(matches!(parse_index_block(&[0; BLOCK_SIZE + 1], 0), Err(PtfsError::Corrupt(_))));
+
+        // names without terminator must stay within their slot
+        let mut data = [b'x'; BLOCK_SIZE];
+        data[BLOCK_SIZE-8..].copy_from_slice(&[0; 8]);
+        data[ENTRY_COUNT_POS] = MAX_ENTRIES as u8;
+        let db = parse_directory_block(&data, 0).unwrap();
+        assert_eq!(db.entries.len(), MAX_ENTRIES);
+        assert!(db.entries.iter().all(|entry| entry.name.len() <= MAX_NAME_LENGTH));
+
+        data[ENTRY_COUNT_POS] = MAX_ENTRIES as u8 + 1;
+        assert!(matches!(parse_directory_block(&data, 0), Err(PtfsError::Corrupt(_))));
+    }
+
+    #[test]
+    fn test_directory_entry_count() {
+        let mut b = DirectoryBlock::new();
+        b.entries.push(DirectoryEntry{ino: 5, name: "a".to_string(), kind: Some(FileType::RegularFile)});
+        b.entries.push(DirectoryEntry{ino: 6, name: "b".to_string(), kind: Some(FileType::Directory)});
+        b.entries.push(DirectoryEntry{ino: 7, name: "c".to_string(), kind: Some(FileType::Symlink)});
+        let mut data = encode_directory_block(&b);
+
+        // bytes past the counted slots are ignored
+        data[3*ENTRY_SIZE] = 9;
+        data[3*ENTRY_SIZE+8] = b'z';
+        assert_eq!(parse_directory_block(&data, 0).unwrap().entries.len(), 3);
+
+        // a cleared slot does not hide the ones behind it
+        data[ENTRY_SIZE..ENTRY_SIZE+8].copy_from_slice(&[0; 8]);
+        let names: Vec<String> = parse_directory_block(&data, 0).unwrap().entries.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["a", "c"]);
+
+        let kinds: Vec<Option<FileType>> = parse_directory_block(&data, 0).unwrap().entries.into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![Some(FileType::RegularFile), Some(FileType::Symlink)]);
+
+        // blocks of older images end at the first free slot and have no file types
+        assert_eq!(parse_old_directory_block(&data, 0).unwrap().entries.len(), 2);
+        data[ENTRY_COUNT_POS] = 0;
+        assert_eq!(parse_directory_block(&data, 0).unwrap().entries.len(), 0);
+        let entries = parse_old_directory_block(&data, 0).unwrap().entries;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, None);
+    }
+
+    #[test]
+    fn test_old_directory_names() {
+        // names of blocks without count run over the count and the file types
+        let mut data = [0; BLOCK_SIZE];
+        for slot in 0..2 {
+            let pos = slot * ENTRY_SIZE;
+            data[pos] = 5 + slot as u8;
+            data[pos+8..pos+ENTRY_SIZE].copy_from_slice(&[b'a' + slot as u8; LEGACY_NAME_LENGTH]);
+        }
+        data[2*ENTRY_SIZE] = 7;
+        data[2*ENTRY_SIZE+8] = b'c';
+
+        let entries = parse_old_directory_block(&data, 0).unwrap().entries;
+        let lengths: Vec<usize> = entries.iter().map(|entry| entry.name.len()).collect();
+        assert_eq!(lengths, vec![LEGACY_NAME_LENGTH, LEGACY_NAME_LENGTH, 1]);
+        assert!(entries.iter().all(|entry| entry.kind.is_none()));
+
+        // a name of the last slot does not run into the chain pointer
+        let mut data = [b'x'; BLOCK_SIZE];
+        data[BLOCK_SIZE-8..].copy_from_slice(&[0; 8]);
+        let entries = parse_old_directory_block(&data, 0).unwrap().entries;
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[MAX_ENTRIES-1].name.len(), ENTRY_SIZE - 16);
     }
 }
 
 
-fn read_time(storage: &[u8]) -> SystemTime {
-    let d = Duration::from_millis(to_u64(storage));
-    let time_opt = UNIX_EPOCH.checked_add(d);
+// Stores a timestamp as whole seconds relative to the epoch, which are 
+// negative for earlier times, and the nanoseconds into that second.
+fn store_time(time: SystemTime, secs: &mut[u8], nanos: &mut[u8]) {
+    let (s, n) = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            let d = err.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            }
+            else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    };
+
+    store(s as u64, secs);
+    store_32(n, nanos);
+}
+
+
+fn read_time(secs: &[u8], nanos: &[u8]) -> SystemTime {
+    let s = to_u64(secs) as i64;
+    let n = std::cmp::min(to_u32(nanos), 999_999_999);
+
+    let time_opt = 
+        if s >= 0 {
+            UNIX_EPOCH.checked_add(Duration::new(s as u64, n))
+        }
+        else {
+            UNIX_EPOCH.checked_sub(Duration::from_secs(s.unsigned_abs()))
+                .and_then(|time| time.checked_add(Duration::from_nanos(n as u64)))
+        };
     
-    time_opt.unwrap()
+    // damaged timestamps must not stop us from reading the entry
+    time_opt.unwrap_or(UNIX_EPOCH)
+}
+
+
+// entry blocks of format version 2 and older store milliseconds since the epoch
+fn read_legacy_time(storage: &[u8]) -> SystemTime {
+    let d = Duration::from_millis(to_u64(storage));
+    UNIX_EPOCH.checked_add(d).unwrap_or(UNIX_EPOCH)
 }
 
+
 fn store_32(value: u32, storage: &mut[u8]) {
     let bytes = u32::to_le_bytes(value);
 
@@ -152,28 +472,28 @@ fn kind_to_u8(kind: FileType) -> u8 {
 }
 
 
-fn u8_to_kind(kindval: u8) -> FileType {
+fn u8_to_kind(kindval: u8) -> Option<FileType> {
     match kindval {
         // Named pipe (S_IFIFO)
-        1 => FileType::NamedPipe,
+        1 => Some(FileType::NamedPipe),
         // Character device (S_IFCHR)
-        2 => FileType::CharDevice,
+        2 => Some(FileType::CharDevice),
         // Block device (S_IFBLK)
-        3 => FileType::BlockDevice,
+        3 => Some(FileType::BlockDevice),
         // Directory (S_IFDIR)
-        4 => FileType::Directory,
+        4 => Some(FileType::Directory),
         // Regular file (S_IFREG)
-        5 => FileType::RegularFile,
+        5 => Some(FileType::RegularFile),
         // Symbolic link (S_IFLNK)
-        6 => FileType::Symlink,
+        6 => Some(FileType::Symlink),
         // Unix domain socket (S_IFSOCK)
-        7 => FileType::Socket,        
-        0_u8 | 8_u8..=u8::MAX => todo!(),
+        7 => Some(FileType::Socket),        
+        0_u8 | 8_u8..=u8::MAX => None,
     }
 }
 
 
-fn to_u64(data: &[u8]) -> u64 {
+pub fn to_u64(data: &[u8]) -> u64 {
     let mut target: [u8; 8] = [0; 8];
     target.copy_from_slice(&data[0..8]);
     
@@ -181,7 +501,7 @@ fn to_u64(data: &[u8]) -> u64 {
 }
 
 
-fn to_u32(data: &[u8]) -> u32 {
+pub fn to_u32(data: &[u8]) -> u32 {
     let mut target: [u8; 4] = [0; 4];
     target.copy_from_slice(&data[0..4]);
 
@@ -189,28 +509,649 @@ fn to_u32(data: &[u8]) -> u32 {
 }
 
 
+// CRC-32 as used by zlib and ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {(crc >> 1) ^ 0xedb88320} else {crc >> 1};
+        }
+    }
+    !crc
+}
+
+
+// Layout of an entry block:
+//   0..8     header "PTFEntry"
+//   8..92    attributes
+//   92       file type
+//   93       tag flag
+//   94       name length, older images have 0 here
+//   95       layout flags
+//   96..104  more_data
+//   104..344 name
+//   344..360 nanoseconds of the timestamps
+//   384..    extension records: kind u8, length u16, value
+//            a kind of 0 ends the list
+const NAME_START:usize = 104;
+const NANOS_START:usize = 344;
+
+// the timestamps are seconds plus nanoseconds, not milliseconds
+const LAYOUT_NSEC_TIMES:u8 = 1;
+
+// nlink of files counts their names, older entries have 2 but one name
+const LAYOUT_NLINK_NAMES:u8 = 2;
+const EXTENSION_START:usize = 384;
+const EXTENSION_HEADER:usize = 3;
+
+// free bytes for extension records, including their headers
+pub const EXTENSION_SPACE:usize = BLOCK_SIZE - EXTENSION_START;
+
+// extension kinds, values of unknown kinds are preserved
+pub const EXT_SYMLINK_TARGET:u8 = 1;
+pub const EXT_XATTR:u8 = 2;
+pub const EXT_TAG_LIST:u8 = 3;
+pub const EXT_RDEV:u8 = 4;
+pub const EXT_INLINE_DATA:u8 = 5;
+
+// the data is in this host file, see the overlay module
+pub const EXT_HOST_PATH:u8 = 6;
+
+// order and pins of a tag directory, see the tag_order module
+pub const EXT_TAG_ORDER:u8 = 7;
+
+// SHA-256 of the contents, removed by changes, see PathTagFs::content_hash()
+pub const EXT_SHA256:u8 = 8;
+
+// inheritance of a tag directory, see the tag_rules module
+pub const EXT_TAG_RULES:u8 = 9;
+
+// free text note of the file, see PathTagFs::set_comment()
+pub const EXT_COMMENT:u8 = 10;
+
+// stars from 1 to 5 as one byte, see PathTagFs::set_rating()
+pub const EXT_RATING:u8 = 11;
+
+
+impl EntryBlock {
+
+    pub fn extension(&self, kind: u8) -> Option<&[u8]> {
+        self.extensions.iter().find(|ext| ext.kind == kind).map(|ext| ext.value.as_slice())
+    }
+
+
+    // bytes left in the extension area
+    pub fn extension_space(&self) -> usize {
+        let used: usize = self.extensions.iter().map(|ext| EXTENSION_HEADER + ext.value.len()).sum();
+        EXTENSION_SPACE.saturating_sub(used)
+    }
+
+
+    // adds or replaces the extension of the given kind
+    pub fn set_extension(&mut self, kind: u8, value: &[u8]) -> Result<(), PtfsError> {
+        if kind == 0 {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let old = self.extension(kind).map_or(0, |value| EXTENSION_HEADER + value.len());
+        if EXTENSION_HEADER + value.len() > self.extension_space() + old {
+            return Err(PtfsError::NoSpace);
+        }
+
+        self.remove_extension(kind);
+        self.extensions.push(Extension{kind: kind, value: value.to_vec(),});
+        
+        Ok(())
+    }
+
+
+    // returns false if there was no extension of this kind
+    pub fn remove_extension(&mut self, kind: u8) -> bool {
+        let count = self.extensions.len();
+        self.extensions.retain(|ext| ext.kind != kind);
+        
+        count != self.extensions.len()
+    }
+}
+
+
+pub fn encode_entry_block(b: &EntryBlock) -> [u8; BLOCK_SIZE] {
+    let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    data[0..8].copy_from_slice("PTFEntry".as_bytes());
+    
+    let attrs = &b.attr;
+    
+    store(attrs.ino, &mut data[8..16]);
+    store(attrs.size, &mut data[16..24]);
+    store(attrs.blocks, &mut data[24..32]);
+    let times = [attrs.atime, attrs.mtime, attrs.ctime, attrs.crtime];
+    for (i, time) in times.iter().enumerate() {
+        let (secs, nanos) = data.split_at_mut(NANOS_START);
+        store_time(*time, &mut secs[32+i*8..40+i*8], &mut nanos[i*4..i*4+4]);
+    }
+    store_32(attrs.perm as u32, &mut data[64..68]);
+    store_32(attrs.nlink, &mut data[68..72]);
+    store_32(attrs.uid, &mut data[72..76]);
+    store_32(attrs.gid, &mut data[76..80]);
+    store_32(attrs.rdev, &mut data[80..84]);
+    store_32(attrs.blksize, &mut data[84..88]);
+    store_32(attrs.flags, &mut data[88..92]);
+
+    // single bytes at the end
+    data[92] = kind_to_u8(attrs.kind);
+    data[93] = if b.is_tag {1} else {0};
+
+    // names are checked on creation, longer ones get cut
+    let name = b.name.as_bytes();
+    let len = std::cmp::min(name.len(), MAX_NAME_LENGTH);
+    data[94] = len as u8;
+    data[95] = LAYOUT_NSEC_TIMES | LAYOUT_NLINK_NAMES;
+    data[NAME_START..NAME_START+len].copy_from_slice(&name[0..len]);
+    
+    store(b.more_data, &mut data[96..104]);
+
+    // set_extension() makes sure all records fit
+    let mut pos = EXTENSION_START;
+    for ext in &b.extensions {
+        let end = pos + EXTENSION_HEADER + ext.value.len();
+        if ext.kind == 0 || end > BLOCK_SIZE {
+            warn!("encode_entry_block() dropping extension of kind {}", ext.kind);
+            continue;
+        }
+        
+        data[pos] = ext.kind;
+        data[pos+1..pos+3].copy_from_slice(&(ext.value.len() as u16).to_le_bytes());
+        data[pos+3..end].copy_from_slice(&ext.value);
+        pos = end;
+    }
+
+    data
+}
+
+
+pub fn encode_index_block(b: &IndexBlock) -> [u8; BLOCK_SIZE] {
+    let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+    for i in 0..b.block.len() {
+        store(b.block[i], &mut data[i*8 .. (i+1)*8]);
+    }
+    
+    let i = b.block.len();
+    store(b.next, &mut data[i*8 .. (i+1)*8]);
+
+    data
+}
+
+
+// Layout of a directory block:
+//   MAX_ENTRIES slots of ENTRY_SIZE bytes, each an inode number and a name
+//   the last byte of the first slot holds the number of used slots
+//   the last 8 bytes of the block hold the chain pointer
+// Names are at most MAX_NAME_LENGTH bytes, so neither the count nor the
+// chain pointer overlap a name. Blocks of images before version 11 may
+// have no count, see parse_old_directory_block().
+const ENTRY_COUNT_POS:usize = ENTRY_SIZE - 1;
+
+// names of blocks without count may fill their whole slot
+const LEGACY_NAME_LENGTH:usize = ENTRY_SIZE - 8;
+
+// the file types of all slots are kept in the spare bytes of the second slot
+const ENTRY_KINDS_POS:usize = 2*ENTRY_SIZE - MAX_ENTRIES;
+
+
+pub fn encode_directory_block(b: &DirectoryBlock) -> [u8; BLOCK_SIZE] {
+    let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    let mut pos = 0;
+    let count = std::cmp::min(b.entries.len(), MAX_ENTRIES);
+
+    data[ENTRY_COUNT_POS] = count as u8;
+
+    for (slot, entry) in b.entries.iter().take(count).enumerate() {
+
+        store(entry.ino, &mut data[pos..pos+8]);
+        data[ENTRY_KINDS_POS + slot] = entry.kind.map_or(0, kind_to_u8);
+
+        let utf8 = entry.name.as_bytes();
+        let len = std::cmp::min(utf8.len(), MAX_NAME_LENGTH);
+        data[pos+8..pos+8+len].copy_from_slice(&utf8[0..len]);
+        
+        pos += ENTRY_SIZE;
+    }
+
+    store(b.next, &mut data[BLOCK_SIZE-8..BLOCK_SIZE]);
+
+    data
+}
+
+
+// The parsers below get the raw contents of block no, which must not be
+// trusted. The block number is only used for error messages.
+
+fn check_block_length(data: &[u8], no: u64) -> Result<(), PtfsError> {
+    if data.len() != BLOCK_SIZE {
+        return Err(PtfsError::Corrupt(format!("block {} has {} bytes instead of {}", no, data.len(), BLOCK_SIZE)));
+    }
+    
+    Ok(())
+}
+
+
+pub fn parse_entry_block(data: &[u8], no: u64) -> Result<EntryBlock, PtfsError> {
+    check_block_length(data, no)?;
+
+    let header = &data[0..8];        
+    if "PTFEntry".as_bytes() != header {
+        return Err(PtfsError::Corrupt(format!("block {} has no entry header", no)));
+    }
+
+    let mut b = EntryBlock::new("", 0, FileType::RegularFile, false);
+    let attrs = &mut b.attr;
+
+    attrs.ino = to_u64(&data[8..16]);
+    attrs.size = to_u64(&data[16..24]);
+    attrs.blocks = to_u64(&data[24..32]);
+    let mut times = [UNIX_EPOCH; 4];
+    for (i, time) in times.iter_mut().enumerate() {
+        let secs = &data[32+i*8..40+i*8];
+        *time = if data[95] & LAYOUT_NSEC_TIMES != 0 {
+            read_time(secs, &data[NANOS_START+i*4..NANOS_START+i*4+4])
+        }
+        else {
+            read_legacy_time(secs)
+        };
+    }
+    attrs.atime = times[0];
+    attrs.mtime = times[1];
+    attrs.ctime = times[2];
+    attrs.crtime = times[3];
+    attrs.perm = to_u32(&data[64..68]) as u16;
+    attrs.nlink = to_u32(&data[68..72]);
+    attrs.uid = to_u32(&data[72..76]);
+    attrs.gid = to_u32(&data[76..80]);
+    attrs.rdev = to_u32(&data[80..84]);
+    attrs.blksize = to_u32(&data[84..88]);
+    attrs.flags = to_u32(&data[88..92]);
+
+    // single bytes at the end
+    attrs.kind = match u8_to_kind(data[92]) {
+        None => return Err(PtfsError::Corrupt(format!("entry block {} has unknown file type {}", no, data[92]))),
+        Some(kind) => kind,
+    };
+
+    b.is_tag = data[93] == 1;
+
+    if data[95] & LAYOUT_NLINK_NAMES == 0 && attrs.kind != FileType::Directory {
+        attrs.nlink = std::cmp::min(attrs.nlink, 1);
+    }
+
+    let len = std::cmp::min(data[94] as usize, MAX_NAME_LENGTH);
+    b.name = String::from_utf8_lossy(&data[NAME_START..NAME_START+len]).to_string();
+    
+    b.more_data = to_u64(&data[96..104]);
+
+    let mut pos = EXTENSION_START;
+    while pos + EXTENSION_HEADER <= BLOCK_SIZE && data[pos] != 0 {
+        let len = u16::from_le_bytes([data[pos+1], data[pos+2]]) as usize;
+        let end = pos + EXTENSION_HEADER + len;
+        if end > BLOCK_SIZE {
+            return Err(PtfsError::Corrupt(format!("extension of kind {} runs past the end of entry block {}", data[pos], no)));
+        }
+
+        b.extensions.push(Extension{kind: data[pos], value: data[pos+3..end].to_vec(),});
+        pos = end;
+    }
+    
+    Ok(b)
+}
+
+
+pub fn parse_index_block(data: &[u8], no: u64) -> Result<IndexBlock, PtfsError> {
+    check_block_length(data, no)?;
+
+    let mut ib = IndexBlock::new();
+    for i in 0..ib.block.len() {
+        ib.block[i] = to_u64(&data[i*8 .. (i+1)*8]);
+    }
+        
+    let i = ib.block.len();
+    ib.next = to_u64(&data[i*8 .. (i+1)*8]);
+
+    Ok(ib)
+}
+
+
+pub fn parse_directory_block(data: &[u8], no: u64) -> Result<DirectoryBlock, PtfsError> {
+    check_block_length(data, no)?;
+
+    let count = data[ENTRY_COUNT_POS] as usize;
+    if count > MAX_ENTRIES {
+        return Err(PtfsError::Corrupt(format!("directory block {} claims {} entries", no, count)));
+    }
+
+    Ok(parse_directory_slots(data, count, false))
+}
+
+
+// Images before version 11 can have blocks without count, where the
+// entries end at the first inode number 0 and names fill their whole slot,
+// over the file types too. A block has a count if the last bytes of the
+// first slot are free but for a count that fits.
+pub fn parse_old_directory_block(data: &[u8], no: u64) -> Result<DirectoryBlock, PtfsError> {
+    check_block_length(data, no)?;
+
+    let count = data[ENTRY_COUNT_POS] as usize;
+    let spare = &data[8+MAX_NAME_LENGTH..ENTRY_COUNT_POS];
+    if count > 0 && count <= MAX_ENTRIES && spare.iter().all(|byte| *byte == 0) {
+        return Ok(parse_directory_slots(data, count, false));
+    }
+
+    Ok(parse_directory_slots(data, MAX_ENTRIES, true))
+}
+
+
+fn parse_directory_slots(data: &[u8], slots: usize, legacy: bool) -> DirectoryBlock {
+    let mut db = DirectoryBlock::new();
+    let name_length = if legacy {LEGACY_NAME_LENGTH} else {MAX_NAME_LENGTH};
+
+    for slot in 0..slots {
+        let pos = slot * ENTRY_SIZE;
+        let ino = to_u64(&data[pos..pos+8]);
+
+        if ino == 0 {
+            if legacy {
+                break;
+            }
+
+            // a free slot, later slots may still be in use
+            continue;
+        }
+
+        // scan for string end, damaged entries must not run past their name
+        // area, and the last one not into the chain pointer
+        let limit = std::cmp::min(pos + 8 + name_length, BLOCK_SIZE - 8);
+        let mut end = pos + 8;
+        while end < limit && data[end] != 0 {
+            end += 1;
+        }
+
+        db.entries.push(DirectoryEntry { 
+            ino: ino,
+            name: String::from_utf8_lossy(&data[pos+8..end]).to_string(),
+            kind: if legacy {None} else {u8_to_kind(data[ENTRY_KINDS_POS + slot])},
+        });
+    }
+
+    db.next = to_u64(&data[BLOCK_SIZE-8..BLOCK_SIZE]);
+
+    db
+}
+
+
+// byte position of block no in the backing store
+fn block_offset(no: u64) -> Result<u64, PtfsError> {
+    match no.checked_mul(BLOCK_SIZE as u64) {
+        None => Err(PtfsError::Corrupt(format!("block number {} is out of range", no))),
+        Some(offset) => Ok(offset),
+    }
+}
+
+
+// Buffer for O_DIRECT transfers, which need memory aligned to the sector
+// size of the device. 4096 covers all common devices.
+#[repr(C, align(4096))]
+pub struct AlignedBlock(pub [u8; BLOCK_SIZE]);
+
+
 pub struct BlockIo {
     file: File,
+
+    // transfers bypass the page cache of the host, see set_direct()
+    direct: bool,
+
+    // runs the transfers when they have a time limit, see set_timeout()
+    worker: Option<IoWorker>,
+
+    // directory blocks may be laid out like before version 11
+    old_directories: bool,
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    faults: Option<FaultPlan>,
 }
 
 impl BlockIo {
 
-    pub fn new(path: &str) -> BlockIo {
+    pub fn new(path: &str) -> Result<BlockIo, PtfsError> {
+        
+        let file = File::options().read(true).write(true).create(true).open(path)?;
+
+        Ok(BlockIo {
+            file: file,
+            direct: false,
+            worker: None,
+            old_directories: false,
+            #[cfg(any(test, feature = "fault-injection"))]
+            faults: FaultPlan::from_env(),
+        })
+    }
+
+
+    // opens an existing backing store without write access
+    pub fn open_read_only(path: &str) -> Result<BlockIo, PtfsError> {
+        
+        let file = File::options().read(true).open(path)?;
+
+        Ok(BlockIo {
+            file: file,
+            direct: false,
+            worker: None,
+            old_directories: false,
+            #[cfg(any(test, feature = "fault-injection"))]
+            faults: FaultPlan::from_env(),
+        })
+    }
+
+
+    // Takes an advisory lock on the backing store, exclusive for writers
+    // and shared for readers. The lock is released when the file is closed.
+    pub fn lock(&mut self, exclusive: bool) -> Result<(), PtfsError> {
+        let operation = if exclusive {libc::LOCK_EX} else {libc::LOCK_SH};
+        let result = unsafe { libc::flock(self.file.as_raw_fd(), operation | libc::LOCK_NB) };
         
-        let file = File::options().read(true).write(true).create(true).open(path);
+        if result != 0 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                return Err(PtfsError::Io(Error::new(ErrorKind::WouldBlock, "image is in use by another instance")));
+            }
+            return Err(PtfsError::Io(err));
+        }
+        
+        Ok(())
+    }
+
+
+    // Switches O_DIRECT on or off, so the blocks are not cached by the host
+    // as well. Fails if the backing store doesn't support it, e.g. on tmpfs.
+    // Block offsets are multiples of BLOCK_SIZE, so devices with larger
+    // sectors can't be used this way.
+    pub fn set_direct(&mut self, direct: bool) -> Result<(), PtfsError> {
+        let fd = self.file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(Error::last_os_error().into());
+        }
+
+        let flags = if direct {flags | libc::O_DIRECT} else {flags & !libc::O_DIRECT};
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+
+        self.direct = direct;
+        Ok(())
+    }
+
+
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+
+    // Transfers that take longer than the timeout fail with EIO and mark
+    // the backing store as degraded. They run in a thread of their own
+    // then, the calling thread only waits for them.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), PtfsError> {
+        self.worker = match timeout {
+            Some(timeout) => Some(IoWorker::new(self.file.try_clone()?, timeout)),
+            None => None,
+        };
+        Ok(())
+    }
+
+
+    // true once a transfer ran into the timeout
+    pub fn is_degraded(&self) -> bool {
+        self.worker.as_ref().is_some_and(|worker| worker.is_degraded())
+    }
+
+
+    // replaces the plan from the environment
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn inject_faults(&mut self, plan: FaultPlan) {
+        self.faults = Some(plan);
+    }
+
+
+    #[cfg(any(test, feature = "fault-injection"))]
+    fn check_crashed(&self) -> Result<(), PtfsError> {
+        match &self.faults {
+            Some(plan) if plan.crashed() => Err(FaultPlan::error().into()),
+            _ => Ok(()),
+        }
+    }
+
+
+    #[cfg(not(any(test, feature = "fault-injection")))]
+    fn check_crashed(&self) -> Result<(), PtfsError> {
+        Ok(())
+    }
+
 
-        BlockIo {
-            file: file.unwrap(),
+    // number of complete blocks in the backing store
+    pub fn block_count(&self) -> u64 {
+        match self.file.metadata() {
+            Ok(meta) => meta.len() / BLOCK_SIZE as u64,
+            Err(_) => 0,
         }
     }
 
 
-    pub fn flush(&mut self) {
-        self.file.flush().unwrap();
+    pub fn flush(&mut self) -> Result<(), PtfsError> {
+        self.check_crashed()?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+
+    // Write barrier, returns when everything written so far is on the disk
+    // and not just in the page cache of the host.
+    pub fn sync(&mut self) -> Result<(), PtfsError> {
+        self.check_crashed()?;
+        if let Some(worker) = &mut self.worker {
+            return worker.sync();
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+
+    // like sync(), but the metadata of the image file is left to the host
+    // unless reading it back needs it, like fdatasync()
+    pub fn sync_data(&mut self) -> Result<(), PtfsError> {
+        self.check_crashed()?;
+        if let Some(worker) = &mut self.worker {
+            return worker.sync_data();
+        }
+        self.file.sync_data()?;
+        Ok(())
     }
     
     
-    pub fn write_block(&mut self, ab: &AnyBlock, no: u64) -> Result<usize, Error> {
+    fn write_raw(&mut self, data: &[u8], no: u64) -> Result<usize, PtfsError> {
+        let offset = block_offset(no)?;
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+
+        #[cfg(any(test, feature = "fault-injection"))]
+        if let Some(plan) = &mut self.faults {
+            if plan.is_bad(no) {
+                return Err(FaultPlan::error().into());
+            }
+            match plan.next_write() {
+                Fault::None => {}
+                Fault::Fail => return Err(FaultPlan::error().into()),
+                Fault::Torn => {
+                    self.file.write_all(&data[..data.len() / 2])?;
+                    return Err(FaultPlan::error().into());
+                }
+            }
+        }
+
+        if let Some(worker) = &mut self.worker {
+            return worker.write(data, offset, self.direct);
+        }
+
+        if self.direct {
+            let mut buffer = AlignedBlock([0; BLOCK_SIZE]);
+            buffer.0[..data.len()].copy_from_slice(data);
+            self.file.write_all(&buffer.0)?;
+        }
+        else {
+            self.file.write_all(data)?;
+        }
+        
+        Ok(data.len())
+    }
+
+
+    // reads as much of the block as there is, missing bytes stay zero
+    fn read_raw(&mut self, data: &mut [u8], no: u64) -> Result<usize, PtfsError> {
+        self.check_crashed()?;
+        let offset = block_offset(no)?;
+
+        #[cfg(any(test, feature = "fault-injection"))]
+        if self.faults.as_ref().is_some_and(|plan| plan.is_bad(no)) {
+            return Err(FaultPlan::error().into());
+        }
+
+        if let Some(worker) = &mut self.worker {
+            let block = worker.read(data.len(), offset, self.direct)?;
+            data[..block.len()].copy_from_slice(&block);
+            return Ok(block.len());
+        }
+
+        self.file.seek(std::io::SeekFrom::Start(offset))?;
+
+        if self.direct {
+            // the image ends at a block boundary, so one read gets it all
+            let mut buffer = AlignedBlock([0; BLOCK_SIZE]);
+            let size = std::cmp::min(self.file.read(&mut buffer.0)?, data.len());
+            data[..size].copy_from_slice(&buffer.0[..size]);
+            return Ok(size);
+        }
+        
+        let mut size = 0;
+        while size < data.len() {
+            let count = self.file.read(&mut data[size..])?;
+            if count == 0 {
+                break;
+            }
+            size += count;
+        }
+        
+        Ok(size)
+    }
+    
+    
+    pub fn write_block(&mut self, ab: &AnyBlock, no: u64) -> Result<usize, PtfsError> {
         let size;
         
         match ab {
@@ -231,211 +1172,79 @@ impl BlockIo {
     }
     
     
-    fn write_entry_block(&mut self, b: &EntryBlock, no: u64) -> Result<usize, Error> {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-        
-        let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-        let mut header = &mut data[0..8];        
-        header.write("PTFEntry".as_bytes()).unwrap();
-        
-        let attrs = &b.attr;
-        
-        store(attrs.ino, &mut data[8..16]);
-        store(attrs.size, &mut data[16..24]);
-        store(attrs.blocks, &mut data[24..32]);
-        store_time(attrs.atime, &mut data[32..40]);
-        store_time(attrs.mtime, &mut data[40..48]);
-        store_time(attrs.ctime, &mut data[48..56]);
-        store_time(attrs.crtime, &mut data[56..64]);
-        store_32(attrs.perm as u32, &mut data[64..68]);
-        store_32(attrs.nlink, &mut data[68..72]);
-        store_32(attrs.uid, &mut data[72..76]);
-        store_32(attrs.gid, &mut data[76..80]);
-        store_32(attrs.rdev, &mut data[80..84]);
-        store_32(attrs.blksize, &mut data[84..88]);
-        store_32(attrs.flags, &mut data[88..92]);
-
-        // single bytes at the end
-        data[92] = kind_to_u8(attrs.kind);
-        data[93] = if b.is_tag {1} else {0};
-        
-        store(b.more_data, &mut data[96..104]);
-        
-        let result = self.file.write(&data);
-        println!("write_entry_block()  block={} -> {:?} bytes written", no, result);
+    fn write_entry_block(&mut self, b: &EntryBlock, no: u64) -> Result<usize, PtfsError> {
+        let result = self.write_raw(&encode_entry_block(b), no);
+        debug!("write_entry_block()  block={} -> {:?} bytes written", no, result);
 
         result
     }
 
 
-    fn write_index_block(&mut self, b: &IndexBlock, no: u64) -> Result<usize, Error> {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-
-        let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-
-        for i in 0..b.block.len() {
-            store(b.block[i], &mut data[i*8 .. (i+1)*8]);
-        }
-        
-        let i = b.block.len();
-        store(b.next, &mut data[i*8 .. (i+1)*8]);
-
-        let result = self.file.write(&data);
-        println!("write_index_block()  block={} -> {:?} bytes written", no, result);
+    fn write_index_block(&mut self, b: &IndexBlock, no: u64) -> Result<usize, PtfsError> {
+        let result = self.write_raw(&encode_index_block(b), no);
+        debug!("write_index_block()  block={} -> {:?} bytes written", no, result);
 
         return result;
     }
 
 
-    fn write_directory_block(&mut self, b: &DirectoryBlock, no: u64) -> Result<usize, Error> {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-
-        let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-        let mut pos = 0;
-
-        for entry in &b.entries {
-
-            store(entry.ino, &mut data[pos..pos+8]);
-    
-            let utf8 = entry.name.as_bytes();
-            for i in 0..utf8.len() {
-                data[pos+8+i] = utf8[i];
-            }
-            
-            pos += ENTRY_SIZE;
-        }
-
-        store(b.next, &mut data[BLOCK_SIZE-8..BLOCK_SIZE]);
-
-        let result = self.file.write(&data);
-        println!("write_directory_block() block={} -> {:?} bytes written", no, result);
+    fn write_directory_block(&mut self, b: &DirectoryBlock, no: u64) -> Result<usize, PtfsError> {
+        let result = self.write_raw(&encode_directory_block(b), no);
+        debug!("write_directory_block() block={} -> {:?} bytes written", no, result);
 
         return result;
     }
 
 
-    pub fn write_data_block(&mut self, b: &DataBlock, no: u64) -> Result<usize, Error> {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-
-        let size = self.file.write(&b.data);
+    pub fn write_data_block(&mut self, b: &DataBlock, no: u64) -> Result<usize, PtfsError> {
+        let size = self.write_raw(&b.data, no);
         // println!("write_data_block() {:?} bytes written", size);
         return size;
     }
 
     
-    pub fn read_entry_block(&mut self, no: u64) -> EntryBlock {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-        
+    pub fn read_entry_block(&mut self, no: u64) -> Result<EntryBlock, PtfsError> {
         let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-        let size = self.file.read(&mut data).unwrap();        
-        assert!(size == BLOCK_SIZE);
-        
-        let header = &data[0..8];        
-        assert!("PTFEntry".as_bytes() == header);
-
-        // single bytes at the end
-        let mut b = EntryBlock::new("", 0, FileType::RegularFile, false);
-        let attrs = &mut b.attr;
-
-        attrs.ino = to_u64(&data[8..16]);
-        attrs.size = to_u64(&data[16..24]);
-        attrs.blocks = to_u64(&data[24..32]);
-        attrs.atime = read_time(&data[32..40]);
-        attrs.mtime = read_time(&data[40..48]);
-        attrs.ctime = read_time(&data[48..56]);
-        attrs.crtime = read_time(&data[56..64]);
-        attrs.perm = to_u32(&data[64..68]) as u16;
-        attrs.nlink = to_u32(&data[68..72]);
-        attrs.uid = to_u32(&data[72..76]);
-        attrs.gid = to_u32(&data[76..80]);
-        attrs.rdev = to_u32(&data[80..84]);
-        attrs.blksize = to_u32(&data[84..88]);
-        attrs.flags = to_u32(&data[88..92]);
-        attrs.kind = u8_to_kind(data[92]);
-
-        b.is_tag = data[93] == 1;
-        
-        b.more_data = to_u64(&data[96..104]);
+        let size = self.read_raw(&mut data, no)?;
+        if size != BLOCK_SIZE {
+            return Err(PtfsError::Corrupt(format!("short read of {} bytes from entry block {}", size, no)));
+        }
         
-        b        
+        parse_entry_block(&data, no)
     }
 
 
-    pub fn read_index_block(&mut self, no: u64) -> IndexBlock {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        let ok = self.file.seek(seek);
+    pub fn read_index_block(&mut self, no: u64) -> Result<IndexBlock, PtfsError> {
+        let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+        self.read_raw(&mut data, no)?;
         
-        let mut ib = IndexBlock::new();
-        if ok.is_ok() {
-            let mut buf = [0u8; 8];
-
-            for i in 0..BLOCK_SIZE/8 - 1 {
-                let check = self.file.read(&mut buf);
-                if check.is_err() {
-                    println!("read_index_block() read failed: {:?}", check);
-                }
-                
-                ib.block[i] = to_u64(&buf);
-            }
-            
-            let _ = self.file.read(&mut buf);
-            ib.next = to_u64(&buf);
-        }
-        return ib;
+        parse_index_block(&data, no)
     }
 
 
-    pub fn read_directory_block(&mut self, no: u64) -> DirectoryBlock {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-
-        let mut db = DirectoryBlock::new();
+    pub fn read_directory_block(&mut self, no: u64) -> Result<DirectoryBlock, PtfsError> {
         let mut data: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
-        let _ = self.file.read(&mut data);        
-        let mut pos = 0;
-
-        let mut ino = 1;
-        while ino != 0 {
-            
-            // scan for string end
-            let mut end = pos + 8;
-            while data[end] != 0 {
-                end += 1;
-            }
-
-            let vec = Vec::from(&data[pos+8..end]);
-
-            let entry = DirectoryEntry { 
-                ino: to_u64(&data[pos..pos+8]),
-                name: String::from_utf8(vec).unwrap(),
-            };
-
-            ino = entry.ino;
-            if ino > 0 {
-                db.entries.push(entry);
-            }
+        self.read_raw(&mut data, no)?;
 
-            pos += ENTRY_SIZE;
+        if self.old_directories {
+            parse_old_directory_block(&data, no)
+        }
+        else {
+            parse_directory_block(&data, no)
         }
+    }
 
-        db.next = to_u64(&data[BLOCK_SIZE-8..BLOCK_SIZE]);
 
-        db
+    // for images before version 11, until they are upgraded
+    pub fn set_old_directories(&mut self, old: bool) {
+        self.old_directories = old;
     }
 
 
-    pub fn read_data_block(&mut self, no: u64) -> DataBlock {
-        let seek = std::io::SeekFrom::Start(no  * BLOCK_SIZE as u64);
-        self.file.seek(seek).unwrap();
-
+    pub fn read_data_block(&mut self, no: u64) -> Result<DataBlock, PtfsError> {
         let mut db = DataBlock::new();
-        let _ = self.file.read(&mut db.data);        
+        self.read_raw(&mut db.data, no)?;
 
-        db
+        Ok(db)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,300 @@
+//
+// The mount options in a file, given with --config. It is a small subset
+// of TOML: keys are the long names of the options, with strings, numbers,
+// booleans and lists as values. Options on the command line win over the
+// file. [mount] and [backend] only group the keys, [tags.NAME] sections
+// set the rules of a tag when the image is mounted read-write.
+//
+// device = "/srv/photos.img"
+// [backend]
+// cache-mem = 64
+// map-uid = ["1000:501"]
+// [tags.holidays]
+// keep = true
+//
+
+use path_tag_fs::tag_rules::TagRules;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = parse(concat!(
+            "# photos\n",
+            "device = \"/srv/photos.img\"  # the image\n",
+            "[backend]\n",
+            "cache-mem = 64\n",
+            "hashes = true\n",
+            "direct-io = false\n",
+            "map-uid = [\"1000:501\", \"0:0\"]\n",
+            "\n",
+            "[tags.\"holidays 2024\"]\n",
+            "keep = true\n",
+            "inherit = false\n",
+        )).unwrap();
+
+        assert_eq!(config.args_without(|key| key == "cache-mem"), vec!["--device", "/srv/photos.img", "--hashes",
+                                       "--map-uid", "1000:501", "--map-uid", "0:0"]);
+        assert_eq!(config.keys(), vec!["device", "cache-mem", "hashes", "direct-io", "map-uid"]);
+
+        let mut rules = TagRules::default();
+        assert_eq!(config.tags.len(), 1);
+        assert_eq!(config.tags[0].0, "holidays 2024");
+        config.tags[0].1.apply(&mut rules).unwrap();
+        assert_eq!(rules, TagRules {inherit: false, propagate: false, keep: true});
+    }
+
+    #[test]
+    fn test_errors() {
+        for bad in ["device", "device = ", "device = \"open", "= 5", "[quotas]\nsize = 5",
+                    "cache-mem = 5 5", "map-uid = [\"a\"", "[tags.music]\nloud = true", "[tags.music]\nkeep = 1"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+
+        let err = parse("hashes = true\nhashes = maybe").unwrap_err();
+        assert!(err.starts_with("line 2:"), "{}", err);
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+
+// the switches of one tag, unset ones are left as they are
+#[derive(Default, Debug)]
+pub struct TagSettings {
+    settings: Vec<(String, bool)>,
+}
+
+
+impl TagSettings {
+
+    pub fn apply(&self, rules: &mut TagRules) -> Result<(), String> {
+        for (key, value) in &self.settings {
+            match rules.switch_mut(&format!("user.ptfs.{}", key)) {
+                Some(switch) => *switch = *value,
+                None => return Err(format!("unknown tag rule '{}'", key)),
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Default, Debug)]
+pub struct Config {
+    options: Vec<(String, Value)>,
+    pub tags: Vec<(String, TagSettings)>,
+}
+
+
+impl Config {
+
+    // the names of all options in the file
+    pub fn keys(&self) -> Vec<&str> {
+        self.options.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+
+    // The options as command line arguments, false switches are left
+    // out, and so are the ones given elsewhere, i.e. on the command line.
+    pub fn args_without(&self, given: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut args = Vec::new();
+        for (key, value) in self.options.iter().filter(|(key, _)| !given(key)) {
+            push_arg(&mut args, key, value);
+        }
+        args
+    }
+}
+
+
+fn push_arg(args: &mut Vec<String>, key: &str, value: &Value) {
+    match value {
+        Value::Bool(false) => {}
+        Value::Bool(true) => args.push(format!("--{}", key)),
+        Value::Str(text) => args.extend([format!("--{}", key), text.clone()]),
+        Value::Int(number) => args.extend([format!("--{}", key), number.to_string()]),
+        Value::List(values) => {
+            for value in values {
+                push_arg(args, key, value);
+            }
+        }
+    }
+}
+
+
+pub fn load(path: &str) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    parse(&text)
+}
+
+
+pub fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+
+    // keys after a [tags.NAME] header belong to the last tag
+    let mut in_tag = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let result = match line.strip_prefix('[') {
+            Some(section) => parse_section(section).map(|tag| {
+                in_tag = tag.is_some();
+                config.tags.extend(tag.map(|name| (name, TagSettings::default())));
+            }),
+            None => parse_key_value(line).and_then(|(key, value)| match (config.tags.last_mut(), value) {
+                (Some((_, settings)), Value::Bool(value)) if in_tag => {
+                    settings.settings.push((key, value));
+                    settings.apply(&mut TagRules::default())
+                }
+                _ if in_tag => Err(format!("tag rule '{}' must be true or false", key)),
+                (_, value) => {
+                    config.options.push((key, value));
+                    Ok(())
+                }
+            }),
+        };
+
+        result.map_err(|err| format!("line {}: {}", number + 1, err))?;
+    }
+
+    Ok(config)
+}
+
+
+// the tag name of a [tags.NAME] header, None for the other sections
+fn parse_section(section: &str) -> Result<Option<String>, String> {
+    let name = section.strip_suffix(']').ok_or("missing ]")?.trim();
+
+    match name {
+        "mount" | "backend" => Ok(None),
+        _ => match name.strip_prefix("tags.") {
+            Some(quoted) if quoted.starts_with('"') => Ok(Some(parse_string(&quoted[1..])?)),
+            Some(tag) if !tag.is_empty() => Ok(Some(tag.to_string())),
+            _ => Err(format!("unsupported section [{}]", name)),
+        },
+    }
+}
+
+
+// a # starts a comment unless it is inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if quoted && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+
+fn parse_key_value(line: &str) -> Result<(String, Value), String> {
+    let (key, value) = line.split_once('=').ok_or("expected key = value")?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("missing key".to_string());
+    }
+
+    let (value, rest) = parse_value(value.trim())?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected '{}' after the value", rest.trim()));
+    }
+    Ok((key.to_string(), value))
+}
+
+
+// the value at the start of text, and what follows it
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(quoted) = text.strip_prefix('"') {
+        let end = string_end(quoted).ok_or("unterminated string")?;
+        return Ok((Value::Str(parse_string(&quoted[..=end])?), &quoted[end + 1..]));
+    }
+
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::List(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+            if rest.is_empty() {
+                return Err("missing ]".to_string());
+            }
+        }
+    }
+
+    let end = text.find([',', ']', ' ', '\t']).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "" => return Err("missing value".to_string()),
+        _ => Value::Int(word.parse().map_err(|_| format!("invalid value '{}'", word))?),
+    };
+    Ok((value, rest))
+}
+
+
+// the index of the closing quote
+fn string_end(text: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+
+// the contents of a string up to its closing quote, which must be last
+fn parse_string(text: &str) -> Result<String, String> {
+    let end = string_end(text).ok_or("unterminated string")?;
+    if end + 1 != text.len() {
+        return Err("unexpected text after the string".to_string());
+    }
+
+    let mut result = String::new();
+    let mut chars = text[..end].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some(c @ ('"' | '\\')) => result.push(c),
+            _ => return Err("invalid escape in string".to_string()),
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,81 @@
+//
+// Publishes change events as signals on the session D-Bus
+//
+// Signals are sent by dbus-send, so there is no D-Bus library to build
+// against. They are emitted from the path /org/pathtagfs/Changes with
+// the interface org.pathtagfs.Changes:
+//
+//   Created(u64 parent, u64 ino, s name)
+//   Deleted(u64 parent, u64 ino, s name)
+//   Modified(u64 ino)
+//   Renamed(u64 ino, u64 old_parent, s old_name, u64 new_parent, s new_name)
+//   Tagged(u64 ino, s tag)
+//   Untagged(u64 ino, s tag)
+//
+
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use log::warn;
+
+use crate::events::ChangeEvent;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_args() {
+        let event = ChangeEvent::Created {parent: 4, ino: 12, name: "song, live".to_string()};
+        assert_eq!(signal_args(&event), ("Created", vec!["uint64:4".to_string(), "uint64:12".to_string(), "string:song, live".to_string()]));
+
+        let event = ChangeEvent::Untagged {ino: 12, tag: "loud".to_string()};
+        assert_eq!(signal_args(&event), ("Untagged", vec!["uint64:12".to_string(), "string:loud".to_string()]));
+    }
+}
+
+
+const OBJECT_PATH:&str = "/org/pathtagfs/Changes";
+const INTERFACE:&str = "org.pathtagfs.Changes";
+
+
+// Sends the events of receiver until the file system drops its sender.
+// A missing bus only costs a warning per event.
+pub fn publish(events: Receiver<ChangeEvent>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for event in events {
+            let (member, args) = signal_args(&event);
+            let status = Command::new("dbus-send")
+                .arg("--session")
+                .arg("--type=signal")
+                .arg(OBJECT_PATH)
+                .arg(format!("{}.{}", INTERFACE, member))
+                .args(args)
+                .stdout(Stdio::null())
+                .status();
+
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("dbus-send {} failed: {}", member, status),
+                Err(err) => warn!("cannot run dbus-send: {}", err),
+            }
+        }
+    })
+}
+
+
+fn signal_args(event: &ChangeEvent) -> (&'static str, Vec<String>) {
+    let id = |value: &u64| format!("uint64:{}", value);
+    let text = |value: &String| format!("string:{}", value);
+
+    match event {
+        ChangeEvent::Created {parent, ino, name} => ("Created", vec![id(parent), id(ino), text(name)]),
+        ChangeEvent::Deleted {parent, ino, name} => ("Deleted", vec![id(parent), id(ino), text(name)]),
+        ChangeEvent::Modified {ino} => ("Modified", vec![id(ino)]),
+        ChangeEvent::Renamed {ino, old_parent, old_name, new_parent, new_name} =>
+            ("Renamed", vec![id(ino), id(old_parent), text(old_name), id(new_parent), text(new_name)]),
+        ChangeEvent::Tagged {ino, tag} => ("Tagged", vec![id(ino), text(tag)]),
+        ChangeEvent::Untagged {ino, tag} => ("Untagged", vec![id(ino), text(tag)]),
+    }
+}
@@ -0,0 +1,153 @@
+//
+// Warnings about damaged or unreadable blocks. A failing block tends to
+// fail again on every access, so repeats are counted instead of logged,
+// and the log gets a few new warnings per minute at most. The recent ones
+// stay in a ring for the stats and the ctl warnings command.
+//
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use log::warn;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats() {
+        let mut diagnostics = Diagnostics::default();
+        let now = Instant::now();
+
+        assert!(diagnostics.record_at("entry block", Some(7), "checksum wrong", now));
+        assert!(!diagnostics.record_at("entry block", Some(7), "checksum wrong", now));
+        assert!(diagnostics.record_at("entry block", Some(8), "checksum wrong", now));
+
+        let recent = diagnostics.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!((recent[0].block, recent[0].count), (Some(7), 2));
+        assert_eq!(recent[1].to_string(), "entry block 8: checksum wrong");
+
+        // the ring keeps the newest ones, a repeat counts as new
+        for block in 0..RING_SIZE as u64 {
+            diagnostics.record_at("data block", Some(100 + block), "I/O error", now + LOG_WINDOW * block as u32);
+        }
+        diagnostics.record_at("entry block", Some(100), "checksum wrong", now + LOG_WINDOW * 100);
+        assert_eq!(diagnostics.recent().len(), RING_SIZE);
+        assert_eq!(diagnostics.recent().last().unwrap().block, Some(100));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut diagnostics = Diagnostics::default();
+        let now = Instant::now();
+
+        let logged = (0..LOG_BURST as u64 + 5).filter(|block| diagnostics.record_at("data block", Some(*block), "I/O error", now)).count();
+        assert_eq!(logged, LOG_BURST);
+        assert_eq!(diagnostics.suppressed(), 5);
+        assert_eq!(diagnostics.recent().len(), LOG_BURST + 5);
+
+        // a new window logs again
+        assert!(diagnostics.record_at("data block", Some(99), "I/O error", now + LOG_WINDOW));
+    }
+}
+
+
+// warnings kept for stats(), and how many may be logged per window
+const RING_SIZE:usize = 32;
+const LOG_BURST:usize = 10;
+const LOG_WINDOW:Duration = Duration::from_secs(60);
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    // "entry block", "data block", ... or "request" for errors without a block
+    pub kind: &'static str,
+    pub block: Option<u64>,
+    pub context: String,
+
+    // how often it happened
+    pub count: u64,
+}
+
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.block {
+            Some(block) => write!(f, "{} {}: {}", self.kind, block, self.context),
+            None => write!(f, "{}: {}", self.kind, self.context),
+        }
+    }
+}
+
+
+#[derive(Default, Debug)]
+pub struct Diagnostics {
+    // the oldest first
+    ring: VecDeque<Warning>,
+
+    window_start: Option<Instant>,
+    logged_in_window: usize,
+
+    // warnings not logged because of the rate limit, in this window and in total
+    window_suppressed: u64,
+    suppressed: u64,
+}
+
+
+impl Diagnostics {
+
+    // logs the warning unless it is a repeat or too many were logged lately
+    pub fn record(&mut self, kind: &'static str, block: Option<u64>, context: &str) -> bool {
+        self.record_at(kind, block, context, Instant::now())
+    }
+
+
+    fn record_at(&mut self, kind: &'static str, block: Option<u64>, context: &str, now: Instant) -> bool {
+        let same = |warning: &Warning| warning.kind == kind && warning.block == block && warning.context == context;
+
+        if let Some(i) = self.ring.iter().position(same) {
+            let mut warning = self.ring.remove(i).unwrap();
+            warning.count += 1;
+            self.ring.push_back(warning);
+            return false;
+        }
+
+        if self.ring.len() == RING_SIZE {
+            self.ring.pop_front();
+        }
+        let warning = Warning {kind, block, context: context.to_string(), count: 1};
+
+        if self.window_start.is_none_or(|start| now.duration_since(start) >= LOG_WINDOW) {
+            if self.window_suppressed > 0 {
+                warn!("{} more warnings were not logged", self.window_suppressed);
+            }
+            self.window_start = Some(now);
+            self.logged_in_window = 0;
+            self.window_suppressed = 0;
+        }
+
+        let logged = self.logged_in_window < LOG_BURST;
+        if logged {
+            warn!("{}", warning);
+            self.logged_in_window += 1;
+        }
+        else {
+            self.window_suppressed += 1;
+            self.suppressed += 1;
+        }
+
+        self.ring.push_back(warning);
+        logged
+    }
+
+
+    pub fn recent(&self) -> Vec<Warning> {
+        self.ring.iter().cloned().collect()
+    }
+
+
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+}
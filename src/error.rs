@@ -0,0 +1,148 @@
+//
+// Errors of the file system layers
+//
+
+use std::fmt;
+use std::os::raw::c_int;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errno_mapping() {
+        assert_eq!(PtfsError::NotFound.to_errno(), libc::ENOENT);
+        assert_eq!(PtfsError::Exists.to_errno(), libc::EEXIST);
+        assert_eq!(PtfsError::NotADirectory.to_errno(), libc::ENOTDIR);
+        assert_eq!(PtfsError::NotEmpty.to_errno(), libc::ENOTEMPTY);
+        assert_eq!(PtfsError::NoSpace.to_errno(), libc::ENOSPC);
+        assert_eq!(PtfsError::NotPermitted.to_errno(), libc::EPERM);
+        assert_eq!(PtfsError::NotSupported.to_errno(), libc::EOPNOTSUPP);
+        assert_eq!(PtfsError::ReadOnly.to_errno(), libc::EROFS);
+        assert_eq!(PtfsError::AccessDenied.to_errno(), libc::EACCES);
+        assert_eq!(PtfsError::NoAttribute.to_errno(), libc::ENODATA);
+        assert_eq!(PtfsError::Corrupt("test".to_string()).to_errno(), libc::EUCLEAN);
+        
+        let io = std::io::Error::from_raw_os_error(libc::EACCES);
+        assert_eq!(PtfsError::from(io).to_errno(), libc::EIO);
+    }
+}
+
+
+#[derive(Debug)]
+pub enum PtfsError {
+    // the backing store failed
+    Io(std::io::Error),
+
+    // an on-disk structure is damaged
+    Corrupt(String),
+
+    // no such inode or name
+    NotFound,
+
+    // the name is taken already
+    Exists,
+
+    // a directory was expected
+    NotADirectory,
+
+    // a directory was not expected
+    IsADirectory,
+
+    // the directory still has entries
+    NotEmpty,
+
+    // names must fit into a directory entry
+    NameTooLong,
+
+    // the operation is not allowed on this node
+    NotPermitted,
+
+    // the operation is not implemented for this case, ENOSYS would make
+    // the kernel stop asking
+    NotSupported,
+
+    // bad parameters, e.g. negative offsets
+    InvalidArgument,
+
+    // no extended attribute of this name
+    NoAttribute,
+
+    // the image is mounted read-only
+    ReadOnly,
+
+    // the file handle was not opened for this
+    AccessDenied,
+
+    // no free blocks left
+    NoSpace,
+
+    // the file does not fit into its index block
+    TooLarge,
+
+    // the image cannot be mounted in its current state
+    Refused(String),
+}
+
+
+impl fmt::Display for PtfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PtfsError::Io(err) => write!(f, "I/O error: {}", err),
+            PtfsError::Corrupt(msg) => write!(f, "file system is damaged: {}", msg),
+            PtfsError::NotFound => write!(f, "no such file or directory"),
+            PtfsError::Exists => write!(f, "file exists"),
+            PtfsError::NotADirectory => write!(f, "not a directory"),
+            PtfsError::IsADirectory => write!(f, "is a directory"),
+            PtfsError::NotEmpty => write!(f, "directory not empty"),
+            PtfsError::NameTooLong => write!(f, "file name too long"),
+            PtfsError::NotPermitted => write!(f, "operation not permitted"),
+            PtfsError::NotSupported => write!(f, "operation not supported"),
+            PtfsError::InvalidArgument => write!(f, "invalid argument"),
+            PtfsError::NoAttribute => write!(f, "no such attribute"),
+            PtfsError::ReadOnly => write!(f, "file system is read-only"),
+            PtfsError::AccessDenied => write!(f, "permission denied"),
+            PtfsError::NoSpace => write!(f, "no space left on file system"),
+            PtfsError::TooLarge => write!(f, "file too large"),
+            PtfsError::Refused(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+
+impl PtfsError {
+
+    // the errno to report to the kernel
+    pub fn to_errno(&self) -> c_int {
+        match self {
+            PtfsError::Io(_) => libc::EIO,
+            PtfsError::Corrupt(_) => libc::EUCLEAN,
+            PtfsError::NotFound => libc::ENOENT,
+            PtfsError::Exists => libc::EEXIST,
+            PtfsError::NotADirectory => libc::ENOTDIR,
+            PtfsError::IsADirectory => libc::EISDIR,
+            PtfsError::NotEmpty => libc::ENOTEMPTY,
+            PtfsError::NameTooLong => libc::ENAMETOOLONG,
+            PtfsError::NotPermitted => libc::EPERM,
+            PtfsError::NotSupported => libc::EOPNOTSUPP,
+            PtfsError::InvalidArgument => libc::EINVAL,
+            PtfsError::NoAttribute => libc::ENODATA,
+            PtfsError::ReadOnly => libc::EROFS,
+            PtfsError::AccessDenied => libc::EACCES,
+            PtfsError::NoSpace => libc::ENOSPC,
+            PtfsError::TooLarge => libc::EFBIG,
+            PtfsError::Refused(_) => libc::EBUSY,
+        }
+    }
+}
+
+
+impl std::error::Error for PtfsError {}
+
+
+impl From<std::io::Error> for PtfsError {
+    fn from(err: std::io::Error) -> PtfsError {
+        PtfsError::Io(err)
+    }
+}
@@ -0,0 +1,66 @@
+//
+// Change notifications for embedders that keep their own view of the
+// file system, e.g. a search index or the model of a GUI
+//
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers() {
+        let mut subscribers = Subscribers::default();
+        subscribers.notify(ChangeEvent::Tagged {ino: 1, tag: "lost".to_string()});
+
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        subscribers.notify(ChangeEvent::Tagged {ino: 2, tag: "old".to_string()});
+
+        assert_eq!(first.try_recv().unwrap(), ChangeEvent::Tagged {ino: 2, tag: "old".to_string()});
+        assert!(first.try_recv().is_err());
+
+        // receivers that are gone are dropped on the next event
+        drop(second);
+        subscribers.notify(ChangeEvent::Untagged {ino: 2, tag: "old".to_string()});
+        assert_eq!(subscribers.senders.len(), 1);
+        assert_eq!(first.try_recv().unwrap(), ChangeEvent::Untagged {ino: 2, tag: "old".to_string()});
+    }
+}
+
+
+// Events are sent after the change was made in the cache. Names are the
+// names in the parent directory. Modified comes when a handle that could
+// write is closed and when a file is truncated.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChangeEvent {
+    Created {parent: u64, ino: u64, name: String},
+    Deleted {parent: u64, ino: u64, name: String},
+    Modified {ino: u64},
+    Renamed {ino: u64, old_parent: u64, old_name: String, new_parent: u64, new_name: String},
+    Tagged {ino: u64, tag: String},
+    Untagged {ino: u64, tag: String},
+}
+
+
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<Sender<ChangeEvent>>,
+}
+
+
+impl Subscribers {
+
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+
+    pub fn notify(&mut self, event: ChangeEvent) {
+        self.senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
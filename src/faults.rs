@@ -0,0 +1,210 @@
+//
+// Fault injection for crash tests. The backing store fails or tears the
+// Nth block write as if the power went out right there, everything after
+// that fails too. Set PTFS_FAIL_WRITE=N or PTFS_TORN_WRITE=N to use it
+// with a binary built with the fault-injection feature. Bad blocks fail
+// each read and write of themselves, the rest of the store keeps working.
+//
+
+use std::io::Error;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuser::FileType;
+    use crate::error::PtfsError;
+    use crate::path_tag_fs::{MountMode, PathTagFs, BLOCK_SIZE, INO_ROOT, PATHS_DIR};
+
+    #[test]
+    fn test_plan() {
+        let mut plan = FaultPlan::fail_at(3);
+        assert_eq!(plan.next_write(), Fault::None);
+        assert_eq!(plan.next_write(), Fault::None);
+        assert!(!plan.crashed());
+        assert_eq!(plan.next_write(), Fault::Fail);
+        assert!(plan.crashed());
+        assert_eq!(plan.next_write(), Fault::Fail);
+
+        let mut plan = FaultPlan::torn_at(1);
+        assert_eq!(plan.next_write(), Fault::Torn);
+        assert_eq!(plan.next_write(), Fault::Fail);
+    }
+
+    fn make_image(path: &str) {
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let old = fs.mknod(paths, &"old".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(old, None, 0, &[1; 2 * BLOCK_SIZE]).unwrap();
+        fs.destroy().unwrap();
+    }
+
+    fn workload(fs: &mut PathTagFs) -> Result<(), PtfsError> {
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string())?.ino;
+        let music = fs.mkdir(paths, &"music".to_string())?.ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile)?.ino;
+        fs.write(song, None, 0, &[5; 3 * BLOCK_SIZE])?;
+        fs.add_tag(song, "loud")?;
+        fs.sync()?;
+
+        let tune = fs.mknod(music, &"tune".to_string(), FileType::RegularFile)?.ino;
+        fs.write(tune, None, 0, b"short")?;
+        fs.sync()
+    }
+
+    // runs the workload with a crash at each of its writes in turn
+    fn crash_everywhere(path: &str, plan: fn(u64) -> FaultPlan, check: fn(&str)) {
+        for n in 1.. {
+            make_image(path);
+            let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+            fs.open(INO_ROOT, false).unwrap();
+            fs.inject_faults(plan(n));
+
+            if workload(&mut fs).is_ok() {
+                assert!(n > 10);
+                break;
+            }
+            drop(fs);
+
+            check(path);
+        }
+    }
+
+    #[test]
+    fn test_failed_writes_keep_image_consistent() {
+        crash_everywhere("/tmp/ptfs_test_fault_fail", FaultPlan::fail_at, |path| {
+            // not cleanly unmounted, but nothing reachable is damaged
+            let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+            assert!(fs.open(INO_ROOT, false).is_err());
+            drop(fs);
+
+            let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+            fs.open(INO_ROOT, true).unwrap();
+            let report = fs.quick_check().unwrap();
+            assert!(report.problems.is_empty(), "{:?}", report.problems);
+
+            let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+            let old = fs.lookup(paths, &"old".to_string()).unwrap().ino;
+            assert_eq!(fs.read_file(old, 0, 3 * BLOCK_SIZE as u64).unwrap(), vec![1; 2 * BLOCK_SIZE]);
+            fs.destroy().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_torn_writes_can_be_salvaged() {
+        crash_everywhere("/tmp/ptfs_test_fault_torn", FaultPlan::torn_at, |path| {
+            // a torn block may be damaged, but rescue mode gets at the rest
+            let mut fs = PathTagFs::new(path, MountMode::Rescue).unwrap();
+            fs.open(INO_ROOT, false).unwrap();
+            fs.quick_check().unwrap();
+        });
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum Fault {
+    None,
+
+    // the write doesn't happen
+    Fail,
+
+    // only the first half of the block is written
+    Torn,
+}
+
+
+pub struct FaultPlan {
+    fail_at: Option<u64>,
+    torn_at: Option<u64>,
+    bad: Vec<u64>,
+
+    // block writes so far
+    writes: u64,
+    crashed: bool,
+}
+
+
+impl FaultPlan {
+
+    pub fn fail_at(n: u64) -> FaultPlan {
+        FaultPlan {
+            fail_at: Some(n),
+            torn_at: None,
+            bad: Vec::new(),
+            writes: 0,
+            crashed: false,
+        }
+    }
+
+
+    pub fn torn_at(n: u64) -> FaultPlan {
+        FaultPlan {
+            fail_at: None,
+            torn_at: Some(n),
+            bad: Vec::new(),
+            writes: 0,
+            crashed: false,
+        }
+    }
+
+
+    pub fn bad_blocks(blocks: &[u64]) -> FaultPlan {
+        FaultPlan {
+            fail_at: None,
+            torn_at: None,
+            bad: blocks.to_vec(),
+            writes: 0,
+            crashed: false,
+        }
+    }
+
+
+    // PTFS_FAIL_WRITE or PTFS_TORN_WRITE, None if neither is set
+    pub fn from_env() -> Option<FaultPlan> {
+        let number = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<u64>().ok());
+
+        match (number("PTFS_FAIL_WRITE"), number("PTFS_TORN_WRITE")) {
+            (Some(n), _) => Some(FaultPlan::fail_at(n)),
+            (None, Some(n)) => Some(FaultPlan::torn_at(n)),
+            (None, None) => None,
+        }
+    }
+
+
+    // called before each block write, tells what happens to it
+    pub fn next_write(&mut self) -> Fault {
+        if self.crashed {
+            return Fault::Fail;
+        }
+
+        self.writes += 1;
+        if self.fail_at == Some(self.writes) {
+            self.crashed = true;
+            return Fault::Fail;
+        }
+        if self.torn_at == Some(self.writes) {
+            self.crashed = true;
+            return Fault::Torn;
+        }
+
+        Fault::None
+    }
+
+
+    pub fn is_bad(&self, no: u64) -> bool {
+        self.bad.contains(&no)
+    }
+
+
+    // after the crash nothing can be read or written anymore
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+
+    pub fn error() -> Error {
+        Error::other("injected fault")
+    }
+}
@@ -0,0 +1,211 @@
+//
+// High level access to an unmounted image, addressed by paths instead of inodes
+//
+
+use fuser::FileType;
+
+use crate::error::PtfsError;
+use crate::path_tag_fs::{MountMode, PathTagFs, INO_ROOT};
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_and_tags() {
+        let path = "/tmp/ptfs_test_handle";
+        let _ = std::fs::remove_file(path);
+
+        let mut handle = PtfsHandle::create_image(path, 128).unwrap();
+        handle.mkdir("/Pathes/music").unwrap();
+        handle.write_file("/Pathes/music/song", b"la la la").unwrap();
+        handle.write_file("/Pathes/music/tune", b"dum di dum").unwrap();
+        assert_eq!(handle.read_file("/Pathes/music/song").unwrap(), b"la la la");
+        assert!(matches!(handle.read_file("/Pathes/music/missing"), Err(PtfsError::NotFound)));
+
+        let names: Vec<String> = handle.list_dir("/Pathes/music").unwrap().into_iter().map(|c| c.2).collect();
+        assert_eq!(names, vec!["song", "tune"]);
+
+        handle.tag("/Pathes/music/song", "loud").unwrap();
+        handle.tag("/Pathes/music/song", "old").unwrap();
+        handle.tag("/Pathes/music/tune", "old").unwrap();
+        assert!(matches!(handle.tag("/Pathes/music/tune", "old"), Err(PtfsError::Exists)));
+
+        assert_eq!(handle.query(&["old"]).unwrap().len(), 2);
+        let both = handle.query(&["old", "loud"]).unwrap();
+        assert_eq!(both.len(), 1);
+        assert_eq!(both[0].1, "song");
+
+        handle.untag("/Pathes/music/song", "loud").unwrap();
+        assert!(handle.query(&["old", "loud"]).unwrap().is_empty());
+        assert!(matches!(handle.query(&["unknown"]), Err(PtfsError::NotFound)));
+        handle.close().unwrap();
+
+        // everything must have reached the image
+        let mut handle = PtfsHandle::open_image(path, MountMode::ReadOnly).unwrap();
+        assert_eq!(handle.read_file("/Pathes/music/tune").unwrap(), b"dum di dum");
+        assert_eq!(handle.query(&["old"]).unwrap().len(), 2);
+        assert!(matches!(handle.tag("/Pathes/music/tune", "new"), Err(PtfsError::ReadOnly)));
+        handle.close().unwrap();
+
+        // a reader next to the writer sees what it has written
+        let mut writer = PtfsHandle::open_image(path, MountMode::ReadWrite).unwrap();
+        writer.write_file("/Pathes/music/new", b"fresh").unwrap();
+        writer.fs().sync().unwrap();
+        assert!(PtfsHandle::open_image(path, MountMode::ReadOnly).is_err());
+        let mut reader = PtfsHandle::open_shared(path).unwrap();
+        assert_eq!(reader.read_file("/Pathes/music/new").unwrap(), b"fresh");
+        assert!(matches!(reader.tag("/Pathes/music/new", "new"), Err(PtfsError::ReadOnly)));
+        reader.close().unwrap();
+        writer.close().unwrap();
+    }
+}
+
+
+pub struct PtfsHandle {
+    fs: PathTagFs,
+}
+
+
+impl PtfsHandle {
+
+    // formats a new image of size blocks and opens it for writing
+    pub fn create_image(path: &str, size: u64) -> Result<PtfsHandle, PtfsError> {
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite)?;
+        fs.mkfs(INO_ROOT, size)?;
+        fs.destroy()?;
+
+        PtfsHandle::open_image(path, MountMode::ReadWrite)
+    }
+
+
+    pub fn open_image(path: &str, mode: MountMode) -> Result<PtfsHandle, PtfsError> {
+        let mut fs = PathTagFs::new(path, mode)?;
+        fs.open(INO_ROOT, false)?;
+
+        Ok(PtfsHandle {
+            fs: fs,
+        })
+    }
+
+
+    // read-only, also while the image is mounted read-write elsewhere
+    pub fn open_shared(path: &str) -> Result<PtfsHandle, PtfsError> {
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly)?;
+        fs.set_shared(true)?;
+        fs.open(INO_ROOT, false)?;
+
+        Ok(PtfsHandle {
+            fs,
+        })
+    }
+
+
+    // writes all pending changes and marks the image clean
+    pub fn close(mut self) -> Result<(), PtfsError> {
+        self.fs.destroy()
+    }
+
+
+    // direct access for operations that are not covered by the handle
+    pub fn fs(&mut self) -> &mut PathTagFs {
+        &mut self.fs
+    }
+
+
+    // returns the inode of an absolute path
+    pub fn resolve(&mut self, path: &str) -> Result<u64, PtfsError> {
+        self.fs.resolve(path).ok_or(PtfsError::NotFound)
+    }
+
+
+    // splits path into the inode of its parent directory and the last component
+    fn resolve_parent(&mut self, path: &str) -> Result<(u64, String), PtfsError> {
+        let path = path.trim_end_matches('/');
+
+        match path.rsplit_once('/') {
+            None => Err(PtfsError::InvalidArgument),
+            Some((parent, name)) => Ok((self.resolve(parent)?, name.to_string())),
+        }
+    }
+
+
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, PtfsError> {
+        let ino = self.resolve(path)?;
+        let size = self.fs.getattr(ino)?.size;
+
+        self.fs.read_file(ino, 0, size)
+    }
+
+
+    // replaces the contents of a file, creates it if needed
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), PtfsError> {
+        let ino = match self.resolve(path) {
+            Ok(ino) => ino,
+            Err(PtfsError::NotFound) => {
+                let (parent, name) = self.resolve_parent(path)?;
+                self.fs.mknod(parent, &name, FileType::RegularFile)?.ino
+            }
+            Err(err) => return Err(err),
+        };
+
+        if self.fs.getattr(ino)?.kind == FileType::Directory {
+            return Err(PtfsError::IsADirectory);
+        }
+
+        self.fs.write(ino, None, 0, data)
+    }
+
+
+    // the data of the new file stays in host_file, see PathTagFs::add_reference()
+    pub fn add_reference(&mut self, path: &str, host_file: &str) -> Result<u64, PtfsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        Ok(self.fs.add_reference(parent, &name, host_file)?.ino)
+    }
+
+
+    pub fn mkdir(&mut self, path: &str) -> Result<u64, PtfsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        Ok(self.fs.mkdir(parent, &name)?.ino)
+    }
+
+
+    // lists a directory without its "." and ".." entries
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<(u64, FileType, String)>, PtfsError> {
+        let ino = self.resolve(path)?;
+        let mut children = self.fs.list_children(ino)?;
+        children.retain(|child| child.2 != "." && child.2 != "..");
+
+        Ok(children)
+    }
+
+
+    pub fn tag(&mut self, path: &str, tag: &str) -> Result<(), PtfsError> {
+        let ino = self.resolve(path)?;
+        self.fs.add_tag(ino, tag)
+    }
+
+
+    pub fn untag(&mut self, path: &str, tag: &str) -> Result<(), PtfsError> {
+        let ino = self.resolve(path)?;
+        self.fs.remove_tag(ino, tag)
+    }
+
+
+    // returns the files that carry all of the given tags
+    pub fn query(&mut self, tags: &[&str]) -> Result<Vec<(u64, String)>, PtfsError> {
+        let mut result: Option<Vec<(u64, String)>> = None;
+
+        for tag in tags {
+            let members = self.fs.list_tagged(tag)?;
+
+            result = match result {
+                None => Some(members),
+                Some(files) => Some(files.into_iter().filter(|file| members.iter().any(|m| m.0 == file.0)).collect()),
+            };
+        }
+
+        Ok(result.unwrap_or_default())
+    }
+}
@@ -0,0 +1,186 @@
+//
+// Translation of owners between the image and the mount, so an image made
+// on another machine shows sane ownership without a chown -R. Each pair
+// maps an id on the image to the id presented in the mount, ids without a
+// pair pass through unchanged. Squashing instead shows all files with one
+// owner and fixed modes, like archive mounts do.
+//
+
+use fuser::{FileAttr, FileType};
+
+use crate::error::PtfsError;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_mapping() {
+        let mut map = IdMap::default();
+        assert!(map.is_empty());
+        map.add_uid("1000:501").unwrap();
+        map.add_gid("100:20").unwrap();
+
+        assert_eq!(map.uid_to_host(501), 1000);
+        assert_eq!(map.uid_to_host(0), 0);
+        assert_eq!(map.uid_to_fs(1000), 501);
+        assert_eq!(map.gid_to_host(20), 100);
+        assert_eq!(map.gid_to_fs(100), 20);
+        assert_eq!(map.gid_to_fs(501), 501);
+
+        // a later pair for the same id replaces the first one
+        map.add_uid("1001:501").unwrap();
+        assert_eq!(map.uid_to_host(501), 1001);
+        assert_eq!(map.uid_to_fs(1000), 1000);
+    }
+
+    fn attr(kind: FileType, perm: u16) -> FileAttr {
+        FileAttr {
+            ino: 5, size: 0, blocks: 0, atime: UNIX_EPOCH, mtime: UNIX_EPOCH, ctime: UNIX_EPOCH, crtime: UNIX_EPOCH,
+            kind, perm, nlink: 1, uid: 501, gid: 20, rdev: 0, blksize: 0, flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_present() {
+        let mut map = IdMap::default();
+        map.add_uid("1000:501").unwrap();
+
+        let mut file = attr(FileType::RegularFile, 0o600);
+        map.present(&mut file);
+        assert_eq!((file.uid, file.gid, file.perm), (1000, 20, 0o600));
+
+        // squashing wins over the pairs, symlinks keep their mode
+        map.squash = Some(Squash {uid: 33, gid: 33, file_mode: 0o644, dir_mode: 0o755});
+        for (kind, perm) in [(FileType::RegularFile, 0o644), (FileType::Directory, 0o755), (FileType::Symlink, 0o777)] {
+            let mut entry = attr(kind, 0o777);
+            map.present(&mut entry);
+            assert_eq!((entry.uid, entry.gid, entry.perm), (33, 33, perm));
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_pair("0:4711").unwrap(), (0, 4711));
+        for bad in ["", "1000", "1000:", ":501", "a:1", "1:2:3", "-1:5"] {
+            assert!(parse_pair(bad).is_err(), "{}", bad);
+        }
+
+        assert_eq!(parse_mode("0644").unwrap(), 0o644);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert!(parse_mode("0800").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+}
+
+
+// the owner and the modes every file is shown with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Squash {
+    pub uid: u32,
+    pub gid: u32,
+    pub file_mode: u16,
+    pub dir_mode: u16,
+}
+
+
+// pairs of host and image id
+#[derive(Clone, Default, Debug)]
+pub struct IdMap {
+    uids: Vec<(u32, u32)>,
+    gids: Vec<(u32, u32)>,
+    pub squash: Option<Squash>,
+}
+
+
+impl IdMap {
+
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty() && self.squash.is_none()
+    }
+
+
+    // the attributes as the mount shows them, the stored ones are kept
+    pub fn present(&self, attr: &mut FileAttr) {
+        match self.squash {
+            Some(squash) => {
+                attr.uid = squash.uid;
+                attr.gid = squash.gid;
+                match attr.kind {
+                    FileType::Directory => attr.perm = squash.dir_mode,
+                    FileType::Symlink => {}
+                    _ => attr.perm = squash.file_mode,
+                }
+            }
+            None => {
+                attr.uid = self.uid_to_host(attr.uid);
+                attr.gid = self.gid_to_host(attr.gid);
+            }
+        }
+    }
+
+
+    // "host:fs" as given to --map-uid
+    pub fn add_uid(&mut self, spec: &str) -> Result<(), PtfsError> {
+        add_pair(&mut self.uids, spec)
+    }
+
+
+    pub fn add_gid(&mut self, spec: &str) -> Result<(), PtfsError> {
+        add_pair(&mut self.gids, spec)
+    }
+
+
+    pub fn uid_to_host(&self, uid: u32) -> u32 {
+        lookup(&self.uids, uid, |(host, fs)| (*fs, *host))
+    }
+
+
+    pub fn uid_to_fs(&self, uid: u32) -> u32 {
+        lookup(&self.uids, uid, |(host, fs)| (*host, *fs))
+    }
+
+
+    pub fn gid_to_host(&self, gid: u32) -> u32 {
+        lookup(&self.gids, gid, |(host, fs)| (*fs, *host))
+    }
+
+
+    pub fn gid_to_fs(&self, gid: u32) -> u32 {
+        lookup(&self.gids, gid, |(host, fs)| (*host, *fs))
+    }
+}
+
+
+// each id is mapped in one pair at most, in both directions
+fn add_pair(pairs: &mut Vec<(u32, u32)>, spec: &str) -> Result<(), PtfsError> {
+    let (host, fs) = parse_pair(spec)?;
+    pairs.retain(|pair| pair.0 != host && pair.1 != fs);
+    pairs.push((host, fs));
+    Ok(())
+}
+
+
+fn parse_pair(spec: &str) -> Result<(u32, u32), PtfsError> {
+    let (host, fs) = spec.split_once(':').ok_or(PtfsError::InvalidArgument)?;
+    let host = host.parse().map_err(|_| PtfsError::InvalidArgument)?;
+    let fs = fs.parse().map_err(|_| PtfsError::InvalidArgument)?;
+    Ok((host, fs))
+}
+
+
+// octal permission bits like "0644", without the file type
+pub fn parse_mode(text: &str) -> Result<u16, PtfsError> {
+    match u16::from_str_radix(text, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(PtfsError::InvalidArgument),
+    }
+}
+
+
+// direction gives the pair as (from, to)
+fn lookup(pairs: &[(u32, u32)], id: u32, direction: impl Fn(&(u32, u32)) -> (u32, u32)) -> u32 {
+    pairs.iter().map(direction).find(|(from, _)| *from == id).map_or(id, |(_, to)| to)
+}
@@ -0,0 +1,229 @@
+//
+// Backing store I/O with a time limit. The transfers run in a thread of
+// their own, so a stalled disk or network backend fails the operation
+// instead of hanging the FUSE request and with it the mount.
+//
+
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use log::warn;
+
+use crate::block_io::AlignedBlock;
+use crate::error::PtfsError;
+use crate::path_tag_fs::BLOCK_SIZE;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfers() {
+        let path = "/tmp/ptfs_test_io_worker";
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(path).unwrap();
+        let mut worker = IoWorker::new(file.try_clone().unwrap(), Duration::from_secs(5));
+
+        assert_eq!(worker.write(b"hello", 10, false).unwrap(), 5);
+        assert_eq!(worker.read(8, 8, false).unwrap(), b"\0\0hello");
+        assert_eq!(worker.read(4, 100, false).unwrap(), b"");
+
+        // aligned transfers fill the block with zeros
+        assert_eq!(worker.write(b"block", BLOCK_SIZE as u64, true).unwrap(), 5);
+        let data = worker.read(BLOCK_SIZE, BLOCK_SIZE as u64, true).unwrap();
+        assert_eq!(data.len(), BLOCK_SIZE);
+        assert_eq!(&data[..6], b"block\0");
+        worker.sync().unwrap();
+        worker.sync_data().unwrap();
+        assert!(!worker.is_degraded());
+    }
+
+    #[test]
+    fn test_timeout() {
+        // a backend that takes the requests but does not answer
+        let (requests, _stalled) = channel();
+        let (backend, answers) = channel();
+        let mut worker = IoWorker {
+            requests,
+            answers,
+            timeout: Duration::from_millis(50),
+            pending: None,
+            next_id: 0,
+            degraded: false,
+        };
+
+        assert_eq!(worker.read(4, 0, false).unwrap_err().to_errno(), libc::EIO);
+        assert!(worker.is_degraded());
+
+        // the stalled request is still running
+        assert_eq!(worker.sync().unwrap_err().to_errno(), libc::EIO);
+
+        // it finally returns, then requests work again
+        backend.send((1, Ok(Vec::new()))).unwrap();
+        backend.send((2, Ok(b"data".to_vec()))).unwrap();
+        assert_eq!(worker.read(4, 0, false).unwrap(), b"data");
+        assert!(worker.is_degraded());
+    }
+}
+
+
+// aligned transfers are for O_DIRECT and move whole blocks
+enum Request {
+    Read {size: usize, offset: u64, aligned: bool},
+    Write {data: Vec<u8>, offset: u64, aligned: bool},
+    // only the data and what is needed to read it back, like fdatasync()
+    Sync {data_only: bool},
+}
+
+
+// the result of a request, with its id
+type Answer = (u64, Result<Vec<u8>, Error>);
+
+
+pub struct IoWorker {
+    requests: Sender<(u64, Request)>,
+    answers: Receiver<Answer>,
+    timeout: Duration,
+
+    // the request that did not answer in time, if it still hangs
+    pending: Option<u64>,
+    next_id: u64,
+
+    // set by the first timeout, it stays for the rest of the mount
+    degraded: bool,
+}
+
+
+impl IoWorker {
+
+    pub fn new(file: File, timeout: Duration) -> IoWorker {
+        let (requests, worker_requests) = channel::<(u64, Request)>();
+        let (worker_answers, answers) = channel();
+
+        thread::spawn(move || {
+            for (id, request) in worker_requests {
+                if worker_answers.send((id, transfer(&file, request))).is_err() {
+                    break;
+                }
+            }
+        });
+
+        IoWorker {
+            requests,
+            answers,
+            timeout,
+            pending: None,
+            next_id: 0,
+            degraded: false,
+        }
+    }
+
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+
+    // reads up to size bytes, less at the end of the file
+    pub fn read(&mut self, size: usize, offset: u64, aligned: bool) -> Result<Vec<u8>, PtfsError> {
+        self.run(Request::Read {size, offset, aligned})
+    }
+
+
+    pub fn write(&mut self, data: &[u8], offset: u64, aligned: bool) -> Result<usize, PtfsError> {
+        self.run(Request::Write {data: data.to_vec(), offset, aligned})?;
+        Ok(data.len())
+    }
+
+
+    pub fn sync(&mut self) -> Result<(), PtfsError> {
+        self.run(Request::Sync {data_only: false})?;
+        Ok(())
+    }
+
+
+    pub fn sync_data(&mut self) -> Result<(), PtfsError> {
+        self.run(Request::Sync {data_only: true})?;
+        Ok(())
+    }
+
+
+    fn run(&mut self, request: Request) -> Result<Vec<u8>, PtfsError> {
+        // requests queue up behind a stalled one, so they fail right away
+        if let Some(id) = self.pending {
+            match self.answers.try_recv() {
+                Ok((answer_id, _)) if answer_id == id => self.pending = None,
+                _ => return Err(stalled()),
+            }
+        }
+
+        self.next_id += 1;
+        let id = self.next_id;
+        if self.requests.send((id, request)).is_err() {
+            return Err(PtfsError::Io(Error::new(ErrorKind::BrokenPipe, "I/O thread of the backing store is gone")));
+        }
+
+        match self.answers.recv_timeout(self.timeout) {
+            Ok((_, result)) => Ok(result?),
+            Err(RecvTimeoutError::Timeout) => {
+                warn!("backing store did not answer within {:?}, it is marked as degraded", self.timeout);
+                self.pending = Some(id);
+                self.degraded = true;
+                Err(stalled())
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(PtfsError::Io(Error::new(ErrorKind::BrokenPipe, "I/O thread of the backing store is gone")))
+            }
+        }
+    }
+}
+
+
+fn stalled() -> PtfsError {
+    PtfsError::Io(Error::new(ErrorKind::TimedOut, "backing store does not answer"))
+}
+
+
+fn transfer(file: &File, request: Request) -> Result<Vec<u8>, Error> {
+    match request {
+        Request::Read {size, offset, aligned: true} => {
+            let mut buffer = AlignedBlock([0; BLOCK_SIZE]);
+            let count = file.read_at(&mut buffer.0, offset)?;
+            Ok(buffer.0[..std::cmp::min(count, size)].to_vec())
+        }
+        Request::Read {size, offset, aligned: false} => {
+            let mut data = vec![0; size];
+            let mut count = 0;
+            while count < size {
+                let n = file.read_at(&mut data[count..], offset + count as u64)?;
+                if n == 0 {
+                    break;
+                }
+                count += n;
+            }
+            data.truncate(count);
+            Ok(data)
+        }
+        Request::Write {data, offset, aligned: true} => {
+            let mut buffer = AlignedBlock([0; BLOCK_SIZE]);
+            buffer.0[..data.len()].copy_from_slice(&data);
+            file.write_all_at(&buffer.0, offset)?;
+            Ok(Vec::new())
+        }
+        Request::Write {data, offset, aligned: false} => {
+            file.write_all_at(&data, offset)?;
+            Ok(Vec::new())
+        }
+        Request::Sync {data_only: false} => {
+            file.sync_all()?;
+            Ok(Vec::new())
+        }
+        Request::Sync {data_only: true} => {
+            file.sync_data()?;
+            Ok(Vec::new())
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//
+// Admin ioctls on the root directory of a mount
+//
+// The numbers follow the Linux _IO/_IOR encoding, so the kernel knows how
+// much data to copy back for QUICK_CHECK without asking the file system.
+//
+
+use crate::diagnostics::Warning;
+use crate::journal::Record;
+use crate::path_tag_fs::CheckReport;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        // same values as _IO('P', 1) and _IOR('P', 3, struct {u64 a, b, c}) in C
+        assert_eq!(IOC_SYNC, 0x5001);
+        assert_eq!(IOC_QUICK_CHECK, 0x80185003);
+        assert_eq!(IOC_TAG_FILES, 0x60005004);
+        assert_eq!(IOC_WARNINGS, 0x90005005);
+        assert_eq!(IOC_FREEZE, 0x5006);
+        assert_eq!(IOC_CHANGES, 0xe0005008);
+
+        // _IOR('f', 1, long) and _IOW('f', 2, long) from linux/fs.h
+        assert_eq!(FS_IOC_GETFLAGS, 0x80086601);
+        assert_eq!(FS_IOC_SETFLAGS, 0x40086602);
+    }
+
+    #[test]
+    fn test_tag_batch() {
+        let data = encode_tag_batch(true, "music/jazz", &[7, 12, 9]).unwrap();
+        assert_eq!(data.len(), TAG_BATCH_SIZE);
+        assert_eq!(decode_tag_batch(&data), Some((true, "music/jazz".to_string(), vec![7, 12, 9])));
+        assert_eq!(decode_tag_batch(&data[1..]), None);
+
+        let full = vec![1; TAG_BATCH_MAX];
+        assert_eq!(decode_tag_batch(&encode_tag_batch(false, &"t".repeat(255), &full).unwrap()).unwrap().2, full);
+        assert!(encode_tag_batch(true, "music", &vec![1; TAG_BATCH_MAX + 1]).is_none());
+        assert!(encode_tag_batch(true, &"t".repeat(256), &[1]).is_none());
+    }
+
+    #[test]
+    fn test_check_result() {
+        let report = CheckReport {inodes: 7, blocks: 30, problems: vec!["lost".to_string()]};
+        let data = encode_check_result(&report);
+        assert_eq!(data.len(), CHECK_RESULT_SIZE);
+        assert_eq!(decode_check_result(&data), Some((7, 30, 1)));
+        assert_eq!(decode_check_result(&data[1..]), None);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let warning = Warning {kind: "data block", block: Some(12), context: "I/O error".to_string(), count: 3};
+        let data = encode_warnings(&[warning.clone()]);
+        assert_eq!(data.len(), WARNINGS_SIZE);
+        assert_eq!(decode_warnings(&data), Some(vec!["3x data block 12: I/O error".to_string()]));
+        assert_eq!(decode_warnings(&data[1..]), None);
+
+        // the newest ones are kept when they don't all fit
+        let many = (0..200).map(|count| Warning {count, ..warning.clone()}).collect::<Vec<_>>();
+        let lines = decode_warnings(&encode_warnings(&many)).unwrap();
+        assert!(lines.len() < 200);
+        assert_eq!(lines.last().unwrap(), "199x data block 12: I/O error");
+    }
+
+    #[test]
+    fn test_changes() {
+        use crate::journal::RecordKind;
+
+        assert_eq!(decode_changes_since(&encode_changes_since(77)), Some(77));
+        assert_eq!(decode_changes_since(&[1, 2]), None);
+
+        let record = Record {seq: 9, kind: RecordKind::Create, ino: 12, parent: 4, name: "song".to_string()};
+        let data = encode_changes(5, &[record.clone(), Record {seq: 10, ..record.clone()}]);
+        assert_eq!(data.len(), CHANGES_SIZE);
+        assert_eq!(decode_changes(&data), Some((5, vec!["9 create 12 4 song".to_string(), "10 create 12 4 song".to_string()])));
+        assert_eq!(decode_changes(&data[1..]), None);
+
+        // the oldest ones go first, the rest comes with the next call
+        let many = (0..1000).map(|seq| Record {seq, ..record.clone()}).collect::<Vec<_>>();
+        let lines = decode_changes(&encode_changes(0, &many)).unwrap().1;
+        assert!(lines.len() < 1000);
+        assert_eq!(lines[0], "0 create 12 4 song");
+    }
+}
+
+
+const IOC_TYPE:u32 = b'P' as u32;
+
+// writes all changes now
+pub const IOC_SYNC:u32 = io(1);
+
+// writes and forgets all cached blocks
+pub const IOC_DROP_CACHES:u32 = io(2);
+
+// checks the chains reachable from the root, returns three u64: inodes,
+// blocks and problems found. The problems are logged by the file system.
+pub const IOC_QUICK_CHECK:u32 = ior(3, CHECK_RESULT_SIZE);
+
+pub const CHECK_RESULT_SIZE:usize = 24;
+
+// Tags or untags many files in one call. The data is a flag, 1 to add and
+// 0 to remove, the length of the tag, the number of inodes as u16, then
+// the tag and the inodes as u64. The result is the number of files that
+// changed.
+pub const IOC_TAG_FILES:u32 = iow(4, TAG_BATCH_SIZE);
+
+pub const TAG_BATCH_SIZE:usize = 8192;
+
+// inodes that fit in one call with the longest tag
+pub const TAG_BATCH_MAX:usize = (TAG_BATCH_SIZE - 4 - 255) / 8;
+
+
+// the recent warnings about damaged blocks, one line each, the oldest
+// first and padded with zeros
+pub const IOC_WARNINGS:u32 = ior(5, WARNINGS_SIZE);
+
+pub const WARNINGS_SIZE:usize = 4096;
+
+
+// Writes all changes and syncs the image, then refuses changes with EBUSY
+// until IOC_THAW, for snapshots of the image file. The kernel handles
+// FIFREEZE itself and does not pass it on to FUSE.
+pub const IOC_FREEZE:u32 = io(6);
+
+pub const IOC_THAW:u32 = io(7);
+
+
+// The records of the change journal after a number, given as u64 at the
+// start of the data. The result is the number from which on no record is
+// missing as u64, then the records, one line each, as many as fit.
+pub const IOC_CHANGES:u32 = iowr(8, CHANGES_SIZE);
+
+pub const CHANGES_SIZE:usize = 8192;
+
+
+// chattr and lsattr on any file, the flags are passed as a long
+pub const FS_IOC_GETFLAGS:u32 = (2 << 30) | (8 << 16) | ((b'f' as u32) << 8) | 1;
+pub const FS_IOC_SETFLAGS:u32 = (1 << 30) | (8 << 16) | ((b'f' as u32) << 8) | 2;
+
+// the file flags path_tag_fs knows, same values as in linux/fs.h
+pub const FS_IMMUTABLE_FL:u32 = 0x10;
+pub const FS_APPEND_FL:u32 = 0x20;
+
+
+const fn io(nr: u32) -> u32 {
+    (IOC_TYPE << 8) | nr
+}
+
+
+const fn ior(nr: u32, size: usize) -> u32 {
+    (2 << 30) | ((size as u32) << 16) | (IOC_TYPE << 8) | nr
+}
+
+
+const fn iow(nr: u32, size: usize) -> u32 {
+    (1 << 30) | ((size as u32) << 16) | (IOC_TYPE << 8) | nr
+}
+
+
+const fn iowr(nr: u32, size: usize) -> u32 {
+    (3 << 30) | ((size as u32) << 16) | (IOC_TYPE << 8) | nr
+}
+
+
+// None if the tag or the list of inodes is too long
+pub fn encode_tag_batch(add: bool, tag: &str, inodes: &[u64]) -> Option<Vec<u8>> {
+    if tag.len() > 255 || inodes.len() > TAG_BATCH_MAX {
+        return None;
+    }
+
+    let mut data = Vec::with_capacity(TAG_BATCH_SIZE);
+    data.push(add as u8);
+    data.push(tag.len() as u8);
+    data.extend_from_slice(&(inodes.len() as u16).to_le_bytes());
+    data.extend_from_slice(tag.as_bytes());
+    for ino in inodes {
+        data.extend_from_slice(&ino.to_le_bytes());
+    }
+    data.resize(TAG_BATCH_SIZE, 0);
+    Some(data)
+}
+
+
+pub fn decode_tag_batch(data: &[u8]) -> Option<(bool, String, Vec<u64>)> {
+    if data.len() != TAG_BATCH_SIZE {
+        return None;
+    }
+
+    let tag_end = 4 + data[1] as usize;
+    let count = u16::from_le_bytes([data[2], data[3]]) as usize;
+    if count > TAG_BATCH_MAX {
+        return None;
+    }
+
+    let tag = String::from_utf8(data[4..tag_end].to_vec()).ok()?;
+    let inodes = data[tag_end..tag_end + count * 8].chunks(8).map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap())).collect();
+    Some((data[0] == 1, tag, inodes))
+}
+
+
+pub fn encode_check_result(report: &CheckReport) -> Vec<u8> {
+    let mut data = Vec::with_capacity(CHECK_RESULT_SIZE);
+    data.extend_from_slice(&report.inodes.to_le_bytes());
+    data.extend_from_slice(&report.blocks.to_le_bytes());
+    data.extend_from_slice(&(report.problems.len() as u64).to_le_bytes());
+    data
+}
+
+
+pub fn decode_check_result(data: &[u8]) -> Option<(u64, u64, u64)> {
+    if data.len() != CHECK_RESULT_SIZE {
+        return None;
+    }
+
+    let value = |i: usize| u64::from_le_bytes(data[i*8..i*8+8].try_into().unwrap());
+    Some((value(0), value(1), value(2)))
+}
+
+
+// the oldest warnings are left out if they don't fit
+pub fn encode_warnings(warnings: &[Warning]) -> Vec<u8> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    for warning in warnings.iter().rev() {
+        let line = format!("{}x {}\n", warning.count, warning);
+        size += line.len();
+        if size > WARNINGS_SIZE {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut data = lines.into_iter().rev().collect::<String>().into_bytes();
+    data.resize(WARNINGS_SIZE, 0);
+    data
+}
+
+
+pub fn decode_warnings(data: &[u8]) -> Option<Vec<String>> {
+    if data.len() != WARNINGS_SIZE {
+        return None;
+    }
+
+    let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    let text = std::str::from_utf8(&data[..end]).ok()?;
+    Some(text.lines().map(str::to_string).collect())
+}
+
+
+pub fn encode_changes_since(seq: u64) -> Vec<u8> {
+    let mut data = seq.to_le_bytes().to_vec();
+    data.resize(CHANGES_SIZE, 0);
+    data
+}
+
+
+pub fn decode_changes_since(data: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(..8)?.try_into().unwrap()))
+}
+
+
+// the newer records are left out if they don't all fit
+pub fn encode_changes(first: u64, records: &[Record]) -> Vec<u8> {
+    let mut data = first.to_le_bytes().to_vec();
+    for record in records {
+        let line = format!("{}\n", record);
+        if data.len() + line.len() > CHANGES_SIZE {
+            break;
+        }
+        data.extend_from_slice(line.as_bytes());
+    }
+
+    data.resize(CHANGES_SIZE, 0);
+    data
+}
+
+
+pub fn decode_changes(data: &[u8]) -> Option<(u64, Vec<String>)> {
+    if data.len() != CHANGES_SIZE {
+        return None;
+    }
+
+    let first = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let end = data[8..].iter().position(|byte| *byte == 0).map_or(data.len(), |end| end + 8);
+    let text = std::str::from_utf8(&data[8..end]).ok()?;
+    Some((first, text.lines().map(str::to_string).collect()))
+}
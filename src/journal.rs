@@ -0,0 +1,294 @@
+//
+// The change journal on the image, so indexers can ask what changed since
+// they last looked instead of scanning the whole tree. The records follow
+// the change events, named like the inotify events. Only the newest ones
+// are kept, a tool asking for older ones learns that it has to scan again.
+//
+
+use std::collections::VecDeque;
+
+use crate::error::PtfsError;
+use crate::events::ChangeEvent;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let mut journal = Journal::new(1);
+        journal.record(&ChangeEvent::Created {parent: 4, ino: 12, name: "song".to_string()});
+        journal.record(&ChangeEvent::Renamed {ino: 12, old_parent: 4, old_name: "song".to_string(), new_parent: 5, new_name: "live\nsong".to_string()});
+        journal.record(&ChangeEvent::Modified {ino: 12});
+
+        let lines = journal.since(0).map(Record::to_string).collect::<Vec<_>>();
+        assert_eq!(lines, vec!["1 create 12 4 song", "2 moved_from 12 4 song", "3 moved_to 12 5 live\\nsong", "4 modify 12 0 "]);
+        assert_eq!(journal.since(2).map(|record| record.seq).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(journal.since(4).count(), 0);
+        assert!(journal.complete_since(0));
+
+        // the oldest records go first
+        for ino in 0..MAX_RECORDS as u64 {
+            journal.record(&ChangeEvent::Tagged {ino, tag: "loud".to_string()});
+        }
+        assert_eq!(journal.len(), MAX_RECORDS);
+        assert_eq!(journal.first(), 5);
+        assert!(!journal.complete_since(3));
+        assert!(journal.complete_since(4));
+        assert_eq!(journal.since(0).next().unwrap().seq, 5);
+
+        // and those that don't fit into the blocks of the journal
+        journal.set_capacity(HEADER_WORDS + 2 * 6);
+        assert_eq!(journal.since(0).map(|record| record.seq).collect::<Vec<_>>(), vec![4099, 4100]);
+        assert_eq!(journal.first(), 4099);
+        journal.record(&ChangeEvent::Created {parent: 4, ino: 12, name: "a longer name".to_string()});
+        assert_eq!(journal.since(0).map(|record| record.seq).collect::<Vec<_>>(), vec![4101]);
+        assert_eq!(journal.encode().len(), HEADER_WORDS + 7);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut journal = Journal::new(7);
+        journal.record(&ChangeEvent::Deleted {parent: 4, ino: 12, name: "a much longer name".to_string()});
+        journal.record(&ChangeEvent::Untagged {ino: 13, tag: "work/urgent".to_string()});
+        journal.record(&ChangeEvent::Created {parent: 4, ino: 14, name: String::new()});
+        assert_eq!(Journal::decode(&journal.encode()).unwrap(), journal);
+
+        assert!(Journal::decode(&[]).is_err());
+        assert!(Journal::decode(&journal.encode()[..6]).is_err());
+        let mut words = journal.encode();
+        words[4] = 99;
+        assert!(Journal::decode(&words).is_err());
+
+        // a later mount goes on with higher numbers, the records are kept
+        let mut journal = Journal::decode(&journal.encode()).unwrap();
+        journal.resume(1 << 32);
+        journal.record(&ChangeEvent::Modified {ino: 14});
+        assert_eq!(journal.since(0).map(|record| record.seq).collect::<Vec<_>>(), vec![7, 8, 9, 1 << 32]);
+        assert!(journal.complete_since(6));
+    }
+}
+
+
+// records kept on the image, older ones are dropped
+const MAX_RECORDS:usize = 4096;
+
+// the first encoded word
+const VERSION:u64 = 1;
+
+// the version, the next and the first number
+const HEADER_WORDS:usize = 3;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordKind {
+    Create,
+    Delete,
+    Modify,
+    MovedFrom,
+    MovedTo,
+    Tag,
+    Untag,
+}
+
+
+impl RecordKind {
+
+    const ALL:[RecordKind; 7] = [RecordKind::Create, RecordKind::Delete, RecordKind::Modify, RecordKind::MovedFrom,
+                                 RecordKind::MovedTo, RecordKind::Tag, RecordKind::Untag];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordKind::Create => "create",
+            RecordKind::Delete => "delete",
+            RecordKind::Modify => "modify",
+            RecordKind::MovedFrom => "moved_from",
+            RecordKind::MovedTo => "moved_to",
+            RecordKind::Tag => "tag",
+            RecordKind::Untag => "untag",
+        }
+    }
+}
+
+
+// Tags have the tag as name and no parent, modify records neither.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub seq: u64,
+    pub kind: RecordKind,
+    pub ino: u64,
+    pub parent: u64,
+    pub name: String,
+}
+
+
+// one line, newlines and backslashes in the name are escaped
+impl std::fmt::Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.name.replace('\\', "\\\\").replace('\n', "\\n");
+        write!(f, "{} {} {} {} {}", self.seq, self.kind.name(), self.ino, self.parent, name)
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub struct Journal {
+    // the oldest first
+    records: VecDeque<Record>,
+
+    // the number of the next record
+    next: u64,
+
+    // no record from this number on is missing
+    first: u64,
+
+    // the encoded records, and what they may take, see set_capacity()
+    words: usize,
+    capacity: usize,
+}
+
+
+impl Journal {
+
+    // an empty journal, numbered from start on
+    pub fn new(start: u64) -> Journal {
+        Journal {records: VecDeque::new(), next: start, first: start, words: 0, capacity: usize::MAX}
+    }
+
+
+    // the encoding must fit into this many words, older records are dropped
+    pub fn set_capacity(&mut self, words: usize) {
+        self.capacity = words;
+        self.trim();
+    }
+
+
+    // a new mount numbers its records from start on at least
+    pub fn resume(&mut self, start: u64) {
+        self.next = self.next.max(start);
+    }
+
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+
+    pub fn first(&self) -> u64 {
+        self.first
+    }
+
+
+    // false if records after seq were dropped, the tree must be scanned again
+    pub fn complete_since(&self, seq: u64) -> bool {
+        seq.saturating_add(1) >= self.first
+    }
+
+
+    pub fn since(&self, seq: u64) -> impl Iterator<Item = &Record> {
+        let start = self.records.partition_point(|record| record.seq <= seq);
+        self.records.range(start..)
+    }
+
+
+    pub fn record(&mut self, event: &ChangeEvent) {
+        match event {
+            ChangeEvent::Created {parent, ino, name} => self.push(RecordKind::Create, *ino, *parent, name),
+            ChangeEvent::Deleted {parent, ino, name} => self.push(RecordKind::Delete, *ino, *parent, name),
+            ChangeEvent::Modified {ino} => self.push(RecordKind::Modify, *ino, 0, ""),
+            ChangeEvent::Renamed {ino, old_parent, old_name, new_parent, new_name} => {
+                self.push(RecordKind::MovedFrom, *ino, *old_parent, old_name);
+                self.push(RecordKind::MovedTo, *ino, *new_parent, new_name);
+            }
+            ChangeEvent::Tagged {ino, tag} => self.push(RecordKind::Tag, *ino, 0, tag),
+            ChangeEvent::Untagged {ino, tag} => self.push(RecordKind::Untag, *ino, 0, tag),
+        }
+    }
+
+
+    fn push(&mut self, kind: RecordKind, ino: u64, parent: u64, name: &str) {
+        let record = Record {seq: self.next, kind, ino, parent, name: name.to_string()};
+        self.words += record_words(&record);
+        self.records.push_back(record);
+        self.next += 1;
+        self.trim();
+    }
+
+
+    fn trim(&mut self) {
+        while self.records.len() > MAX_RECORDS || HEADER_WORDS + self.words > self.capacity {
+            let record = match self.records.pop_front() {
+                Some(record) => record,
+                None => break,
+            };
+
+            self.words -= record_words(&record);
+            self.first = self.records.front().map_or(self.next, |record| record.seq);
+        }
+    }
+
+
+    // The version, the next and the first number, then of each record the
+    // number, the kind, the inode, the parent, the length of the name and
+    // the name in words of eight bytes.
+    pub fn encode(&self) -> Vec<u64> {
+        let mut words = Vec::with_capacity(HEADER_WORDS + self.words);
+        words.extend_from_slice(&[VERSION, self.next, self.first]);
+
+        for record in &self.records {
+            let kind = RecordKind::ALL.iter().position(|kind| *kind == record.kind).unwrap() as u64;
+            words.extend_from_slice(&[record.seq, kind, record.ino, record.parent, record.name.len() as u64]);
+            for chunk in record.name.as_bytes().chunks(8) {
+                let mut bytes = [0; 8];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                words.push(u64::from_le_bytes(bytes));
+            }
+        }
+        words
+    }
+
+
+    pub fn decode(words: &[u64]) -> Result<Journal, PtfsError> {
+        let damaged = || PtfsError::Corrupt("change journal is damaged".to_string());
+
+        let (next, first, mut rest) = match words {
+            [VERSION, next, first, rest @ ..] => (*next, *first, rest),
+            _ => return Err(damaged()),
+        };
+
+        let mut journal = Journal {records: VecDeque::new(), next, first, words: 0, capacity: usize::MAX};
+        while let [seq, kind, ino, parent, length, tail @ ..] = rest {
+            let kind = RecordKind::ALL.get(*kind as usize).ok_or_else(damaged)?;
+            let name_words = (*length as usize).div_ceil(8);
+            if name_words > tail.len() {
+                return Err(damaged());
+            }
+
+            let mut name = tail[..name_words].iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<_>>();
+            name.truncate(*length as usize);
+            let name = String::from_utf8(name).map_err(|_| damaged())?;
+
+            let record = Record {seq: *seq, kind: *kind, ino: *ino, parent: *parent, name};
+            journal.words += record_words(&record);
+            journal.records.push_back(record);
+            rest = &tail[name_words..];
+        }
+
+        if !rest.is_empty() {
+            return Err(damaged());
+        }
+        Ok(journal)
+    }
+}
+
+
+// the number, the kind, the inode, the parent, the length and the name
+fn record_words(record: &Record) -> usize {
+    5 + record.name.len().div_ceil(8)
+}
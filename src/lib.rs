@@ -0,0 +1,37 @@
+//
+// Path tag file system as a library, for tools that work on unmounted images
+//
+
+pub mod nodes;
+pub mod path_tag_fs;
+pub mod attr_index;
+pub mod block_cache;
+pub mod block_io;
+pub mod diagnostics;
+pub mod error;
+pub mod events;
+pub mod handle;
+pub mod id_map;
+pub mod io_worker;
+pub mod ioctl;
+pub mod journal;
+pub mod overlay;
+pub mod query;
+pub mod sha256;
+pub mod stats;
+pub mod tag_order;
+pub mod tag_rules;
+
+#[cfg(feature = "ffi")]
+pub mod ptfs_ffi;
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod faults;
+
+pub use crate::error::PtfsError;
+pub use crate::events::ChangeEvent;
+pub use crate::handle::PtfsHandle;
+pub use crate::path_tag_fs::{Access, AllocPolicy, MountMode, PathTagFs, Preload, SyncMode, TagView, BLOCK_SIZE, INO_ROOT};
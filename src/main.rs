@@ -1,23 +1,27 @@
-mod nodes;
-mod path_tag_fs;
-mod block_cache;
-mod block_io;
-
-use path_tag_fs::PathTagFs;
+mod config;
+mod offline;
+
+use path_tag_fs::{Access, AllocPolicy, MountMode, PathTagFs, Preload, PtfsError, SyncMode, TagView, BLOCK_SIZE, INO_ROOT};
+use path_tag_fs::id_map::{self, IdMap, Squash};
+use path_tag_fs::ioctl;
+use path_tag_fs::journal::Record;
+use path_tag_fs::nodes::MAX_NAME_LENGTH;
 use clap::{Arg, ArgAction, Command};
 use fuser::{
-    FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow
+    consts, FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow
 };
-use libc::{ENOENT, ENOSYS, EPERM};
-use std::ffi::OsStr;
+use libc::{EIO, ENOSYS, ENOTTY, EPERM, ERANGE};
+use log::debug;
+use std::ffi::{OsStr, OsString};
+use std::os::fd::AsRawFd;
 use std::os::raw::c_int;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::sync::atomic::AtomicU64;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 const TTL: Duration = Duration::from_secs(1); // 1 second
-
-const INO_ROOT:u64 = 1;
+const PRELOAD_STEP: usize = 64; // inodes and data blocks per request
 
 
 fn safe_to_string(osstr: &OsStr) -> String {	
@@ -44,6 +48,14 @@ fn as_file_type(mut mode: u32) -> FileType {
         return FileType::Symlink;
     } else if mode == libc::S_IFDIR as u32 {
         return FileType::Directory;
+    } else if mode == libc::S_IFIFO as u32 {
+        return FileType::NamedPipe;
+    } else if mode == libc::S_IFCHR as u32 {
+        return FileType::CharDevice;
+    } else if mode == libc::S_IFBLK as u32 {
+        return FileType::BlockDevice;
+    } else if mode == libc::S_IFSOCK as u32 {
+        return FileType::Socket;
     } else {
         print!("as_file_kind() unknown mode, mode={}", mode);
         return FileType::RegularFile;
@@ -51,37 +63,145 @@ fn as_file_type(mut mode: u32) -> FileType {
 }
 
 
+fn to_system_time(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+
+// The kernel knows the root of the mounted sub-volume as inode 1, the
+// image root is not reachable from there.
+fn to_kernel_ino(ino: u64, root: u64) -> u64 {
+    if ino == root {INO_ROOT} else {ino}
+}
+
+
+// size 0 asks for the size only, values that don't fit are an error
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    }
+    else if data.len() > size as usize {
+        reply.error(ERANGE);
+    }
+    else {
+        reply.data(data);
+    }
+}
+
+
 struct PathTagFsFuse {
     _reserved: u64,             // We reserve block zero for future use
     _root: u64,                 // root is usually block 1
     next_file_handle: AtomicU64,
     fs: PathTagFs,
+    mode: MountMode,
+    id_map: IdMap,
+
+    // read into the caches a step after each request, see preload_step()
+    preload: Preload,
 }
 
 impl PathTagFsFuse {
 
-	fn new(device: &str) -> PathTagFsFuse {
-        let fs = PathTagFs::new(device);
+	fn new(device: &str, mode: MountMode) -> Result<PathTagFsFuse, PtfsError> {
+        let fs = PathTagFs::new(device, mode)?;
 
-		PathTagFsFuse {
+		Ok(PathTagFsFuse {
             _reserved: 0,
             _root: 0,
             next_file_handle: AtomicU64::new(1),
             fs: fs,
-		}
+            mode: mode,
+            id_map: IdMap::default(),
+            preload: Preload::None,
+		})
 	}
 	
 	
-	fn open(&mut self) {
-        self.fs.open(INO_ROOT);
+	fn open(&mut self, force: bool, subvol: Option<&String>) -> Result<(), PtfsError> {
+        self.fs.open(INO_ROOT, force)?;
+
+        match subvol {
+            None => Ok(()),
+            Some(name) => self.fs.enter_subvolume(name).or_else(|err| {
+                // the image stays clean if the sub-volume is missing
+                self.fs.destroy()?;
+                Err(err)
+            }),
+        }
     }
 	
 	
-	fn mkfs(& mut self, size: u64) {
-        self.fs.mkfs(INO_ROOT, size);
+	fn mkfs(& mut self, size: u64, reserved_percent: u64) -> Result<(), PtfsError> {
+        self.fs.mkfs(INO_ROOT, size)?;
+        self.fs.set_reserved_percent(reserved_percent)?;
+        self.fs.destroy()
 	}
 	
 	
+	// picks the errno to report to the kernel, damage is logged rate limited
+	fn errno(&mut self, err: &PtfsError) -> c_int {
+        debug!("  error: {}", err);
+        self.fs.report_error(err);
+        
+        match err {
+            // tools salvaging data from a damaged image know how to skip I/O errors
+            PtfsError::Corrupt(_) if self.mode == MountMode::Rescue => EIO,
+            _ => err.to_errno(),
+        }
+    }
+	
+	
+	fn fs_ino(&self, ino: u64) -> u64 {
+        if ino == INO_ROOT {self.fs.root()} else {ino}
+    }
+	
+	
+	// the kernel sees the owners as mapped by --map-uid and --map-gid, or squashed
+	// entries carry a generation, so NFS clients notice reused inodes
+	fn reply_entry(&mut self, reply: ReplyEntry, ttl: &Duration, attr: FileAttr) {
+        match self.fs.generation(attr.ino) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(generation) => reply.entry(ttl, &self.kernel_attr(attr), generation),
+        }
+    }
+
+
+	fn kernel_attr(&self, mut attr: FileAttr) -> FileAttr {
+        attr.ino = to_kernel_ino(attr.ino, self.fs.root());
+        self.id_map.present(&mut attr);
+        attr
+    }
+	
+	
+	// calls into the file system and counts the call and its duration
+	fn timed<T>(&mut self, op: &'static str, call: impl FnOnce(&mut PathTagFs) -> Result<T, PtfsError>) -> Result<T, PtfsError> {
+        let start = Instant::now();
+        let result = call(&mut self.fs);
+        self.fs.record_op(op, start.elapsed(), result.is_ok());
+        self.preload_step();
+        result
+    }
+
+
+	// goes on with the --preload walk started in init(), a few inodes at a
+	// time so requests are not held up for long
+	fn preload_step(&mut self) {
+        if !self.fs.is_preloading() {
+            return;
+        }
+
+        match self.fs.preload_step(PRELOAD_STEP) {
+            Ok(None) => {}
+            Ok(Some(count)) => println!("preload_step() preloaded {} inodes", count),
+            Err(err) => println!("preload_step() preloading stopped: {}", err),
+        }
+    }
+	
+	
 	fn take_next_handle(&mut self) -> u64 {
         let fh = self.next_file_handle.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         return fh;
@@ -94,7 +214,16 @@ impl Filesystem for PathTagFsFuse {
     /// Initialize filesystem.
     /// Called before any other filesystem method.
     /// The kernel module connection can be configured using the KernelConfig object
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // knfsd looks up "." and ".." to reconnect the handles of an export
+        if config.add_capabilities(consts::FUSE_EXPORT_SUPPORT).is_err() {
+            println!("init() the kernel can't export this mount over NFS");
+        }
+
+        // the walk goes on between the requests, the mount is served right away
+        if let Err(err) = self.fs.start_preload(self.preload) {
+            println!("init() preloading stopped: {}", err);
+        }
         Ok(())
     }
 
@@ -102,7 +231,28 @@ impl Filesystem for PathTagFsFuse {
     /// Clean up filesystem.
     /// Called on filesystem exit.
     fn destroy(&mut self) {
-        self.fs.destroy();
+        let stats = self.fs.stats();
+        println!("destroy() cache hits={} misses={} evictions={} hit rate={:.1}%",
+            stats.cache.hits, stats.cache.misses, stats.cache.evictions, stats.cache.hit_rate() * 100.0);
+        println!("  queries hits={} misses={} invalidations={} cached={}",
+            stats.queries.hits, stats.queries.misses, stats.queries.invalidations, stats.queries.cached);
+        for (op, counter) in stats.ops.ops() {
+            println!("  {:<10} calls={} errors={} average={:?} max={:?}",
+                op, counter.count, counter.errors, counter.average(), counter.max);
+        }
+        if stats.cache.throttled > 0 {
+            println!("  {} writes waited for a flush of {} dirty blocks", stats.cache.throttled, stats.cache.dirty_limit);
+        }
+        if stats.cache.degraded {
+            println!("  backing store is degraded, some of its reads or writes timed out");
+        }
+        for warning in &stats.warnings {
+            println!("  warning: {} ({} times)", warning, warning.count);
+        }
+
+        if let Err(err) = self.fs.destroy() {
+            println!("destroy() file system could not be closed cleanly: {}", err);
+        }
     }
 
 
@@ -111,37 +261,26 @@ impl Filesystem for PathTagFsFuse {
 				
 		let fname = safe_to_string(os_fname); 		
 		println!("lookup() name={} parent={}", fname, parent_ino);
+
+		// the parent of a mounted sub-volume is out of reach
+		let fname = if parent_ino == INO_ROOT && fname == ".." {".".to_string()} else {fname};
 		
-        let ino: Option<u64> = self.fs.find_child(parent_ino, &fname); 
-		match ino {
-            None => {
-                println!("  no entry found");
-                reply.error(ENOENT)
-            }
-			Some(ino) => {
-				let node = self.fs.retrieve_entry_block(ino).unwrap();
-                // println!("  attr={:?}", node.attr);
-				reply.entry(&TTL, &node.attr, 0);
-			}
+		let parent_ino = self.fs_ino(parent_ino);
+		match self.timed("lookup", |fs| fs.lookup(parent_ino, &fname)) {
+            Err(err) => reply.error(self.errno(&err)),
+			Ok(attr) => self.reply_entry(reply, &TTL, attr),
 		}
     }
 
 
     /// Get file attributes.
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
 		println!("getattr() inode={}", ino);
 
-        let node_opt = self.fs.retrieve_entry_block(ino);
-
-        match node_opt {
-            None => {
-                println!("  no entry found");
-                reply.error(ENOENT)
-            }
-            Some(node) => {
-                println!("  attr={:?}", node.attr);
-                reply.attr(&TTL, &node.attr)
-            }
+        let ino = self.fs_ino(ino);
+        match self.timed("getattr", |fs| fs.getattr(ino)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attr) => reply.attr(&TTL, &self.kernel_attr(attr)),
         }
     }
 
@@ -149,14 +288,14 @@ impl Filesystem for PathTagFsFuse {
     /// Set file attributes.
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         fh: Option<u64>,
         _crtime: Option<SystemTime>,
@@ -170,43 +309,19 @@ impl Filesystem for PathTagFsFuse {
             gid={:?} size={:?}, fh={:?} flags={:?}",
             ino, mode, uid, gid, size, fh, flags
         );
-        
-        let node_opt = self.fs.retrieve_entry_block(ino);
-        
-        match node_opt {
-            None => {
-                println!("  setattr(): error: {} is not an entry block", ino);
-            }
-            Some(node) => {
-                let attrs = &mut node.attr;
-                let time = &SystemTime::now();
-
-                if let Some(size) = size {
-                    println!("  setattr():setting new size {}", size);
-                    attrs.size = size;                    
-                    attrs.mtime = *time;
-                }
 
-                if let Some(uid) = uid {
-                    println!("  setattr():setting new uid {}", uid);
-                    attrs.uid = uid;                    
-                    attrs.mtime = *time;                    
-                }
-
-                if let Some(gid) = gid {
-                    println!("  setattr():setting new gid {}", gid);
-                    attrs.gid = gid;                    
-                    attrs.mtime = *time;                    
-                }
+        let atime = atime.map(to_system_time);
+        let mtime = mtime.map(to_system_time);
+        let uid = uid.map(|uid| self.id_map.uid_to_fs(uid));
+        let gid = gid.map(|gid| self.id_map.gid_to_fs(gid));
 
+        self.fs.set_privileged(req.uid() == 0);
 
-                // self.write_inode(&attrs);
-                reply.attr(&Duration::new(0, 0), &attrs);
-            }
+        let ino = self.fs_ino(ino);
+        match self.timed("setattr", |fs| fs.setattr(ino, uid, gid, size, atime, mtime, fh)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attr) => reply.attr(&Duration::new(0, 0), &self.kernel_attr(attr)),
         }
-        
-        
-        // reply.error(ENOSYS);
     }
 
    
@@ -214,7 +329,7 @@ impl Filesystem for PathTagFsFuse {
     /// Create a regular file, character device, block device, fifo or socket node.    
 	fn mknod(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent_ino: u64,
         os_name: &OsStr,
         mode: u32,
@@ -226,52 +341,22 @@ impl Filesystem for PathTagFsFuse {
             parent_ino, os_name, mode, umask
         );
 
-
-        let file_type = mode & libc::S_IFMT as u32;
-
-        if file_type != libc::S_IFREG as u32
-            && file_type != libc::S_IFLNK as u32
-            && file_type != libc::S_IFDIR as u32
-        {
-            println!("mknod() implementation only supports regular files, symlinks, and directories. Got {:o}", mode);
-            reply.error(libc::ENOSYS);
-            return;
-        }
-
         let name = safe_to_string(os_name);            
-        if self.fs.find_child(parent_ino, &name) != None {
-            reply.error(libc::EEXIST);
-            return;
-        }
-
-
-        let parent_opt = self.fs.retrieve_entry_block(parent_ino);
+        let kind = as_file_type(mode);   
 
-        match parent_opt {
-            None => {
-                reply.error(ENOENT);
-            }
-            Some(_parent) => {
+        self.fs.set_privileged(req.uid() == 0);
 
-                let kind = as_file_type(mode);   
-                let attrs = self.fs.mknod(parent_ino, &name, kind);
-        
-                match attrs {
-                    None => {
-                        reply.error(libc::ENOENT);
-                    }
-                    Some(attrs) => {
-                        reply.entry(&Duration::new(0, 0), &attrs, 0);
-                    }
-                }
-            }
+        let parent_ino = self.fs_ino(parent_ino);
+        match self.timed("mknod", |fs| fs.mknod(parent_ino, &name, kind)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attrs) => self.reply_entry(reply, &Duration::new(0, 0), attrs),
         }
     }    
     
     /// Create a directory.
 	fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent_ino: u64,
         os_name: &OsStr,
         mode: u32,
@@ -283,22 +368,14 @@ impl Filesystem for PathTagFsFuse {
             parent_ino, os_name, mode, umask
         );
 
-        let storage = &mut self.fs;
         let name = safe_to_string(os_name);
-        if storage.find_child(parent_ino, &name) != None {
-            reply.error(libc::EEXIST);
-            return;
-        }
         
-        let attrs = storage.mkdir(parent_ino, &name);
-        
-        match attrs {
-            None => {
-                reply.error(libc::ENOENT);
-            }
-            Some(attrs) => {
-                reply.entry(&Duration::new(0, 0), &attrs, 0);        
-            }
+        self.fs.set_privileged(req.uid() == 0);
+
+        let parent_ino = self.fs_ino(parent_ino);
+        match self.timed("mkdir", |fs| fs.mkdir(parent_ino, &name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attrs) => self.reply_entry(reply, &Duration::new(0, 0), attrs),
         }
     }
 
@@ -326,27 +403,37 @@ impl Filesystem for PathTagFsFuse {
 
     /// Read symbolic link.
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
-        println!("[Not Implemented] readlink(ino: {:#x?})", ino);
-        reply.error(ENOSYS);
+        println!("readlink() called for inode={}", ino);
+
+        match self.fs.readlink(self.fs_ino(ino)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(target) => reply.data(target.as_bytes()),
+        }
     }
 
 
     /// Remove a file.
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        println!(
-            "[Not Implemented] unlink(parent: {:#x?}, name: {:?})",
-            parent, name,
-        );
-        reply.error(ENOSYS);
+        let name = safe_to_string(name);
+        println!("unlink() parent={} name={}", parent, name);
+
+        let parent = self.fs_ino(parent);
+        match self.timed("unlink", |fs| fs.unlink(parent, &name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
     /// Remove a directory.
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        println!(
-            "[Not Implemented] rmdir(parent: {:#x?}, name: {:?})",
-            parent, name,
-        );
-        reply.error(ENOSYS);
+        let name = safe_to_string(name);
+        println!("rmdir() parent={} name={}", parent, name);
+
+        let parent = self.fs_ino(parent);
+        match self.timed("rmdir", |fs| fs.rmdir(parent, &name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
@@ -359,11 +446,23 @@ impl Filesystem for PathTagFsFuse {
         target: &Path,
         reply: ReplyEntry,
     ) {
-        println!(
-            "[Not Implemented] symlink(parent: {:#x?}, link_name: {:?}, target: {:?})",
-            parent, link_name, target,
-        );
-        reply.error(EPERM);
+        let name = safe_to_string(link_name);
+        println!("symlink() parent={} name={} target={:?}", parent, name, target);
+
+        // targets are kept as text, like names
+        let target = match target.to_str() {
+            Some(target) => target.to_string(),
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        let parent = self.fs_ino(parent);
+        match self.timed("symlink", |fs| fs.symlink(parent, &name, &target)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attrs) => self.reply_entry(reply, &Duration::new(0, 0), attrs),
+        }
     }
 
 
@@ -378,12 +477,26 @@ impl Filesystem for PathTagFsFuse {
         flags: u32,
         reply: ReplyEmpty,
     ) {
-        println!(
-            "[Not Implemented] rename(parent: {:#x?}, name: {:?}, newparent: {:#x?}, \
-            newname: {:?}, flags: {})",
-            parent, name, newparent, newname, flags,
-        );
-        reply.error(ENOSYS);
+        let name = safe_to_string(name);
+        let newname = safe_to_string(newname);
+        println!("rename() parent={} name={} new parent={} new name={} flags={}", parent, name, newparent, newname, flags);
+
+        let parent = self.fs_ino(parent);
+        let newparent = self.fs_ino(newparent);
+        let result = if flags & libc::RENAME_EXCHANGE != 0 {
+            Err(PtfsError::InvalidArgument)
+        }
+        else if flags & libc::RENAME_NOREPLACE != 0 {
+            self.timed("rename", |fs| fs.rename(parent, &name, newparent, &newname))
+        }
+        else {
+            self.timed("rename", |fs| fs.rename_over(parent, &name, newparent, &newname))
+        };
+
+        match result {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
@@ -401,7 +514,14 @@ impl Filesystem for PathTagFsFuse {
             inode, new_parent, new_name
         );
 
-        reply.error(EPERM);
+        let name = safe_to_string(new_name);
+        let inode = self.fs_ino(inode);
+        let new_parent = self.fs_ino(new_parent);
+
+        match self.timed("link", |fs| fs.link(inode, new_parent, &name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(attrs) => self.reply_entry(reply, &Duration::new(0, 0), attrs),
+        }
     }
 
 
@@ -419,15 +539,14 @@ impl Filesystem for PathTagFsFuse {
         // access forbidden
         // reply.error(libc::EACCES);
 
-        let node_opt = self.fs.retrieve_entry_block(inode);
+        let handle = self.take_next_handle();
+        let ino = self.fs_ino(inode);
+        let result = self.fs.getattr(ino)
+            .and_then(|_attr| self.fs.open_handle(handle, ino, Access::from_flags(flags)));
 
-        match node_opt {
-            None => {
-                // invalid value, ist that ok here?
-                reply.error(libc::EINVAL);
-            }
-            Some(_node) => {
-                let handle = self.take_next_handle();
+        match result {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => {
                 let open_flags = 0; // ???
                 reply.opened(handle, open_flags);
             }
@@ -460,23 +579,15 @@ impl Filesystem for PathTagFsFuse {
             "read() called for inode={:?} handle={} flags={:b} offset={:?} size={:?}",
             inode, handle, flags, offset, req_size
         );
-        assert!(offset >= 0);
         
         // if !self.check_file_handle_read(fh) {
         //    reply.error(libc::EACCES);
         //    return;
         // }
 
-        // right now we just assume that all parameters were ok
-        if true {
-            let node = self.fs.retrieve_entry_block(inode).unwrap();
-            let size = std::cmp::min(req_size as u64, node.attr.size);
-            let more_data = node.more_data;
-            let buffer = self.fs.read(more_data, offset, size);
-
-            reply.data(&buffer);
-        } else {
-            reply.error(libc::ENOENT);
+        match self.timed("read", |fs| fs.read_file(inode, offset, req_size as u64)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(buffer) => reply.data(&buffer),
         }
     }
 
@@ -495,7 +606,7 @@ impl Filesystem for PathTagFsFuse {
     /// lock_owner: only supported with ABI >= 7.9
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         inode: u64,
         handle: u64,
         offset: i64,
@@ -507,23 +618,19 @@ impl Filesystem for PathTagFsFuse {
     ) {
         println!("write() called for inode={:?} handle={} flags={:b} size={:?} at offset={}", 
             inode, handle, flags, data.len(), offset);
-        assert!(offset >= 0);
         // if !self.check_file_handle_write(fh) {
         //    reply.error(libc::EACCES);
         //    return;
         // }
 
-        // right now we do not write anyways, just framework for later
-        if true {
-            println!("  setting file size to {}", data.len());
-            
-            let storage = &mut self.fs;
-            storage.write(inode, offset, data);            
+        println!("  setting file size to {}", data.len());
+
+        // root may use the reserved blocks
+        self.fs.set_privileged(req.uid() == 0);
 
-            // fake it if we can't make it ...
-            reply.written(data.len() as u32);
-        } else {
-            reply.error(libc::EBADF);
+        match self.timed("write", |fs| fs.write(inode, Some(handle), offset, data)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.written(data.len() as u32),
         }
     }
 
@@ -539,11 +646,13 @@ impl Filesystem for PathTagFsFuse {
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
     fn flush(&mut self, _req: &Request<'_>, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
-        println!(
-            "[Not Implemented] flush(ino: {:#x?}, fh: {}, lock_owner: {:?})",
-            ino, fh, lock_owner
-        );
-        reply.error(ENOSYS);
+        println!("flush(ino: {:#x?}, fh: {}, lock_owner: {:?})", ino, fh, lock_owner);
+
+        let ino = self.fs_ino(ino);
+        match self.timed("flush", |fs| fs.flush_file(ino, fh)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(_) => reply.ok(),
+        }
     }
     
 
@@ -558,26 +667,42 @@ impl Filesystem for PathTagFsFuse {
     fn release(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
+        ino: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        self.fs.close_handle(fh);
+
+        // unnamed files that were not linked in are gone now
+        let mut result = self.timed("release", |fs| fs.release_unnamed(ino));
+
+        // in writeback mode, closed files are on disk
+        if result.is_ok() && self.fs.sync_mode() == SyncMode::Writeback && self.fs.dirty_blocks() > 0 {
+            result = self.fs.sync();
+        }
+
+        match result {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
+    /// All cached changes are written, not only those of the file.
     fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
-        println!(
-            "[Not Implemented] fsync(ino: {:#x?}, fh: {}, datasync: {})",
-            ino, fh, datasync
-        );
-        reply.error(ENOSYS);
+        println!("fsync(ino: {:#x?}, fh: {}, datasync: {})", ino, fh, datasync);
+
+        let ino = self.fs_ino(ino);
+        match self.timed("fsync", |fs| fs.fsync(ino, datasync)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
@@ -610,31 +735,37 @@ impl Filesystem for PathTagFsFuse {
     ) {
         println!("readdir directory_inode={} offset={}", ino, offset);
 
-        let eb_opt = self.fs.retrieve_entry_block(ino); 
+        let ino = self.fs_ino(ino);
+        let root = self.fs.root();
+        let mut result = Ok(());
         
-        match eb_opt {
-            None => { 
-                reply.error(ENOENT)
-            }
-            Some(_eb) => {
-                let entries = self.fs.list_children(ino);
-                let mut i = 0;
-                                
-                for (ino, kind, name) in entries {                    
-                    if i >= offset {
-                        println!("  entry: inode={} name={}", ino, name);
-                                
-                        // i + 1 means the index of the next entry
-                        if reply.add(ino, (i + 1) as i64, kind, name) {
+        // the offset is a cookie of the position in the directory
+        match self.fs.children_at(ino, offset as u64) {
+            Err(err) => result = Err(err),
+            Ok(mut children) => {
+                while let Some(child) = children.next() {
+                    match child {
+                        Err(err) => {
+                            result = Err(err);
                             break;
                         }
+                        Ok((ino, kind, name)) => {
+                            println!("  entry: inode={} name={}", ino, name);
+
+                            // the cookie of the entry after this one
+                            if reply.add(to_kernel_ino(ino, root), children.cookie() as i64, kind, name) {
+                                break;
+                            }
+                        }
                     }
-                    i = i + 1;
-                }               
-                
-                reply.ok();
+                }
             }
         }
+
+        match result {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
@@ -687,17 +818,20 @@ impl Filesystem for PathTagFsFuse {
         datasync: bool,
         reply: ReplyEmpty,
     ) {
-        println!(
-            "[Not Implemented] fsyncdir(ino: {:#x?}, fh: {}, datasync: {})",
-            ino, fh, datasync
-        );
-        reply.error(ENOSYS);
+        println!("fsyncdir(ino: {:#x?}, fh: {}, datasync: {})", ino, fh, datasync);
+
+        let ino = self.fs_ino(ino);
+        match self.timed("fsyncdir", |fs| fs.fsync(ino, datasync)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
     
 
     /// Get file system statistics.
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        let (blocks, free, available) = self.fs.statfs();
+        reply.statfs(blocks, free, available, 0, 0, BLOCK_SIZE as u32, MAX_NAME_LENGTH as u32, BLOCK_SIZE as u32);
     }
     
 
@@ -707,16 +841,20 @@ impl Filesystem for PathTagFsFuse {
         _req: &Request<'_>,
         ino: u64,
         name: &OsStr,
-        _value: &[u8],
+        value: &[u8],
         flags: i32,
         position: u32,
         reply: ReplyEmpty,
     ) {
         println!(
-            "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
+            "setxattr() called for inode={} name={:?} flags={:#x?} position={}",
             ino, name, flags, position
         );
-        reply.error(ENOSYS);
+
+        match self.fs.setxattr(self.fs_ino(ino), &safe_to_string(name), value) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
     
 
@@ -732,11 +870,12 @@ impl Filesystem for PathTagFsFuse {
         size: u32,
         reply: ReplyXattr,
     ) {
-        println!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
-            ino, name, size
-        );
-        reply.error(ENOSYS);
+        println!("getxattr() called for inode={} name={:?} size={}", ino, name, size);
+
+        match self.fs.getxattr(self.fs_ino(ino), &safe_to_string(name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(value) => reply_xattr(reply, size, &value),
+        }
     }
     
 
@@ -745,21 +884,27 @@ impl Filesystem for PathTagFsFuse {
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
-        println!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino, size
-        );
-        reply.error(ENOSYS);
+        println!("listxattr() called for inode={} size={}", ino, size);
+
+        match self.fs.listxattr(self.fs_ino(ino)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(names) => {
+                // each name ends with a zero byte
+                let data = names.iter().flat_map(|name| name.bytes().chain([0])).collect::<Vec<_>>();
+                reply_xattr(reply, size, &data);
+            }
+        }
     }
 
 
     /// Remove an extended attribute.
     fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        println!(
-            "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
-            ino, name
-        );
-        reply.error(ENOSYS);
+        println!("removexattr() called for inode={} name={:?}", ino, name);
+
+        match self.fs.removexattr(self.fs_ino(ino), &safe_to_string(name)) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok(()) => reply.ok(),
+        }
     }
 
 
@@ -785,7 +930,7 @@ impl Filesystem for PathTagFsFuse {
     /// and open() methods will be called instead.
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -793,12 +938,19 @@ impl Filesystem for PathTagFsFuse {
         flags: i32,
         reply: ReplyCreate,
     ) {
-        println!(
-            "[Not Implemented] create(parent: {:#x?}, name: {:?}, mode: {}, umask: {:#x?}, \
-            flags: {:#x?})",
-            parent, name, mode, umask, flags
-        );
-        reply.error(ENOSYS);
+        let name = safe_to_string(name);
+        println!("create() parent={} name={} mode={:o} umask={:o} flags={:b}", parent, name, mode, umask, flags);
+
+        self.fs.set_privileged(req.uid() == 0);
+
+        let parent = self.fs_ino(parent);
+        let handle = self.take_next_handle();
+        let result = self.timed("create", |fs| fs.create(parent, &name, flags, handle));
+
+        match result.and_then(|attr| Ok((self.fs.generation(attr.ino)?, attr))) {
+            Err(err) => reply.error(self.errno(&err)),
+            Ok((generation, attr)) => reply.created(&Duration::new(0, 0), &self.kernel_attr(attr), generation, handle, 0),
+        }
     }
 
 
@@ -856,18 +1008,22 @@ impl Filesystem for PathTagFsFuse {
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
+    /// Only called for block device mounts (--blkdev), the image starts at
+    /// the beginning of the device.
     fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
-        println!(
-            "[Not Implemented] bmap(ino: {:#x?}, blocksize: {}, idx: {})",
-            ino, blocksize, idx,
-        );
-        reply.error(ENOSYS);
+        println!("bmap(ino: {:#x?}, blocksize: {}, idx: {})", ino, blocksize, idx);
+
+        match self.fs.bmap(ino, blocksize, idx) {
+            Ok(block) => reply.bmap(block),
+            Err(err) => reply.error(self.errno(&err)),
+        }
     }
 
-    /// control device
+    /// control device, the admin ioctls of path_tag_fs::ioctl on the root directory,
+    /// and the chattr flags on any file
     fn ioctl(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         fh: u64,
         flags: u32,
@@ -877,16 +1033,98 @@ impl Filesystem for PathTagFsFuse {
         reply: ReplyIoctl,
     ) {
         println!(
-            "[Not Implemented] ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {}, \
-            in_data.len(): {}, out_size: {})",
-            ino,
-            fh,
-            flags,
-            cmd,
-            in_data.len(),
-            out_size,
+            "ioctl(ino: {:#x?}, fh: {}, flags: {}, cmd: {:#x}, in_data.len(): {}, out_size: {})",
+            ino, fh, flags, cmd, in_data.len(), out_size,
         );
-        reply.error(ENOSYS);
+
+        if cmd == ioctl::FS_IOC_GETFLAGS {
+            match self.fs.file_flags(self.fs_ino(ino)) {
+                Ok(flags) => reply.ioctl(0, &flags.to_le_bytes()),
+                Err(err) => reply.error(self.errno(&err)),
+            }
+            return;
+        }
+
+        if cmd == ioctl::FS_IOC_SETFLAGS {
+            if in_data.len() < 4 {
+                reply.error(libc::EINVAL);
+                return;
+            }
+
+            // like CAP_LINUX_IMMUTABLE, which the request doesn't tell
+            if req.uid() != 0 {
+                reply.error(EPERM);
+                return;
+            }
+
+            let flags = u32::from_le_bytes(in_data[..4].try_into().unwrap());
+            match self.fs.set_file_flags(self.fs_ino(ino), flags) {
+                Ok(()) => reply.ioctl(0, &[]),
+                Err(err) => reply.error(self.errno(&err)),
+            }
+            return;
+        }
+
+        if ino != INO_ROOT {
+            reply.error(ENOTTY);
+            return;
+        }
+
+        let result = match cmd {
+            ioctl::IOC_SYNC => self.fs.sync().map(|_| Vec::new()),
+            ioctl::IOC_DROP_CACHES => self.fs.drop_caches().map(|_| Vec::new()),
+            ioctl::IOC_QUICK_CHECK => self.fs.quick_check().map(|report| {
+                for problem in &report.problems {
+                    println!("  quick check: {}", problem);
+                }
+                ioctl::encode_check_result(&report)
+            }),
+            ioctl::IOC_WARNINGS => Ok(ioctl::encode_warnings(&self.fs.stats().warnings)),
+            ioctl::IOC_FREEZE => self.fs.freeze().map(|_| Vec::new()),
+            ioctl::IOC_THAW => self.fs.thaw().map(|_| Vec::new()),
+            ioctl::IOC_CHANGES => {
+                let since = match ioctl::decode_changes_since(in_data) {
+                    Some(since) => since,
+                    None => {
+                        reply.error(libc::EINVAL);
+                        return;
+                    }
+                };
+
+                // the inodes as the mount shows them
+                let root = self.fs.root();
+                let journal = self.fs.journal();
+                let records = journal.since(since)
+                    .map(|record| Record {ino: to_kernel_ino(record.ino, root), parent: to_kernel_ino(record.parent, root), ..record.clone()})
+                    .collect::<Vec<_>>();
+                Ok(ioctl::encode_changes(journal.first(), &records))
+            }
+            ioctl::IOC_TAG_FILES => {
+                let (add, tag, inodes) = match ioctl::decode_tag_batch(in_data) {
+                    Some(batch) => batch,
+                    None => {
+                        reply.error(libc::EINVAL);
+                        return;
+                    }
+                };
+
+                let inodes = inodes.iter().map(|ino| self.fs_ino(*ino)).collect::<Vec<_>>();
+                match self.fs.tag_many(&inodes, &tag, add) {
+                    Ok(changed) => reply.ioctl(changed as i32, &[]),
+                    Err(err) => reply.error(self.errno(&err)),
+                }
+                return;
+            }
+            _ => {
+                reply.error(ENOTTY);
+                return;
+            }
+        };
+
+        match result {
+            Ok(data) => reply.ioctl(0, &data),
+            Err(err) => reply.error(self.errno(&err)),
+        }
     }
     
 
@@ -975,8 +1213,168 @@ impl Filesystem for PathTagFsFuse {
 
 
 
-fn main() {
-    let matches = Command::new("path_tag_fs")
+fn parse_mode_arg(text: &str) -> u16 {
+    match id_map::parse_mode(text) {
+        Ok(mode) => mode,
+        Err(_) => {
+            println!("Invalid mode '{}', expected octal permissions like 0644", text);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+fn parse_number(text: &str, what: &str) -> u64 {
+    match text.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => {
+            println!("Invalid {} '{}'", what, text);
+            std::process::exit(1);
+        }
+    }
+}
+
+
+// sends an admin ioctl to the root directory of a mount
+fn ctl_command(mountpoint: &str, action: &str) -> Result<(), PtfsError> {
+    let dir = std::fs::File::open(mountpoint)?;
+
+    let cmd = match action {
+        "sync" => ioctl::IOC_SYNC,
+        "drop-caches" => ioctl::IOC_DROP_CACHES,
+        "warnings" => ioctl::IOC_WARNINGS,
+        "freeze" => ioctl::IOC_FREEZE,
+        "thaw" => ioctl::IOC_THAW,
+        _ => ioctl::IOC_QUICK_CHECK,
+    };
+    let mut data = vec![0u8; if cmd == ioctl::IOC_WARNINGS {ioctl::WARNINGS_SIZE} else {ioctl::CHECK_RESULT_SIZE}];
+
+    let result = unsafe { libc::ioctl(dir.as_raw_fd(), cmd as _, data.as_mut_ptr()) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    if let Some((inodes, blocks, problems)) = ioctl::decode_check_result(&data).filter(|_| cmd == ioctl::IOC_QUICK_CHECK) {
+        println!("{} inodes, {} blocks checked, {} problems", inodes, blocks, problems);
+        if problems > 0 {
+            return Err(PtfsError::Corrupt("see the log of the file system for details".to_string()));
+        }
+    }
+
+    if let Some(lines) = ioctl::decode_warnings(&data).filter(|_| cmd == ioctl::IOC_WARNINGS) {
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+
+    Ok(())
+}
+
+
+// Prints the records of the change journal after since, asking the mount
+// again until no more come. If records after since are gone already, the
+// caller has to scan the tree and can go on from the first one kept.
+fn ctl_changes_command(mountpoint: &str, since: u64) -> Result<(), PtfsError> {
+    let dir = std::fs::File::open(mountpoint)?;
+    let mut since = since;
+
+    loop {
+        let mut data = ioctl::encode_changes_since(since);
+        let result = unsafe { libc::ioctl(dir.as_raw_fd(), ioctl::IOC_CHANGES as _, data.as_mut_ptr()) };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let (first, lines) = ioctl::decode_changes(&data).ok_or(PtfsError::InvalidArgument)?;
+        if since.saturating_add(1) < first {
+            return Err(PtfsError::Refused(format!("the journal starts at record {}, scan the tree again", first)));
+        }
+
+        let last = match lines.last() {
+            Some(line) => line.split(' ').next().and_then(|seq| seq.parse().ok()).ok_or(PtfsError::InvalidArgument)?,
+            None => return Ok(()),
+        };
+        for line in lines {
+            println!("{}", line);
+        }
+        since = last;
+    }
+}
+
+
+// Tags or untags the files listed in list_file, one path per line, "-"
+// reads the list from stdin. The inodes go to the mount in a few large
+// ioctls instead of one request per file.
+fn ctl_tag_command(mountpoint: &str, add: bool, tag: &str, list_file: &str) -> Result<(), PtfsError> {
+    let list = if list_file == "-" {std::io::read_to_string(std::io::stdin())?} else {std::fs::read_to_string(list_file)?};
+
+    let mut inodes = Vec::new();
+    for path in list.lines().filter(|line| !line.is_empty()) {
+        inodes.push(std::fs::metadata(path)?.ino());
+    }
+
+    let dir = std::fs::File::open(mountpoint)?;
+    let mut changed = 0;
+
+    for batch in inodes.chunks(ioctl::TAG_BATCH_MAX) {
+        let data = ioctl::encode_tag_batch(add, tag, batch).ok_or(PtfsError::NameTooLong)?;
+        let result = unsafe { libc::ioctl(dir.as_raw_fd(), ioctl::IOC_TAG_FILES as _, data.as_ptr()) };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        changed += result as usize;
+    }
+
+    println!("{} of {} files {}", changed, inodes.len(), if add {"tagged"} else {"untagged"});
+    Ok(())
+}
+
+
+// the file given with --config, looked up before clap parses the arguments
+fn config_path(args: &[OsString]) -> Option<String> {
+    args.iter().enumerate().find_map(|(i, arg)| match arg.to_str()?.strip_prefix("--config")? {
+        "" => args.get(i + 1)?.to_str().map(String::from),
+        rest => rest.strip_prefix('=').map(String::from),
+    })
+}
+
+
+// Puts the options of the config file in front of the command line,
+// without the ones that are given there, so the command line wins.
+fn add_config_args(command: &Command, args: &mut Vec<OsString>, config: &config::Config) -> Result<(), String> {
+    for key in config.keys() {
+        if key == "config" || !command.get_arguments().any(|arg| arg.get_long() == Some(key)) {
+            return Err(format!("unknown option '{}'", key));
+        }
+    }
+
+    let given = |key: &str| {
+        let short = command.get_arguments().find(|arg| arg.get_long() == Some(key)).and_then(|arg| arg.get_short());
+        args.iter().filter_map(|arg| arg.to_str()).any(|arg| {
+            arg == format!("--{}", key) || arg.starts_with(&format!("--{}=", key))
+                || short.is_some_and(|short| arg.starts_with(&format!("-{}", short)))
+        })
+    };
+
+    let extra = config.args_without(given);
+    args.splice(1..1, extra.into_iter().map(OsString::from));
+    Ok(())
+}
+
+
+// the [tags.NAME] sections of the config file
+fn apply_tag_rules(fs: &mut PathTagFs, config: &config::Config) -> Result<(), String> {
+    for (tag, settings) in &config.tags {
+        let mut rules = fs.tag_rules(tag).map_err(|err| format!("tag {}: {}", tag, err))?;
+        settings.apply(&mut rules)?;
+        fs.set_tag_rules(tag, rules).map_err(|err| format!("tag {}: {}", tag, err))?;
+    }
+    Ok(())
+}
+
+
+fn cli() -> Command {
+    Command::new("path_tag_fs")
         // .version(crate_version!())
         .version("0.1.0")
         .author("H. Malthaner")
@@ -986,6 +1384,13 @@ fn main() {
                 .index(1)
                 .help("Act as a client, and mount FUSE at given path"),
         )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .num_args(1)
+                .help("Read mount options and tag rules from a TOML file, options given here win"),
+        )
         .arg(
             Arg::new("auto_unmount")
                 .long("auto_unmount")
@@ -1008,6 +1413,69 @@ fn main() {
                 .action(ArgAction::Append)
                 .help("The device or file to use for data storage"),
         )
+        .arg(
+            Arg::new("rescue")
+                .long("rescue")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("mkfs")
+                .help("Mount a damaged file system read-only to salvage data"),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["mkfs", "rescue"])
+                .help("Mount read-only, the image can be shared with other read-only mounts"),
+        )
+        .arg(
+            Arg::new("shared")
+                .long("shared")
+                .action(ArgAction::SetTrue)
+                .requires("read-only")
+                .help("Mount read-only next to a read-write mount of the image, e.g. for an indexer"),
+        )
+        .arg(
+            Arg::new("blkdev")
+                .long("blkdev")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("mkfs")
+                .help("Mount a block device as fuseblk, this enables bmap for swapfiles and boot loaders (root only)"),
+        )
+        .arg(
+            Arg::new("direct-io")
+                .long("direct-io")
+                .action(ArgAction::SetTrue)
+                .help("Access the device or file with O_DIRECT, so the host doesn't cache its blocks too"),
+        )
+        .arg(
+            Arg::new("io-timeout")
+                .long("io-timeout")
+                .value_name("SECS")
+                .num_args(1)
+                .help("Fail reads and writes of a stalled device or network backend with EIO after SECS seconds, 0 waits forever"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Mount even if the image is locked, was not cleanly unmounted or failed the periodic check"),
+        )
+        .arg(
+            Arg::new("subvol")
+                .long("subvol")
+                .value_name("NAME")
+                .num_args(1)
+                .conflicts_with("mkfs")
+                .help("Mount a sub-volume instead of the whole image, see the subvol command"),
+        )
+        .arg(
+            Arg::new("overlay")
+                .long("overlay")
+                .value_name("DIR")
+                .num_args(1)
+                .conflicts_with_all(["mkfs", "read-only", "rescue"])
+                .help("Show the files of a host directory in /Pathes, so they can be tagged without copying them"),
+        )
         .arg(
             Arg::new("mkfs")
                 .short('m')
@@ -1017,12 +1485,796 @@ fn main() {
                 .action(ArgAction::Append)
                 .help("Create a new file system in the data storage with SIZE blocks"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("reserved")
+                .long("reserved")
+                .value_name("PERCENT")
+                .num_args(1)
+                .default_value("5")
+                .requires("mkfs")
+                .help("Percentage of blocks kept for metadata and root when creating a file system"),
+        )
+        .arg(
+            Arg::new("alloc")
+                .long("alloc")
+                .value_name("POLICY")
+                .num_args(1)
+                .value_parser(["first", "near"])
+                .default_value("first")
+                .help("Place new blocks in the first free spot, or near their directory"),
+        )
+        .arg(
+            Arg::new("preload")
+                .long("preload")
+                .value_name("WHAT")
+                .num_args(1)
+                .value_parser(["none", "metadata", "all"])
+                .default_value("none")
+                .help("Read the directories and attributes, or everything, into the caches, a few inodes after each request"),
+        )
+        .arg(
+            Arg::new("sync-mode")
+                .long("sync-mode")
+                .value_name("MODE")
+                .num_args(1)
+                .value_parser(["sync", "writeback", "async"])
+                .default_value("sync")
+                .help("Write each block at once, when its file is closed, or only when the cache is full"),
+        )
+        .arg(
+            Arg::new("dirty-limit")
+                .long("dirty-limit")
+                .value_name("BLOCKS")
+                .num_args(1)
+                .help("Flush when this many blocks wait to be written in the writeback and async modes, the write waits for it [default: 256]"),
+        )
+        .arg(
+            Arg::new("cache-mem")
+                .long("cache-mem")
+                .value_name("MB")
+                .num_args(1)
+                .help("Bound the memory of the block, attribute and query caches, e.g. on a Raspberry Pi"),
+        )
+        .arg(
+            Arg::new("hashes")
+                .long("hashes")
+                .action(ArgAction::SetTrue)
+                .help("Keep a SHA-256 of each file, updated when it is closed after writing, shown as user.ptfs.sha256"),
+        )
+        .arg(
+            Arg::new("remove-empty-tags")
+                .long("remove-empty-tags")
+                .action(ArgAction::SetTrue)
+                .help("Remove a tag when its last file is untagged, unless user.ptfs.keep is set on it"),
+        )
+        .arg(
+            Arg::new("windows-names")
+                .long("windows-names")
+                .action(ArgAction::SetTrue)
+                .help("Refuse new names that Windows can't use, for mounts exported over Samba"),
+        )
+        .arg(
+            Arg::new("map-uid")
+                .long("map-uid")
+                .value_name("HOST:FS")
+                .action(ArgAction::Append)
+                .help("Show the owner FS of the image as HOST in the mount, may be given more than once"),
+        )
+        .arg(
+            Arg::new("map-gid")
+                .long("map-gid")
+                .value_name("HOST:FS")
+                .action(ArgAction::Append)
+                .help("Show the group FS of the image as HOST in the mount, may be given more than once"),
+        )
+        .arg(
+            Arg::new("squash-all")
+                .long("squash-all")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["map-uid", "map-gid"])
+                .help("Show every file with the same owner and fixed modes, the stored ones are ignored"),
+        )
+        .arg(
+            Arg::new("uid")
+                .long("uid")
+                .value_name("N")
+                .num_args(1)
+                .requires("squash-all")
+                .help("Owner of all files with --squash-all, by default the user mounting the image"),
+        )
+        .arg(
+            Arg::new("gid")
+                .long("gid")
+                .value_name("M")
+                .num_args(1)
+                .requires("squash-all")
+                .help("Group of all files with --squash-all, by default the group of the user mounting the image"),
+        )
+        .arg(
+            Arg::new("file-mode")
+                .long("file-mode")
+                .value_name("MODE")
+                .num_args(1)
+                .default_value("0644")
+                .help("Octal permissions of all files but directories and symlinks with --squash-all"),
+        )
+        .arg(
+            Arg::new("dir-mode")
+                .long("dir-mode")
+                .value_name("MODE")
+                .num_args(1)
+                .default_value("0755")
+                .help("Octal permissions of all directories with --squash-all"),
+        )
+        .arg(
+            Arg::new("tag-view")
+                .long("tag-view")
+                .value_name("VIEW")
+                .num_args(1)
+                .value_parser(["links", "symlinks"])
+                .default_value("links")
+                .help("Show the files of a tag as hard links, or as symlinks to their place below /Pathes"),
+        )
+        .subcommand(
+            Command::new("upgrade")
+                .about("Migrate an unmounted image to the newest on-disk format")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                ),
+        )
+        .subcommand(
+            Command::new("gc-tags")
+                .about("Remove the tags without files from an unmounted image, except the ones marked keep")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only list the tags that would be removed"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("reindex")
+                .about("Build the indexes of an unmounted image again from its directory tree")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                ),
+        )
+        .subcommand(
+            Command::new("tune")
+                .about("Change settings of an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("reserved")
+                        .long("reserved")
+                        .value_name("PERCENT")
+                        .num_args(1)
+                        .required(true)
+                        .help("Percentage of blocks kept for metadata and root"),
+                ),
+        )
+        .subcommand(
+            Command::new("query")
+                .about("List the files of an unmounted image that match a tag query")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("EXPRESSION")
+                        .required(true)
+                        .index(2)
+                        .help("Tags combined with AND, OR, NOT and parentheses, or terms like size>1M and mtime<30d"),
+                )
+                .arg(
+                    Arg::new("namespace")
+                        .long("namespace")
+                        .value_name("NAMESPACE")
+                        .num_args(1)
+                        .help("Look up the tags of the expression in this namespace, e.g. work for work/urgent"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("tag")
+                .about("Add, remove or list the tags of a file in an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("ACTION")
+                        .required(true)
+                        .index(2)
+                        .value_parser(["add", "rm", "ls"])
+                        .help("What to do with the tags of the file"),
+                )
+                .arg(
+                    Arg::new("PATH")
+                        .required(true)
+                        .index(3)
+                        .help("Absolute path of the file in the image"),
+                )
+                .arg(
+                    Arg::new("TAGS")
+                        .index(4)
+                        .num_args(1..)
+                        .required_if_eq_any([("ACTION", "add"), ("ACTION", "rm")])
+                        .help("The tags to add or remove"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("ls")
+                .about("List a directory of an unmounted image with types, sizes, times and tags")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("PATH")
+                        .index(2)
+                        .default_value("/")
+                        .help("Absolute path of the directory or file in the image"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("cat")
+                .about("Write a file of an unmounted image to stdout")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("PATH")
+                        .required(true)
+                        .index(2)
+                        .help("Absolute path of the file in the image"),
+                ),
+        )
+        .subcommand(
+            Command::new("put")
+                .about("Copy a host file into an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("SOURCE")
+                        .required(true)
+                        .index(2)
+                        .help("The host file to copy"),
+                )
+                .arg(
+                    Arg::new("DEST")
+                        .required(true)
+                        .index(3)
+                        .help("Absolute path of the file or directory in the image"),
+                ),
+        )
+        .subcommand(
+            Command::new("addref")
+                .about("Add a host file to an unmounted image by reference, its data is not copied")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("HOST_FILE")
+                        .required(true)
+                        .index(2)
+                        .help("The host file, reads and writes of the entry go there"),
+                )
+                .arg(
+                    Arg::new("TAGS")
+                        .index(3)
+                        .num_args(0..)
+                        .help("Tags for the new entry"),
+                )
+                .arg(
+                    Arg::new("dest")
+                        .long("dest")
+                        .value_name("PATH")
+                        .num_args(1)
+                        .default_value("/Pathes")
+                        .help("Absolute path of the entry or its directory in the image"),
+                ),
+        )
+        .subcommand(
+            Command::new("materialize")
+                .about("Create or refresh a host directory of symlinks to the files matching a query")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("EXPRESSION")
+                        .required(true)
+                        .index(2)
+                        .help("A tag or a query, like for the query command"),
+                )
+                .arg(
+                    Arg::new("HOST_DIR")
+                        .required(true)
+                        .index(3)
+                        .help("The directory for the symlinks, other files in it are left alone"),
+                )
+                .arg(
+                    Arg::new("mountpoint")
+                        .long("mountpoint")
+                        .value_name("DIR")
+                        .num_args(1)
+                        .help("Where the image is mounted, files of the image are linked there"),
+                ),
+        )
+        .subcommand(
+            Command::new("playlist")
+                .about("Write an m3u playlist of the files matching a query, for media players")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("EXPRESSION")
+                        .required(true)
+                        .index(2)
+                        .help("A tag or a query, like for the query command"),
+                )
+                .arg(
+                    Arg::new("mountpoint")
+                        .long("mountpoint")
+                        .value_name("DIR")
+                        .num_args(1)
+                        .help("Where the image is mounted, files of the image are listed there"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .value_name("FILE")
+                        .num_args(1)
+                        .help("Write the playlist to this file instead of stdout"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Read all files of an unmounted image and compare them with their stored hashes")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("PATH")
+                        .index(2)
+                        .default_value("/Pathes")
+                        .help("Only check the files below this directory, or this file"),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Show the settings, state and block usage of an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("du")
+                .about("Show the space used per top level directory or per tag of an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("by-tag")
+                        .long("by-tag")
+                        .action(ArgAction::SetTrue)
+                        .help("Attribute the space to tags"),
+                )
+                .arg(
+                    Arg::new("by-path")
+                        .long("by-path")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("by-tag")
+                        .help("Attribute the space to the top level directories below /Pathes (default)"),
+                )
+                .arg(
+                    Arg::new("apparent-size")
+                        .long("apparent-size")
+                        .action(ArgAction::SetTrue)
+                        .help("Sum up file sizes instead of allocated blocks"),
+                )
+                .arg(
+                    Arg::new("space")
+                        .long("space")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("apparent-size")
+                        .help("Show file sizes, allocated space and the difference saved by inline data, holes and shared files"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as JSON"),
+                ),
+        )
+        .subcommand(
+            Command::new("subvol")
+                .about("Create or list the sub-volumes of an unmounted image")
+                .arg(
+                    Arg::new("IMAGE")
+                        .required(true)
+                        .index(1)
+                        .help("The device or file holding the file system"),
+                )
+                .arg(
+                    Arg::new("ACTION")
+                        .required(true)
+                        .index(2)
+                        .value_parser(["create", "ls"])
+                        .help("Create a sub-volume or list them"),
+                )
+                .arg(
+                    Arg::new("NAME")
+                        .index(3)
+                        .required_if_eq("ACTION", "create")
+                        .help("Name of the new sub-volume"),
+                ),
+        )
+        .subcommand(
+            Command::new("ctl")
+                .about("Control a mounted file system")
+                .arg(
+                    Arg::new("MOUNT_POINT")
+                        .required(true)
+                        .index(1)
+                        .help("Where the file system is mounted"),
+                )
+                .arg(
+                    Arg::new("ACTION")
+                        .required(true)
+                        .index(2)
+                        .value_parser(["sync", "drop-caches", "check", "warnings", "freeze", "thaw", "tag-add", "tag-rm", "changes"])
+                        .help("Write all changes now, empty the block cache, check the block chains, show the recent warnings, \
+                               hold changes for a snapshot of the image and let them go on, tag and untag files, \
+                               or list the records of the change journal"),
+                )
+                .arg(
+                    Arg::new("TAG")
+                        .index(3)
+                        .required_if_eq_any([("ACTION", "tag-add"), ("ACTION", "tag-rm")])
+                        .help("The tag to add to or remove from the listed files"),
+                )
+                .arg(
+                    Arg::new("from-file")
+                        .long("from-file")
+                        .value_name("FILE")
+                        .num_args(1)
+                        .required_if_eq_any([("ACTION", "tag-add"), ("ACTION", "tag-rm")])
+                        .help("Paths of the files on the mount, one per line, - for stdin"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("SEQ")
+                        .num_args(1)
+                        .default_value("0")
+                        .help("The last journal record seen before, changes lists the ones after it"),
+                ),
+        )
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+}
+
+
+fn main() {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+
+    let config = config_path(&args).map(|path| {
+        match config::load(&path).and_then(|config| add_config_args(&cli(), &mut args, &config).map(|_| config)) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Invalid config file {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let matches = cli().get_matches_from(args);
+        
+    // library traces are debug messages, warnings are shown by default
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    
+    if let Some(sub_matches) = matches.subcommand_matches("upgrade") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
         
-    env_logger::init();
+        match PathTagFs::new(image, MountMode::ReadWrite).and_then(|mut fs| fs.upgrade()) {
+            Ok((old, new)) if old == new => println!("{} already uses format version {}", image, new),
+            Ok((old, new)) => println!("{} upgraded from format version {} to {}", image, old, new),
+            Err(err) => {
+                println!("Cannot upgrade {}: {}", image, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("gc-tags") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+
+        if let Err(err) = offline::gc_tags_command(image, sub_matches.get_flag("dry-run"), sub_matches.get_flag("json")) {
+            println!("Cannot collect the tags of {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("reindex") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+
+        if let Err(err) = offline::reindex_command(image) {
+            println!("Cannot reindex {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("tune") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let percent = parse_number(sub_matches.get_one::<String>("reserved").unwrap(), "reserved percentage");
+
+        let result = PathTagFs::new(image, MountMode::ReadWrite).and_then(|mut fs| {
+            fs.open(INO_ROOT, false)?;
+
+            // the image is closed cleanly also if the setting is refused
+            let result = fs.set_reserved_percent(percent);
+            fs.destroy().and(result)
+        });
+
+        if let Err(err) = result {
+            println!("Cannot tune {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("query") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let expression = sub_matches.get_one::<String>("EXPRESSION").unwrap();
+
+        let namespace = sub_matches.get_one::<String>("namespace").map(|namespace| namespace.as_str());
+
+        if let Err(err) = offline::query_command(image, namespace, expression, sub_matches.get_flag("json")) {
+            println!("Cannot query {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
     
-    // let mut options = vec![MountOption::RO, MountOption::FSName("path_tag_fs".to_string())];
-    let mut options = vec![MountOption::RW, MountOption::FSName("path_tag_fs".to_string())];
+    if let Some(sub_matches) = matches.subcommand_matches("tag") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let action = sub_matches.get_one::<String>("ACTION").unwrap();
+        let path = sub_matches.get_one::<String>("PATH").unwrap();
+        let tags: Vec<&String> = sub_matches.get_many::<String>("TAGS").unwrap_or_default().collect();
+
+        if let Err(err) = offline::tag_command(image, action, path, &tags, sub_matches.get_flag("json")) {
+            println!("Cannot change tags in {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("ls") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let path = sub_matches.get_one::<String>("PATH").unwrap();
+
+        if let Err(err) = offline::ls_command(image, path, sub_matches.get_flag("json")) {
+            println!("Cannot list {} in {}: {}", path, image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("cat") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let path = sub_matches.get_one::<String>("PATH").unwrap();
+
+        // stdout carries the file, errors go elsewhere
+        if let Err(err) = offline::cat_command(image, path) {
+            eprintln!("Cannot read {} from {}: {}", path, image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("put") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let source = sub_matches.get_one::<String>("SOURCE").unwrap();
+        let dest = sub_matches.get_one::<String>("DEST").unwrap();
+
+        if let Err(err) = offline::put_command(image, source, dest) {
+            println!("Cannot copy {} to {} in {}: {}", source, dest, image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("addref") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let host_file = sub_matches.get_one::<String>("HOST_FILE").unwrap();
+        let dest = sub_matches.get_one::<String>("dest").unwrap();
+        let tags: Vec<&String> = sub_matches.get_many::<String>("TAGS").unwrap_or_default().collect();
+
+        if let Err(err) = offline::addref_command(image, host_file, dest, &tags) {
+            println!("Cannot add {} to {}: {}", host_file, image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("materialize") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let expression = sub_matches.get_one::<String>("EXPRESSION").unwrap();
+        let dir = sub_matches.get_one::<String>("HOST_DIR").unwrap();
+        let mountpoint = sub_matches.get_one::<String>("mountpoint").map(|mountpoint| mountpoint.as_str());
+
+        if let Err(err) = offline::materialize_command(image, expression, dir, mountpoint) {
+            println!("Cannot link the files of {} in {}: {}", image, dir, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("playlist") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let expression = sub_matches.get_one::<String>("EXPRESSION").unwrap();
+        let mountpoint = sub_matches.get_one::<String>("mountpoint").map(|mountpoint| mountpoint.as_str());
+        let output = sub_matches.get_one::<String>("output").map(|output| output.as_str());
+
+        // stdout may carry the playlist
+        if let Err(err) = offline::playlist_command(image, expression, mountpoint, output) {
+            eprintln!("Cannot write a playlist of {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("verify") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let path = sub_matches.get_one::<String>("PATH").unwrap();
+
+        if let Err(err) = offline::verify_command(image, path) {
+            println!("Verification of {} failed: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("info") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+
+        if let Err(err) = offline::info_command(image, sub_matches.get_flag("json")) {
+            println!("Cannot inspect {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("du") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+
+        if let Err(err) = offline::du_command(image, sub_matches.get_flag("by-tag"), sub_matches.get_flag("apparent-size"),
+                                                sub_matches.get_flag("space"), sub_matches.get_flag("json")) {
+            println!("Cannot compute usage of {}: {}", image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("subvol") {
+        let image = sub_matches.get_one::<String>("IMAGE").unwrap();
+        let action = sub_matches.get_one::<String>("ACTION").unwrap();
+        let name = sub_matches.get_one::<String>("NAME");
+
+        if let Err(err) = offline::subvol_command(image, action, name.map(|name| name.as_str())) {
+            println!("Cannot {} sub-volumes of {}: {}", action, image, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    if let Some(sub_matches) = matches.subcommand_matches("ctl") {
+        let mountpoint = sub_matches.get_one::<String>("MOUNT_POINT").unwrap();
+        let action = sub_matches.get_one::<String>("ACTION").unwrap();
+
+        let result = match (sub_matches.get_one::<String>("TAG"), sub_matches.get_one::<String>("from-file")) {
+            (Some(tag), Some(list)) if action.starts_with("tag-") => ctl_tag_command(mountpoint, action == "tag-add", tag, list),
+            _ if action == "changes" => ctl_changes_command(mountpoint, parse_number(sub_matches.get_one::<String>("since").unwrap(), "record number")),
+            _ => ctl_command(mountpoint, action),
+        };
+
+        if let Err(err) = result {
+            println!("{} on {} failed: {}", action, mountpoint, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    let mode = 
+        if matches.get_flag("rescue") {MountMode::Rescue} 
+        else if matches.get_flag("read-only") {MountMode::ReadOnly} 
+        else {MountMode::ReadWrite};
+
+    let mut options = match mode {
+        MountMode::ReadOnly | MountMode::Rescue => vec![MountOption::RO, MountOption::FSName("path_tag_fs".to_string())],
+        MountMode::ReadWrite => vec![MountOption::RW, MountOption::FSName("path_tag_fs".to_string())],
+    };
     
     if matches.get_flag("auto_unmount") {
         options.push(MountOption::AutoUnmount);
@@ -1033,19 +2285,140 @@ fn main() {
     }
     
     let device = matches.get_one::<String>("device").unwrap();
+
+    if matches.get_flag("blkdev") {
+        // the kernel opens the device itself, it must be the fsname
+        options.retain(|option| !matches!(option, MountOption::FSName(_)));
+        options.push(MountOption::FSName(device.to_string()));
+        options.push(MountOption::CUSTOM("blkdev".to_string()));
+        options.push(MountOption::CUSTOM(format!("blksize={}", BLOCK_SIZE)));
+    }
     
-    let mut file_system = PathTagFsFuse::new(device);     
+    let mut file_system = match PathTagFsFuse::new(device, mode) {
+        Ok(file_system) => file_system,
+        Err(err) => {
+            println!("Cannot open {}: {}", device, err);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.get_one::<String>("alloc").unwrap() == "near" {
+        file_system.fs.set_alloc_policy(AllocPolicy::NearParent);
+    }
+
+    if matches.get_flag("shared") {
+        if let Err(err) = file_system.fs.set_shared(true) {
+            println!("Cannot share {}: {}", device, err);
+            std::process::exit(1);
+        }
+    }
+
+    if matches.get_flag("direct-io") {
+        if let Err(err) = file_system.fs.set_direct_io(true) {
+            println!("Cannot use direct I/O on {}: {}", device, err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(seconds) = matches.get_one::<String>("io-timeout") {
+        let seconds = parse_number(seconds, "I/O timeout");
+        let timeout = (seconds > 0).then(|| Duration::from_secs(seconds));
+        if let Err(err) = file_system.fs.set_io_timeout(timeout) {
+            println!("Cannot watch the I/O of {}: {}", device, err);
+            std::process::exit(1);
+        }
+    }
+
+    match matches.get_one::<String>("sync-mode").unwrap().as_str() {
+        "writeback" => file_system.fs.set_sync_mode(SyncMode::Writeback),
+        "async" => file_system.fs.set_sync_mode(SyncMode::Async),
+        _ => {}
+    }
+
+    if let Some(blocks) = matches.get_one::<String>("dirty-limit") {
+        file_system.fs.set_dirty_limit(parse_number(blocks, "dirty limit") as usize);
+    }
+
+    file_system.preload = match matches.get_one::<String>("preload").unwrap().as_str() {
+        "metadata" => Preload::Metadata,
+        "all" => Preload::All,
+        _ => Preload::None,
+    };
+
+    if matches.get_one::<String>("tag-view").unwrap() == "symlinks" {
+        file_system.fs.set_tag_view(TagView::Symlinks);
+    }
+
+    file_system.fs.set_content_hashes(matches.get_flag("hashes"));
+    file_system.fs.set_remove_empty_tags(matches.get_flag("remove-empty-tags"));
+    file_system.fs.set_windows_names(matches.get_flag("windows-names"));
+
+    for spec in matches.get_many::<String>("map-uid").unwrap_or_default() {
+        if file_system.id_map.add_uid(spec).is_err() {
+            println!("Invalid uid mapping '{}', expected HOST:FS", spec);
+            std::process::exit(1);
+        }
+    }
+
+    for spec in matches.get_many::<String>("map-gid").unwrap_or_default() {
+        if file_system.id_map.add_gid(spec).is_err() {
+            println!("Invalid gid mapping '{}', expected HOST:FS", spec);
+            std::process::exit(1);
+        }
+    }
+
+    if matches.get_flag("squash-all") {
+        let uid = matches.get_one::<String>("uid").map_or(unsafe { libc::getuid() } as u64, |uid| parse_number(uid, "uid"));
+        let gid = matches.get_one::<String>("gid").map_or(unsafe { libc::getgid() } as u64, |gid| parse_number(gid, "gid"));
+        file_system.id_map.squash = Some(Squash {
+            uid: uid as u32,
+            gid: gid as u32,
+            file_mode: parse_mode_arg(matches.get_one::<String>("file-mode").unwrap()),
+            dir_mode: parse_mode_arg(matches.get_one::<String>("dir-mode").unwrap()),
+        });
+    }
+
+    if let Some(megabytes) = matches.get_one::<String>("cache-mem") {
+        let megabytes = parse_number(megabytes, "cache memory");
+        file_system.fs.set_cache_memory(megabytes.saturating_mul(1024 * 1024) as usize);
+    }
 
     if matches.get_one::<String>("mkfs") != None {
-        let size_string = matches.get_one::<String>("mkfs").unwrap();
-        let size = size_string.parse::<u64>().unwrap();
+        let size = parse_number(matches.get_one::<String>("mkfs").unwrap(), "file system size");
+        let reserved = parse_number(matches.get_one::<String>("reserved").unwrap(), "reserved percentage");
 
-        file_system.mkfs(size);
+        if let Err(err) = file_system.mkfs(size, reserved) {
+            println!("Cannot create file system on {}: {}", device, err);
+            std::process::exit(1);
+        }
     }
     else {
         let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
-        file_system.open();
-        fuser::mount2(file_system, mountpoint, &options).unwrap();
+        if let Err(err) = file_system.open(matches.get_flag("force"), matches.get_one::<String>("subvol")) {
+            println!("Cannot open file system on {}: {}", device, err);
+            std::process::exit(1);
+        }
+        if let Some(config) = config.as_ref().filter(|_| mode == MountMode::ReadWrite) {
+            if let Err(err) = apply_tag_rules(&mut file_system.fs, config) {
+                println!("Cannot apply the config file: {}", err);
+                let _ = file_system.fs.destroy();
+                std::process::exit(1);
+            }
+        }
+        if let Some(dir) = matches.get_one::<String>("overlay") {
+            if let Err(err) = file_system.fs.attach_overlay(dir) {
+                println!("Cannot pass {} through: {}", dir, err);
+                let _ = file_system.fs.destroy();
+                std::process::exit(1);
+            }
+        }
+        #[cfg(feature = "dbus")]
+        path_tag_fs::dbus::publish(file_system.fs.subscribe());
+
+        if let Err(err) = fuser::mount2(file_system, mountpoint, &options) {
+            println!("Cannot mount file system at {}: {}", mountpoint, err);
+            std::process::exit(1);
+        }
     }
 
 }
@@ -1,10 +1,12 @@
-use std::os::unix::fs::MetadataExt;
 use fuser::{FileAttr, FileType};
 use crate::path_tag_fs::BLOCK_SIZE;
 
 pub const ENTRY_SIZE:usize = 256;
 pub const MAX_ENTRIES:usize = BLOCK_SIZE/ENTRY_SIZE;
 
+// the last entry of a directory block shares its slot with the chain pointer
+pub const MAX_NAME_LENGTH:usize = ENTRY_SIZE - 16;
+
 pub struct EntryBlock {
     pub name: String,
     pub is_tag: bool,
@@ -13,6 +15,17 @@ pub struct EntryBlock {
     // - if this is a file, more_data will point to an IndexNode
     // - if this is a directory, more_data will point to an DirectoryNode
     pub more_data: u64,
+
+    // optional attributes, see the accessors in block_io
+    pub extensions: Vec<Extension>,
+}
+
+
+// A typed record in the extension area of an entry block. Kinds which
+// are unknown to this version are kept as they are.
+pub struct Extension {
+    pub kind: u8,
+    pub value: Vec<u8>,
 }
 
 impl EntryBlock {
@@ -23,6 +36,7 @@ impl EntryBlock {
             is_tag: is_tag,
             attr: make_attr(ino, kind),
             more_data: 0, 
+            extensions: Vec::new(),
         };
         
         return node;        
@@ -50,6 +64,9 @@ impl IndexBlock {
 pub struct DirectoryEntry {
     pub ino: u64,
     pub name: String,
+
+    // None for entries written by older versions
+    pub kind: Option<FileType>,
 }
 
 
@@ -93,9 +110,12 @@ pub enum AnyBlock {
 
 fn make_attr(ino: u64, kind: FileType) -> FileAttr
 {
-    let meta = std::fs::metadata("/proc/self").unwrap();
 
-    let perm = if kind == FileType::Directory {0o755} else {0o644};
+    let perm = match kind {
+        FileType::Directory => 0o755,
+        FileType::Symlink => 0o777,
+        _ => 0o644,
+    };
     let now = std::time::SystemTime::now();
 
     FileAttr {
@@ -108,9 +128,9 @@ fn make_attr(ino: u64, kind: FileType) -> FileAttr
         crtime: now,
         kind: kind,
         perm: perm,
-        nlink: 2,
-        uid: meta.uid(),
-        gid: meta.gid(),
+        nlink: if kind == FileType::Directory {2} else {1},
+        uid: unsafe { libc::geteuid() },
+        gid: unsafe { libc::getegid() },
         rdev: 0,
         flags: 0,
         blksize: BLOCK_SIZE as u32,
@@ -0,0 +1,841 @@
+//
+// Subcommands that work on unmounted images
+//
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use fuser::FileType;
+
+use path_tag_fs::{MountMode, PathTagFs, PtfsError, PtfsHandle, BLOCK_SIZE, INO_ROOT};
+use path_tag_fs::block_cache::FsInfo;
+use path_tag_fs::overlay;
+use path_tag_fs::sha256::to_hex;
+use path_tag_fs::path_tag_fs::{BlockUsage, PATHS_DIR};
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("song"), "\"song\"");
+        assert_eq!(json_string("a \"b\" \\ c"), "\"a \\\"b\\\" \\\\ c\"");
+        assert_eq!(json_string("tab\tline\n\u{1}"), "\"tab\\tline\\n\\u0001\"");
+        assert_eq!(json_list(&["a".to_string(), "b".to_string()]), "[\"a\",\"b\"]");
+    }
+
+    #[test]
+    fn test_link_name() {
+        let mut taken = HashSet::new();
+        assert_eq!(link_name("song", 7, &mut taken), "song");
+        assert_eq!(link_name("song", 9, &mut taken), "song~9");
+        assert_eq!(link_name("tune", 9, &mut taken), "tune");
+    }
+
+    #[test]
+    fn test_playlist() {
+        let mut entries = vec![
+            ("b.mp3".to_string(), PathBuf::from("/mnt/Pathes/music/b.mp3")),
+            ("a.ogg".to_string(), PathBuf::from("/home/me/a.ogg")),
+        ];
+        assert_eq!(playlist(&mut entries),
+                   "#EXTM3U\n#EXTINF:-1,a.ogg\n/home/me/a.ogg\n#EXTINF:-1,b.mp3\n/mnt/Pathes/music/b.mp3\n");
+        assert_eq!(playlist(&mut Vec::new()), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn test_du_savings() {
+        // a sparse file of 1 MiB with one data block, and an inline file
+        let entry = DuEntry {
+            name: "music".to_string(),
+            blocks: HashSet::from([10, 11, 12, 13]),
+            inodes: HashMap::from([(5, 1024 * 1024), (6, 100)]),
+        };
+        assert_eq!(entry.logical(), 1024 * 1024 + 100);
+        assert_eq!(entry.physical(), 4 * BLOCK_SIZE as u64);
+        assert_eq!(entry.saved(), 1024 * 1024 + 100 - 4 * BLOCK_SIZE as u64);
+
+        let empty_dir = DuEntry {blocks: HashSet::from([1]), inodes: HashMap::from([(1, 0)]), ..Default::default()};
+        assert_eq!(empty_dir.saved(), 0);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3723)), "2000-02-29 01:02");
+        assert_eq!(format_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000)), "2023-11-14 22:13");
+    }
+}
+
+
+// cat and put move files in pieces of this size
+const COPY_CHUNK:usize = 64 * BLOCK_SIZE;
+
+
+// space used by a group of files, shared blocks are counted once
+#[derive(Default)]
+struct DuEntry {
+    name: String,
+    blocks: HashSet<u64>,
+
+    // the files with their sizes
+    inodes: HashMap<u64, u64>,
+}
+
+
+impl DuEntry {
+
+    // the sizes of the files
+    fn logical(&self) -> u64 {
+        self.inodes.values().sum()
+    }
+
+
+    // the blocks they use, metadata included
+    fn physical(&self) -> u64 {
+        (self.blocks.len() * BLOCK_SIZE) as u64
+    }
+
+
+    fn saved(&self) -> u64 {
+        self.logical().saturating_sub(self.physical())
+    }
+}
+
+
+// one line of the ls output
+struct ListEntry {
+    ino: u64,
+    kind: FileType,
+    size: u64,
+
+    // bytes of the blocks of the file, metadata included
+    allocated: u64,
+    mtime: SystemTime,
+    name: String,
+    tags: Vec<String>,
+}
+
+
+// Edits the tags of one file. All tags are tried, the first failure is
+// reported after the image was closed.
+pub fn tag_command(image: &str, action: &str, path: &str, tags: &[&String], json: bool) -> Result<(), PtfsError> {
+    let mode = if action == "ls" {MountMode::ReadOnly} else {MountMode::ReadWrite};
+    let mut handle = PtfsHandle::open_image(image, mode)?;
+    let mut result = Ok(());
+
+    match action {
+        "ls" => match handle.resolve(path).and_then(|ino| handle.fs().list_tags(ino)) {
+            Ok(tags) if json => println!("{}", json_list(&tags)),
+            Ok(tags) => tags.iter().for_each(|tag| println!("{}", tag)),
+            Err(err) => result = Err(err),
+        },
+        _ => {
+            for tag in tags {
+                let done = if action == "add" {handle.tag(path, tag)} else {handle.untag(path, tag)};
+                if let Err(err) = done {
+                    println!("{} {}: {}", path, tag, err);
+                    result = result.and(Err(err));
+                }
+            }
+        }
+    }
+
+    handle.close().and(result)
+}
+
+
+// creates a sub-volume or lists them, one name per line
+pub fn subvol_command(image: &str, action: &str, name: Option<&str>) -> Result<(), PtfsError> {
+    let mode = if action == "ls" {MountMode::ReadOnly} else {MountMode::ReadWrite};
+    let mut handle = PtfsHandle::open_image(image, mode)?;
+
+    let result = match name {
+        Some(name) if action == "create" => handle.fs().create_subvolume(name).map(|_| Vec::new()),
+        _ => handle.fs().list_subvolumes(),
+    };
+    let names = handle.close().and(result)?;
+
+    names.iter().for_each(|name| println!("{}", name));
+    Ok(())
+}
+
+
+// Lists a directory like debugfs does, a file is listed on its own.
+pub fn ls_command(image: &str, path: &str, json: bool) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = list_entries(&mut handle, path);
+    let entries = handle.close().and(result)?;
+
+    if json {
+        let items: Vec<String> = entries.iter().map(|entry| format!(
+            "{{\"ino\":{},\"type\":{},\"size\":{},\"allocated\":{},\"mtime\":{},\"name\":{},\"tags\":{}}}",
+            entry.ino, json_string(kind_name(entry.kind)), entry.size, entry.allocated, unix_time(entry.mtime), json_string(&entry.name), json_list(&entry.tags))).collect();
+        println!("[{}]", items.join(","));
+        return Ok(());
+    }
+
+    for entry in entries {
+        let tags = if entry.tags.is_empty() {String::new()} else {format!(" [{}]", entry.tags.join(", "))};
+        println!("{:>8} {} {:>10} {} {}{}", entry.ino, kind_char(entry.kind), entry.size, format_time(entry.mtime), entry.name, tags);
+    }
+
+    Ok(())
+}
+
+
+// lists the files that match a query expression
+pub fn query_command(image: &str, namespace: Option<&str>, expression: &str, json: bool) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = find_files(handle.fs(), namespace, expression);
+    let files = handle.close().and(result)?;
+
+    if json {
+        let items: Vec<String> = files.iter().map(|(ino, name)| format!("{{\"ino\":{},\"name\":{}}}", ino, json_string(name))).collect();
+        println!("[{}]", items.join(","));
+        return Ok(());
+    }
+
+    for (ino, name) in files {
+        println!("{:>8} {}", ino, name);
+    }
+
+    Ok(())
+}
+
+
+fn find_files(fs: &mut PathTagFs, namespace: Option<&str>, expression: &str) -> Result<Vec<(u64, String)>, PtfsError> {
+    let inos = match namespace {
+        Some(namespace) => fs.query_in(namespace, expression)?,
+        None => fs.query(expression)?,
+    };
+
+    let mut files = Vec::new();
+    for ino in inos {
+        files.push((ino, fs.retrieve_entry_block(ino)?.name.to_string()));
+    }
+
+    Ok(files)
+}
+
+
+// Makes dir a directory of symlinks to the files matching a query, for
+// tools that can't read the mount. Running it again refreshes the links:
+// symlinks in dir that don't point at a matching file are removed, other
+// files are never touched. Files of the image itself need the mount point,
+// host files are linked directly.
+pub fn materialize_command(image: &str, expression: &str, dir: &str, mountpoint: Option<&str>) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = link_targets(handle.fs(), expression, mountpoint);
+    let targets = handle.close().and(result)?;
+
+    std::fs::create_dir_all(dir)?;
+    let dir = Path::new(dir);
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let target = match std::fs::read_link(entry.path()) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !targets.iter().any(|link| link.0 == name && link.1 == target) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+
+    for (name, target) in &targets {
+        let link = dir.join(name);
+        if std::fs::symlink_metadata(&link).is_err() {
+            std::os::unix::fs::symlink(target, &link)?;
+        }
+    }
+
+    println!("{} links in {}", targets.len(), dir.display());
+    Ok(())
+}
+
+
+// the names of the links with their targets
+fn link_targets(fs: &mut PathTagFs, expression: &str, mountpoint: Option<&str>) -> Result<Vec<(String, PathBuf)>, PtfsError> {
+    let paths = canonical_paths(fs)?;
+    let mut taken = HashSet::new();
+    let mut targets = Vec::new();
+
+    for ino in fs.query(expression)? {
+        let eb = fs.retrieve_entry_block(ino)?;
+        let name = eb.name.to_string();
+
+        let target = match (overlay::host_path(eb), mountpoint, paths.get(&ino)) {
+            (Some(host), _, _) => host,
+            (None, Some(mountpoint), Some(path)) => Path::new(mountpoint).join(path.trim_start_matches('/')),
+            (None, None, _) => {
+                eprintln!("{}: lives in the image, use --mountpoint to link it", name);
+                continue;
+            }
+            (None, _, None) => continue,
+        };
+
+        targets.push((link_name(&name, ino, &mut taken), target));
+    }
+
+    Ok(targets)
+}
+
+
+// the path below /Pathes of every file, the first one for files with more names
+fn canonical_paths(fs: &mut PathTagFs) -> Result<HashMap<u64, String>, PtfsError> {
+    let mut paths = HashMap::new();
+    let mut pending = vec![(fs.lookup(fs.root(), &PATHS_DIR.to_string())?.ino, format!("/{}", PATHS_DIR))];
+
+    while let Some((dir, path)) = pending.pop() {
+        for (ino, kind, name) in fs.list_children(dir)? {
+            if name == "." || name == ".." || paths.contains_key(&ino) {
+                continue;
+            }
+
+            let child = format!("{}/{}", path, name);
+            if kind == FileType::Directory {
+                pending.push((ino, child.clone()));
+            }
+            paths.insert(ino, child);
+        }
+    }
+
+    Ok(paths)
+}
+
+
+// Writes an m3u playlist of the files matching a query, to stdout or to
+// output. Like for materialize_command(), files of the image need the
+// mount point and host files are listed with their own path.
+pub fn playlist_command(image: &str, expression: &str, mountpoint: Option<&str>, output: Option<&str>) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = link_targets(handle.fs(), expression, mountpoint);
+    let mut entries = handle.close().and(result)?;
+
+    let text = playlist(&mut entries);
+    match output {
+        Some(output) => std::fs::write(output, text)?,
+        None => print!("{}", text),
+    }
+
+    Ok(())
+}
+
+
+// sorted by path, so the tracks of an album stay in order
+fn playlist(entries: &mut [(String, PathBuf)]) -> String {
+    entries.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut text = "#EXTM3U\n".to_string();
+    for (name, path) in entries.iter() {
+        text += &format!("#EXTINF:-1,{}\n{}\n", name, path.display());
+    }
+    text
+}
+
+
+// files of the same name get their inode appended
+fn link_name(name: &str, ino: u64, taken: &mut HashSet<String>) -> String {
+    let mut name = name.to_string();
+    if taken.contains(&name) {
+        name = format!("{}~{}", name, ino);
+    }
+
+    taken.insert(name.clone());
+    name
+}
+
+
+// Reads all files below path and compares their data with the stored
+// hashes. Files without a hash are only checked for being readable.
+pub fn verify_command(image: &str, path: &str) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = verify_files(&mut handle, path);
+    handle.close()?;
+
+    match result? {
+        0 => Ok(()),
+        problems => Err(PtfsError::Corrupt(format!("{} files failed the check", problems))),
+    }
+}
+
+
+fn verify_files(handle: &mut PtfsHandle, path: &str) -> Result<usize, PtfsError> {
+    let start = handle.resolve(path)?;
+    let fs = handle.fs();
+    let mut files = Vec::new();
+    let mut visited = HashSet::from([start]);
+    let mut pending = vec![(start, path.trim_end_matches('/').to_string())];
+
+    // a single file can be checked too
+    if fs.getattr(start)?.kind == FileType::RegularFile {
+        files.push((path.to_string(), start));
+        pending.clear();
+    }
+
+    while let Some((dir, dir_path)) = pending.pop() {
+        for (ino, kind, name) in fs.list_children(dir)? {
+            if name == "." || name == ".." || !visited.insert(ino) {
+                continue;
+            }
+
+            let child = format!("{}/{}", dir_path, name);
+            match kind {
+                FileType::Directory => pending.push((ino, child)),
+                FileType::RegularFile => files.push((child, ino)),
+                _ => {}
+            }
+        }
+    }
+
+    files.sort();
+    let (mut hashed, mut problems) = (0, 0);
+
+    for (file_path, ino) in &files {
+        let checked = fs.stored_content_hash(*ino).and_then(|stored| Ok((stored, fs.compute_content_hash(*ino)?)));
+
+        match checked {
+            Err(err) => {
+                println!("{}: {}", file_path, err);
+                problems += 1;
+            }
+            Ok((Some(stored), actual)) if stored != actual => {
+                println!("{}: hash mismatch, stored {}, data {}", file_path, to_hex(&stored), to_hex(&actual));
+                hashed += 1;
+                problems += 1;
+            }
+            Ok((stored, _)) => hashed += stored.is_some() as usize,
+        }
+    }
+
+    println!("{} files checked, {} with a stored hash, {} problems", files.len(), hashed, problems);
+    Ok(problems)
+}
+
+
+// Removes the tags without files, or only lists them. Tags marked keep
+// are left alone.
+pub fn gc_tags_command(image: &str, dry_run: bool, json: bool) -> Result<(), PtfsError> {
+    let mode = if dry_run {MountMode::ReadOnly} else {MountMode::ReadWrite};
+    let mut fs = PathTagFs::new(image, mode)?;
+    fs.open(INO_ROOT, false)?;
+    let result = if dry_run {fs.empty_tags()} else {fs.gc_tags()};
+    let tags = fs.destroy().and(result)?;
+
+    if json {
+        println!("{}", json_list(&tags));
+    }
+    else {
+        tags.iter().for_each(|tag| println!("{}", tag));
+    }
+    Ok(())
+}
+
+
+// builds the indexes of an image again from its directory tree
+pub fn reindex_command(image: &str) -> Result<(), PtfsError> {
+    let mut fs = PathTagFs::new(image, MountMode::ReadWrite)?;
+    fs.open(INO_ROOT, false)?;
+    let result = fs.reindex();
+    let files = fs.destroy().and(result)?;
+
+    println!("{} files indexed", files);
+    Ok(())
+}
+
+
+// writes a file of the image to stdout
+pub fn cat_command(image: &str, path: &str) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = copy_out(&mut handle, path);
+    handle.close().and(result)
+}
+
+
+fn copy_out(handle: &mut PtfsHandle, path: &str) -> Result<(), PtfsError> {
+    let ino = handle.resolve(path)?;
+    let attr = handle.fs().getattr(ino)?;
+    if attr.kind == FileType::Directory {
+        return Err(PtfsError::IsADirectory);
+    }
+
+    let mut out = std::io::stdout().lock();
+    let mut offset = 0;
+
+    while offset < attr.size {
+        let data = handle.fs().read_file(ino, offset as i64, COPY_CHUNK as u64)?;
+        if data.is_empty() {
+            break;
+        }
+        out.write_all(&data)?;
+        offset += data.len() as u64;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+
+// Adds an entry for a host file without copying its data and tags it. If
+// dest is a directory the entry gets the name of the host file.
+pub fn addref_command(image: &str, host_file: &str, dest: &str, tags: &[&String]) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadWrite)?;
+    let result = add_reference(&mut handle, host_file, dest, tags);
+    handle.close().and(result)
+}
+
+
+fn add_reference(handle: &mut PtfsHandle, host_file: &str, dest: &str, tags: &[&String]) -> Result<(), PtfsError> {
+    let mut dest = dest.to_string();
+    if let Ok(ino) = handle.resolve(&dest) {
+        if handle.fs().getattr(ino)?.kind == FileType::Directory {
+            let name = host_file.trim_end_matches('/').rsplit('/').next().unwrap_or(host_file);
+            dest = format!("{}/{}", dest.trim_end_matches('/'), name);
+        }
+    }
+
+    let ino = handle.add_reference(&dest, host_file)?;
+    for tag in tags {
+        handle.fs().add_tag(ino, tag)?;
+    }
+
+    Ok(())
+}
+
+
+// Copies a host file into the image. An existing file is replaced, if
+// dest is a directory the file keeps its name.
+pub fn put_command(image: &str, source: &str, dest: &str) -> Result<(), PtfsError> {
+    let mut file = File::open(source)?;
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadWrite)?;
+    let result = copy_in(&mut handle, &mut file, source, dest);
+    handle.close().and(result)
+}
+
+
+fn copy_in(handle: &mut PtfsHandle, file: &mut File, source: &str, dest: &str) -> Result<(), PtfsError> {
+    let mut dest = dest.to_string();
+    if let Ok(ino) = handle.resolve(&dest) {
+        if handle.fs().getattr(ino)?.kind == FileType::Directory {
+            let name = source.trim_end_matches('/').rsplit('/').next().unwrap_or(source);
+            dest = format!("{}/{}", dest.trim_end_matches('/'), name);
+        }
+    }
+
+    // creates the file or empties it
+    handle.write_file(&dest, &[])?;
+    let ino = handle.resolve(&dest)?;
+    handle.fs().setattr(ino, None, None, Some(0), None, None, None)?;
+
+    let mut buffer = vec![0; COPY_CHUNK];
+    let mut offset = 0;
+
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(());
+        }
+        handle.fs().write(ino, None, offset, &buffer[..count])?;
+        offset += count as i64;
+    }
+}
+
+
+// Prints the fsinfo block and what the blocks of the image are used for.
+// Works in rescue mode, so damaged and not cleanly unmounted images can
+// be inspected too.
+pub fn info_command(image: &str, json: bool) -> Result<(), PtfsError> {
+    let mut fs = PathTagFs::new(image, MountMode::Rescue)?;
+    fs.open(INO_ROOT, false)?;
+    let result = collect_info(&mut fs);
+    // the lookups of the walk above, a first hint at how well the image caches
+    let cache = fs.stats().cache;
+    let (info, usage, tags) = fs.destroy().and(result)?;
+    let (total, free, available) = fs.statfs();
+
+    let used = total - free;
+    let accounted = usage.fixed + usage.bitmap + usage.inode_table + usage.attr_index + usage.bad_blocks + usage.journal + usage.entries + usage.directories + usage.indexes + usage.data;
+
+    if json {
+        println!("{{\"format_version\":{},\"block_size\":{},\"total_blocks\":{},\"free_blocks\":{},\"available_blocks\":{},\
+                  \"reserved_blocks\":{},\"free_list_blocks\":{},\"clean\":{},\"mount_epoch\":{},\"mounts_since_check\":{},\"relocated_inodes\":{},\"tags\":{},\"used_blocks\":{},\
+                  \"usage\":{{\"fixed\":{},\"bitmap\":{},\"inode_table\":{},\"attr_index\":{},\"bad_blocks\":{},\"journal\":{},\"entries\":{},\"directories\":{},\"indexes\":{},\"data\":{},\"unreferenced\":{}}},\
+                  \"cache\":{{\"hits\":{},\"misses\":{},\"evictions\":{},\"hit_rate\":{:.3}}}}}",
+                 info.version, BLOCK_SIZE, total, free, available, info.reserved_blocks, fs.free_list_blocks(), info.is_clean(), info.epoch, info.mount_count,
+                 fs.relocated_inodes(), tags, used, usage.fixed, usage.bitmap, usage.inode_table, usage.attr_index, usage.bad_blocks, usage.journal, usage.entries,
+                 usage.directories, usage.indexes, usage.data, used.saturating_sub(accounted),
+                 cache.hits, cache.misses, cache.evictions, cache.hit_rate());
+        return Ok(());
+    }
+
+    println!("Format version:    {}", info.version);
+    println!("Block size:        {}", BLOCK_SIZE);
+    println!("Blocks:            {} total, {} free, {} available", total, free, available);
+    println!("Reserved blocks:   {}", info.reserved_blocks);
+    println!("Free list:         {}", fs.free_list_blocks());
+    println!("State:             {}", if info.is_clean() {"clean"} else {"not cleanly unmounted"});
+    println!("Mount epoch:       {}", info.epoch);
+    println!("Mounts since check: {}", info.mount_count);
+    println!("Relocated inodes:  {}", fs.relocated_inodes());
+    println!("Tags:              {}", tags);
+    println!("Used blocks:       {}", used);
+    println!("  fixed            {}", usage.fixed);
+    println!("  bitmap           {}", usage.bitmap);
+    println!("  inode table      {}", usage.inode_table);
+    println!("  attribute index  {}", usage.attr_index);
+    println!("  bad blocks       {}", usage.bad_blocks);
+    println!("  change journal   {}", usage.journal);
+    println!("  entries          {}", usage.entries);
+    println!("  directories      {}", usage.directories);
+    println!("  indexes          {}", usage.indexes);
+    println!("  data             {}", usage.data);
+    println!("  unreferenced     {}", used.saturating_sub(accounted));
+    println!("Cache:             {} hits, {} misses, {:.1}% hit rate", cache.hits, cache.misses, cache.hit_rate() * 100.0);
+
+    Ok(())
+}
+
+
+fn collect_info(fs: &mut PathTagFs) -> Result<(FsInfo, BlockUsage, usize), PtfsError> {
+    let tags = fs.all_tags()?.len();
+
+    Ok((fs.fsinfo()?, fs.block_usage()?, tags))
+}
+
+
+// Shows the space used by each top level directory below /Pathes, or by
+// the files of each tag, followed by the total.
+// With space, the file sizes are shown next to the allocated space and
+// what was saved by inline data, holes and files reached more than once.
+pub fn du_command(image: &str, by_tag: bool, apparent: bool, space: bool, json: bool) -> Result<(), PtfsError> {
+    let mut handle = PtfsHandle::open_image(image, MountMode::ReadOnly)?;
+    let result = collect_du(handle.fs(), by_tag);
+    let entries = handle.close().and(result)?;
+
+    let mut total = DuEntry {name: "total".to_string(), ..Default::default()};
+    for entry in &entries {
+        total.blocks.extend(&entry.blocks);
+        total.inodes.extend(&entry.inodes);
+    }
+
+    if json {
+        // both sizes, so apparent doesn't matter here
+        let item = |entry: &DuEntry| format!("{{\"name\":{},\"blocks\":{},\"bytes\":{},\"apparent_bytes\":{},\"saved_bytes\":{},\"files\":{}}}",
+            json_string(&entry.name), entry.blocks.len(), entry.physical(), entry.logical(), entry.saved(), entry.inodes.len());
+        let items: Vec<String> = entries.iter().map(item).collect();
+        println!("{{\"entries\":[{}],\"total\":{}}}", items.join(","), item(&total));
+        return Ok(());
+    }
+
+    if space {
+        println!("{:>10}  {:>10}  {:>10}  {:>6}  name", "logical", "physical", "saved", "saved%");
+    }
+
+    for entry in entries.iter().chain([&total]) {
+        if space {
+            let percent = if entry.logical() == 0 {0.0} else {entry.saved() as f64 * 100.0 / entry.logical() as f64};
+            println!("{:>10}  {:>10}  {:>10}  {:>5.1}%  {}", entry.logical().div_ceil(1024), entry.physical().div_ceil(1024),
+                entry.saved().div_ceil(1024), percent, entry.name);
+            continue;
+        }
+
+        let bytes = if apparent {entry.logical()} else {entry.physical()};
+        println!("{:>10}  {}", bytes.div_ceil(1024), entry.name);
+    }
+
+    Ok(())
+}
+
+
+fn collect_du(fs: &mut PathTagFs, by_tag: bool) -> Result<Vec<DuEntry>, PtfsError> {
+    let mut entries = Vec::new();
+
+    if by_tag {
+        // tags in namespaces are listed with their full names
+        for (tag_ino, tag) in fs.all_tags()? {
+            let mut entry = DuEntry {name: tag, ..Default::default()};
+            for (member, name) in fs.list_children_names(tag_ino)? {
+                if name != "." && name != ".." {
+                    add_tree(fs, member, &mut entry)?;
+                }
+            }
+            entries.push(entry);
+        }
+        return Ok(entries);
+    }
+
+    let paths_ino = fs.lookup(INO_ROOT, &PATHS_DIR.to_string())?.ino;
+
+    for (ino, name) in fs.list_children_names(paths_ino)? {
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let mut entry = DuEntry {name: name.to_string(), ..Default::default()};
+        add_tree(fs, ino, &mut entry)?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+
+// adds the blocks of ino and, for directories, of everything below it
+fn add_tree(fs: &mut PathTagFs, ino: u64, entry: &mut DuEntry) -> Result<(), PtfsError> {
+    let mut pending = vec![ino];
+
+    while let Some(ino) = pending.pop() {
+        if entry.inodes.contains_key(&ino) {
+            continue;
+        }
+
+        let (chain, data) = fs.file_blocks(ino)?;
+        entry.blocks.insert(fs.entry_block_no(ino));
+        entry.blocks.extend(chain);
+        entry.blocks.extend(data);
+
+        let attr = fs.getattr(ino)?;
+        entry.inodes.insert(ino, if attr.kind == FileType::Directory {0} else {attr.size});
+
+        if attr.kind == FileType::Directory {
+            for (child, name) in fs.list_children_names(ino)? {
+                if name != "." && name != ".." {
+                    pending.push(child);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+
+fn list_entries(handle: &mut PtfsHandle, path: &str) -> Result<Vec<ListEntry>, PtfsError> {
+    let ino = handle.resolve(path)?;
+    let tag_map = tag_map(handle)?;
+
+    let children = if handle.fs().getattr(ino)?.kind == FileType::Directory {
+        handle.list_dir(path)?
+    }
+    else {
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or(path);
+        vec![(ino, FileType::RegularFile, name.to_string())]
+    };
+
+    let mut entries = Vec::new();
+    for (ino, _, name) in children {
+        let attr = handle.fs().getattr(ino)?;
+        let (chain, data) = handle.fs().file_blocks(ino)?;
+        entries.push(ListEntry {
+            ino: ino,
+            kind: attr.kind,
+            size: attr.size,
+            allocated: ((1 + chain.len() + data.len()) * BLOCK_SIZE) as u64,
+            mtime: attr.mtime,
+            name: name,
+            tags: tag_map.get(&ino).cloned().unwrap_or_default(),
+        });
+    }
+
+    Ok(entries)
+}
+
+
+// the tags of all tagged files, read once instead of per file
+fn tag_map(handle: &mut PtfsHandle) -> Result<HashMap<u64, Vec<String>>, PtfsError> {
+    let fs = handle.fs();
+    let mut map: HashMap<u64, Vec<String>> = HashMap::new();
+
+    for (_, tag) in fs.all_tags()? {
+        for (ino, _) in fs.list_tagged(&tag)? {
+            map.entry(ino).or_default().push(tag.to_string());
+        }
+    }
+
+    Ok(map)
+}
+
+
+fn kind_char(kind: FileType) -> char {
+    match kind {
+        FileType::Directory => 'd',
+        FileType::RegularFile => '-',
+        FileType::Symlink => 'l',
+        FileType::NamedPipe => 'p',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Socket => 's',
+    }
+}
+
+
+fn kind_name(kind: FileType) -> &'static str {
+    match kind {
+        FileType::Directory => "directory",
+        FileType::RegularFile => "file",
+        FileType::Symlink => "symlink",
+        FileType::NamedPipe => "pipe",
+        FileType::CharDevice => "char_device",
+        FileType::BlockDevice => "block_device",
+        FileType::Socket => "socket",
+    }
+}
+
+
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+
+fn json_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|item| json_string(item)).collect();
+    format!("[{}]", items.join(","))
+}
+
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+
+// formats a time as UTC date and minute, without pulling in a date crate
+fn format_time(time: SystemTime) -> String {
+    let secs = unix_time(time);
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // civil date from day number, after Howard Hinnant's days_from_civil
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rest / 3600, rest % 3600 / 60)
+}
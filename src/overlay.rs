@@ -0,0 +1,238 @@
+//
+// Passthrough of /Pathes to a host directory. The image holds an entry
+// with the host path for each host file and directory, so they can be
+// tagged like any other file, while their data stays on the host. Single
+// host files can be added the same way, see PathTagFs::add_reference().
+//
+
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use fuser::{FileAttr, FileType};
+use log::{debug, warn};
+
+use crate::block_io::EXT_HOST_PATH;
+use crate::error::PtfsError;
+use crate::nodes::EntryBlock;
+use crate::path_tag_fs::{PathTagFs, PATHS_DIR};
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tag_fs::{MountMode, INO_ROOT};
+
+    fn make_host(dir: &str) -> PathBuf {
+        let dir = PathBuf::from(dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("music")).unwrap();
+        std::fs::write(dir.join("music/song"), b"la la la").unwrap();
+        std::fs::write(dir.join("note"), b"remember").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let host = make_host("/tmp/ptfs_test_overlay_host");
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_overlay", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        fs.attach_overlay(host.to_str().unwrap()).unwrap();
+
+        let song = fs.resolve("/Pathes/music/song").unwrap();
+        assert_eq!(fs.getattr(song).unwrap().size, 8);
+        assert_eq!(fs.read_file(song, 3, 100).unwrap(), b"la la");
+        fs.add_tag(song, "catchy").unwrap();
+
+        // changes go to the host file
+        fs.write(song, None, 8, b" la").unwrap();
+        assert_eq!(std::fs::read(host.join("music/song")).unwrap(), b"la la la la");
+        fs.setattr(song, None, None, Some(2), None, None, None).unwrap();
+        assert_eq!(std::fs::read(host.join("music/song")).unwrap(), b"la");
+
+        // new files and directories below /Pathes are created on the host
+        let music = fs.resolve("/Pathes/music").unwrap();
+        let tune = fs.mknod(music, &"tune".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(tune, None, 0, b"hum").unwrap();
+        assert_eq!(std::fs::read(host.join("music/tune")).unwrap(), b"hum");
+        fs.mkdir(music, &"live".to_string()).unwrap();
+        assert!(host.join("music/live").is_dir());
+
+        // the next attach picks up host changes, tags of kept files stay
+        std::fs::remove_file(host.join("note")).unwrap();
+        std::fs::write(host.join("music/new"), b"").unwrap();
+        fs.attach_overlay(host.to_str().unwrap()).unwrap();
+        assert_eq!(fs.resolve("/Pathes/note"), None);
+        assert!(fs.resolve("/Pathes/music/new").is_some());
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["catchy"]);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+
+        assert!(fs.attach_overlay("/tmp/ptfs_test_overlay_missing").is_err());
+    }
+}
+
+
+pub fn host_path(eb: &EntryBlock) -> Option<PathBuf> {
+    eb.extension(EXT_HOST_PATH).map(|path| PathBuf::from(OsStr::from_bytes(path)))
+}
+
+
+pub fn set_host_path(eb: &mut EntryBlock, path: &Path) -> Result<(), PtfsError> {
+    eb.set_extension(EXT_HOST_PATH, path.as_os_str().as_bytes())
+}
+
+
+pub fn read_host(path: &Path, offset: u64, size: u64) -> Result<Vec<u8>, PtfsError> {
+    let file = File::open(path)?;
+    let mut buffer = vec![0; size as usize];
+    let mut count = 0;
+
+    // stops at the end of the file
+    while count < buffer.len() {
+        let n = file.read_at(&mut buffer[count..], offset + count as u64)?;
+        if n == 0 {
+            break;
+        }
+        count += n;
+    }
+
+    buffer.truncate(count);
+    Ok(buffer)
+}
+
+
+pub fn write_host(path: &Path, offset: u64, data: &[u8]) -> Result<(), PtfsError> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.write_all_at(data, offset)?;
+    Ok(())
+}
+
+
+// any handle of the host file will do, fsync() goes to its inode
+pub fn sync_host(path: &Path, data_only: bool) -> Result<(), PtfsError> {
+    let file = File::open(path)?;
+    if data_only {
+        file.sync_data()?;
+    }
+    else {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+
+pub fn truncate_host(path: &Path, size: u64) -> Result<(), PtfsError> {
+    OpenOptions::new().write(true).open(path)?.set_len(size)?;
+    Ok(())
+}
+
+
+pub fn create_host(path: &Path, kind: FileType) -> Result<(), PtfsError> {
+    match kind {
+        FileType::Directory => std::fs::create_dir(path)?,
+        _ => {
+            OpenOptions::new().write(true).create_new(true).open(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+
+pub fn remove_host(path: &Path) -> Result<(), PtfsError> {
+    if path.is_dir() {
+        std::fs::remove_dir(path)?;
+    }
+    else {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+
+// size and times come from the host file, the rest from the entry
+pub fn host_attr(path: &Path, attr: &mut FileAttr) -> Result<(), PtfsError> {
+    let meta = std::fs::metadata(path)?;
+
+    if attr.kind == FileType::RegularFile {
+        attr.size = meta.len();
+        attr.blocks = meta.len().div_ceil(512);
+    }
+    attr.mtime = meta.modified()?;
+    attr.atime = meta.accessed()?;
+
+    Ok(())
+}
+
+
+// Makes /Pathes pass through to dir. Host files without an entry get one,
+// entries of host files that are gone are removed, their tags too.
+// Changes made on the host while mounted show up at the next attach.
+pub fn attach(fs: &mut PathTagFs, dir: &str) -> Result<(), PtfsError> {
+    let dir = std::fs::canonicalize(dir)?;
+    if !dir.is_dir() {
+        return Err(PtfsError::NotADirectory);
+    }
+
+    let paths = fs.lookup(fs.root(), &PATHS_DIR.to_string())?.ino;
+    set_host_path(fs.retrieve_entry_block(paths)?, &dir)?;
+
+    mirror(fs, paths, &dir)
+}
+
+
+fn mirror(fs: &mut PathTagFs, ino: u64, dir: &Path) -> Result<(), PtfsError> {
+    debug!("mirror() inode={} host directory={:?}", ino, dir);
+    let mut host_names = Vec::new();
+
+    for host_entry in std::fs::read_dir(dir)? {
+        let host_entry = host_entry?;
+        let kind = match host_entry.file_type()? {
+            kind if kind.is_dir() => FileType::Directory,
+            kind if kind.is_file() => FileType::RegularFile,
+            _ => {
+                warn!("  skipping {:?}, only files and directories pass through", host_entry.path());
+                continue;
+            }
+        };
+
+        let name = match host_entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => {
+                warn!("  skipping {:?}, the name is no valid UTF-8", host_entry.path());
+                continue;
+            }
+        };
+
+        // new entries get their host path from the directory
+        let child = match fs.find_child(ino, &name)? {
+            Some(child) => child,
+            None if kind == FileType::Directory => fs.mkdir(ino, &name)?.ino,
+            None => fs.mknod(ino, &name, kind)?.ino,
+        };
+
+        if host_path(fs.retrieve_entry_block(child)?).is_none() {
+            warn!("  {:?} is hidden by a file of the image with the same name", host_entry.path());
+            continue;
+        }
+
+        if kind == FileType::Directory {
+            mirror(fs, child, &host_entry.path())?;
+        }
+        host_names.push(name);
+    }
+
+    // entries of the image itself are kept
+    for (child, name) in fs.list_children_names(ino)? {
+        if name == "." || name == ".." || host_names.contains(&name) {
+            continue;
+        }
+
+        if host_path(fs.retrieve_entry_block(child)?).is_some() {
+            fs.forget_host_entry(ino, &name, child)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,7 +1,25 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::mpsc::Receiver;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use fuser::{FileAttr, FileType};
+use log::{debug, warn};
 
-use crate::nodes::{AnyBlock, DataBlock, DirectoryBlock, DirectoryEntry, EntryBlock, IndexBlock, MAX_ENTRIES};
-use crate::block_cache::BlockCache;
+use crate::attr_index::{AttrIndex, IndexedAttr};
+use crate::nodes::{AnyBlock, DataBlock, DirectoryBlock, DirectoryEntry, EntryBlock, IndexBlock, MAX_ENTRIES, MAX_NAME_LENGTH};
+use crate::block_cache::{BlockCache, BlockOwner, FsInfo, CACHED_BLOCK_MEMORY, MAX_MOUNTS_WITHOUT_CHECK};
+use crate::block_io::{EXT_COMMENT, EXT_INLINE_DATA, EXT_RATING, EXT_SHA256, EXT_SYMLINK_TARGET, EXT_TAG_ORDER, EXT_TAG_RULES};
+use crate::error::PtfsError;
+use crate::events::{ChangeEvent, Subscribers};
+use crate::ioctl::{FS_APPEND_FL, FS_IMMUTABLE_FL};
+use crate::journal::Journal;
+use crate::overlay;
+use crate::query::{self, QueryCache};
+use crate::sha256::{self, Sha256, DIGEST_SIZE, XATTR_SHA256};
+use crate::stats::{OpStats, Stats};
+use crate::tag_order::{TagOrder, XATTR_ORDER, XATTR_PINNED};
+use crate::tag_rules::{self, TagRules, RULE_XATTRS};
 
 
 /*
@@ -30,459 +48,5526 @@ fn debug_any_block(ab: &AnyBlock) {
 
 pub const BLOCK_SIZE:usize = 2048;
 
+// the root directory lives in block 1
+pub const INO_ROOT:u64 = 1;
+
+// top level directories created by mkfs
+pub const PATHS_DIR:&str = "Pathes";
+pub const TAGS_DIR:&str = "Tags";
+
+// holds the sub-volumes, each with its own Pathes and Tags
+pub const SUBVOLS_DIR:&str = "Subvolumes";
+
+// creation time as seconds since 1970, with nanoseconds after the dot
+pub const XATTR_CRTIME:&str = "user.ptfs.crtime";
+
+// the tags of a file one per line, its own ones and those of the tagged
+// directories above it
+pub const XATTR_TAGS:&str = "user.ptfs.tags";
+pub const XATTR_INHERITED_TAGS:&str = "user.ptfs.tags.inherited";
+
+// a free text note, kept in the entry block
+pub const XATTR_COMMENT:&str = "user.ptfs.comment";
+const MAX_COMMENT_LENGTH:usize = 1024;
+
+// stars as a number, files without a rating don't have the attribute
+pub const XATTR_RATING:&str = "user.ptfs.rating";
+pub const MAX_RATING:u8 = 5;
+
+// a file fits into one index block
+const MAX_FILE_BLOCKS:usize = BLOCK_SIZE/8 - 1;
+
+// content_hash() reads files in pieces of this size
+const HASH_READ_SIZE:u64 = 16 * BLOCK_SIZE as u64;
+
+// the attribute cache is dropped as a whole when it gets this large
+const ATTR_CACHE_SIZE:usize = 4096;
+
+// a memory budget keeps at least this many blocks cached
+const MIN_CACHED_BLOCKS:usize = 32;
+
+// resolve() gives up on paths that follow more symlinks than this
+const MAX_SYMLINK_DEPTH:usize = 40;
+
+// the longest symlink target, PATH_MAX of Linux
+const MAX_SYMLINK_TARGET:usize = 4096;
+
+// Readdir offsets are cookies of the directory block and the slot of the
+// next entry in it, so a listing resumes at the same entry after entries
+// before it were removed. Ordered tag directories are sorted as a whole, there it is the index of
+// the next entry with this bit. The kernel needs them to fit into an i64.
+const SLOT_BITS:u32 = 4;
+const ORDERED_COOKIE:u64 = 1 << 62;
+const END_COOKIE:u64 = i64::MAX as u64;
+
+
+// How the backing store is accessed. Read-only mounts may share an image
+// with other read-only mounts. Rescue mode is for salvaging data from a 
+// damaged image: it never writes, ignores the dirty state and tolerates 
+// broken block chains.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MountMode {
+    ReadWrite,
+    ReadOnly,
+    Rescue,
+}
+
+
+// How the members of a tag show up in its directory below /Tags
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TagView {
+    // the entries are the files themselves
+    HardLinks,
+
+    // the entries are symlinks to the files below /Pathes, so their
+    // real location can be found
+    Symlinks,
+}
+
+
+// How much of the image is read into the caches right after mounting
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Preload {
+    None,
+
+    // directories, entry blocks and attributes, and the attribute index
+    Metadata,
+
+    // the contents of the files too
+    All,
+}
+
+
+// the state of a preload walk between its steps, see preload_step()
+struct PreloadWalk {
+    preload: Preload,
+    visited: HashSet<u64>,
+
+    // directories to list, and data blocks to read
+    pending: Vec<u64>,
+    blocks: Vec<u64>,
+}
+
+
+// Symlinks in tag views get made up inode numbers: this bit, the inode of
+// the tag in the upper half and the one of the file in the lower half.
+const TAG_LINK:u64 = 1 << 63;
+
+// "report.pdf" becomes "report (n).pdf", shortened to fit MAX_NAME_LENGTH
+fn numbered_name(name: &str, n: u32) -> String {
+    // a leading dot starts a hidden name, not an extension
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let suffix = format!(" ({}){}", n, extension);
+    let mut end = MAX_NAME_LENGTH.saturating_sub(suffix.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    stem[..end].to_string() + &suffix
+}
+
+
+fn tag_link_ino(tag_ino: u64, ino: u64) -> Option<u64> {
+    if tag_ino >= 1 << 31 || ino >= 1 << 32 {
+        return None;
+    }
+
+    Some(TAG_LINK | tag_ino << 32 | ino)
+}
+
+fn split_tag_link(ino: u64) -> Option<(u64, u64)> {
+    if ino & TAG_LINK == 0 {
+        return None;
+    }
+
+    Some(((ino & !TAG_LINK) >> 32, ino & 0xFFFF_FFFF))
+}
+
+
+// What an open file handle may do, from the O_ACCMODE bits of open()
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+
+impl Access {
+
+    pub fn from_flags(flags: i32) -> Access {
+        match flags & libc::O_ACCMODE {
+            libc::O_WRONLY => Access::Write,
+            libc::O_RDWR => Access::ReadWrite,
+            _ => Access::Read,
+        }
+    }
+}
+
+
+// What the used blocks of an image hold, see PathTagFs::block_usage()
+#[derive(Default, Debug, PartialEq)]
+pub struct BlockUsage {
+    // block 0 and the fsinfo block
+    pub fixed: u64,
+    pub bitmap: u64,
+    pub inode_table: u64,
+    pub attr_index: u64,
+    pub bad_blocks: u64,
+    pub journal: u64,
+    pub entries: u64,
+    pub directories: u64,
+    pub indexes: u64,
+    pub data: u64,
+}
+
+
+// Result of PathTagFs::quick_check()
+#[derive(Default, Debug)]
+pub struct CheckReport {
+    pub inodes: u64,
+    pub blocks: u64,
+    pub problems: Vec<String>,
+}
+
+
+// How new blocks are placed. NearParent keeps the blocks of a file close
+// to its directory and to each other, which shortens seeks on disks.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AllocPolicy {
+    FirstFree,
+    NearParent,
+}
+
+
+// When written blocks reach the backing store. Sync writes each block
+// before the call returns. Writeback keeps them in the cache until a file
+// is closed, the cache holds too many or the file system is synced. Async
+// waits for the last two only, so a crash may lose closed files too.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncMode {
+    Sync,
+    Writeback,
+    Async,
+}
+
 
 fn comp(one: &String, two: &String) -> bool {
     let b1 = one.as_bytes();
     let b2 = two.as_bytes();
     
     if b1.len() != b2.len() {
-        println!("Difference in length {} != {}", b1.len(), b2.len());
+        debug!("Difference in length {} != {}", b1.len(), b2.len());
         return false;
     }
     
     
     for i in 0..b2.len() {
         if b1[i] != b2[i] {
-            println!("Difference at index {} -> {} != {}", i, b1[i], b2[i]);
+            debug!("Difference at index {} -> {} != {}", i, b1[i], b2[i]);
             return false;
         }
     }
-    
-    true
-}
-        
+    
+    true
+}
+        
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag_rules::{XATTR_INHERIT, XATTR_KEEP, XATTR_PROPAGATE};
+
+    fn make_fs(path: &str) -> PathTagFs {
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_export_handles() {
+        let mut fs = make_fs("/tmp/ptfs_test_export_handles");
+        let dir = fs.mkdir(INO_ROOT, &"dir".to_string()).unwrap().ino;
+        let file = fs.mknod(dir, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let generation = fs.generation(file).unwrap();
+
+        // handles outlive the caches, knfsd finds the parent with ".."
+        fs.drop_caches().unwrap();
+        assert_eq!(fs.generation(file).unwrap(), generation);
+        assert_eq!(fs.getattr(file).unwrap().ino, file);
+        assert_eq!(fs.lookup(dir, &"..".to_string()).unwrap().ino, INO_ROOT);
+        assert_eq!(fs.lookup(dir, &".".to_string()).unwrap().ino, dir);
+
+        // a reused inode is a different file
+        let unnamed = fs.create_unnamed(FileType::RegularFile).unwrap().ino;
+        let old = fs.generation(unnamed).unwrap();
+        fs.release_unnamed(unnamed).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let again = fs.create_unnamed(FileType::RegularFile).unwrap().ino;
+        assert_eq!(again, unnamed);
+        assert_ne!(fs.generation(again).unwrap(), old);
+    }
+
+    #[test]
+    fn test_windows_names() {
+        let mut fs = make_fs("/tmp/ptfs_test_windows_names");
+        fs.mknod(INO_ROOT, &"what?".to_string(), FileType::RegularFile).unwrap();
+
+        fs.set_windows_names(true);
+        for bad in ["what?", "a<b", "c:d", "back\\slash", "tab\t", "trailing.", "trailing ", "CON", "nul.txt", "Com1 .log", "lpt9"] {
+            assert!(matches!(fs.mknod(INO_ROOT, &bad.to_string(), FileType::RegularFile), Err(PtfsError::InvalidArgument)), "{}", bad);
+        }
+        for good in ["report.pdf", "console", "COM10", ".hidden", "a b"] {
+            fs.mknod(INO_ROOT, &good.to_string(), FileType::RegularFile).unwrap();
+        }
+
+        // tags are directories too
+        let file = fs.lookup(INO_ROOT, &"report.pdf".to_string()).unwrap().ino;
+        assert!(matches!(fs.add_tag(file, "aux"), Err(PtfsError::InvalidArgument)));
+        fs.add_tag(file, "work").unwrap();
+    }
+
+    #[test]
+    fn test_create_errors() {
+        let mut fs = make_fs("/tmp/ptfs_test_create_errors");
+        
+        let attr = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap();
+        assert_eq!(fs.lookup(INO_ROOT, &"file".to_string()).unwrap().ino, attr.ino);
+
+        assert!(matches!(fs.mkdir(INO_ROOT, &"file".to_string()), Err(PtfsError::Exists)));
+        assert!(matches!(fs.mknod(attr.ino, &"child".to_string(), FileType::RegularFile), Err(PtfsError::NotADirectory)));
+        assert!(matches!(fs.mknod(INO_ROOT, &"pipe".to_string(), FileType::NamedPipe), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.mknod(INO_ROOT, &"dev".to_string(), FileType::CharDevice), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.mknod(INO_ROOT, &"x".repeat(MAX_NAME_LENGTH + 1), FileType::RegularFile), Err(PtfsError::NameTooLong)));
+        assert!(matches!(fs.lookup(INO_ROOT, &"missing".to_string()), Err(PtfsError::NotFound)));
+        assert!(matches!(fs.list_children(attr.ino), Err(PtfsError::NotADirectory)));
+    }
+
+    #[test]
+    fn test_attr_cache() {
+        let mut fs = make_fs("/tmp/ptfs_test_attr_cache");
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        
+        assert_eq!(fs.getattr(ino).unwrap().size, 0);
+        assert!(fs.attrs.contains_key(&ino));
+
+        // every change must show up in the next getattr()
+        fs.write(ino, None, 0, b"data").unwrap();
+        assert_eq!(fs.getattr(ino).unwrap().size, 4);
+
+        fs.setattr(ino, Some(4711), None, None, None, None, None).unwrap();
+        assert_eq!(fs.getattr(ino).unwrap().uid, 4711);
+
+        fs.retrieve_entry_block(ino).unwrap().attr.perm = 0o600;
+        assert_eq!(fs.getattr(ino).unwrap().perm, 0o600);
+        
+        assert!(fs.getattr(9999).is_err());
+        assert!(!fs.attrs.contains_key(&9999));
+    }
+
+    #[test]
+    fn test_inline_data() {
+        let mut fs = make_fs("/tmp/ptfs_test_inline");
+        let ino = fs.mknod(INO_ROOT, &"small".to_string(), FileType::RegularFile).unwrap().ino;
+        let free = fs.cache.find_free_block();
+
+        // small files need no blocks besides their entry block
+        fs.write(ino, None, 0, b"hello world").unwrap();
+        fs.write(ino, None, 6, b"there").unwrap();
+        assert_eq!(fs.cache.find_free_block(), free);
+        assert_eq!(fs.getattr(ino).unwrap().size, 11);
+        assert_eq!(fs.read_file(ino, 0, 100).unwrap(), b"hello there");
+        assert_eq!(fs.read_file(ino, 6, 3).unwrap(), b"the");
+
+        fs.setattr(ino, None, None, Some(5), None, None, None).unwrap();
+        assert_eq!(fs.read_file(ino, 0, 100).unwrap(), b"hello");
+
+        // growing past the entry block moves the data to data blocks
+        let big: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        fs.write(ino, None, 5, &big).unwrap();
+        assert_ne!(fs.cache.find_free_block(), free);
+        assert!(fs.retrieve_entry_block(ino).unwrap().extension(EXT_INLINE_DATA).is_none());
+        assert_eq!(fs.getattr(ino).unwrap().size, 3005);
+
+        let data = fs.read_file(ino, 0, 3005).unwrap();
+        assert_eq!(&data[0..5], b"hello");
+        assert_eq!(&data[5..3005], &big[..]);
+    }
+
+    #[test]
+    fn test_relocated_inodes() {
+        let path = "/tmp/ptfs_test_relocate";
+        let mut fs = make_fs(path);
+        let dir = fs.mkdir(INO_ROOT, &"dir".to_string()).unwrap().ino;
+        let file = fs.mknod(dir, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(file, None, 0, b"content").unwrap();
+
+        let block = fs.relocate_inode(file).unwrap();
+        assert_ne!(block, file);
+        assert_eq!(fs.getattr(file).unwrap().ino, file);
+        assert_eq!(fs.read_file(file, 0, 100).unwrap(), b"content");
+
+        // the old block is reused, but not the inode number
+        let other = fs.mknod(dir, &"other".to_string(), FileType::RegularFile).unwrap().ino;
+        assert_ne!(other, file);
+        assert_eq!(fs.cache.entry_block_no(other), file);
+
+        fs.relocate_inode(dir).unwrap();
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.lookup(INO_ROOT, &"dir".to_string()).unwrap().ino, dir);
+        assert_eq!(fs.lookup(dir, &"file".to_string()).unwrap().ino, file);
+        assert_eq!(fs.lookup(dir, &"other".to_string()).unwrap().ino, other);
+        assert_eq!(fs.read_file(file, 0, 100).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_hard_links() {
+        let path = "/tmp/ptfs_test_hard_links";
+        let mut fs = make_fs(path);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let music = fs.mkdir(paths, &"music".to_string()).unwrap().ino;
+        let best = fs.mkdir(paths, &"best".to_string()).unwrap().ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(song, None, 0, &[7; 2 * BLOCK_SIZE]).unwrap();
+        fs.add_tag(song, "rock").unwrap();
+        let free = fs.statfs().1;
+
+        assert_eq!(fs.link(song, best, &"favourite".to_string()).unwrap().nlink, 2);
+        assert_eq!(fs.lookup(best, &"favourite".to_string()).unwrap().ino, song);
+        assert!(matches!(fs.link(song, best, &"favourite".to_string()), Err(PtfsError::Exists)));
+        assert!(matches!(fs.link(music, best, &"dir".to_string()), Err(PtfsError::NotPermitted)));
+        let rock = fs.resolve("/Tags/rock").unwrap();
+        assert!(matches!(fs.link(song, rock, &"again".to_string()), Err(PtfsError::Exists)));
+
+        // a link into a tag directory tags the file
+        let jazz = fs.create_tag("jazz").unwrap();
+        assert_eq!(fs.link(song, jazz, &"tune".to_string()).unwrap().nlink, 2);
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["jazz", "rock"]);
+        assert_eq!(fs.lookup(jazz, &"tune".to_string()).unwrap().ino, song);
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.getattr(song).unwrap().nlink, 2);
+
+        // the blocks and tags stay until the last name is gone
+        fs.unlink(music, &"song".to_string()).unwrap();
+        assert_eq!(fs.getattr(song).unwrap().nlink, 1);
+        assert_eq!(fs.read_file(song, 0, 10).unwrap(), vec![7; 10]);
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["jazz", "rock"]);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+
+        fs.unlink(best, &"favourite".to_string()).unwrap();
+        assert!(fs.statfs().1 > free);
+        assert!(fs.query("rock").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unnamed_files() {
+        let mut fs = make_fs("/tmp/ptfs_test_unnamed");
+        let free = fs.statfs().1;
+
+        // never linked, everything is given back
+        let temp = fs.create_unnamed(FileType::RegularFile).unwrap().ino;
+        fs.write(temp, None, 0, &[1u8; 3 * BLOCK_SIZE]).unwrap();
+        assert_eq!(fs.getattr(temp).unwrap().nlink, 0);
+        fs.release_unnamed(temp).unwrap();
+        assert_eq!(fs.statfs().1, free);
+
+        // linked in, it stays
+        let temp = fs.create_unnamed(FileType::RegularFile).unwrap().ino;
+        fs.write(temp, None, 0, b"atomic").unwrap();
+        assert_eq!(fs.link(temp, INO_ROOT, &"final".to_string()).unwrap().nlink, 1);
+        fs.release_unnamed(temp).unwrap();
+        assert_eq!(fs.lookup(INO_ROOT, &"final".to_string()).unwrap().ino, temp);
+        assert_eq!(fs.read_file(temp, 0, 100).unwrap(), b"atomic");
+
+        assert_eq!(fs.link(temp, INO_ROOT, &"again".to_string()).unwrap().nlink, 2);
+        assert!(matches!(fs.create_unnamed(FileType::Directory), Err(PtfsError::NotSupported)));
+    }
+
+    #[test]
+    fn test_create() {
+        let mut fs = make_fs("/tmp/ptfs_test_create");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let file = fs.create(paths, &"file".to_string(), libc::O_WRONLY | libc::O_CREAT, 1).unwrap().ino;
+        assert_eq!(fs.lookup(paths, &"file".to_string()).unwrap().ino, file);
+        fs.write(file, None, 0, b"first").unwrap();
+
+        // a file created in the meantime is opened, unless O_EXCL asks for a new one
+        let flags = libc::O_RDWR | libc::O_CREAT;
+        assert_eq!(fs.create(paths, &"file".to_string(), flags, 2).unwrap().ino, file);
+        assert_eq!(fs.read_file(file, 0, 100).unwrap(), b"first");
+        assert!(matches!(fs.create(paths, &"file".to_string(), flags | libc::O_EXCL, 3), Err(PtfsError::Exists)));
+
+        fs.mkdir(paths, &"dir".to_string()).unwrap();
+        assert!(matches!(fs.create(paths, &"dir".to_string(), flags, 4), Err(PtfsError::IsADirectory)));
+
+        let open: Vec<u64> = [1, 2, 3, 4].into_iter().filter(|fh| fs.handles.contains_key(fh)).collect();
+        assert_eq!(open, vec![1, 2]);
+        assert_eq!(fs.handles[&2], (file, Access::ReadWrite));
+    }
+
+    #[test]
+    fn test_unlink() {
+        let mut fs = make_fs("/tmp/ptfs_test_unlink");
+
+        let dir = fs.mkdir(INO_ROOT, &"dir".to_string()).unwrap().ino;
+        let file = fs.mknod(dir, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(file, None, 0, &[1u8; 3 * BLOCK_SIZE]).unwrap();
+        fs.add_tag(file, "music").unwrap();
+        fs.add_tag(file, "jazz").unwrap();
+        let used = fs.statfs().1;
+
+        assert!(matches!(fs.unlink(INO_ROOT, &"dir".to_string()), Err(PtfsError::IsADirectory)));
+        assert!(matches!(fs.unlink(dir, &"none".to_string()), Err(PtfsError::NotFound)));
+
+        // in a tag directory, the file just loses the tag
+        let tags = fs.lookup(INO_ROOT, &TAGS_DIR.to_string()).unwrap().ino;
+        let jazz = fs.lookup(tags, &"jazz".to_string()).unwrap().ino;
+        fs.unlink(jazz, &"file".to_string()).unwrap();
+        assert_eq!(fs.list_tags(file).unwrap(), vec!["music"]);
+        assert_eq!(fs.lookup(dir, &"file".to_string()).unwrap().ino, file);
+
+        fs.retrieve_entry_block(dir).unwrap().attr.mtime = UNIX_EPOCH;
+        fs.unlink(dir, &"file".to_string()).unwrap();
+        assert!(matches!(fs.lookup(dir, &"file".to_string()), Err(PtfsError::NotFound)));
+        assert!(fs.getattr(dir).unwrap().mtime > UNIX_EPOCH);
+        assert!(fs.statfs().1 > used);
+
+        let music = fs.lookup(tags, &"music".to_string()).unwrap().ino;
+        assert_eq!(fs.list_children_names(music).unwrap().iter().filter(|child| child.0 == file).count(), 0);
+
+        // an open file keeps its blocks until its last handle is closed
+        let before = fs.statfs().1;
+        let open = fs.mknod(dir, &"open".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(open, None, 0, b"still there").unwrap();
+        fs.open_handle(1, open, Access::Read).unwrap();
+        fs.open_handle(2, open, Access::Read).unwrap();
+        fs.unlink(dir, &"open".to_string()).unwrap();
+        assert_eq!(fs.read_file(open, 0, 100).unwrap(), b"still there");
+
+        fs.close_handle(1);
+        fs.release_unnamed(open).unwrap();
+        let other = fs.mknod(dir, &"other".to_string(), FileType::RegularFile).unwrap().ino;
+        assert_ne!(other, open);
+        fs.write(other, None, 0, b"other data!").unwrap();
+        assert_eq!(fs.read_file(open, 0, 100).unwrap(), b"still there");
+
+        fs.close_handle(2);
+        fs.release_unnamed(open).unwrap();
+        fs.unlink(dir, &"other".to_string()).unwrap();
+        assert_eq!(fs.statfs().1, before);
+    }
+
+    #[test]
+    fn test_reserved_space() {
+        let mut fs = make_fs("/tmp/ptfs_test_reserved_space");
+        fs.set_reserved_percent(25).unwrap();
+        let (total, free, available) = fs.statfs();
+        assert_eq!(free - available, total / 4);
+        assert!(matches!(fs.set_reserved_percent(90), Err(PtfsError::InvalidArgument)));
+
+        // fill up what ordinary writers may use
+        let ino = fs.mknod(INO_ROOT, &"big".to_string(), FileType::RegularFile).unwrap().ino;
+        let chunk = [0u8; BLOCK_SIZE];
+        let mut offset = 0;
+        while fs.write(ino, None, offset, &chunk).is_ok() {
+            offset += BLOCK_SIZE as i64;
+        }
+        assert_eq!(fs.statfs().2, 0);
+
+        // directories can still grow
+        for i in 0..20 {
+            fs.add_directory_entry(INO_ROOT, &format!("extra{}", i), ino, FileType::RegularFile).unwrap();
+        }
+
+        fs.set_privileged(true);
+        assert!(fs.write(ino, None, offset, &chunk).is_ok());
+    }
+
+    #[test]
+    fn test_children_from_offset() {
+        let mut fs = make_fs("/tmp/ptfs_test_children");
+        let dir = fs.mkdir(INO_ROOT, &"dir".to_string()).unwrap().ino;
+        for i in 0..20 {
+            fs.mknod(dir, &format!("file{}", i), FileType::RegularFile).unwrap();
+        }
+        fs.mkdir(dir, &"sub".to_string()).unwrap();
+
+        let all = fs.list_children(dir).unwrap();
+        assert_eq!(all.len(), 23);
+        assert_eq!(all[22].1, FileType::Directory);
+        
+        // offsets reach into later directory blocks
+        for offset in [0, 1, 7, 8, 9, 16, 22, 23, 30] {
+            let rest: Vec<_> = fs.children(dir, offset).unwrap().map(|child| child.unwrap().2).collect();
+            let expected: Vec<_> = all.iter().skip(offset).map(|child| child.2.clone()).collect();
+            assert_eq!(rest, expected);
+        }
+
+        let first: Vec<_> = fs.children(dir, 3).unwrap().take(2).map(|child| child.unwrap().0).collect();
+        assert_eq!(first, vec![all[3].0, all[4].0]);
+
+        // the cookie after each entry resumes with the next one
+        let mut cookies = Vec::new();
+        let mut children = fs.children_at(dir, 0).unwrap();
+        while children.next().is_some() {
+            cookies.push(children.cookie());
+        }
+        for (i, cookie) in cookies.iter().enumerate() {
+            let rest: Vec<_> = fs.children_at(dir, *cookie).unwrap().map(|child| child.unwrap().2).collect();
+            let expected: Vec<_> = all.iter().skip(i + 1).map(|child| child.2.clone()).collect();
+            assert_eq!(rest, expected);
+
+            // also the one of a listing started at an offset
+            let cookie = fs.children(dir, i + 1).unwrap().cookie();
+            assert_eq!(fs.children_at(dir, cookie).unwrap().map(|child| child.unwrap().2).collect::<Vec<_>>(), expected);
+        }
+        let cookie = fs.children(dir, 30).unwrap().cookie();
+        assert_eq!(fs.children_at(dir, cookie).unwrap().count(), 0);
+        assert!(matches!(fs.children_at(dir, cookies[0] | 0xf), Err(PtfsError::InvalidArgument)));
+
+        // blocks of other directories and of files are no cookies
+        let file = fs.lookup(dir, &"file3".to_string()).unwrap().ino;
+        fs.write(file, None, 0, &[5; BLOCK_SIZE]).unwrap();
+        let data = fs.file_blocks(file).unwrap().1[0];
+        let sub = fs.lookup(dir, &"sub".to_string()).unwrap().ino;
+        let sub_block = fs.retrieve_directory_entry(sub).unwrap().more_data;
+        for block in [data, sub_block, 1 << 40] {
+            assert!(matches!(fs.children_at(dir, block << SLOT_BITS), Err(PtfsError::InvalidArgument)));
+        }
+        assert_eq!(fs.read_file(file, 0, BLOCK_SIZE as u64).unwrap(), vec![5; BLOCK_SIZE]);
+
+        // entries added later show up in a listing that is not through yet
+        fs.mknod(dir, &"late".to_string(), FileType::RegularFile).unwrap();
+        let rest: Vec<_> = fs.children_at(dir, cookies[20]).unwrap().map(|child| child.unwrap().2).collect();
+        assert_eq!(rest, vec!["file19", "sub", "late"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut fs = make_fs("/tmp/ptfs_test_resolve");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let music = fs.mkdir(paths, &"music".to_string()).unwrap().ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+
+        let relative = fs.mknod(paths, &"rel".to_string(), FileType::Symlink).unwrap().ino;
+        fs.write(relative, None, 0, b"music/./song").unwrap();
+        let absolute = fs.mknod(paths, &"abs".to_string(), FileType::Symlink).unwrap().ino;
+        fs.write(absolute, None, 0, b"/Pathes/music").unwrap();
+        let looping = fs.mknod(paths, &"loop".to_string(), FileType::Symlink).unwrap().ino;
+        fs.write(looping, None, 0, b"loop").unwrap();
+
+        assert_eq!(fs.resolve("/"), Some(INO_ROOT));
+        assert_eq!(fs.resolve("/.."), Some(INO_ROOT));
+        assert_eq!(fs.resolve("//Pathes/music/song"), Some(song));
+        assert_eq!(fs.resolve("/Pathes/music/../music/./song"), Some(song));
+        assert_eq!(fs.resolve("/Pathes/rel"), Some(song));
+        assert_eq!(fs.resolve("/Pathes/abs/song"), Some(song));
+        assert_eq!(fs.resolve("/Pathes/abs/.."), Some(paths));
+
+        assert_eq!(fs.resolve("/Pathes/missing"), None);
+        assert_eq!(fs.resolve("/Pathes/music/song/more"), None);
+        assert_eq!(fs.resolve("/Pathes/loop"), None);
+    }
+
+
+    #[test]
+    fn test_tags() {
+        let mut fs = make_fs("/tmp/ptfs_test_tags");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        let tune = fs.mknod(paths, &"tune".to_string(), FileType::RegularFile).unwrap().ino;
+
+        fs.add_tag(song, "loud").unwrap();
+        fs.add_tag(song, "old").unwrap();
+        fs.add_tag(tune, "old").unwrap();
+        assert!(matches!(fs.add_tag(tune, "old"), Err(PtfsError::Exists)));
+        assert!(matches!(fs.add_tag(tune, "a/"), Err(PtfsError::InvalidArgument)));
+
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["loud", "old"]);
+        assert_eq!(fs.list_tagged("old").unwrap(), vec![(song, "song".to_string()), (tune, "tune".to_string())]);
+
+        fs.remove_tag(song, "loud").unwrap();
+        assert!(matches!(fs.remove_tag(song, "loud"), Err(PtfsError::NotFound)));
+        assert!(matches!(fs.list_tagged("unknown"), Err(PtfsError::NotFound)));
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["old"]);
+        assert!(fs.list_tagged("loud").unwrap().is_empty());
+    }
+
+
+    #[test]
+    fn test_block_usage() {
+        let mut fs = make_fs("/tmp/ptfs_test_block_usage");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(song, None, 0, &[7; 3 * BLOCK_SIZE]).unwrap();
+        fs.add_tag(song, "loud").unwrap();
+
+        // root, Pathes, Tags, loud and song, the tag doesn't count song twice
+        let usage = fs.block_usage().unwrap();
+        assert_eq!(usage, BlockUsage {fixed: 2, bitmap: 1, inode_table: 0, attr_index: 1, bad_blocks: 0, journal: 2, entries: 5, directories: 4, indexes: 1, data: 3});
+
+        // everything that is allocated is accounted for
+        let (total, free, _) = fs.statfs();
+        let sum = usage.fixed + usage.bitmap + usage.inode_table + usage.attr_index + usage.bad_blocks + usage.journal + usage.entries + usage.directories + usage.indexes + usage.data;
+        assert_eq!(sum, total - free);
+
+        let info = fs.fsinfo().unwrap();
+        assert_eq!(info.bitmap_count, 1);
+        assert_eq!(info.version, crate::block_cache::FORMAT_VERSION);
+    }
+
+
+    #[test]
+    fn test_change_events() {
+        let mut fs = make_fs("/tmp/ptfs_test_change_events");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let events = fs.subscribe();
+
+        let music = fs.mkdir(paths, &"music".to_string()).unwrap().ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        assert!(fs.mknod(music, &"song".to_string(), FileType::RegularFile).is_err());
+        fs.add_tag(song, "loud").unwrap();
+        fs.remove_tag(song, "loud").unwrap();
+
+        let tags = fs.lookup(INO_ROOT, &TAGS_DIR.to_string()).unwrap().ino;
+        let loud = fs.lookup(tags, &"loud".to_string()).unwrap().ino;
+
+        let received: Vec<ChangeEvent> = events.try_iter().collect();
+        assert_eq!(received, vec![
+            ChangeEvent::Created {parent: paths, ino: music, name: "music".to_string()},
+            ChangeEvent::Created {parent: music, ino: song, name: "song".to_string()},
+            ChangeEvent::Created {parent: tags, ino: loud, name: "loud".to_string()},
+            ChangeEvent::Tagged {ino: song, tag: "loud".to_string()},
+            ChangeEvent::Untagged {ino: song, tag: "loud".to_string()},
+        ]);
+    }
+
+
+    #[test]
+    fn test_quick_check() {
+        let mut fs = make_fs("/tmp/ptfs_test_quick_check");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(song, None, 0, &[7; 3 * BLOCK_SIZE]).unwrap();
+        fs.add_tag(song, "loud").unwrap();
+
+        let report = fs.quick_check().unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(report.inodes, 5);
+        assert_eq!(report.blocks, 5 + 4 + 1 + 3);
+
+        // cached blocks are written before they are dropped
+        assert!(fs.drop_caches().unwrap() > 0);
+        assert_eq!(fs.read_file(song, 0, 4).unwrap(), vec![7; 4]);
+
+        // a data block that is marked free
+        let (_, data) = fs.file_blocks(song).unwrap();
+        fs.cache.release_block(data[1]).unwrap();
+        let report = fs.quick_check().unwrap();
+        assert_eq!(report.problems, vec![format!("inode {}: block {} is marked as free", song, data[1])]);
+    }
+
+
+    #[test]
+    fn test_check_after_many_mounts() {
+        let path = "/tmp/ptfs_test_many_mounts";
+        let mut fs = make_fs(path);
+        let song = fs.mknod(INO_ROOT, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(song, None, 0, &[7; 2 * BLOCK_SIZE]).unwrap();
+        let (_, data) = fs.file_blocks(song).unwrap();
+        fs.cache.release_block(data[0]).unwrap();
+        fs.destroy().unwrap();
+
+        for _ in 0..MAX_MOUNTS_WITHOUT_CHECK {
+            let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+            fs.open(INO_ROOT, false).unwrap();
+            fs.destroy().unwrap();
+        }
+
+        // the damage is found and the image stays clean
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        assert!(matches!(fs.open(INO_ROOT, false), Err(PtfsError::Refused(_))));
+        drop(fs);
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, true).unwrap();
+        fs.cache.take_block(data[0] as usize).unwrap();
+        fs.destroy().unwrap();
+        drop(fs);
+
+        // once it passes, counting starts again
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        fs.destroy().unwrap();
+        assert_eq!(fs.fsinfo().unwrap().mount_count, 0);
+    }
+
+
+    #[test]
+    fn test_mutation_gate() {
+        let mut fs = make_fs("/tmp/ptfs_test_mutation_gate");
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+
+        fs.open_handle(1, ino, Access::from_flags(libc::O_RDONLY)).unwrap();
+        fs.open_handle(2, ino, Access::from_flags(libc::O_RDWR)).unwrap();
+        assert!(matches!(fs.check_mutation(Some(1)), Err(PtfsError::AccessDenied)));
+        assert!(fs.check_mutation(Some(2)).is_ok());
+
+        // writes and ftruncate() through a handle need it open for writing
+        assert!(matches!(fs.write(ino, Some(1), 0, b"x"), Err(PtfsError::AccessDenied)));
+        assert!(matches!(fs.setattr(ino, None, None, Some(0), None, None, Some(1)), Err(PtfsError::AccessDenied)));
+        fs.write(ino, Some(2), 0, b"x").unwrap();
+        assert_eq!(fs.setattr(ino, None, None, Some(0), None, None, Some(2)).unwrap().size, 0);
+        fs.close_handle(1);
+        assert!(fs.check_mutation(Some(1)).is_ok());
+        fs.destroy().unwrap();
+        drop(fs);
+
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_mutation_gate", MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert!(fs.open_handle(1, ino, Access::Read).is_ok());
+        assert!(matches!(fs.open_handle(2, ino, Access::Write), Err(PtfsError::ReadOnly)));
+        assert!(matches!(fs.check_mutation(Some(1)), Err(PtfsError::ReadOnly)));
+        assert!(matches!(fs.write(ino, None, 0, b"x"), Err(PtfsError::ReadOnly)));
+        assert!(matches!(fs.setattr(ino, None, None, Some(0), None, None, None), Err(PtfsError::ReadOnly)));
+        assert!(matches!(fs.add_tag(ino, "tag"), Err(PtfsError::ReadOnly)));
+    }
+
+    #[test]
+    fn test_subvolumes() {
+        let mut fs = make_fs("/tmp/ptfs_test_subvolumes");
+        assert!(fs.list_subvolumes().unwrap().is_empty());
+
+        let work = fs.create_subvolume("work").unwrap();
+        fs.create_subvolume("photos").unwrap();
+        assert_eq!(fs.list_subvolumes().unwrap(), vec!["photos", "work"]);
+        assert!(matches!(fs.create_subvolume("work"), Err(PtfsError::Exists)));
+
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let outer = fs.mknod(paths, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(outer, "urgent").unwrap();
+
+        fs.enter_subvolume("work").unwrap();
+        assert_eq!(fs.root(), work);
+        assert_eq!(fs.resolve("/"), Some(work));
+        assert_eq!(fs.resolve("/Pathes/file"), None);
+
+        let paths = fs.resolve("/Pathes").unwrap();
+        let inner = fs.mknod(paths, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(inner, "urgent").unwrap();
+        assert_eq!(fs.list_tagged("urgent").unwrap(), vec![(inner, "file".to_string())]);
+        assert!(fs.list_tags(outer).unwrap().is_empty());
+
+        assert!(matches!(fs.enter_subvolume("missing"), Err(PtfsError::NotFound)));
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_tag_namespaces() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_namespaces");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let report = fs.mknod(paths, &"report".to_string(), FileType::RegularFile).unwrap().ino;
+        let photo = fs.mknod(paths, &"photo".to_string(), FileType::RegularFile).unwrap().ino;
+
+        fs.add_tag(report, "work/urgent").unwrap();
+        fs.add_tag(photo, "photos/urgent").unwrap();
+        fs.add_tag(photo, "urgent").unwrap();
+        fs.add_tag(report, "work/clients/acme").unwrap();
+
+        assert_eq!(fs.list_tags(report).unwrap(), vec!["work/clients/acme", "work/urgent"]);
+        assert_eq!(fs.list_tagged("work/urgent").unwrap(), vec![(report, "report".to_string())]);
+        assert_eq!(fs.list_tagged("urgent").unwrap(), vec![(photo, "photo".to_string())]);
+        assert!(matches!(fs.list_tagged("work"), Err(PtfsError::NotFound)));
+        let names: Vec<String> = fs.all_tags().unwrap().into_iter().map(|tag| tag.1).collect();
+        assert_eq!(names, vec!["photos/urgent", "urgent", "work/clients/acme", "work/urgent"]);
+
+        // the namespaces show up as directories below /Tags
+        assert!(fs.resolve("/Tags/work/clients/acme/report").is_some());
+
+        assert_eq!(fs.query_in("work", "urgent").unwrap(), BTreeSet::from([report]));
+        assert_eq!(fs.query_in("photos", "urgent").unwrap(), BTreeSet::from([photo]));
+        assert_eq!(fs.query("urgent").unwrap(), BTreeSet::from([photo]));
+
+        assert!(matches!(fs.add_tag(report, "urgent/more"), Err(PtfsError::NotADirectory)));
+        assert!(matches!(fs.add_tag(report, "work"), Err(PtfsError::Exists)));
+        assert!(matches!(fs.add_tag(report, "work//x"), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.query_in("/work", "urgent"), Err(PtfsError::InvalidArgument)));
+
+        fs.remove_tag(report, "work/urgent").unwrap();
+        assert_eq!(fs.list_tags(report).unwrap(), vec!["work/clients/acme"]);
+    }
+
+    #[test]
+    fn test_references() {
+        let host = "/tmp/ptfs_test_references_movie";
+        std::fs::write(host, b"frames").unwrap();
+
+        let mut fs = make_fs("/tmp/ptfs_test_references");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let ino = fs.add_reference(paths, &"movie".to_string(), host).unwrap().ino;
+        assert_eq!(fs.getattr(ino).unwrap().size, 6);
+        assert_eq!(fs.read_file(ino, 0, 100).unwrap(), b"frames");
+
+        fs.write(ino, None, 6, b"!").unwrap();
+        assert_eq!(std::fs::read(host).unwrap(), b"frames!");
+        assert!(fs.file_blocks(ino).unwrap().1.is_empty());
+
+        fs.add_tag(ino, "big").unwrap();
+        assert_eq!(fs.query("big").unwrap(), BTreeSet::from([ino]));
+
+        assert!(matches!(fs.add_reference(paths, &"movie".to_string(), host), Err(PtfsError::Exists)));
+        assert!(matches!(fs.add_reference(paths, &"dir".to_string(), "/tmp"), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.add_reference(paths, &"gone".to_string(), "/tmp/ptfs_test_references_missing"), Err(PtfsError::Io(_))));
+    }
+
+    #[test]
+    fn test_tag_name_collisions() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_names");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let mut reports = Vec::new();
+        for dir in ["2023", "2024", "2025"] {
+            let dir = fs.mkdir(paths, &dir.to_string()).unwrap().ino;
+            let report = fs.mknod(dir, &"report.pdf".to_string(), FileType::RegularFile).unwrap().ino;
+            fs.add_tag(report, "tax").unwrap();
+            reports.push(report);
+        }
+
+        let names = vec!["report.pdf", "report (2).pdf", "report (3).pdf"];
+        let tagged = reports.iter().cloned().zip(names.iter().map(|name| name.to_string())).collect::<Vec<_>>();
+        assert_eq!(fs.list_tagged("tax").unwrap(), tagged);
+        assert!(matches!(fs.add_tag(reports[1], "tax"), Err(PtfsError::Exists)));
+
+        // a freed name is used again, the others stay
+        fs.remove_tag(reports[0], "tax").unwrap();
+        fs.add_tag(reports[0], "tax").unwrap();
+        assert_eq!(fs.resolve("/Tags/tax/report.pdf"), Some(reports[0]));
+        assert_eq!(fs.resolve("/Tags/tax/report (3).pdf"), Some(reports[2]));
+
+        // the names are kept in the image
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_tag_names", MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.resolve("/Tags/tax/report (2).pdf"), Some(reports[1]));
+
+        assert_eq!(numbered_name(".profile", 2), ".profile (2)");
+        assert_eq!(numbered_name("a.tar.gz", 10), "a.tar (10).gz");
+        let long = numbered_name(&"x".repeat(MAX_NAME_LENGTH), 2);
+        assert_eq!(long.len(), MAX_NAME_LENGTH);
+        assert!(long.ends_with("x (2)"));
+    }
+
+    #[test]
+    fn test_rename_over() {
+        let mut fs = make_fs("/tmp/ptfs_test_rename_over");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let docs = fs.mkdir(paths, &"docs".to_string()).unwrap().ino;
+        let draft = fs.mknod(paths, &"draft".to_string(), FileType::RegularFile).unwrap().ino;
+        let old = fs.mknod(docs, &"report".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(draft, None, 0, b"new").unwrap();
+        fs.add_tag(old, "work").unwrap();
+
+        // the old report and its tag are gone
+        fs.rename_over(paths, &"draft".to_string(), docs, &"report".to_string()).unwrap();
+        assert_eq!(fs.lookup(docs, &"report".to_string()).unwrap().ino, draft);
+        assert_eq!(fs.read_file(draft, 0, 10).unwrap(), b"new");
+        assert!(fs.list_tagged("work").unwrap().is_empty());
+
+        // directories replace empty directories only, and no files
+        let empty = fs.mkdir(paths, &"empty".to_string()).unwrap().ino;
+        assert!(matches!(fs.rename_over(paths, &"empty".to_string(), paths, &"docs".to_string()), Err(PtfsError::NotEmpty)));
+        assert!(matches!(fs.rename_over(docs, &"report".to_string(), paths, &"empty".to_string()), Err(PtfsError::IsADirectory)));
+        assert!(matches!(fs.rename_over(paths, &"empty".to_string(), docs, &"report".to_string()), Err(PtfsError::NotADirectory)));
+        fs.rename_over(paths, &"docs".to_string(), paths, &"empty".to_string()).unwrap();
+        assert_eq!(fs.lookup(paths, &"empty".to_string()).unwrap().ino, docs);
+        assert_eq!(fs.resolve("/Pathes/empty/report"), Some(draft));
+        assert!(fs.list_children_names(paths).unwrap().iter().all(|(child, _)| *child != empty));
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_rename_over_failure() {
+        let mut fs = make_fs("/tmp/ptfs_test_rename_over_failure");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let draft = fs.mknod(paths, &"draft".to_string(), FileType::RegularFile).unwrap().ino;
+        let old = fs.mknod(paths, &"a:b".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(old, None, 0, b"old").unwrap();
+        fs.add_tag(old, "work").unwrap();
+
+        // the name is refused by rename() after the target left its slot
+        fs.set_windows_names(true);
+        let err = fs.rename_over(paths, &"draft".to_string(), paths, &"a:b".to_string()).unwrap_err();
+        assert!(matches!(err, PtfsError::InvalidArgument));
+        assert_eq!(fs.lookup(paths, &"a:b".to_string()).unwrap().ino, old);
+        assert_eq!(fs.lookup(paths, &"draft".to_string()).unwrap().ino, draft);
+        assert_eq!(fs.read_file(old, 0, 10).unwrap(), b"old");
+        assert_eq!(fs.list_tagged("work").unwrap().len(), 1);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+
+        // in a tag directory the target keeps its tag
+        fs.set_windows_names(false);
+        fs.add_tag(draft, "work").unwrap();
+        let work = fs.find_tag("work").unwrap().unwrap();
+        fs.rename_over(work, &"draft".to_string(), work, &"a:b".to_string()).unwrap();
+        assert_eq!(fs.lookup(work, &"a:b".to_string()).unwrap().ino, draft);
+        assert_eq!(fs.list_tags(old).unwrap(), Vec::<String>::new());
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_rename_keeps_tags() {
+        let mut fs = make_fs("/tmp/ptfs_test_rename_tags");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let inbox = fs.mkdir(paths, &"inbox".to_string()).unwrap().ino;
+        let photos = fs.mkdir(paths, &"photos".to_string()).unwrap().ino;
+        let trip = fs.mkdir(inbox, &"trip".to_string()).unwrap().ino;
+        let beach = fs.mknod(trip, &"img1.jpg".to_string(), FileType::RegularFile).unwrap().ino;
+        let other = fs.mknod(photos, &"beach.jpg".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(beach, "holiday").unwrap();
+        fs.add_tag(other, "holiday").unwrap();
+        fs.add_tag(beach, "places/coast").unwrap();
+        let events = fs.subscribe();
+
+        // the new name is taken in the tag directory by the other file
+        fs.rename(trip, &"img1.jpg".to_string(), trip, &"beach.jpg".to_string()).unwrap();
+        assert_eq!(fs.list_tags(beach).unwrap(), vec!["holiday", "places/coast"]);
+        assert_eq!(fs.resolve("/Tags/holiday/beach.jpg"), Some(other));
+        assert_eq!(fs.resolve("/Tags/holiday/beach (2).jpg"), Some(beach));
+        assert_eq!(fs.resolve("/Tags/places/coast/beach.jpg"), Some(beach));
+        assert_eq!(fs.resolve("/Tags/holiday/img1.jpg"), None);
+
+        // moving the directory takes its files along with their tags
+        fs.rename(inbox, &"trip".to_string(), photos, &"2024-trip".to_string()).unwrap();
+        assert_eq!(fs.resolve("/Pathes/photos/2024-trip/beach.jpg"), Some(beach));
+        assert_eq!(fs.resolve("/Pathes/photos/2024-trip/../beach.jpg"), Some(other));
+        assert_eq!(fs.resolve("/Pathes/inbox/trip"), None);
+        assert_eq!(fs.query("holiday places/coast").unwrap(), BTreeSet::from([beach]));
+        assert_eq!(fs.canonical_path(beach).unwrap(), "Pathes/photos/2024-trip/beach.jpg");
+
+        let received: Vec<ChangeEvent> = events.try_iter().collect();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1], ChangeEvent::Renamed {ino: trip, old_parent: inbox, old_name: "trip".to_string(),
+                                                      new_parent: photos, new_name: "2024-trip".to_string()});
+
+        // renaming in a tag directory leaves the file alone
+        let holiday = fs.resolve("/Tags/holiday").unwrap();
+        fs.rename(holiday, &"beach (2).jpg".to_string(), holiday, &"sunset.jpg".to_string()).unwrap();
+        assert_eq!(fs.resolve("/Tags/holiday/sunset.jpg"), Some(beach));
+        assert_eq!(fs.resolve("/Pathes/photos/2024-trip/beach.jpg"), Some(beach));
+
+        // no tagging by moving, no directory below itself, no replacing
+        let coast = fs.resolve("/Tags/places/coast").unwrap();
+        assert!(matches!(fs.rename(holiday, &"sunset.jpg".to_string(), coast, &"sunset.jpg".to_string()), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.rename(paths, &"photos".to_string(), trip, &"photos".to_string()), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.rename(photos, &"beach.jpg".to_string(), trip, &"beach.jpg".to_string()), Err(PtfsError::Exists)));
+
+        // all of it is kept in the image
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_rename_tags", MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.resolve("/Tags/places/coast/beach.jpg"), Some(beach));
+        assert_eq!(fs.getattr(beach).unwrap().ino, beach);
+        assert_eq!(fs.list_tags(beach).unwrap(), vec!["holiday", "places/coast"]);
+    }
+
+    #[test]
+    fn test_directory_tags() {
+        let mut fs = make_fs("/tmp/ptfs_test_directory_tags");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let trip = fs.mkdir(paths, &"2024-trip".to_string()).unwrap().ino;
+        let day1 = fs.mkdir(trip, &"day1".to_string()).unwrap().ino;
+        let photo = fs.mknod(day1, &"beach.jpg".to_string(), FileType::RegularFile).unwrap().ino;
+        let other = fs.mknod(paths, &"other.jpg".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(trip, "holiday").unwrap();
+        fs.add_tag(photo, "best").unwrap();
+
+        assert_eq!(fs.query("holiday").unwrap(), BTreeSet::from([trip, photo]));
+        assert_eq!(fs.list_tagged("holiday").unwrap(), vec![(trip, "2024-trip".to_string())]);
+
+        // later files are found too, also by a cached query
+        let video = fs.mknod(day1, &"video.mp4".to_string(), FileType::RegularFile).unwrap().ino;
+        assert_eq!(fs.query("holiday").unwrap(), BTreeSet::from([trip, photo, video]));
+        assert_eq!(fs.query("holiday best").unwrap(), BTreeSet::from([photo]));
+        assert!(!fs.query("NOT holiday").unwrap().contains(&video));
+        assert!(fs.query("NOT holiday").unwrap().contains(&other));
+
+        // own and inherited tags are told apart
+        assert_eq!(fs.getxattr(photo, XATTR_TAGS).unwrap(), b"best");
+        assert_eq!(fs.getxattr(photo, XATTR_INHERITED_TAGS).unwrap(), b"holiday");
+        assert_eq!(fs.getxattr(day1, XATTR_INHERITED_TAGS).unwrap(), b"holiday");
+        assert_eq!(fs.getxattr(trip, XATTR_TAGS).unwrap(), b"holiday");
+        assert!(matches!(fs.getxattr(trip, XATTR_INHERITED_TAGS), Err(PtfsError::NoAttribute)));
+        assert!(matches!(fs.getxattr(other, XATTR_TAGS), Err(PtfsError::NoAttribute)));
+        assert_eq!(fs.listxattr(video).unwrap(), vec![XATTR_CRTIME, XATTR_INHERITED_TAGS]);
+
+        // moved out of the directory, the file loses the tag
+        fs.rename(day1, &"video.mp4".to_string(), paths, &"video.mp4".to_string()).unwrap();
+        assert_eq!(fs.query("holiday").unwrap(), BTreeSet::from([trip, photo]));
+        assert!(fs.inherited_tags(video).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tag_rules() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_rules");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let inbox = fs.mkdir(paths, &"inbox".to_string()).unwrap().ino;
+        let done = fs.mkdir(paths, &"done".to_string()).unwrap().ino;
+        let mail = fs.mknod(inbox, &"mail".to_string(), FileType::RegularFile).unwrap().ino;
+        let note = fs.mknod(inbox, &"note".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(inbox, "todo").unwrap();
+        fs.add_tag(inbox, "work").unwrap();
+        let todo = fs.resolve("/Tags/todo").unwrap();
+
+        // tags only on the directory itself
+        assert_eq!(fs.getxattr(todo, XATTR_INHERIT).unwrap(), b"yes");
+        fs.setxattr(todo, XATTR_INHERIT, b"no").unwrap();
+        assert_eq!(fs.query("todo").unwrap(), BTreeSet::from([inbox]));
+        assert_eq!(fs.inherited_tags(mail).unwrap(), vec!["work"]);
+        assert_eq!(fs.listxattr(todo).unwrap(), vec![XATTR_CRTIME, XATTR_INHERIT, XATTR_PROPAGATE, XATTR_KEEP]);
+        fs.removexattr(todo, XATTR_INHERIT).unwrap();
+        assert_eq!(fs.tag_rules("todo").unwrap(), TagRules::default());
+        assert_eq!(fs.query("todo").unwrap(), BTreeSet::from([inbox, mail, note]));
+
+        // moved files keep the tags that propagate, and lose the others
+        fs.set_tag_rules("work", TagRules {inherit: true, propagate: true, keep: false}).unwrap();
+        fs.rename(inbox, &"mail".to_string(), done, &"mail".to_string()).unwrap();
+        assert_eq!(fs.list_tags(mail).unwrap(), vec!["work"]);
+        assert!(fs.inherited_tags(mail).unwrap().is_empty());
+        assert_eq!(fs.query("work").unwrap(), BTreeSet::from([inbox, mail, note]));
+        assert_eq!(fs.query("todo").unwrap(), BTreeSet::from([inbox, note]));
+
+        assert!(matches!(fs.setxattr(todo, XATTR_PROPAGATE, b"maybe"), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.getxattr(inbox, XATTR_INHERIT), Err(PtfsError::NoAttribute)));
+
+        // the rules are kept in the image
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_tag_rules", MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.tag_rules("work").unwrap(), TagRules {inherit: true, propagate: true, keep: false});
+    }
+
+    #[test]
+    fn test_attr_index() {
+        let path = "/tmp/ptfs_test_attr_index";
+        let mut fs = make_fs(path);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        let tune = fs.mknod(paths, &"tune".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(tune, None, 0, &[1; 3000]).unwrap();
+        let all = 0..=u64::MAX;
+        assert_eq!(fs.indexed_files(IndexedAttr::Size, 1..=u64::MAX).unwrap(), Some(BTreeSet::from([tune])));
+
+        // a clean unmount keeps it, changes go in right away
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.attr_index.as_ref().unwrap().len(), 2);
+        fs.setattr(song, None, None, None, None, Some(UNIX_EPOCH + Duration::from_secs(1000)), None).unwrap();
+        assert_eq!(fs.indexed_files(IndexedAttr::Mtime, 0..=1000).unwrap(), Some(BTreeSet::from([song])));
+        assert_eq!(fs.query("mtime<1980-01-01").unwrap(), BTreeSet::from([song]));
+        assert!(fs.block_usage().unwrap().attr_index > 0);
+
+        // after a crash it is built again from the tree
+        fs.sync().unwrap();
+        drop(fs);
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, true).unwrap();
+        assert!(fs.attr_index.is_none());
+        assert_eq!(fs.indexed_files(IndexedAttr::Mtime, 0..=1000).unwrap(), Some(BTreeSet::from([song])));
+
+        // host files are left to the scan
+        let host = "/tmp/ptfs_test_attr_index_host";
+        std::fs::write(host, b"data").unwrap();
+        fs.add_reference(paths, &"host".to_string(), host).unwrap();
+        assert_eq!(fs.indexed_files(IndexedAttr::Size, all.clone()).unwrap(), None);
+
+        // a damaged index is built again from the tree
+        fs.attr_index = Some(AttrIndex::default());
+        assert_eq!(fs.reindex().unwrap(), 3);
+        assert_eq!(fs.attr_index.as_ref().unwrap().len(), 2);
+        assert!(fs.attr_index.as_ref().unwrap().partial);
+    }
+
+    #[test]
+    fn test_journal() {
+        let path = "/tmp/ptfs_test_journal";
+        let mut fs = make_fs(path);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let start = fs.journal().since(0).last().unwrap().seq;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.open_handle(1, song, Access::Write).unwrap();
+        fs.write(song, None, 0, b"la la").unwrap();
+        fs.close_handle(1);
+        fs.add_tag(song, "loud").unwrap();
+        fs.rename(paths, &"song".to_string(), paths, &"tune".to_string()).unwrap();
+
+        let records = |fs: &PathTagFs, since| fs.journal().since(since).map(|record| (record.kind.name(), record.ino, record.name.clone())).collect::<Vec<_>>();
+        let tags = fs.lookup(INO_ROOT, &TAGS_DIR.to_string()).unwrap().ino;
+        let loud = fs.lookup(tags, &"loud".to_string()).unwrap().ino;
+        assert_eq!(records(&fs, start), vec![("create", song, "song".to_string()), ("modify", song, String::new()),
+                                             ("create", loud, "loud".to_string()), ("tag", song, "loud".to_string()),
+                                             ("moved_from", song, "song".to_string()), ("moved_to", song, "tune".to_string())]);
+        let last = fs.journal().since(0).last().unwrap().seq;
+        assert_eq!(last, start + 6);
+
+        // a clean unmount keeps the records, the next mount numbers above them
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert!(fs.journal().complete_since(start));
+        assert_eq!(records(&fs, start).len(), 6);
+        fs.setattr(song, None, None, Some(2), None, None, None).unwrap();
+        let truncated = fs.journal().since(last).next().unwrap().clone();
+        assert_eq!((truncated.kind.name(), truncated.ino), ("modify", song));
+        assert!(truncated.seq > last + 1);
+        assert!(fs.block_usage().unwrap().journal > 0);
+
+        // after a crash the records of the last mount may be missing
+        fs.sync().unwrap();
+        drop(fs);
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, true).unwrap();
+        assert!(fs.journal().is_empty());
+        assert!(!fs.journal().complete_since(truncated.seq));
+        fs.unlink(paths, &"tune".to_string()).unwrap();
+        assert!(fs.journal().since(0).next().unwrap().seq > truncated.seq);
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut fs = make_fs("/tmp/ptfs_test_comments");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        let content = vec![7; 1500];
+        fs.write(song, None, 0, &content).unwrap();
+
+        assert!(matches!(fs.getxattr(song, XATTR_COMMENT), Err(PtfsError::NoAttribute)));
+        fs.setxattr(song, XATTR_COMMENT, "Live in Köln, 1975".as_bytes()).unwrap();
+        assert_eq!(fs.getxattr(song, XATTR_COMMENT).unwrap(), "Live in Köln, 1975".as_bytes());
+        assert_eq!(fs.listxattr(song).unwrap(), vec![XATTR_CRTIME, XATTR_COMMENT]);
+
+        // the inline contents made room for a long comment
+        let long = "x".repeat(MAX_COMMENT_LENGTH);
+        fs.set_comment(song, &long).unwrap();
+        assert_eq!(fs.comment(song).unwrap(), Some(long));
+        assert_eq!(fs.read_file(song, 0, 2000).unwrap(), content);
+        assert!(matches!(fs.set_comment(song, &"x".repeat(MAX_COMMENT_LENGTH + 1)), Err(PtfsError::NoSpace)));
+        assert!(matches!(fs.setxattr(song, XATTR_COMMENT, &[0xff]), Err(PtfsError::InvalidArgument)));
+
+        fs.removexattr(song, XATTR_COMMENT).unwrap();
+        assert_eq!(fs.comment(song).unwrap(), None);
+        assert!(matches!(fs.removexattr(song, XATTR_COMMENT), Err(PtfsError::NoAttribute)));
+    }
+
+    #[test]
+    fn test_ratings() {
+        let mut fs = make_fs("/tmp/ptfs_test_ratings");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let photo = fs.mknod(paths, &"photo".to_string(), FileType::RegularFile).unwrap().ino;
+        let other = fs.mknod(paths, &"other".to_string(), FileType::RegularFile).unwrap().ino;
+
+        assert!(matches!(fs.getxattr(photo, XATTR_RATING), Err(PtfsError::NoAttribute)));
+        fs.setxattr(photo, XATTR_RATING, b"4\n").unwrap();
+        fs.set_rating(other, 2).unwrap();
+        assert_eq!(fs.getxattr(photo, XATTR_RATING).unwrap(), b"4");
+        assert_eq!(fs.listxattr(photo).unwrap(), vec![XATTR_CRTIME, XATTR_RATING]);
+        assert_eq!(fs.indexed_files(IndexedAttr::Rating, 4..=5).unwrap(), Some(BTreeSet::from([photo])));
+        assert_eq!(fs.query("rating>=4").unwrap(), BTreeSet::from([photo]));
+        assert_eq!(fs.query("rating<4").unwrap(), BTreeSet::from([other]));
+
+        assert!(matches!(fs.setxattr(photo, XATTR_RATING, b"6"), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.setxattr(photo, XATTR_RATING, b"many"), Err(PtfsError::InvalidArgument)));
+        fs.removexattr(photo, XATTR_RATING).unwrap();
+        assert_eq!(fs.rating(photo).unwrap(), 0);
+        assert_eq!(fs.query("rating=0").unwrap(), BTreeSet::from([photo]));
+    }
+
+    #[test]
+    fn test_tag_gc() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_gc");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        for tag in ["rock", "jazz", "work/urgent", "wishlist"] {
+            fs.add_tag(song, tag).unwrap();
+        }
+        fs.set_tag_rules("wishlist", TagRules {keep: true, ..Default::default()}).unwrap();
+        for tag in ["jazz", "work/urgent", "wishlist"] {
+            fs.remove_tag(song, tag).unwrap();
+        }
+
+        assert_eq!(fs.empty_tags().unwrap(), vec!["jazz", "work/urgent"]);
+        let free = fs.statfs().1;
+        assert_eq!(fs.gc_tags().unwrap(), vec!["jazz", "work/urgent"]);
+        assert!(fs.statfs().1 > free);
+        let names = fs.all_tags().unwrap().into_iter().map(|tag| tag.1).collect::<Vec<_>>();
+        assert_eq!(names, vec!["rock", "wishlist"]);
+        assert!(fs.resolve("/Tags/work").is_some());
+        let wishlist = fs.resolve("/Tags/wishlist").unwrap();
+        assert_eq!(fs.getxattr(wishlist, XATTR_KEEP).unwrap(), b"yes");
+        fs.setxattr(wishlist, XATTR_KEEP, b"no").unwrap();
+        assert_eq!(fs.empty_tags().unwrap(), vec!["wishlist"]);
+        fs.setxattr(wishlist, XATTR_KEEP, b"yes").unwrap();
+
+        // or right away when the last file goes
+        fs.set_remove_empty_tags(true);
+        fs.remove_tag(song, "rock").unwrap();
+        assert!(fs.resolve("/Tags/rock").is_none());
+        assert!(fs.resolve("/Tags/wishlist").is_some());
+    }
+
+    #[test]
+    fn test_tag_many() {
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_tag_many", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 512).unwrap();
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let mut songs = Vec::new();
+        for i in 0..200 {
+            songs.push(fs.mknod(paths, &format!("song{}.flac", i), FileType::RegularFile).unwrap().ino);
+        }
+        fs.add_tag(songs[3], "music").unwrap();
+
+        assert_eq!(fs.tag_many(&songs, "music", true).unwrap(), 199);
+        assert_eq!(fs.list_tagged("music").unwrap().len(), 200);
+        assert_eq!(fs.cache.dirty_blocks(), 0);
+        assert_eq!(fs.tag_many(&songs[..50], "music", false).unwrap(), 50);
+        assert_eq!(fs.query("music").unwrap(), songs[50..].iter().cloned().collect());
+
+        // a bad inode stops the batch before any change
+        let tags = fs.lookup(INO_ROOT, &TAGS_DIR.to_string()).unwrap().ino;
+        let music = fs.lookup(tags, &"music".to_string()).unwrap().ino;
+        assert!(matches!(fs.tag_many(&[songs[0], music], "music", true), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.tag_many(&songs[..1], "unknown", false), Err(PtfsError::NotFound)));
+        assert_eq!(fs.list_tagged("music").unwrap().len(), 150);
+    }
+
+    #[test]
+    fn test_tag_order() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_order");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let mut songs = Vec::new();
+        for name in ["a", "b", "c", "d"] {
+            let song = fs.mknod(paths, &name.to_string(), FileType::RegularFile).unwrap().ino;
+            fs.add_tag(song, "playlist").unwrap();
+            songs.push(song);
+        }
+        let playlist = fs.resolve("/Tags/playlist").unwrap();
+        let names = |fs: &mut PathTagFs, offset| fs.children(playlist, offset).unwrap().map(|child| child.unwrap().2).collect::<Vec<_>>();
+
+        assert!(matches!(fs.getxattr(playlist, XATTR_ORDER), Err(PtfsError::NoAttribute)));
+        fs.setxattr(playlist, XATTR_ORDER, b"c\nb\n").unwrap();
+        fs.setxattr(playlist, XATTR_PINNED, b"d").unwrap();
+        assert_eq!(names(&mut fs, 0), vec![".", "..", "d", "c", "b", "a"]);
+        assert_eq!(names(&mut fs, 3), vec!["c", "b", "a"]);
+        let cookie = {
+            let mut children = fs.children_at(playlist, 0).unwrap();
+            children.by_ref().take(4).count();
+            children.cookie()
+        };
+        assert_eq!(fs.children_at(playlist, cookie).unwrap().map(|child| child.unwrap().2).collect::<Vec<_>>(), vec!["b", "a"]);
+        assert_eq!(fs.getxattr(playlist, XATTR_ORDER).unwrap(), b"c\nb");
+        assert_eq!(fs.listxattr(playlist).unwrap(), vec![XATTR_CRTIME, XATTR_ORDER, XATTR_PINNED]);
+        assert_eq!(fs.tag_order("playlist").unwrap(), TagOrder {pinned: vec![songs[3]], order: vec![songs[2], songs[1]]});
+
+        // members only, and only on tags
+        assert!(matches!(fs.setxattr(playlist, XATTR_ORDER, b"x"), Err(PtfsError::NotFound)));
+        assert!(matches!(fs.setxattr(playlist, "user.other", b"a"), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.getxattr(paths, XATTR_ORDER), Err(PtfsError::NoAttribute)));
+        assert!(matches!(fs.set_tag_order("playlist", &TagOrder {pinned: vec![paths], order: vec![]}), Err(PtfsError::NotFound)));
+
+        // untagged files leave the order
+        fs.remove_tag(songs[3], "playlist").unwrap();
+        assert!(matches!(fs.getxattr(playlist, XATTR_PINNED), Err(PtfsError::NoAttribute)));
+        fs.removexattr(playlist, XATTR_ORDER).unwrap();
+        assert!(fs.retrieve_entry_block(playlist).unwrap().extension(EXT_TAG_ORDER).is_none());
+        assert_eq!(names(&mut fs, 0), vec![".", "..", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_file_flags() {
+        let mut fs = make_fs("/tmp/ptfs_test_file_flags");
+        let ino = fs.mknod(INO_ROOT, &"log".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(ino, None, 0, b"first\n").unwrap();
+
+        // append-only takes writes at the end only
+        fs.set_file_flags(ino, FS_APPEND_FL).unwrap();
+        fs.write(ino, None, 6, b"second\n").unwrap();
+        assert!(matches!(fs.write(ino, None, 0, b"x"), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.setattr(ino, None, None, Some(0), None, None, None), Err(PtfsError::NotPermitted)));
+
+        fs.set_file_flags(ino, FS_IMMUTABLE_FL).unwrap();
+        assert!(matches!(fs.write(ino, None, 13, b"third\n"), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.setattr(ino, Some(7), None, None, None, None, None), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.set_file_flags(ino, 0x80000), Err(PtfsError::InvalidArgument)));
+        assert_eq!(fs.read_file(ino, 0, 100).unwrap(), b"first\nsecond\n");
+
+        // the flags are kept in the image
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_file_flags", MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.file_flags(ino).unwrap(), FS_IMMUTABLE_FL);
+        fs.set_file_flags(ino, 0).unwrap();
+        fs.setattr(ino, None, None, Some(0), None, None, None).unwrap();
+
+        let crtime = fs.getattr(ino).unwrap().crtime.duration_since(std::time::UNIX_EPOCH).unwrap();
+        let value = String::from_utf8(fs.getxattr(ino, XATTR_CRTIME).unwrap()).unwrap();
+        assert_eq!(value, format!("{}.{:09}", crtime.as_secs(), crtime.subsec_nanos()));
+        assert!(matches!(fs.setxattr(ino, XATTR_CRTIME, b"0.0"), Err(PtfsError::NotPermitted)));
+    }
+
+    #[test]
+    fn test_protected_names() {
+        let mut fs = make_fs("/tmp/ptfs_test_protected_names");
+        let archive = fs.mkdir(INO_ROOT, &"archive".to_string()).unwrap().ino;
+        let logs = fs.mkdir(INO_ROOT, &"logs".to_string()).unwrap().ino;
+        let scan = fs.mknod(archive, &"scan.pdf".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.mknod(logs, &"old.log".to_string(), FileType::RegularFile).unwrap();
+
+        // immutable files stay where they are
+        fs.set_file_flags(scan, FS_IMMUTABLE_FL).unwrap();
+        assert!(matches!(fs.rename(archive, &"scan.pdf".to_string(), archive, &"x.pdf".to_string()), Err(PtfsError::NotPermitted)));
+
+        // immutable directories take no new entries
+        fs.set_file_flags(scan, 0).unwrap();
+        fs.set_file_flags(archive, FS_IMMUTABLE_FL).unwrap();
+        assert!(matches!(fs.mknod(archive, &"new".to_string(), FileType::RegularFile), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.mkdir(archive, &"new".to_string()), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.rename(archive, &"scan.pdf".to_string(), logs, &"scan.pdf".to_string()), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.rename(logs, &"old.log".to_string(), archive, &"old.log".to_string()), Err(PtfsError::NotPermitted)));
+
+        // append-only directories take new entries, but keep the old ones
+        fs.set_file_flags(logs, FS_APPEND_FL).unwrap();
+        fs.mknod(logs, &"new.log".to_string(), FileType::RegularFile).unwrap();
+        assert!(matches!(fs.rename(logs, &"old.log".to_string(), logs, &"older.log".to_string()), Err(PtfsError::NotPermitted)));
+        assert!(matches!(fs.setattr(logs, Some(5), None, None, None, None, None), Err(PtfsError::NotPermitted)));
+
+        fs.set_file_flags(archive, 0).unwrap();
+        fs.rename(archive, &"scan.pdf".to_string(), logs, &"scan.pdf".to_string()).unwrap();
+        assert_eq!(fs.resolve("/logs/scan.pdf"), Some(scan));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_content_hash", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 256).unwrap();
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+        // only shown when enabled or asked for
+        assert!(matches!(fs.getxattr(ino, XATTR_SHA256), Err(PtfsError::NoAttribute)));
+        assert_eq!(fs.listxattr(ino).unwrap(), vec![XATTR_CRTIME]);
+        fs.set_content_hashes(true);
+        assert_eq!(fs.getxattr(ino, XATTR_SHA256).unwrap(), empty.as_bytes());
+        assert_eq!(fs.listxattr(ino).unwrap(), vec![XATTR_CRTIME, XATTR_SHA256]);
+        assert!(matches!(fs.getxattr(INO_ROOT, XATTR_SHA256), Err(PtfsError::NoAttribute)));
+        assert!(matches!(fs.setxattr(ino, XATTR_SHA256, b"00"), Err(PtfsError::NotPermitted)));
+
+        // changes drop the hash, closing the last writer brings it back
+        fs.open_handle(1, ino, Access::Write).unwrap();
+        fs.open_handle(2, ino, Access::ReadWrite).unwrap();
+        fs.write(ino, None, 0, b"abc").unwrap();
+        assert!(fs.retrieve_entry_block(ino).unwrap().extension(EXT_SHA256).is_none());
+        fs.close_handle(1);
+        assert!(fs.retrieve_entry_block(ino).unwrap().extension(EXT_SHA256).is_none());
+        fs.close_handle(2);
+        assert_eq!(sha256::to_hex(fs.retrieve_entry_block(ino).unwrap().extension(EXT_SHA256).unwrap()), abc);
+
+        // files in data blocks are read in pieces
+        let data = vec![7; 3 * HASH_READ_SIZE as usize + 5];
+        fs.write(ino, None, 0, &data).unwrap();
+        assert_eq!(fs.content_hash(ino).unwrap(), sha256::sha256(&data));
+        fs.setattr(ino, None, None, Some(3), None, None, None).unwrap();
+        assert!(fs.retrieve_entry_block(ino).unwrap().extension(EXT_SHA256).is_none());
+        assert_eq!(sha256::to_hex(&fs.content_hash(ino).unwrap()), sha256::to_hex(&sha256::sha256(&[7; 3])));
+
+        // damage shows up when the data is read again
+        fs.retrieve_entry_block(ino).unwrap().set_extension(EXT_SHA256, &[1; DIGEST_SIZE]).unwrap();
+        assert_eq!(fs.stored_content_hash(ino).unwrap(), Some([1; DIGEST_SIZE]));
+        assert_eq!(fs.compute_content_hash(ino).unwrap(), sha256::sha256(&[7; 3]));
+        fs.retrieve_entry_block(ino).unwrap().set_extension(EXT_SHA256, &[1; 5]).unwrap();
+        assert!(matches!(fs.stored_content_hash(ino), Err(PtfsError::Corrupt(_))));
+        assert!(matches!(fs.stored_content_hash(INO_ROOT), Err(PtfsError::InvalidArgument)));
+    }
+
+    #[test]
+    fn test_symlink() {
+        let path = "/tmp/ptfs_test_symlink";
+        let mut fs = make_fs(path);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+
+        let link = fs.symlink(paths, &"link".to_string(), "song").unwrap();
+        assert_eq!((link.kind, link.size, link.perm), (FileType::Symlink, 4, 0o777));
+        let long_target = "a/".repeat(2000);
+        let long = fs.symlink(paths, &"long".to_string(), &long_target).unwrap().ino;
+        assert_eq!(fs.file_blocks(long).unwrap().1.len(), 2);
+
+        assert!(matches!(fs.symlink(paths, &"link".to_string(), "other"), Err(PtfsError::Exists)));
+        assert!(matches!(fs.symlink(paths, &"empty".to_string(), ""), Err(PtfsError::NotFound)));
+        assert!(matches!(fs.symlink(paths, &"huge".to_string(), &"a".repeat(5000)), Err(PtfsError::NameTooLong)));
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.readlink(link.ino).unwrap(), "song");
+        assert_eq!(fs.readlink(long).unwrap(), long_target);
+        assert_eq!(fs.getattr(long).unwrap().size, 4000);
+        assert_eq!(fs.resolve("/Pathes/link"), Some(song));
+    }
+
+    #[test]
+    fn test_tag_view_symlinks() {
+        let mut fs = make_fs("/tmp/ptfs_test_tag_view");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let music = fs.mkdir(paths, &"music".to_string()).unwrap().ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(song, "rock").unwrap();
+        fs.add_tag(song, "mood/calm").unwrap();
+        let rock = fs.resolve("/Tags/rock").unwrap();
+
+        // hard links by default
+        assert_eq!(fs.lookup(rock, &"song".to_string()).unwrap().ino, song);
+        assert!(matches!(fs.readlink(song), Err(PtfsError::InvalidArgument)));
+
+        fs.set_tag_view(TagView::Symlinks);
+        let link = fs.lookup(rock, &"song".to_string()).unwrap();
+        assert_eq!(link.kind, FileType::Symlink);
+        assert_ne!(link.ino, song);
+        assert_eq!(fs.readlink(link.ino).unwrap(), "../../Pathes/music/song");
+        assert_eq!(fs.getattr(link.ino).unwrap().size, 23);
+
+        let calm = fs.resolve("/Tags/mood/calm").unwrap();
+        let children = fs.list_children(calm).unwrap();
+        let entry = children.iter().find(|child| child.2 == "song").unwrap();
+        assert_eq!(entry.1, FileType::Symlink);
+        assert_eq!(fs.readlink(entry.0).unwrap(), "../../../Pathes/music/song");
+
+        // the symlinks lead to the file itself, tags are still found
+        assert_eq!(fs.resolve("/Tags/rock/song"), Some(song));
+        assert_eq!(fs.list_tags(song).unwrap(), vec!["mood/calm", "rock"]);
+        assert_eq!(fs.lookup(music, &"song".to_string()).unwrap().ino, song);
+    }
+
+    #[test]
+    fn test_cache_memory() {
+        let mut fs = make_fs("/tmp/ptfs_test_cache_memory");
+        fs.set_cache_memory(64 * 1024);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+
+        let mut files = Vec::new();
+        for i in 0..40 {
+            let ino = fs.mknod(paths, &format!("file{}", i), FileType::RegularFile).unwrap().ino;
+            fs.write(ino, None, 0, &[i as u8; 1000]).unwrap();
+            fs.add_tag(ino, "many").unwrap();
+            files.push(ino);
+        }
+
+        let stats = fs.stats();
+        assert!(stats.cache.cached <= MIN_CACHED_BLOCKS as u64);
+        assert!(stats.cache.evictions > 0);
+        assert!(fs.attrs.len() <= fs.max_attrs);
+
+        // evicted blocks were written with their changes
+        for (i, ino) in files.iter().enumerate() {
+            assert_eq!(fs.read_file(*ino, 0, 1000).unwrap(), vec![i as u8; 1000]);
+        }
+        assert_eq!(fs.list_tagged("many").unwrap().len(), 40);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_relocate_blocks() {
+        let path = "/tmp/ptfs_test_relocate_blocks";
+        let mut fs = make_fs(path);
+        let dir = fs.mkdir(INO_ROOT, &"dir".to_string()).unwrap().ino;
+        let file = fs.mknod(dir, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let content: Vec<u8> = (0..3 * BLOCK_SIZE as u32).map(|i| (i % 253) as u8).collect();
+        fs.write(file, None, 0, &content).unwrap();
+
+        let (chain, data) = fs.file_blocks(file).unwrap();
+        let (dir_chain, _) = fs.file_blocks(dir).unwrap();
+        let free = (0..fs.cache.block_count()).rev().filter(|bno| !fs.cache.is_block_used(*bno)).take(4).collect::<Vec<_>>();
+
+        fs.relocate_block(data[1], free[0]).unwrap();
+        fs.relocate_block(chain[0], free[1]).unwrap();
+        fs.relocate_block(dir_chain[0], free[2]).unwrap();
+        fs.relocate_block(fs.cache.entry_block_no(file), free[3]).unwrap();
+        assert!(!fs.cache.is_block_used(data[1]));
+
+        assert!(matches!(fs.relocate_block(data[0], data[2]), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.relocate_block(3, data[1]), Err(PtfsError::NotFound)));
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.file_blocks(file).unwrap(), (vec![free[1]], vec![data[0], free[0], data[2]]));
+        assert_eq!(fs.lookup(dir, &"file".to_string()).unwrap().ino, file);
+        assert_eq!(fs.read_file(file, 0, content.len() as u64).unwrap(), content);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_bad_blocks() {
+        use crate::faults::FaultPlan;
+        let path = "/tmp/ptfs_test_bad_blocks";
+        let mut fs = make_fs(path);
+        let file = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let other = fs.mknod(INO_ROOT, &"other".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(file, None, 0, &[1; 3 * BLOCK_SIZE]).unwrap();
+        fs.write(other, None, 0, &[2; BLOCK_SIZE]).unwrap();
+        let (_, data) = fs.file_blocks(file).unwrap();
+        let (_, other_data) = fs.file_blocks(other).unwrap();
+        fs.inject_faults(FaultPlan::bad_blocks(&[data[1]]));
+
+        // the block is still cached after failed writes, so it can be moved
+        for _ in 0..3 {
+            let err = fs.write(file, None, BLOCK_SIZE as i64, &[3; BLOCK_SIZE]).unwrap_err();
+            assert!(matches!(err, PtfsError::Io(_)));
+            fs.report_error(&err);
+        }
+        assert_eq!(fs.cache.bad_blocks(), vec![data[1]]);
+        let moved = fs.file_blocks(file).unwrap().1;
+        assert_ne!(moved[1], data[1]);
+
+        // unreadable ones are marked bad, their content is lost
+        fs.drop_caches().unwrap();
+        fs.inject_faults(FaultPlan::bad_blocks(&[data[1], other_data[0]]));
+        for _ in 0..3 {
+            assert!(fs.read_file(other, 0, BLOCK_SIZE as u64).is_err());
+        }
+        assert_eq!(fs.relocate_bad_blocks().unwrap(), 0);
+        assert_eq!(fs.cache.bad_blocks(), vec![data[1], other_data[0]]);
+
+        // bad blocks are never freed
+        fs.unlink(INO_ROOT, &"other".to_string()).unwrap();
+        assert!(fs.cache.is_block_used(other_data[0]));
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.cache.bad_blocks(), vec![data[1], other_data[0]]);
+        assert!(fs.cache.is_block_used(data[1]));
+        assert_eq!(fs.block_usage().unwrap().bad_blocks, 3);
+        let mut expected = vec![1; 3 * BLOCK_SIZE];
+        expected[BLOCK_SIZE..2 * BLOCK_SIZE].fill(3);
+        assert_eq!(fs.read_file(file, 0, 3 * BLOCK_SIZE as u64).unwrap(), expected);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_flush_file() {
+        use crate::faults::FaultPlan;
+        let mut fs = make_fs("/tmp/ptfs_test_flush_file");
+        fs.set_sync_mode(SyncMode::Writeback);
+        let file = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let other = fs.mknod(INO_ROOT, &"other".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.open_handle(1, file, Access::Write).unwrap();
+        fs.open_handle(2, other, Access::Read).unwrap();
+        fs.write(file, None, 0, &[1; 2 * BLOCK_SIZE]).unwrap();
+        fs.write(other, None, 0, &[2; BLOCK_SIZE]).unwrap();
+
+        // only the blocks of the file are written, and nothing for readers
+        let dirty = fs.dirty_blocks();
+        assert_eq!(fs.flush_file(other, 2).unwrap(), 0);
+        let written = fs.flush_file(file, 1).unwrap();
+        assert!(written >= 3);
+        assert_eq!(fs.dirty_blocks(), dirty - written);
+        assert_eq!(fs.flush_file(file, 1).unwrap(), 0);
+
+        // write errors are reported, the blocks wait for the next try
+        fs.write(file, None, 0, &[3; BLOCK_SIZE]).unwrap();
+        let (_, data) = fs.file_blocks(file).unwrap();
+        fs.inject_faults(FaultPlan::bad_blocks(&[data[0]]));
+        assert!(matches!(fs.flush_file(file, 1), Err(PtfsError::Io(_))));
+        assert!(fs.dirty_blocks() > 0);
+        fs.inject_faults(FaultPlan::bad_blocks(&[]));
+        assert_eq!(fs.flush_file(file, 1).unwrap(), 1);
+        fs.close_handle(1);
+        fs.close_handle(2);
+        fs.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_fsync() {
+        let path = "/tmp/ptfs_test_fsync";
+        let mut fs = make_fs(path);
+        fs.set_sync_mode(SyncMode::Writeback);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let dir = fs.mkdir(paths, &"dir".to_string()).unwrap().ino;
+        let mut files = Vec::new();
+        for n in 0..5 {
+            let ino = fs.mknod(dir, &format!("file{}", n), FileType::RegularFile).unwrap().ino;
+            fs.write(ino, None, 0, format!("data {}", n).as_bytes()).unwrap();
+            fs.fsync(ino, n % 2 == 0).unwrap();
+            files.push(ino);
+        }
+        fs.fsync(dir, false).unwrap();
+        assert_eq!(fs.dirty_blocks(), 0);
+
+        // a crash after fsync() loses none of the files
+        drop(fs);
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, true).unwrap();
+        for (n, ino) in files.iter().enumerate() {
+            assert_eq!(fs.lookup(dir, &format!("file{}", n)).unwrap().ino, *ino);
+            assert_eq!(fs.read_file(*ino, 0, 100).unwrap(), format!("data {}", n).as_bytes());
+        }
+        fs.destroy().unwrap();
+        drop(fs);
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        fs.fsync(files[0], false).unwrap();
+    }
+
+    #[test]
+    fn test_unmount_full_image() {
+        let path = "/tmp/ptfs_test_unmount_full_image";
+        let mut fs = make_fs(path);
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let file = fs.mknod(paths, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let mut name = "file".to_string();
+        for n in 0..2000 {
+            let next = format!("file{}", n);
+            fs.rename(paths, &name, paths, &next).unwrap();
+            name = next;
+        }
+
+        let mut size = 0;
+        while fs.write(file, None, size as i64, &[7; BLOCK_SIZE]).is_ok() {
+            size += BLOCK_SIZE;
+        }
+        assert!(fs.journal().len() < 2000);
+
+        // the journal fits into its blocks, an index that outgrew its
+        // blocks is dropped and built again
+        let index = fs.attr_index.as_mut().unwrap();
+        for ino in 1000..2000 {
+            index.update(ino, 1, 1, 0);
+        }
+        fs.destroy().unwrap();
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.lookup(paths, &name).unwrap().ino, file);
+        assert_eq!(fs.read_file(file, 0, size as u64).unwrap(), vec![7; size]);
+        assert!(fs.journal().complete_since(fs.journal().first()));
+        assert!(fs.attr_index.as_ref().is_none_or(|index| !index.range(IndexedAttr::Size, 1..=1).contains(&1000)));
+        fs.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_rmdir() {
+        let mut fs = make_fs("/tmp/ptfs_test_rmdir");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let dir = fs.mkdir(paths, &"dir".to_string()).unwrap().ino;
+        let sub = fs.mkdir(dir, &"sub".to_string()).unwrap().ino;
+        fs.mknod(sub, &"file".to_string(), FileType::RegularFile).unwrap();
+        let free = fs.statfs().1;
+
+        assert!(matches!(fs.rmdir(dir, &"sub".to_string()), Err(PtfsError::NotEmpty)));
+        assert!(matches!(fs.rmdir(sub, &"file".to_string()), Err(PtfsError::NotADirectory)));
+        assert!(matches!(fs.rmdir(dir, &"none".to_string()), Err(PtfsError::NotFound)));
+        assert!(matches!(fs.rmdir(sub, &"..".to_string()), Err(PtfsError::NotEmpty)));
+        assert!(matches!(fs.rmdir(INO_ROOT, &PATHS_DIR.to_string()), Err(PtfsError::NotPermitted)));
+
+        fs.unlink(sub, &"file".to_string()).unwrap();
+        fs.rmdir(dir, &"sub".to_string()).unwrap();
+        assert!(matches!(fs.lookup(dir, &"sub".to_string()), Err(PtfsError::NotFound)));
+        assert_eq!(fs.list_children_names(dir).unwrap().len(), 2);
+        assert!(fs.statfs().1 > free);
+
+        // an empty tag goes away with its directory
+        let file = fs.mknod(dir, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(file, "music").unwrap();
+        let tags = fs.lookup(INO_ROOT, &TAGS_DIR.to_string()).unwrap().ino;
+        assert!(matches!(fs.rmdir(tags, &"music".to_string()), Err(PtfsError::NotEmpty)));
+        fs.remove_tag(file, "music").unwrap();
+        fs.rmdir(tags, &"music".to_string()).unwrap();
+        assert!(fs.all_tags().unwrap().is_empty());
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_freeze() {
+        let path = "/tmp/ptfs_test_freeze";
+        let mut fs = make_fs(path);
+        let file = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(file, None, 0, b"before").unwrap();
+
+        assert!(matches!(fs.thaw(), Err(PtfsError::InvalidArgument)));
+        fs.freeze().unwrap();
+        let image = std::fs::read(path).unwrap();
+
+        // reads go on, changes wait for the thaw, the image stays as it is
+        assert_eq!(fs.read_file(file, 0, 100).unwrap(), b"before");
+        assert!(matches!(fs.write(file, None, 0, b"during"), Err(PtfsError::Refused(_))));
+        assert!(matches!(fs.mknod(INO_ROOT, &"new".to_string(), FileType::RegularFile), Err(PtfsError::Refused(_))));
+        assert!(matches!(fs.freeze(), Err(PtfsError::Refused(_))));
+        fs.sync().unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), image);
+
+        fs.thaw().unwrap();
+        fs.write(file, None, 0, b"after").unwrap();
+        fs.destroy().unwrap();
+
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(fs.read_file(file, 0, 100).unwrap(), b"aftere");
+    }
+
+    #[test]
+    fn test_preload() {
+        let mut fs = make_fs("/tmp/ptfs_test_preload");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let file = fs.mknod(paths, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(file, None, 0, &[7u8; 2 * BLOCK_SIZE]).unwrap();
+        fs.add_tag(file, "music").unwrap();
+
+        fs.drop_caches().unwrap();
+        assert_eq!(fs.preload(Preload::None).unwrap(), 0);
+        assert_eq!(fs.stats().cache.cached, 0);
+
+        // the file is visited once, through /Pathes or its tag
+        fs.preload(Preload::Metadata).unwrap();
+        assert!(fs.attrs.contains_key(&file));
+        let misses = fs.stats().cache.misses;
+        fs.lookup(paths, &"file".to_string()).unwrap();
+        assert_eq!(fs.stats().cache.misses, misses);
+
+        let visited = fs.preload(Preload::All).unwrap();
+        let misses = fs.stats().cache.misses;
+        fs.read_file(file, 0, 2 * BLOCK_SIZE as u64).unwrap();
+        assert_eq!(fs.stats().cache.misses, misses);
+        assert_eq!(fs.preload(Preload::All).unwrap(), visited);
+
+        // in steps, with changes in between, a removed directory is skipped
+        let dirs: Vec<u64> = (0..20).map(|i| fs.mkdir(paths, &format!("dir{}", i)).unwrap().ino).collect();
+        fs.drop_caches().unwrap();
+        fs.start_preload(Preload::All).unwrap();
+        while !fs.attrs.contains_key(&dirs[3]) {
+            assert_eq!(fs.preload_step(1).unwrap(), None);
+        }
+        fs.rmdir(paths, &"dir3".to_string()).unwrap();
+        assert_eq!(fs.mknod(paths, &"other".to_string(), FileType::RegularFile).unwrap().ino, dirs[3]);
+
+        let count = loop {
+            if let Some(count) = fs.preload_step(4).unwrap() {
+                break count;
+            }
+        };
+        assert!(!fs.is_preloading());
+        assert_eq!(count, visited + dirs.len());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut fs = make_fs("/tmp/ptfs_test_stats");
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.drop_caches().unwrap();
+
+        fs.read_file(ino, 0, 10).unwrap();
+        fs.record_op("read", Duration::from_millis(1), true);
+        let stats = fs.stats();
+        assert!(stats.cache.misses > 0 && stats.cache.evictions > 0);
+        assert_eq!(stats.ops.ops()["read"].count, 1);
+    }
+
+    #[test]
+    fn test_bmap() {
+        let mut fs = make_fs("/tmp/ptfs_test_bmap");
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(song, None, 0, &[1; BLOCK_SIZE]).unwrap();
+        fs.write(song, None, 2 * BLOCK_SIZE as i64, &[3; BLOCK_SIZE]).unwrap();
+        let (_, data) = fs.file_blocks(song).unwrap();
+
+        assert_eq!(fs.bmap(song, BLOCK_SIZE as u32, 0).unwrap(), data[0]);
+        assert_eq!(fs.bmap(song, BLOCK_SIZE as u32, 1).unwrap(), 0);
+        assert_eq!(fs.bmap(song, BLOCK_SIZE as u32, 2).unwrap(), data[1]);
+        assert_eq!(fs.bmap(song, BLOCK_SIZE as u32, 100).unwrap(), 0);
+
+        // smaller units map into the middle of a block
+        assert_eq!(fs.bmap(song, 512, 9).unwrap(), data[1] * 4 + 1);
+
+        // the image must hold what bmap says
+        fs.sync().unwrap();
+        let image = std::fs::read("/tmp/ptfs_test_bmap").unwrap();
+        let at = fs.bmap(song, 512, 9).unwrap() as usize * 512;
+        assert_eq!(image[at..at+512], [3; 512]);
+
+        assert!(matches!(fs.bmap(song, 3000, 0), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.bmap(song, 0, 0), Err(PtfsError::InvalidArgument)));
+        assert!(matches!(fs.bmap(paths, 512, 0), Err(PtfsError::InvalidArgument)));
+        let note = fs.mknod(paths, &"note".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(note, None, 0, b"inline").unwrap();
+        assert!(matches!(fs.bmap(note, 512, 0), Err(PtfsError::InvalidArgument)));
+    }
+
+
+    #[test]
+    fn test_read_eof() {
+        let mut fs = make_fs("/tmp/ptfs_test_read_eof");
+        let small = fs.mknod(INO_ROOT, &"small".to_string(), FileType::RegularFile).unwrap().ino;
+        let large = fs.mknod(INO_ROOT, &"large".to_string(), FileType::RegularFile).unwrap().ino;
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        fs.write(small, None, 0, b"hello").unwrap();
+        fs.write(large, None, 0, &data).unwrap();
+
+        for (ino, content) in [(small, &b"hello"[..]), (large, &data[..])] {
+            let size = content.len() as i64;
+            assert!(fs.read_file(ino, size, 10).unwrap().is_empty());
+            assert!(fs.read_file(ino, size + 4711, 10).unwrap().is_empty());
+            assert!(fs.read_file(ino, 0, 0).unwrap().is_empty());
+            assert_eq!(fs.read_file(ino, size - 3, 100).unwrap(), &content[content.len() - 3..]);
+            assert!(matches!(fs.read_file(ino, -1, 10), Err(PtfsError::InvalidArgument)));
+        }
+
+        // reads that start inside a block or cross block boundaries
+        assert_eq!(fs.read_file(large, 100, 50).unwrap(), &data[100..150]);
+        assert_eq!(fs.read_file(large, 2040, 20).unwrap(), &data[2040..2060]);
+        assert_eq!(fs.read_file(large, 2048, 2048).unwrap(), &data[2048..4096]);
+        assert_eq!(fs.read_file(large, 1000, 5000).unwrap(), &data[1000..]);
+    }
+
+    #[test]
+    fn test_overwrite_in_place() {
+        let mut fs = make_fs("/tmp/ptfs_test_overwrite");
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let data = vec![7u8; 3 * BLOCK_SIZE];
+        fs.write(ino, None, 0, &data).unwrap();
+        let free = fs.cache.find_free_block();
+
+        // rewriting the file must not use up more blocks
+        for _ in 0..10 {
+            fs.write(ino, None, 0, &data).unwrap();
+            fs.write(ino, None, BLOCK_SIZE as i64, &data[..BLOCK_SIZE]).unwrap();
+        }
+        assert_eq!(fs.cache.find_free_block(), free);
+        assert_eq!(fs.getattr(ino).unwrap().size, data.len() as u64);
+
+        // appending only allocates the new block
+        fs.write(ino, None, data.len() as i64, b"tail").unwrap();
+        assert_eq!(fs.getattr(ino).unwrap().size, data.len() as u64 + 4);
+        assert_eq!(fs.read_file(ino, data.len() as i64, 10).unwrap(), b"tail");
+        assert_eq!(fs.read_file(ino, 0, data.len() as u64).unwrap(), data);
+        assert_ne!(fs.cache.find_free_block(), free);
+    }
+
+    #[test]
+    fn test_partial_block_writes() {
+        let mut fs = make_fs("/tmp/ptfs_test_partial_writes");
+        let ino = fs.mknod(INO_ROOT, &"file".to_string(), FileType::RegularFile).unwrap().ino;
+        let mut expected: Vec<u8> = (0..3 * BLOCK_SIZE as u32).map(|i| (i % 251) as u8).collect();
+        fs.write(ino, None, 0, &expected).unwrap();
+
+        // head, tail, and both ends of a write fall inside blocks
+        let writes = [(10, 5), (BLOCK_SIZE - 3, 6), (BLOCK_SIZE + 100, BLOCK_SIZE), (3 * BLOCK_SIZE - 1, 1)];
+        for (offset, len) in writes {
+            let patch = vec![0xee; len];
+            fs.write(ino, None, offset as i64, &patch).unwrap();
+            expected[offset..offset + len].copy_from_slice(&patch);
+        }
+        assert_eq!(fs.read_file(ino, 0, expected.len() as u64).unwrap(), expected);
+
+        // appending keeps what is already in the last block
+        fs.write(ino, None, expected.len() as i64, b"more").unwrap();
+        expected.extend_from_slice(b"more");
+        fs.write(ino, None, 7, b"x").unwrap();
+        expected[7] = b'x';
+        assert_eq!(fs.read_file(ino, 0, expected.len() as u64).unwrap(), expected);
+    }
+
+    // Directories with thousands of entries span hundreds of directory
+    // blocks, the tests below walk such chains like large imports do.
+    // Adding an entry walks the whole chain, so the time grows with the
+    // square of the size, the largest ones run with --ignored only.
+    const LARGE_DIRECTORY:usize = 2_000;
+    const HUGE_DIRECTORY:usize = 10_000;
+
+    fn make_large_directory(path: &str, size: usize) -> (PathTagFs, u64, Vec<(u64, String)>) {
+        let mut fs = PathTagFs::new(path, MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 2 * size as u64 + 64).unwrap();
+        fs.set_sync_mode(SyncMode::Writeback);
+
+        let dir = fs.mkdir(INO_ROOT, &"big".to_string()).unwrap().ino;
+        let files = (0..size).map(|n| {
+            let name = format!("file {:05}", n);
+            (fs.mknod(dir, &name, FileType::RegularFile).unwrap().ino, name)
+        }).collect();
+
+        (fs, dir, files)
+    }
+
+    // pseudo random numbers, the same in each run
+    fn next_random(seed: &mut u64) -> usize {
+        *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (*seed >> 33) as usize
+    }
+
+    // reads the directory like the kernel does, a few entries per readdir
+    fn read_in_batches(fs: &mut PathTagFs, dir: u64, seed: &mut u64) -> Vec<(u64, String)> {
+        let mut entries = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let count = next_random(seed) % 300 + 1;
+            let mut children = fs.children_at(dir, cookie).unwrap();
+            let batch = children.by_ref().take(count).map(|child| child.unwrap()).collect::<Vec<_>>();
+            cookie = children.cookie();
+            if batch.is_empty() {
+                return entries;
+            }
+            entries.extend(batch.into_iter().map(|(ino, _, name)| (ino, name)));
+        }
+    }
+
+    fn check_listing(path: &str, size: usize) {
+        let (mut fs, dir, files) = make_large_directory(path, size);
+        assert_eq!(fs.file_blocks(dir).unwrap().0.len(), (size + 2).div_ceil(MAX_ENTRIES));
+
+        let listed = fs.list_children_names(dir).unwrap();
+        assert_eq!(listed.len(), size + 2);
+        assert_eq!(&listed[2..], &files[..]);
+        for (ino, name) in &files {
+            assert_eq!(fs.lookup(dir, name).unwrap().ino, *ino);
+        }
+
+        // restarts at any offset, including the ends of blocks and past the end
+        let mut seed = 1;
+        assert_eq!(read_in_batches(&mut fs, dir, &mut seed), listed);
+        for _ in 0..200 {
+            let offset = next_random(&mut seed) % listed.len();
+            let first = fs.children(dir, offset).unwrap().next().unwrap().unwrap();
+            assert_eq!((first.0, first.2), listed[offset]);
+        }
+        for offset in [MAX_ENTRIES - 1, MAX_ENTRIES, listed.len() - 1] {
+            assert_eq!(fs.children(dir, offset).unwrap().count(), listed.len() - offset);
+        }
+        assert_eq!(fs.children(dir, listed.len()).unwrap().count(), 0);
+        assert_eq!(fs.children(dir, listed.len() + 100).unwrap().count(), 0);
+        fs.destroy().unwrap();
+
+        let mut fs = PathTagFs::new(path, MountMode::ReadOnly).unwrap();
+        fs.open(INO_ROOT, false).unwrap();
+        assert_eq!(read_in_batches(&mut fs, dir, &mut seed), listed);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    fn check_deletions(path: &str, size: usize) {
+        let (mut fs, dir, files) = make_large_directory(path, size);
+        let blocks = fs.file_blocks(dir).unwrap().0.len();
+
+        // every third file, then a run from the front in reverse order,
+        // which empties whole blocks at the start of the chain
+        let mut removed = HashSet::new();
+        for (_, name) in files.iter().step_by(3) {
+            fs.unlink(dir, name).unwrap();
+            removed.insert(name.clone());
+        }
+        for (_, name) in files[..size / 10].iter().rev().filter(|(_, name)| !removed.contains(name)) {
+            fs.unlink(dir, name).unwrap();
+        }
+        removed.extend(files[..size / 10].iter().map(|(_, name)| name.clone()));
+
+        let kept: Vec<(u64, String)> = files.iter().filter(|(_, name)| !removed.contains(name)).cloned().collect();
+        for (ino, name) in &files {
+            match fs.lookup(dir, name) {
+                Ok(attr) => assert_eq!((attr.ino, removed.contains(name)), (*ino, false)),
+                Err(err) => assert!(matches!(err, PtfsError::NotFound) && removed.contains(name)),
+            }
+        }
+
+        let mut seed = 7;
+        let listed = read_in_batches(&mut fs, dir, &mut seed);
+        assert_eq!(&listed[2..], &kept[..]);
+        assert!(matches!(fs.unlink(dir, &files[0].1), Err(PtfsError::NotFound)));
+
+        // new entries fill the emptied blocks before the chain grows
+        for n in 0..removed.len() {
+            fs.mknod(dir, &format!("new {:05}", n), FileType::RegularFile).unwrap();
+        }
+        assert_eq!(fs.file_blocks(dir).unwrap().0.len(), blocks);
+        assert_eq!(read_in_batches(&mut fs, dir, &mut seed).len(), size + 2);
+        assert!(fs.quick_check().unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn test_large_directory() {
+        check_listing("/tmp/ptfs_test_large_directory", LARGE_DIRECTORY);
+        check_deletions("/tmp/ptfs_test_large_directory_deletions", LARGE_DIRECTORY);
+    }
+
+    #[test]
+    #[ignore = "takes about a minute"]
+    fn test_huge_directory() {
+        check_listing("/tmp/ptfs_test_huge_directory", HUGE_DIRECTORY);
+        check_deletions("/tmp/ptfs_test_huge_directory_deletions", HUGE_DIRECTORY);
+    }
+}
+
+
+pub struct PathTagFs {
+    cache: BlockCache,
+    mode: MountMode,
+
+    // getattr() is the most frequent call, its results are kept 
+    // here until the inode is changed
+    attrs: HashMap<u64, FileAttr>,
+    max_attrs: usize,
+
+    // inodes without a name, they are freed unless linked in before release
+    unnamed: HashSet<u64>,
+
+    subscribers: Subscribers,
+
+    // results of recent queries, kept up to date through the change events
+    queries: QueryCache,
+
+    // filled in by the FUSE layer, see record_op()
+    ops: OpStats,
+
+    // open file handles, their inode and how they were opened, see
+    // check_mutation()
+    handles: HashMap<u64, (u64, Access)>,
+
+    // the root of the mounted sub-volume, or of the image
+    root: u64,
+
+    tag_view: TagView,
+
+    // hash files when they are closed after writing, see content_hash()
+    content_hashes: bool,
+
+    // sizes and mtimes of the files, built on first use if the image has
+    // no trusted one, see indexed_files()
+    attr_index: Option<AttrIndex>,
+
+    // the changes for external indexers, kept on the image
+    journal: Journal,
+
+    // a tag goes when its last file is untagged, unless it is marked keep
+    remove_empty_tags: bool,
+
+    // new names must be valid on Windows, see windows_safe_name()
+    windows_names: bool,
+
+    // set by start_preload() until the walk is done
+    preloading: Option<PreloadWalk>,
+}
+
+
+impl PathTagFs {
+    
+    pub fn new(backingstore: &str, mode: MountMode) -> Result<PathTagFs, PtfsError> {
+        Ok(PathTagFs {
+            cache: BlockCache::new(backingstore, mode)?,
+            mode: mode,
+            attrs: HashMap::new(),
+            max_attrs: ATTR_CACHE_SIZE,
+            unnamed: HashSet::new(),
+            subscribers: Subscribers::default(),
+            queries: QueryCache::default(),
+            ops: OpStats::default(),
+            handles: HashMap::new(),
+            root: INO_ROOT,
+            tag_view: TagView::HardLinks,
+            content_hashes: false,
+            attr_index: None,
+            journal: Journal::new(1),
+            remove_empty_tags: false,
+            windows_names: false,
+            preloading: None,
+        })
+    }
+    
+    
+    // force: mount even if another instance holds the image lock
+    pub fn open(& mut self, ino_root: u64, force: bool) -> Result<(), PtfsError> {
+        self.cache.open(force)?;
+
+        self.attr_index = match self.cache.take_attr_index().map(|words| AttrIndex::decode(&words)) {
+            Some(Ok(index)) => Some(index),
+            Some(Err(err)) => {
+                warn!("open()  {}, it is built again", err);
+                None
+            }
+            None => None,
+        };
+
+        // Records of a mount are numbered above the ones of all mounts
+        // before, so the numbers a mount without close() handed out are
+        // not used again. Read-only mounts add none, but without a trusted
+        // journal they can't tell what is missing either.
+        let start = if self.mode == MountMode::ReadWrite {self.cache.epoch() << 32} else {u64::MAX};
+        self.journal = match self.cache.take_journal().map(|words| Journal::decode(&words)) {
+            Some(Ok(journal)) => journal,
+            Some(Err(err)) => {
+                warn!("open()  {}, it starts again", err);
+                Journal::new(start)
+            }
+            None => Journal::new(start),
+        };
+        if self.mode == MountMode::ReadWrite {
+            self.journal.resume(start);
+            self.reserve_journal()?;
+        }
+
+        if self.mode != MountMode::Rescue {
+            // the root must at least be a readable directory
+            let root_ok = match self.cache.retrieve_entry_block(ino_root) {
+                Err(_) => false,
+                Ok(root) => root.attr.kind == FileType::Directory,
+            };
+            
+            if !root_ok || !self.root_chain_ok(ino_root) {
+                return Err(PtfsError::Refused( 
+                    format!("root inode {} is damaged, try --rescue to salvage data", ino_root)));
+            }
+        }
+
+        // like fsck after too many mounts, but it only looks and doesn't repair
+        if self.mode == MountMode::ReadWrite && self.cache.needs_check() {
+            warn!("open()  mounted {} times without a check, checking now", MAX_MOUNTS_WITHOUT_CHECK);
+            let report = self.quick_check()?;
+
+            if report.problems.is_empty() {
+                self.cache.reset_mount_count();
+            }
+            else if !force {
+                for problem in &report.problems {
+                    warn!("  {}", problem);
+                }
+                // nothing was changed, the image stays as it was
+                self.cache.close()?;
+                return Err(PtfsError::Refused(
+                    format!("check found {} problems, use --rescue to salvage data or --force to mount anyway", report.problems.len())));
+            }
+        }
+        
+        self.list_fs(ino_root)
+    }
+
+
+    // the directory blocks of the root must be inside the image and in use
+    fn root_chain_ok(&mut self, ino_root: u64) -> bool {
+        let block_count = self.cache.block_count();
+
+        match self.file_blocks(ino_root) {
+            Err(_) => false,
+            Ok((chain, _)) => chain.iter().all(|bno| *bno < block_count && self.cache.is_block_used(*bno)),
+        }
+    }
+    
+
+    pub fn destroy(& mut self) -> Result<(), PtfsError> {
+        self.cache.set_frozen(false);
+
+        // handles the kernel did not release don't keep files at unmount
+        self.handles.clear();
+        let unnamed: Vec<u64> = self.unnamed.iter().copied().collect();
+        for ino in unnamed {
+            self.release_unnamed(ino)?;
+        }
+
+        if self.mode == MountMode::ReadWrite {
+            // The blocks in the cache must not wait for the index or the
+            // journal. If one of them can't be written, the next mount
+            // builds the index again or starts a new journal.
+            let written = match &self.attr_index {
+                Some(index) => self.cache.write_attr_index(&index.encode())
+                    .map_err(|err| warn!("destroy()  attribute index dropped: {}", err)).is_ok(),
+                None => false,
+            };
+            if !written {
+                self.cache.drop_attr_index()?;
+            }
+            if let Err(err) = self.cache.write_journal(&self.journal.encode()) {
+                warn!("destroy()  change journal dropped: {}", err);
+                self.cache.drop_journal()?;
+            }
+        }
+
+        self.cache.close()
+    }
+
+
+    // the journal keeps the blocks it gets now until the unmount
+    fn reserve_journal(&mut self) -> Result<(), PtfsError> {
+        let words = self.cache.reserve_journal()?;
+        self.journal.set_capacity(words);
+        self.cache.write_journal(&self.journal.encode())
+    }
+
+
+    // writes all changes now and waits until they are on the disk, the
+    // image stays mounted
+    pub fn sync(&mut self) -> Result<(), PtfsError> {
+        self.cache.sync()
+    }
+
+
+    // Writes the changed blocks of ino that wait in the cache when a
+    // handle is closed, so write errors reach close() instead of getting
+    // lost. Handles opened for reading have nothing to write.
+    pub fn flush_file(&mut self, ino: u64, fh: u64) -> Result<usize, PtfsError> {
+        if self.handles.get(&fh).map(|handle| handle.1) == Some(Access::Read) || self.mode != MountMode::ReadWrite {
+            return Ok(0);
+        }
+
+        let blocks = self.inode_blocks(ino)?;
+        self.cache.flush_blocks(&blocks)
+    }
+
+
+    // Writes the changes and waits until they are on the disk, for fsync()
+    // and fsyncdir(). The whole cache is written, not only the blocks of
+    // ino: cached blocks are changed in place, and new inodes need the
+    // fsinfo block and the inode table to be found after a crash.
+    // datasync leaves the metadata of the image file alone.
+    pub fn fsync(&mut self, ino: u64, datasync: bool) -> Result<(), PtfsError> {
+        if self.mode != MountMode::ReadWrite {
+            return Ok(());
+        }
+
+        if let Some(path) = overlay::host_path(self.cache.retrieve_entry_block(ino)?) {
+            overlay::sync_host(&path, datasync)?;
+        }
+
+        if datasync {
+            self.cache.sync_data()
+        }
+        else {
+            self.cache.sync()
+        }
+    }
+
+
+    // the entry block of ino, its chain and its data blocks
+    fn inode_blocks(&mut self, ino: u64) -> Result<Vec<u64>, PtfsError> {
+        let (mut blocks, data) = self.file_blocks(ino)?;
+        blocks.push(self.cache.entry_block_no(ino));
+        blocks.extend(data);
+        Ok(blocks)
+    }
+
+
+    // Writes all changes and syncs the backing store, then refuses changes
+    // until thaw(), so a snapshot of the image file taken in between is
+    // consistent. Reads go on.
+    pub fn freeze(&mut self) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        self.cache.sync()?;
+        self.cache.set_frozen(true);
+        Ok(())
+    }
+
+
+    pub fn thaw(&mut self) -> Result<(), PtfsError> {
+        if !self.cache.is_frozen() {
+            return Err(PtfsError::InvalidArgument);
+        }
+        self.cache.set_frozen(false);
+        Ok(())
+    }
+
+
+    // writes and forgets all cached blocks and attributes, returns the number of blocks
+    pub fn drop_caches(&mut self) -> Result<usize, PtfsError> {
+        self.attrs.clear();
+        self.cache.drop_blocks()
+    }
+
+
+    // Follows all chains reachable from the root and checks that their
+    // blocks are inside the image, marked as used and used only once.
+    // Damaged chains are reported and skipped, nothing is repaired.
+    pub fn quick_check(&mut self) -> Result<CheckReport, PtfsError> {
+        let mut report = CheckReport::default();
+        let mut owners: HashMap<u64, u64> = HashMap::new();
+        let mut visited = HashSet::from([INO_ROOT]);
+        let mut pending = vec![INO_ROOT];
+        let block_count = self.cache.block_count();
+
+        while let Some(ino) = pending.pop() {
+            report.inodes += 1;
+
+            let (chain, data) = match self.file_blocks(ino) {
+                Ok(blocks) => blocks,
+                Err(err) => {
+                    report.problems.push(format!("inode {}: {}", ino, err));
+                    continue;
+                }
+            };
+
+            let entry = self.cache.entry_block_no(ino);
+            for bno in std::iter::once(entry).chain(chain).chain(data) {
+                report.blocks += 1;
+
+                if bno >= block_count {
+                    report.problems.push(format!("inode {}: block {} is outside of the image", ino, bno));
+                }
+                else if !self.cache.is_block_used(bno) {
+                    report.problems.push(format!("inode {}: block {} is marked as free", ino, bno));
+                }
+
+                if let Some(owner) = owners.insert(bno, ino) {
+                    report.problems.push(format!("inode {}: block {} is also used by inode {}", ino, bno, owner));
+                }
+            }
+
+            if self.cache.retrieve_entry_block(ino)?.attr.kind != FileType::Directory {
+                continue;
+            }
+
+            match self.list_children_names(ino) {
+                Err(err) => report.problems.push(format!("inode {}: {}", ino, err)),
+                Ok(children) => {
+                    for (child, name) in children {
+                        if name != "." && name != ".." && visited.insert(child) {
+                            pending.push(child);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+
+    // keeps percent of the blocks for metadata and privileged callers
+    pub fn set_reserved_percent(&mut self, percent: u64) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+
+        if percent > 50 {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        self.cache.set_reserved_blocks(self.cache.block_count() * percent / 100);
+        Ok(())
+    }
+
+
+    // the receiver gets all changes made from now on, see ChangeEvent
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        self.subscribers.subscribe()
+    }
+
+
+    fn notify(&mut self, event: ChangeEvent) {
+        match event {
+            ChangeEvent::Created {ino, ..} => {
+                if let Err(err) = self.index_file(ino) {
+                    warn!("notify() inode {} is not indexed: {}", ino, err);
+                    self.attr_index = None;
+                }
+            }
+            ChangeEvent::Deleted {ino, ..} => {
+                if let Some(index) = &mut self.attr_index {
+                    index.remove(ino);
+                }
+            }
+            _ => {}
+        }
+
+        self.journal.record(&event);
+        self.queries.invalidate(&event);
+        self.subscribers.notify(event);
+    }
+
+
+    // what changed after seq, see Journal::complete_since() for older ones
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+
+
+    // Bounds the memory of the caches, for small machines. Blocks get
+    // three quarters, attributes and query results an eighth each.
+    pub fn set_cache_memory(&mut self, bytes: usize) {
+        let attr_memory = 2 * std::mem::size_of::<(u64, FileAttr)>();
+        let inode_memory = 4 * std::mem::size_of::<u64>();
+
+        self.cache.set_max_blocks(Some(std::cmp::max(bytes / 4 * 3 / CACHED_BLOCK_MEMORY, MIN_CACHED_BLOCKS)));
+        self.max_attrs = std::cmp::min(bytes / 8 / attr_memory, ATTR_CACHE_SIZE);
+        self.attrs.clear();
+        self.queries.set_max_inodes(bytes / 8 / inode_memory);
+    }
+
+
+    pub fn set_alloc_policy(&mut self, policy: AllocPolicy) {
+        self.cache.set_alloc_policy(policy);
+    }
+
+
+    pub fn set_privileged(&mut self, privileged: bool) {
+        self.cache.set_privileged(privileged);
+    }
+
+
+    pub fn set_tag_view(&mut self, tag_view: TagView) {
+        self.tag_view = tag_view;
+        self.attrs.clear();
+    }
+
+
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.cache.set_sync_mode(sync_mode);
+    }
+
+
+    pub fn sync_mode(&self) -> SyncMode {
+        self.cache.sync_mode()
+    }
+
+
+    // in the writeback and async modes, the most dirty blocks kept in memory
+    pub fn set_dirty_limit(&mut self, dirty_limit: usize) {
+        self.cache.set_dirty_limit(dirty_limit);
+    }
+
+
+    // blocks written to the cache but not yet to the backing store
+    pub fn dirty_blocks(&self) -> usize {
+        self.cache.dirty_blocks()
+    }
+
+
+    // opening for writing fails on read-only mounts already, like open(2)
+    pub fn open_handle(&mut self, fh: u64, ino: u64, access: Access) -> Result<(), PtfsError> {
+        if access != Access::Read {
+            self.check_mutation(None)?;
+        }
+
+        self.handles.insert(fh, (ino, access));
+        Ok(())
+    }
+
+
+    // closing a writer counts as a change, the last one brings the hash
+    // of the file up to date
+    pub fn close_handle(&mut self, fh: u64) {
+        let (ino, access) = match self.handles.remove(&fh) {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        if access == Access::Read || self.mode != MountMode::ReadWrite {
+            return;
+        }
+        self.notify(ChangeEvent::Modified {ino});
+
+        if !self.content_hashes {
+            return;
+        }
+
+        if self.handles.values().any(|handle| handle.0 == ino && handle.1 != Access::Read) {
+            return;
+        }
+
+        if let Err(err) = self.content_hash(ino) {
+            warn!("close_handle() cannot hash inode {}: {}", ino, err);
+        }
+    }
+
+
+    // the hash kept in the entry block, if the file did not change since
+    pub fn stored_content_hash(&mut self, ino: u64) -> Result<Option<[u8; DIGEST_SIZE]>, PtfsError> {
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        if eb.attr.kind != FileType::RegularFile {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        match eb.extension(EXT_SHA256).map(<[u8; DIGEST_SIZE]>::try_from) {
+            None => Ok(None),
+            Some(Ok(digest)) => Ok(Some(digest)),
+            Some(Err(_)) => Err(PtfsError::Corrupt(format!("inode {} has a hash of the wrong size", ino))),
+        }
+    }
+
+
+    // reads the whole file, e.g. to check the stored hash
+    pub fn compute_content_hash(&mut self, ino: u64) -> Result<[u8; DIGEST_SIZE], PtfsError> {
+        let size = self.getattr(ino)?.size;
+        let mut hasher = Sha256::new();
+        let mut offset = 0;
+
+        while offset < size {
+            let data = self.read_file(ino, offset as i64, HASH_READ_SIZE)?;
+            if data.is_empty() {
+                break;
+            }
+            hasher.update(&data);
+            offset += data.len() as u64;
+        }
+
+        Ok(hasher.finish())
+    }
+
+
+    pub fn set_content_hashes(&mut self, enabled: bool) {
+        self.content_hashes = enabled;
+    }
+
+
+    pub fn set_remove_empty_tags(&mut self, enabled: bool) {
+        self.remove_empty_tags = enabled;
+    }
+
+
+    // for mounts that are exported to Windows clients over SMB
+    pub fn set_windows_names(&mut self, enabled: bool) {
+        self.windows_names = enabled;
+    }
+
+
+    // The SHA-256 of a regular file. It is kept in the entry block until
+    // the file changes, hashes of host files are not kept since the host
+    // may change them anytime.
+    pub fn content_hash(&mut self, ino: u64) -> Result<[u8; DIGEST_SIZE], PtfsError> {
+        if let Some(digest) = self.stored_content_hash(ino)? {
+            return Ok(digest);
+        }
+
+        let digest = self.compute_content_hash(ino)?;
+        let host = overlay::host_path(self.cache.retrieve_entry_block(ino)?).is_some();
+
+        // full entry blocks keep the inline data, the hash is computed again next time
+        if !host && self.mode == MountMode::ReadWrite {
+            match self.cache.retrieve_entry_block(ino)?.set_extension(EXT_SHA256, &digest) {
+                Ok(()) | Err(PtfsError::NoSpace) => {},
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(digest)
+    }
+
+
+    pub fn record_op(&mut self, op: &'static str, elapsed: Duration, ok: bool) {
+        self.ops.record(op, elapsed, ok);
+    }
+
+
+    // counters since the image was opened, the cache ones are kept by the cache
+    pub fn stats(&self) -> Stats {
+        Stats {
+            cache: self.cache.stats(),
+            queries: self.queries.stats(),
+            ops: self.ops.clone(),
+            warnings: self.cache.diagnostics().recent(),
+        }
+    }
+
+
+    // Logs an I/O error or damage met outside of the block reads, rate
+    // limited like those. Other errors are part of normal operation.
+    pub fn report_error(&mut self, err: &PtfsError) {
+        if matches!(err, PtfsError::Io(_) | PtfsError::Corrupt(_)) {
+            self.cache.diagnostics_mut().record("request", None, &err.to_string());
+        }
+        if matches!(err, PtfsError::Io(_)) {
+            if let Err(err) = self.relocate_bad_blocks() {
+                warn!("cannot relocate bad blocks: {}", err);
+            }
+        }
+    }
+
+
+    // lets the backing store fail like a crashing disk, see the faults module
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn inject_faults(&mut self, plan: crate::faults::FaultPlan) {
+        self.cache.inject_faults(plan);
+    }
+
+
+    // Reads and writes the image with O_DIRECT. Blocks are cached only
+    // once then, which matters for large images on small machines.
+    pub fn set_direct_io(&mut self, direct: bool) -> Result<(), PtfsError> {
+        self.cache.set_direct_io(direct)
+    }
+
+
+    // Fails reads and writes of the image with EIO if they take longer,
+    // so a stalled backend can't hang the mount. The first timeout marks
+    // the backing store as degraded in the stats.
+    pub fn set_io_timeout(&mut self, timeout: Option<Duration>) -> Result<(), PtfsError> {
+        self.cache.set_io_timeout(timeout)
+    }
+
+
+    // Read-only instances may open the image next to its writer, e.g. for
+    // an indexer. They see the writer's flushed state at the time a block
+    // is first read, drop_caches() picks up newer changes.
+    pub fn set_shared(&mut self, shared: bool) -> Result<(), PtfsError> {
+        self.cache.set_shared(shared)
+    }
+
+
+    // total, free, and free blocks that are not reserved
+    // blocks on the metadata free list count as free
+    pub fn statfs(&self) -> (u64, u64, u64) {
+        let free = self.cache.free_blocks() + self.cache.free_list_blocks();
+        (self.cache.block_count(), free, free.saturating_sub(self.cache.reserved_blocks()))
+    }
+
+
+    // migrate an unmounted image to the current on-disk format
+    pub fn fsinfo(&mut self) -> Result<FsInfo, PtfsError> {
+        self.cache.read_fsinfo()
+    }
+
+
+    pub fn relocated_inodes(&self) -> usize {
+        self.cache.relocated_inodes()
+    }
+
+
+    // Counts the blocks reachable from the root by their kind. Files with
+    // more than one name are counted once.
+    pub fn block_usage(&mut self) -> Result<BlockUsage, PtfsError> {
+        let mut usage = BlockUsage {
+            fixed: 2,
+            bitmap: self.cache.bitmap_blocks(),
+            inode_table: self.cache.inode_table_blocks(),
+            attr_index: self.cache.attr_index_blocks(),
+            bad_blocks: self.cache.bad_blocks_used(),
+            journal: self.cache.journal_blocks(),
+            ..Default::default()
+        };
+
+        let mut visited = HashSet::from([INO_ROOT]);
+        let mut pending = vec![INO_ROOT];
+
+        while let Some(ino) = pending.pop() {
+            let (chain, data) = self.file_blocks(ino)?;
+            usage.entries += 1;
+            usage.data += data.len() as u64;
+
+            if self.getattr(ino)?.kind == FileType::Directory {
+                usage.directories += chain.len() as u64;
+
+                for (child, name) in self.list_children_names(ino)? {
+                    if name != "." && name != ".." && visited.insert(child) {
+                        pending.push(child);
+                    }
+                }
+            }
+            else {
+                usage.indexes += chain.len() as u64;
+            }
+        }
+
+        Ok(usage)
+    }
+
+
+    // released metadata blocks waiting for reuse, counted as free
+    pub fn free_list_blocks(&self) -> u64 {
+        self.cache.free_list_blocks()
+    }
+
+
+    pub fn entry_block_no(&self, ino: u64) -> u64 {
+        self.cache.entry_block_no(ino)
+    }
+
+
+    // Translates block idx of a file, in units of blocksize, to the block
+    // of the image that holds it, in the same units. Holes are 0. Inline
+    // data has no block of its own and can't be mapped.
+    pub fn bmap(&mut self, ino: u64, blocksize: u32, idx: u64) -> Result<u64, PtfsError> {
+        let blocksize = blocksize as u64;
+        if !(BLOCK_SIZE as u64).is_multiple_of(blocksize) {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        if eb.attr.kind != FileType::RegularFile || eb.extension(EXT_INLINE_DATA).is_some() {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let offset = idx * blocksize;
+        let mut n = offset as usize / BLOCK_SIZE;
+        let mut ib_no = eb.more_data;
+
+        while ib_no != 0 {
+            let ib = self.cache.retrieve_index_block(ib_no)?;
+            if n < ib.block.len() {
+                let bno = ib.block[n];
+                return Ok(if bno == 0 {0} else {(bno * BLOCK_SIZE as u64 + offset % BLOCK_SIZE as u64) / blocksize});
+            }
+
+            n -= ib.block.len();
+            ib_no = ib.next;
+        }
+
+        Ok(0)
+    }
+
+
+    // Returns the blocks of ino besides its entry block: the directory or
+    // index chain, and the data blocks.
+    pub fn file_blocks(&mut self, ino: u64) -> Result<(Vec<u64>, Vec<u64>), PtfsError> {
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        let is_dir = eb.attr.kind == FileType::Directory;
+        let mut next = eb.more_data;
+        let mut chain = Vec::new();
+        let mut data = Vec::new();
+
+        while next != 0 {
+            chain.push(next);
+
+            if is_dir {
+                next = self.cache.retrieve_directory_block(next)?.next;
+            }
+            else {
+                let ib = self.cache.retrieve_index_block(next)?;
+                data.extend(ib.block.iter().copied().filter(|bno| *bno != 0));
+                next = ib.next;
+            }
+        }
+
+        Ok((chain, data))
+    }
+
+
+    pub fn upgrade(& mut self) -> Result<(u32, u32), PtfsError> {
+        self.cache.upgrade()
+    }
+
+    
+    pub fn mkfs(& mut self, ino_root: u64, size: u64) -> Result<(), PtfsError> {
+        
+        self.cache.size_filesystem(size)?;
+        
+        // take special blocks (reserved, fs info block, root inode)
+        self.cache.take_block(0)?;
+        self.cache.take_block(1)?;
+        self.cache.take_block(2)?;
+        
+        let root = EntryBlock::new("Root", ino_root, FileType::Directory, false);
+        self.attr_index = Some(AttrIndex::default());
+
+        self.cache.write_block(AnyBlock::EntryBlock(root), ino_root)?;
+
+        self.mkdir(ino_root, &PATHS_DIR.to_string())?;
+        self.mkdir(ino_root, &TAGS_DIR.to_string())?;
+
+        // written at unmount, their blocks are taken now while the image is empty
+        self.cache.write_attr_index(&AttrIndex::default().encode())?;
+        self.reserve_journal()?;
+        
+        // persist data
+        self.cache.flush()?;
+        
+        self.list_fs(ino_root)
+    }
+
+
+    // In rescue mode a damaged block ends the chain that led to it,
+    // otherwise the damage is reported.
+    fn truncate_chain(&self, err: PtfsError) -> Result<(), PtfsError> {
+        if self.mode == MountMode::Rescue {
+            warn!("  chain truncated: {}", err);
+            Ok(())
+        }
+        else {
+            Err(err)
+        }
+    }
+
+
+    // list file system structure for debugging
+    fn list_fs(&mut self, ino: u64) -> Result<(), PtfsError> {
+       
+        let mut subdirs = Vec::new();
+        let children = self.list_children(ino)?;
+        
+        debug!("Inode {}", ino);
+        
+        for child in children {
+            debug!("  child ino={} type={:?} name={}", child.0, child.1, child.2);
+            if child.1 == FileType::Directory && child.2.starts_with(".") ==false {
+                subdirs.push(child.0);
+            }            
+        }
+        
+        for subdir in subdirs {
+            self.list_fs(subdir)?;
+        }
+        
+        Ok(())
+    }
+    
+
+    // the caller may change the entry, so its attributes are not cached anymore
+    pub fn retrieve_entry_block(&mut self, ino: u64) -> Result<&mut EntryBlock, PtfsError> {
+        self.attrs.remove(&ino);
+        self.cache.retrieve_entry_block(ino)
+    }
+
+
+    // moves the entry block of an inode, e.g. to free the end of the image
+    pub fn relocate_inode(&mut self, ino: u64) -> Result<u64, PtfsError> {
+        self.check_mutation(None)?;
+        self.cache.relocate_inode(ino)
+    }
+
+
+    // Moves a block of a file or directory to the free block new. Blocks
+    // outside of the tree, like the bitmap or the inode table, stay where
+    // they are and give NotFound.
+    pub fn relocate_block(&mut self, old: u64, new: u64) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        let owner = self.block_owner(old)?.ok_or(PtfsError::NotFound)?;
+        self.cache.relocate_block(old, new, owner)
+    }
+
+
+    // Moves the content of blocks marked bad since the last call to free
+    // blocks. Only cached content can be saved, there is no second copy
+    // to recover the rest from. Returns the number of blocks moved.
+    pub fn relocate_bad_blocks(&mut self) -> Result<usize, PtfsError> {
+        let mut moved = 0;
+        for bno in self.cache.take_new_bad_blocks() {
+            if self.mode != MountMode::ReadWrite || self.cache.is_frozen() {
+                continue;
+            }
+            if !self.cache.is_cached(bno) {
+                self.cache.diagnostics_mut().record("bad block", Some(bno), "content is lost");
+                continue;
+            }
+
+            // blocks outside of the tree are written from memory to new places anyway
+            let Some(owner) = self.block_owner(bno)? else {
+                continue;
+            };
+            let new = self.cache.find_free_block().ok_or(PtfsError::NoSpace)? as u64;
+            self.cache.relocate_block(bno, new, owner)?;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+
+    // walks the tree, and the unnamed files, for the owner of block bno
+    fn block_owner(&mut self, bno: u64) -> Result<Option<BlockOwner>, PtfsError> {
+        let mut visited = HashSet::from([INO_ROOT]);
+        visited.extend(self.unnamed.iter().copied());
+        let mut pending: Vec<u64> = visited.iter().copied().collect();
+
+        while let Some(ino) = pending.pop() {
+            if self.cache.entry_block_no(ino) == bno {
+                return Ok(Some(BlockOwner::Inode(ino)));
+            }
+
+            let eb = self.cache.retrieve_entry_block(ino)?;
+            let is_dir = eb.attr.kind == FileType::Directory;
+            let mut owner = BlockOwner::ChainHead(ino);
+            let mut next = eb.more_data;
+
+            while next != 0 {
+                if next == bno {
+                    return Ok(Some(owner));
+                }
+
+                if is_dir {
+                    owner = BlockOwner::DirectoryNext(next);
+                    next = self.cache.retrieve_directory_block(next)?.next;
+                }
+                else {
+                    let ib = self.cache.retrieve_index_block(next)?;
+                    if let Some(slot) = ib.block.iter().position(|data| *data == bno) {
+                        return Ok(Some(BlockOwner::IndexSlot(next, slot)));
+                    }
+                    owner = BlockOwner::IndexNext(next);
+                    next = ib.next;
+                }
+            }
+
+            if is_dir {
+                for (child, _) in self.list_children_names(ino)? {
+                    if visited.insert(child) {
+                        pending.push(child);
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+
+    // Clients of an NFS export keep handles of inode and generation. An
+    // inode is reused after its file is deleted, the creation time tells the
+    // new file from the old one. Tag links have the generation of their file.
+    pub fn generation(&mut self, ino: u64) -> Result<u64, PtfsError> {
+        let ino = split_tag_link(ino).map_or(ino, |(_, file)| file);
+        let crtime = self.cache.retrieve_entry_block(ino)?.attr.crtime;
+        Ok(crtime.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64))
+    }
+
+
+    pub fn getattr(&mut self, ino: u64) -> Result<FileAttr, PtfsError> {
+        if let Some(attr) = self.attrs.get(&ino) {
+            return Ok(*attr);
+        }
+
+        // made up, they are not cached since the target may move
+        if let Some((_, file)) = split_tag_link(ino) {
+            let mut attr = self.getattr(file)?;
+            attr.ino = ino;
+            attr.kind = FileType::Symlink;
+            attr.size = self.readlink(ino)?.len() as u64;
+            attr.blocks = 0;
+            attr.perm = 0o777;
+            attr.nlink = 1;
+            return Ok(attr);
+        }
+
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        let mut attr = eb.attr;
+
+        if let Some(path) = overlay::host_path(eb) {
+            if let Err(err) = overlay::host_attr(&path, &mut attr) {
+                warn!("getattr() inode {}: host file {:?}: {}", ino, path, err);
+            }
+        }
+
+        if self.attrs.len() >= self.max_attrs {
+            self.attrs.clear();
+        }
+        self.attrs.insert(ino, attr);
+        
+        Ok(attr)
+    }
+
+
+    // fh is the handle of ftruncate(), it must be open for writing
+    #[allow(clippy::too_many_arguments)]
+    pub fn setattr(&mut self, ino: u64, uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
+                   atime: Option<SystemTime>, mtime: Option<SystemTime>, fh: Option<u64>) -> Result<FileAttr, PtfsError> {
+        self.check_mutation(fh)?;
+        self.check_unchanged(ino)?;
+        self.attrs.remove(&ino);
+        let time = &SystemTime::now();
+
+        if let Some(size) = size {
+            debug!("  setattr():setting new size {}", size);
+            let node = self.cache.retrieve_entry_block(ino)?;
+            node.remove_extension(EXT_SHA256);
+            
+            if let Some(path) = overlay::host_path(node) {
+                overlay::truncate_host(&path, size)?;
+            }
+            else if let Some(inline) = node.extension(EXT_INLINE_DATA) {
+                let mut content = inline.to_vec();
+                if size as usize <= content.len() + node.extension_space() {
+                    content.resize(size as usize, 0);
+                    node.set_extension(EXT_INLINE_DATA, &content)?;
+                }
+                else {
+                    // grows past the entry block, the tail is zero
+                    node.remove_extension(EXT_INLINE_DATA);
+                    self.write_blocks(ino, 0, &content)?;
+                }
+            }
+
+            let node = self.cache.retrieve_entry_block(ino)?;
+            node.attr.size = size;                    
+            node.attr.mtime = *time;
+        }
+
+        let node = self.cache.retrieve_entry_block(ino)?;
+        let attrs = &mut node.attr;
+
+        if let Some(uid) = uid {
+            debug!("  setattr():setting new uid {}", uid);
+            attrs.uid = uid;                    
+            attrs.mtime = *time;                    
+        }
+
+        if let Some(gid) = gid {
+            debug!("  setattr():setting new gid {}", gid);
+            attrs.gid = gid;                    
+            attrs.mtime = *time;                    
+        }
+
+        // explicit times win over the ones set above
+        if let Some(atime) = atime {
+            attrs.atime = atime;
+        }
+
+        if let Some(mtime) = mtime {
+            attrs.mtime = mtime;
+        }
+
+        let attr = *attrs;
+        self.index_file(ino)?;
+        if size.is_some() {
+            self.notify(ChangeEvent::Modified {ino});
+        }
+        Ok(attr)
+    }
+
+
+    // the chattr flags of a file, see ioctl::FS_IMMUTABLE_FL
+    pub fn file_flags(&mut self, ino: u64) -> Result<u32, PtfsError> {
+        Ok(self.cache.retrieve_entry_block(ino)?.attr.flags)
+    }
+
+
+    // only root may change them, the caller checks that
+    pub fn set_file_flags(&mut self, ino: u64, flags: u32) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+
+        if flags & !(FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        self.attrs.remove(&ino);
+        let eb = self.retrieve_entry_block(ino)?;
+        eb.attr.flags = flags;
+        eb.attr.ctime = SystemTime::now();
+        Ok(())
+    }
+
+
+    // immutable and append-only files keep their attributes
+    fn check_unchanged(&mut self, ino: u64) -> Result<(), PtfsError> {
+        if self.cache.retrieve_entry_block(ino)?.attr.flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            return Err(PtfsError::NotPermitted);
+        }
+        Ok(())
+    }
+
+
+    // Immutable and append-only files keep their names, and so do the
+    // entries of immutable and append-only directories.
+    fn check_removable(&mut self, parent_ino: u64, ino: u64) -> Result<(), PtfsError> {
+        self.check_unchanged(parent_ino)?;
+        self.check_unchanged(ino)
+    }
+
+
+    // A sub-volume is a directory below /Subvolumes with its own Pathes
+    // and Tags. It shares the blocks of the image, but not the tags.
+    pub fn create_subvolume(&mut self, name: &str) -> Result<u64, PtfsError> {
+        self.check_mutation(None)?;
+
+        let subvols = match self.find_child(INO_ROOT, &SUBVOLS_DIR.to_string())? {
+            Some(subvols) => subvols,
+            None => self.mkdir(INO_ROOT, &SUBVOLS_DIR.to_string())?.ino,
+        };
+
+        let ino = self.mkdir(subvols, &name.to_string())?.ino;
+        self.mkdir(ino, &PATHS_DIR.to_string())?;
+        self.mkdir(ino, &TAGS_DIR.to_string())?;
+
+        Ok(ino)
+    }
+
+
+    pub fn list_subvolumes(&mut self) -> Result<Vec<String>, PtfsError> {
+        let subvols = match self.find_child(INO_ROOT, &SUBVOLS_DIR.to_string())? {
+            Some(subvols) => subvols,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut names: Vec<String> = self.list_children_names(subvols)?.into_iter()
+            .map(|(_, name)| name)
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        names.sort();
+
+        Ok(names)
+    }
+
+
+    // Paths and tags are taken from the sub-volume from now on. The image
+    // root is still reachable by its inode, e.g. for checks.
+    pub fn enter_subvolume(&mut self, name: &str) -> Result<(), PtfsError> {
+        let subvols = self.find_child(INO_ROOT, &SUBVOLS_DIR.to_string())?.ok_or(PtfsError::NotFound)?;
+        let ino = self.find_child(subvols, &name.to_string())?.ok_or(PtfsError::NotFound)?;
+
+        if self.getattr(ino)?.kind != FileType::Directory {
+            return Err(PtfsError::NotADirectory);
+        }
+
+        self.root = ino;
+        self.queries.clear();
+        Ok(())
+    }
+
+
+    // the root of the mounted sub-volume, INO_ROOT without one
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+
+    pub fn lookup(&mut self, parent_ino: u64, name: &String) -> Result<FileAttr, PtfsError> {
+        let ino = self.find_child(parent_ino, name)?.ok_or(PtfsError::NotFound)?;
+
+        match self.tag_link(parent_ino, ino, name)? {
+            Some(link) => self.getattr(link),
+            None => self.getattr(ino),
+        }
+    }
+
+
+    // the symlink that stands for ino in a tag directory, see TagView
+    fn tag_link(&mut self, parent_ino: u64, ino: u64, name: &str) -> Result<Option<u64>, PtfsError> {
+        if self.tag_view != TagView::Symlinks || name == "." || name == ".." {
+            return Ok(None);
+        }
+
+        if !self.cache.retrieve_entry_block(parent_ino)?.is_tag {
+            return Ok(None);
+        }
+
+        Ok(tag_link_ino(parent_ino, ino))
+    }
+
+
+    // The target of a symlink. Those of tag views are relative, so they
+    // also work through the mount point.
+    pub fn readlink(&mut self, ino: u64) -> Result<String, PtfsError> {
+        if let Some((tag_ino, ino)) = split_tag_link(ino) {
+            let tag = self.all_tags()?.into_iter().find(|tag| tag.0 == tag_ino).ok_or(PtfsError::NotFound)?;
+            let up = "../".repeat(tag.1.split('/').count() + 1);
+            return Ok(up + &self.canonical_path(ino)?);
+        }
+
+        let attr = self.getattr(ino)?;
+        if attr.kind != FileType::Symlink {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let target = match self.cache.retrieve_entry_block(ino)?.extension(EXT_SYMLINK_TARGET) {
+            Some(target) => target.to_vec(),
+            None => self.read_file(ino, 0, attr.size)?,
+        };
+        String::from_utf8(target).map_err(|_| PtfsError::Corrupt(format!("symlink {} is no UTF-8 text", ino)))
+    }
+
+
+    // the first path below /Pathes that leads to ino, without the leading /
+    pub fn canonical_path(&mut self, ino: u64) -> Result<String, PtfsError> {
+        let paths = self.lookup(self.root, &PATHS_DIR.to_string())?.ino;
+        let mut visited = HashSet::from([paths]);
+        let mut pending = VecDeque::from([(paths, PATHS_DIR.to_string())]);
+
+        while let Some((dir, path)) = pending.pop_front() {
+            for (child, name) in self.list_children_names(dir)? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                let child_path = format!("{}/{}", path, name);
+                if child == ino {
+                    return Ok(child_path);
+                }
+
+                if visited.insert(child) && self.find_filetype(child)? == FileType::Directory {
+                    pending.push_back((child, child_path));
+                }
+            }
+        }
+
+        Err(PtfsError::NotFound)
+    }
+
+
+    // Walks an absolute path from the root of the mounted sub-volume and
+    // returns its inode. "." and ".."
+    // are resolved by the walk itself, so they work in any directory, and ".."
+    // stays at the root. Symlinks are followed, also as the last component.
+    pub fn resolve(&mut self, path: &str) -> Option<u64> {
+        let mut names: Vec<String> = path.split('/').rev().map(|name| name.to_string()).collect();
+        let mut dirs = vec![self.root];
+        let mut links = 0;
+
+        while let Some(name) = names.pop() {
+            match name.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attr = self.lookup(*dirs.last()?, &name).ok()?;
+
+            if attr.kind == FileType::Symlink {
+                links += 1;
+                if links > MAX_SYMLINK_DEPTH {
+                    return None;
+                }
+
+                let target = self.readlink(attr.ino).ok()?;
+                if target.starts_with('/') {
+                    dirs.truncate(1);
+                }
+                names.extend(target.split('/').rev().map(|name| name.to_string()));
+            }
+            else {
+                dirs.push(attr.ino);
+            }
+        }
+
+        dirs.last().copied()
+    }
+
+
+    // returns the entry block of ino if it is a directory
+    fn retrieve_directory_entry(&mut self, ino: u64) -> Result<&mut EntryBlock, PtfsError> {
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        
+        if eb.attr.kind != FileType::Directory {
+            return Err(PtfsError::NotADirectory);
+        }
+        
+        Ok(eb)
+    }
+
+
+    // The gate in front of every change: the image must be mounted writable
+    // and the handle, if the change comes through one, opened for writing.
+    // Changes must not even reach the cache otherwise.
+    pub fn check_mutation(&self, fh: Option<u64>) -> Result<(), PtfsError> {
+        if self.mode != MountMode::ReadWrite {
+            return Err(PtfsError::ReadOnly);
+        }
+
+        if self.cache.is_frozen() {
+            return Err(PtfsError::Refused("file system is frozen".to_string()));
+        }
+
+        if let Some(fh) = fh {
+            if self.handles.get(&fh).map(|handle| handle.1) == Some(Access::Read) {
+                return Err(PtfsError::AccessDenied);
+            }
+        }
+        
+        Ok(())
+    }
+
+
+    // checks if name can be added to the directory parent_ino
+    pub fn check_new_name(&mut self, parent_ino: u64, name: &String) -> Result<(), PtfsError> {
+        if name.is_empty() || name.contains('/') || name.contains('\0') {
+            return Err(PtfsError::InvalidArgument);
+        }
+        
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(PtfsError::NameTooLong);
+        }
+
+        if self.windows_names && !windows_safe_name(name) {
+            return Err(PtfsError::InvalidArgument);
+        }
+        
+        if self.find_child(parent_ino, name)?.is_some() {
+            return Err(PtfsError::Exists);
+        }
+
+        if self.cache.retrieve_entry_block(parent_ino)?.attr.flags & FS_IMMUTABLE_FL != 0 {
+            return Err(PtfsError::NotPermitted);
+        }
+        
+        Ok(())
+    }
+    
+    
+    pub fn find_child(&mut self, parent_ino: u64, name: &String) -> Result<Option<u64>, PtfsError> {
+
+        debug!("find_child()  finding {} from inode {}", name, parent_ino);                
+
+        let eb = self.retrieve_directory_entry(parent_ino)?;
+        let mut next = eb.more_data;
+
+        debug!("  find_child(): next directory block is {}", next);                
+
+        while next != 0 {
+            let db = match self.cache.retrieve_directory_block(next) {
+                Err(err) => {
+                    self.truncate_chain(err)?;
+                    break;
+                }
+                Ok(db) => db,
+            };
+            
+            for entry in &db.entries {
+                
+                // println!("  find_child(): comparing search='{}' entry='{}'", name, entry.name);                
+                if comp(name, &entry.name) {
+                    return Ok(Some(entry.ino));   
+                }
+            }
+                            
+            next = db.next;
+        }
+
+        Ok(None)
+    }
+
+
+    pub fn list_children_names(&mut self, parent_ino: u64) -> Result<Vec<(u64, String)>, PtfsError> {
+        let mut result = Vec::new();
+
+        debug!("list_children_names()  listing from inode {}", parent_ino);                
+
+        let eb = self.retrieve_directory_entry(parent_ino)?;
+        let mut next = eb.more_data;
+
+        debug!("  next directory block is {}", next);                
+
+        while next != 0 {
+            match self.cache.retrieve_directory_block(next) {
+                Err(err) => {
+                    self.truncate_chain(err)?;
+                    next = 0;
+                }
+                Ok(db) => {
+                    for entry in &db.entries {
+                        let name = entry.name.to_string();
+                        let ino = entry.ino;
+                        result.push((ino, name));                
+                    }
+                    next = db.next;
+                    debug!("  next directory block is {}", next);                
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+
+    fn find_filetype(&mut self, ino: u64) -> Result<FileType, PtfsError> {
+        debug!("find_filetype()  finding type of inode {}", ino);                
+
+        let entry = self.cache.retrieve_entry_block(ino)?;
+        Ok(entry.attr.kind)
+    }
+
+
+    pub fn list_children(&mut self, parent_ino: u64) -> Result<Vec<(u64, fuser::FileType, String)>, PtfsError> {
+        self.children(parent_ino, 0)?.collect()
+    }
+
+
+    // Walks the directory lazily, starting with the entry at offset. Only
+    // the directory blocks up to the offset are loaded to skip entries.
+    pub fn children(&mut self, parent_ino: u64, offset: usize) -> Result<Children<'_>, PtfsError> {
+        debug!("children()  listing inode {} from offset {}", parent_ino, offset);                
+
+        let eb = self.retrieve_directory_entry(parent_ino)?;
+        let mut next = eb.more_data;
+        let order = eb.extension(EXT_TAG_ORDER).map(TagOrder::decode).transpose()?;
+        let mut skip = offset;
+        let mut entries = Vec::new();
+        let mut block = 0;
+
+        while next != 0 {
+            let db = match self.cache.retrieve_directory_block(next) {
+                Err(err) => {
+                    // nothing follows the damage, the listing ends there
+                    self.truncate_chain(err)?;
+                    next = 0;
+                    block = 0;
+                    break;
+                }
+                Ok(db) => db,
+            };
+
+            block = next;
+            next = db.next;
+
+            // an ordered tag directory is sorted as a whole
+            if order.is_some() {
+                entries.extend(db.entries.iter().map(|entry| (entry.ino, entry.kind, entry.name.to_string())));
+                continue;
+            }
+
+            if skip < db.entries.len() || next == 0 {
+                entries = db.entries.iter().skip(skip).map(|entry| (entry.ino, entry.kind, entry.name.to_string())).collect();
+                break;
+            }
+            skip -= db.entries.len();
+        }
+
+        let index = order.map(|order| {
+            order.sort(&mut entries);
+            entries.drain(..offset.min(entries.len()));
+            offset as u64
+        });
+
+        self.make_children(parent_ino, Position {next, block, slot: skip.min(MAX_ENTRIES), index}, entries)
+    }
+
+
+    // Like children(), but resumes at a cookie of Children::cookie(), 0
+    // starts at the front. The kernel passes on any offset a program seeks
+    // to, the block of the cookie must be one of the directory's.
+    pub fn children_at(&mut self, parent_ino: u64, cookie: u64) -> Result<Children<'_>, PtfsError> {
+        debug!("children_at()  listing inode {} from cookie {:#x}", parent_ino, cookie);                
+
+        let eb = self.retrieve_directory_entry(parent_ino)?;
+        let ordered = eb.extension(EXT_TAG_ORDER).is_some();
+
+        if cookie == 0 || ordered {
+            return self.children(parent_ino, (cookie & !ORDERED_COOKIE) as usize);
+        }
+
+        if cookie == END_COOKIE {
+            return self.make_children(parent_ino, Position {next: 0, block: 0, slot: 0, index: None}, Vec::new());
+        }
+
+        let block = cookie >> SLOT_BITS;
+        let slot = (cookie & ((1 << SLOT_BITS) - 1)) as usize;
+        if cookie & ORDERED_COOKIE != 0 || slot > MAX_ENTRIES {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        // the blocks of a directory stay in its chain while it exists
+        let mut next = self.retrieve_directory_entry(parent_ino)?.more_data;
+        while next != block {
+            if next == 0 {
+                return Err(PtfsError::InvalidArgument);
+            }
+            next = self.cache.retrieve_directory_block(next)?.next;
+        }
+
+        let db = self.cache.retrieve_directory_block(block)?;
+        let entries = db.entries.iter().skip(slot).map(|entry| (entry.ino, entry.kind, entry.name.to_string())).collect();
+        let next = db.next;
+
+        self.make_children(parent_ino, Position {next, block, slot, index: None}, entries)
+    }
+
+
+    fn make_children(&mut self, parent_ino: u64, position: Position, entries: Vec<(u64, Option<FileType>, String)>) -> Result<Children<'_>, PtfsError> {
+        let tag_dir = if self.tag_view == TagView::Symlinks && self.cache.retrieve_entry_block(parent_ino)?.is_tag {Some(parent_ino)} else {None};
+
+        Ok(Children {
+            fs: self,
+            position,
+            entries: entries.into_iter(),
+            tag: tag_dir,
+        })
+    }
+
+    
+    pub fn read_file(&mut self, ino: u64, offset: i64, size: u64) -> Result<Vec<u8>, PtfsError> {
+        let node = self.cache.retrieve_entry_block(ino)?;
+        
+        if node.attr.kind == FileType::Directory {
+            return Err(PtfsError::IsADirectory);
+        }
+        
+        if offset < 0 {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        // the host file knows its size better than the entry
+        if let Some(path) = overlay::host_path(node) {
+            return overlay::read_host(&path, offset as u64, size);
+        }
+
+        // nothing is read past the end of the file
+        let size = std::cmp::min(size, node.attr.size.saturating_sub(offset as u64));
+        let more_data = node.more_data;
+
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+
+        if let Some(inline) = node.extension(EXT_INLINE_DATA) {
+            let start = std::cmp::min(offset as usize, inline.len());
+            let end = std::cmp::min(start + size as usize, inline.len());
+            return Ok(inline[start..end].to_vec());
+        }
+
+        self.read(more_data, offset, size)
+    }
+
+    
+    // reads size bytes starting at offset, the caller must keep them inside the file
+    pub fn read(&mut self, index_block: u64, offset: i64, size: u64) -> Result<Vec<u8>, PtfsError> {
+        debug!("read() reading data");
+        let mut result = Vec::new();
+
+        if offset < 0 {
+            warn!("  error: data offset is negative, cannot read there.");
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        if size == 0 {
+            return Ok(result);
+        }
+
+        let mut list = Vec::new();
+        let mut ib_no = index_block;
+        
+        while ib_no != 0 {
+            let ib = self.cache.retrieve_index_block(ib_no)?;
+            
+            let start = offset as usize / BLOCK_SIZE;
+            let end = (offset as usize + size as usize - 1) / BLOCK_SIZE;    
+            let end = std::cmp::min(end, ib.block.len() - 1);
+
+            for n in start..=end {
+                let dbno = ib.block[n];
+                list.push(dbno);
+            }
+            ib_no = ib.next;
+        }
+
+        for bno in list {
+            // blocks that were never written are holes
+            if bno == 0 {
+                result.resize(result.len() + BLOCK_SIZE, 0);
+                continue;
+            }
+
+            debug!("  reading data block {}.", bno);                
+
+            let db = self.cache.retrieve_data_block(bno)?;
+            debug!("  copy data");                
+            result.extend_from_slice(&db.data);
+        }
+
+        // the blocks cover more than was asked for
+        let skip = std::cmp::min(offset as usize % BLOCK_SIZE, result.len());
+        result.drain(..skip);
+        result.truncate(size as usize);
+            
+        Ok(result)
+    }
+
+
+    // writes through the handle fh, if any, which must be open for writing
+    pub fn write(&mut self, inode: u64, fh: Option<u64>, offset: i64, data: &[u8]) -> Result<(), PtfsError> {
+        self.check_mutation(fh)?;
+        self.write_data(inode, offset, data)?;
+        self.index_file(inode)
+    }
+
+
+    fn write_data(&mut self, inode: u64, offset: i64, data: &[u8]) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+
+        if offset < 0 {
+            warn!("  data offset is negative, cannot write there.");
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let end = offset as usize + data.len();
+        self.attrs.remove(&inode);
+        let eb = self.cache.retrieve_entry_block(inode)?;
+
+        // append-only files take writes at their end only
+        if eb.attr.flags & FS_IMMUTABLE_FL != 0 || (eb.attr.flags & FS_APPEND_FL != 0 && (offset as u64) < eb.attr.size) {
+            return Err(PtfsError::NotPermitted);
+        }
+        eb.remove_extension(EXT_SHA256);
+
+        if let Some(path) = overlay::host_path(eb) {
+            overlay::write_host(&path, offset as u64, data)?;
+            eb.attr.size = std::cmp::max(eb.attr.size, end as u64);
+            eb.attr.mtime = SystemTime::now();
+            return Ok(());
+        }
+
+        // Small files are kept in the entry block. When they outgrow it,
+        // their contents move to data blocks.
+        if eb.more_data == 0 && eb.attr.kind == FileType::RegularFile {
+            let mut content = eb.extension(EXT_INLINE_DATA).map_or(Vec::new(), |inline| inline.to_vec());
+            
+            if end > MAX_FILE_BLOCKS * BLOCK_SIZE {
+                return Err(PtfsError::TooLarge);
+            }
+            if end > content.len() {
+                content.resize(end, 0);
+            }
+            content[offset as usize..end].copy_from_slice(data);
+
+            match eb.set_extension(EXT_INLINE_DATA, &content) {
+                Ok(()) => {
+                    debug!("  keeping {} bytes inline in entry block {}", content.len(), inode);
+                    eb.attr.size = content.len() as u64;
+                    return Ok(());
+                }
+                Err(PtfsError::NoSpace) => {
+                    eb.remove_extension(EXT_INLINE_DATA);
+                    return self.write_blocks(inode, 0, &content);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        self.write_blocks(inode, offset, data)
+    }
+
+
+    fn write_blocks(&mut self, inode: u64, offset: i64, data: &[u8]) -> Result<(), PtfsError> {
+        let offset = offset as usize;
+        let end = offset + data.len();
+
+        if data.is_empty() {
+            return Ok(());
+        }
+        if (end - 1) / BLOCK_SIZE >= MAX_FILE_BLOCKS {
+            return Err(PtfsError::TooLarge);
+        }
+
+        let eb = self.cache.retrieve_entry_block(inode)?;
+        let mut ib_no = eb.more_data;
+
+        if ib_no == 0 {
+            ib_no = self.cache.allocate_block_near(self.cache.entry_block_no(inode))?;
+            self.cache.write_block(AnyBlock::IndexBlock(IndexBlock::new()), ib_no)?;
+        }
+
+        self.write_data_blocks(ib_no, offset, data)?;
+        
+        let eb = self.cache.retrieve_entry_block(inode)?;
+        
+        eb.more_data = ib_no;
+        eb.attr.size = std::cmp::max(eb.attr.size, end as u64);
+        
+        Ok(())
+    }
+
+    
+    // Blocks that are already part of the file are overwritten in place,
+    // only the missing ones are allocated. Bytes of a block outside of
+    // the written range keep their old contents.
+    fn write_data_blocks(&mut self, ib_no: u64, offset: usize, data: &[u8]) -> Result<(), PtfsError> {
+        let start = offset / BLOCK_SIZE;
+        let end = (offset + data.len() - 1) / BLOCK_SIZE;    
+
+        for n in start..=end {
+            let block_start = n * BLOCK_SIZE;
+            let data_start = block_start.saturating_sub(offset);
+            let data_end = std::cmp::min(block_start + BLOCK_SIZE - offset, data.len());
+            let pos = offset + data_start - block_start;
+
+            let mut db_no = self.cache.retrieve_index_block(ib_no)?.block[n];
+            let mut db = DataBlock::new();
+
+            if db_no == 0 {
+                db_no = self.cache.allocate_block_near(ib_no)?;
+                self.cache.retrieve_index_block(ib_no)?.block[n] = db_no;
+            }
+            else if data_end - data_start < BLOCK_SIZE {
+                db.data = self.cache.retrieve_data_block(db_no)?.data;
+            }
+
+            debug!("  writing {} bytes to data block {} chain={}", data_end - data_start, db_no, n);
+            
+            db.data[pos..pos + data_end - data_start].copy_from_slice(&data[data_start..data_end]);
+            self.cache.write_block(AnyBlock::DataBlock(db), db_no)?;
+        }        
+        
+        Ok(())
+    }
+
+
+    // Creates a regular file and opens it as handle fh. Another process may
+    // have created it since the kernel looked for it, then it is opened
+    // unless flags have O_EXCL.
+    pub fn create(&mut self, parent_ino: u64, name: &String, flags: i32, fh: u64) -> Result<FileAttr, PtfsError> {
+        let attr = match self.mknod(parent_ino, name, FileType::RegularFile) {
+            Err(PtfsError::Exists) if flags & libc::O_EXCL == 0 => self.lookup(parent_ino, name)?,
+            result => result?,
+        };
+        if attr.kind == FileType::Directory {
+            return Err(PtfsError::IsADirectory);
+        }
+
+        self.open_handle(fh, attr.ino, Access::from_flags(flags))?;
+        Ok(attr)
+    }
+
+
+    pub fn mknod(&mut self, parent_ino: u64, name: &String, kind: FileType) -> Result<FileAttr, PtfsError> {
+        debug!("mknod() parent={} name={} kind={:?}", parent_ino, name, kind);
+
+        match kind {
+            FileType::RegularFile | FileType::Symlink | FileType::Directory => {}
+            _ => {
+                // like file systems without device nodes and pipes
+                warn!("  mknod() only supports regular files, symlinks, and directories");
+                return Err(PtfsError::NotPermitted);
+            }
+        }
+
+        self.check_mutation(None)?;
+        self.check_new_name(parent_ino, name)?;
+        let host = self.create_host_child(parent_ino, name, kind)?;
+
+        let (ino, bno) = self.cache.allocate_inode_near(self.cache.entry_block_no(parent_ino))?;
+        self.add_directory_entry(parent_ino, &name.to_string(), ino, kind)?;
+        
+        let mut entry = EntryBlock::new(&name, ino, kind, false);
+        if let Some(path) = host {
+            overlay::set_host_path(&mut entry, &path)?;
+        }
+        let attr: FileAttr = entry.attr.into();
+        
+        self.cache.write_block(AnyBlock::EntryBlock(entry), bno)?;
+        self.notify(ChangeEvent::Created {parent: parent_ino, ino: ino, name: name.to_string()});
+        
+        Ok(attr)
+    }
+
+
+    // Creates a file that has no name (O_TMPFILE). It is freed by 
+    // release_unnamed() unless link() gives it a name first.
+    pub fn create_unnamed(&mut self, kind: FileType) -> Result<FileAttr, PtfsError> {
+        debug!("create_unnamed() kind={:?}", kind);
+
+        if kind != FileType::RegularFile {
+            return Err(PtfsError::NotSupported);
+        }
+
+        self.check_mutation(None)?;
+
+        let (ino, bno) = self.cache.allocate_inode()?;
+        let mut entry = EntryBlock::new("", ino, kind, false);
+        entry.attr.nlink = 0;
+        let attr: FileAttr = entry.attr.into();
+
+        self.cache.write_block(AnyBlock::EntryBlock(entry), bno)?;
+        self.unnamed.insert(ino);
+
+        Ok(attr)
+    }
+
+
+    // Gives a file another name, or an unnamed file its first one. The
+    // entry block keeps the first name, the tag directories list that one.
+    // Directories have one name only. A link into a tag directory tags the
+    // file, under the name of the link, without counting as a name.
+    pub fn link(&mut self, ino: u64, parent_ino: u64, name: &String) -> Result<FileAttr, PtfsError> {
+        debug!("link() ino={} parent={} name={}", ino, parent_ino, name);
+
+        self.check_mutation(None)?;
+        let kind = self.find_filetype(ino)?;
+        if kind == FileType::Directory {
+            return Err(PtfsError::NotPermitted);
+        }
+
+        if self.cache.retrieve_entry_block(parent_ino)?.is_tag {
+            if self.unnamed.contains(&ino) {
+                return Err(PtfsError::NotPermitted);
+            }
+            let (_, tag) = self.all_tags()?.into_iter().find(|(tag_ino, _)| *tag_ino == parent_ino).ok_or(PtfsError::NotFound)?;
+            self.check_new_name(parent_ino, name)?;
+            self.add_tag_entry(ino, parent_ino, &tag, name, kind)?;
+            return self.getattr(ino);
+        }
+
+        // host files keep their names, see the overlay module
+        for host_ino in [ino, parent_ino] {
+            if overlay::host_path(self.cache.retrieve_entry_block(host_ino)?).is_some() {
+                return Err(PtfsError::NotPermitted);
+            }
+        }
+
+        self.check_new_name(parent_ino, name)?;
+        self.add_directory_entry(parent_ino, name, ino, kind)?;
+        let first = self.unnamed.remove(&ino);
+        self.notify(ChangeEvent::Created {parent: parent_ino, ino: ino, name: name.to_string()});
+
+        let now = SystemTime::now();
+        let parent = self.retrieve_entry_block(parent_ino)?;
+        parent.attr.mtime = now;
+        parent.attr.ctime = now;
+
+        let eb = self.retrieve_entry_block(ino)?;
+        if first {
+            eb.name = name.to_string();
+        }
+        eb.attr.nlink += 1;
+        eb.attr.ctime = now;
+
+        Ok(eb.attr)
+    }
+
+
+    // Moves an entry to another name or directory. Tags refer to the inode,
+    // so they stay with the file, only the names of its entries in the tag
+    // directories follow the new name. Renaming in a tag directory renames
+    // just that entry.
+    pub fn rename(&mut self, parent_ino: u64, name: &String, new_parent_ino: u64, new_name: &String) -> Result<(), PtfsError> {
+        debug!("rename() parent={} name={} new parent={} new name={}", parent_ino, name, new_parent_ino, new_name);
+
+        let (ino, kind, in_tag) = self.check_rename(parent_ino, name, new_parent_ino)?;
+        self.check_new_name(new_parent_ino, new_name)?;
+
+        // tags that propagate stay with files leaving their directory
+        let inherited = if parent_ino != new_parent_ino && !in_tag {self.inherited_tags(ino)?} else {Vec::new()};
+
+        self.remove_directory_entry(parent_ino, name)?;
+        self.add_directory_entry(new_parent_ino, new_name, ino, kind)?;
+
+        if kind == FileType::Directory && parent_ino != new_parent_ino {
+            self.remove_directory_entry(ino, &"..".to_string())?;
+            self.add_directory_entry(ino, &"..".to_string(), new_parent_ino, FileType::Directory)?;
+        }
+
+        if !in_tag && name != new_name {
+            self.retrieve_entry_block(ino)?.name = new_name.to_string();
+
+            for (tag_ino, _) in self.all_tags()? {
+                let entry = self.list_children_names(tag_ino)?.into_iter().find(|child| child.0 == ino);
+                if let Some((_, tag_name)) = entry {
+                    self.remove_directory_entry(tag_ino, &tag_name)?;
+                    let tag_name = self.free_tag_entry_name(tag_ino, new_name)?;
+                    self.add_directory_entry(tag_ino, &tag_name, ino, kind)?;
+                }
+            }
+        }
+
+        self.notify(ChangeEvent::Renamed {
+            ino,
+            old_parent: parent_ino,
+            old_name: name.to_string(),
+            new_parent: new_parent_ino,
+            new_name: new_name.to_string(),
+        });
+
+        if !inherited.is_empty() {
+            let kept = self.inherited_tags(ino)?;
+            for tag in inherited {
+                if !kept.contains(&tag) && self.tag_rules(&tag)?.propagate {
+                    self.add_tag(ino, &tag)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+
+    // Like rename(2), an existing entry of the new name is replaced. It must
+    // be of the same kind as the moved one, and empty for directories.
+    pub fn rename_over(&mut self, parent_ino: u64, name: &String, new_parent_ino: u64, new_name: &String) -> Result<(), PtfsError> {
+        debug!("rename_over() parent={} name={} new parent={} new name={}", parent_ino, name, new_parent_ino, new_name);
+
+        let (ino, kind, _) = self.check_rename(parent_ino, name, new_parent_ino)?;
+        if new_name == "." || new_name == ".." {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let target = match self.find_child(new_parent_ino, new_name)? {
+            Some(target) => target,
+            None => return self.rename(parent_ino, name, new_parent_ino, new_name),
+        };
+
+        // hard links of the same file, nothing to do
+        if target == ino {
+            return Ok(());
+        }
+
+        let target_kind = self.find_filetype(target)?;
+        match (kind, target_kind) {
+            (FileType::Directory, FileType::Directory) => self.check_rmdir(new_parent_ino, target)?,
+            (FileType::Directory, _) => return Err(PtfsError::NotADirectory),
+            (_, FileType::Directory) => return Err(PtfsError::IsADirectory),
+            _ => self.check_removable(new_parent_ino, target)?,
+        }
+
+        // The target is freed only once the moved entry has its name. Until
+        // then a failed rename puts it back into the slot it left, which
+        // needs no new block.
+        self.remove_directory_entry(new_parent_ino, new_name)?;
+        let result = self.rename(parent_ino, name, new_parent_ino, new_name);
+        if self.find_child(new_parent_ino, new_name)? != Some(ino) {
+            self.add_directory_entry(new_parent_ino, new_name, target, target_kind)?;
+            return result;
+        }
+
+        if self.cache.retrieve_entry_block(new_parent_ino)?.is_tag {
+            let (_, tag) = self.all_tags()?.into_iter().find(|(tag_ino, _)| *tag_ino == new_parent_ino).ok_or(PtfsError::NotFound)?;
+            self.untagged(target, new_parent_ino, &tag)?;
+        } else if target_kind == FileType::Directory {
+            self.release_directory(new_parent_ino, new_name, target)?;
+        } else {
+            self.release_link(new_parent_ino, new_name, target)?;
+        }
+
+        result
+    }
+
+
+    // the checks of rename() that don't depend on the new name, returns
+    // the inode and kind of the entry and if it is in a tag directory
+    fn check_rename(&mut self, parent_ino: u64, name: &String, new_parent_ino: u64) -> Result<(u64, FileType, bool), PtfsError> {
+        self.check_mutation(None)?;
+
+        if name == "." || name == ".." {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        let ino = self.find_child(parent_ino, name)?.ok_or(PtfsError::NotFound)?;
+        self.check_removable(parent_ino, ino)?;
+
+        // moving an entry between tags would tag and untag the file
+        let in_tag = self.cache.retrieve_entry_block(parent_ino)?.is_tag;
+        let to_tag = self.cache.retrieve_entry_block(new_parent_ino)?.is_tag;
+        if (in_tag || to_tag) && parent_ino != new_parent_ino {
+            return Err(PtfsError::NotPermitted);
+        }
+
+        // host files keep their names, see the overlay module
+        for host_ino in [ino, parent_ino, new_parent_ino] {
+            if overlay::host_path(self.cache.retrieve_entry_block(host_ino)?).is_some() {
+                return Err(PtfsError::NotPermitted);
+            }
+        }
+
+        let kind = self.find_filetype(ino)?;
+        if kind == FileType::Directory && parent_ino != new_parent_ino && self.is_below(new_parent_ino, ino)? {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        Ok((ino, kind, in_tag))
+    }
+
+
+    // true if dir is ancestor or dir itself, following the ".." entries
+    fn is_below(&mut self, dir: u64, ancestor: u64) -> Result<bool, PtfsError> {
+        let mut visited = HashSet::new();
+        let mut current = dir;
+
+        while visited.insert(current) {
+            if current == ancestor {
+                return Ok(true);
+            }
+
+            match self.find_child(current, &"..".to_string())? {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
+
+        Ok(false)
+    }
+
+
+    // frees a file from create_unnamed() when its last handle is closed,
+    // named files and those with other handles open are left alone. While
+    // frozen, they wait for the next release or the unmount.
+    pub fn release_unnamed(&mut self, ino: u64) -> Result<(), PtfsError> {
+        if self.cache.is_frozen() || self.handles.values().any(|handle| handle.0 == ino) {
+            return Ok(());
+        }
+        if !self.unnamed.remove(&ino) {
+            return Ok(());
+        }
+
+        debug!("release_unnamed() freeing inode {}", ino);
+        self.attrs.remove(&ino);
+
+        let mut ib_no = self.cache.retrieve_entry_block(ino)?.more_data;
+        while ib_no != 0 {
+            let ib = self.cache.retrieve_index_block(ib_no)?;
+            let data_blocks: Vec<u64> = ib.block.iter().copied().filter(|bno| *bno != 0).collect();
+            let next = ib.next;
+
+            for bno in data_blocks {
+                self.cache.release_block(bno)?;
+            }
+            self.cache.release_metadata_block(ib_no)?;
+            ib_no = next;
+        }
+
+        self.cache.release_inode(ino)
+    }
+
+
+    // Files and directories in a passthrough directory are created on the
+    // host too, unless the host has them already. Returns their host path.
+    fn create_host_child(&mut self, parent_ino: u64, name: &str, kind: FileType) -> Result<Option<PathBuf>, PtfsError> {
+        if kind != FileType::RegularFile && kind != FileType::Directory {
+            return Ok(None);
+        }
+
+        let dir = match overlay::host_path(self.cache.retrieve_entry_block(parent_ino)?) {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+
+        let path = dir.join(name);
+        if !path.exists() {
+            overlay::create_host(&path, kind)?;
+        }
+
+        Ok(Some(path))
+    }
+
+
+    // Creates an entry whose data stays in a host file, for files too large
+    // to copy into the image. Reads and writes go to the host file.
+    pub fn add_reference(&mut self, parent_ino: u64, name: &String, host_file: &str) -> Result<FileAttr, PtfsError> {
+        debug!("add_reference() parent={} name={} host file={}", parent_ino, name, host_file);
+        self.check_mutation(None)?;
+
+        let path = std::fs::canonicalize(host_file)?;
+        if !path.is_file() {
+            return Err(PtfsError::InvalidArgument);
+        }
+
+        // passthrough directories take their files from the host directory
+        if overlay::host_path(self.retrieve_directory_entry(parent_ino)?).is_some() {
+            return Err(PtfsError::NotPermitted);
+        }
+
+        let ino = self.mknod(parent_ino, name, FileType::RegularFile)?.ino;
+        overlay::set_host_path(self.retrieve_entry_block(ino)?, &path)?;
+        self.index_file(ino)?;
+
+        self.getattr(ino)
+    }
+
+
+    // makes /Pathes a passthrough view of a host directory, see the overlay module
+    pub fn attach_overlay(&mut self, dir: &str) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        overlay::attach(self, dir)
+    }
+
+
+    // Drops the entry of a host file that is gone from the image, with its
+    // tags and, for directories, everything below. The host is not touched.
+    pub fn forget_host_entry(&mut self, parent_ino: u64, name: &String, ino: u64) -> Result<(), PtfsError> {
+        debug!("forget_host_entry() parent={} name={} inode={}", parent_ino, name, ino);
+        self.check_mutation(None)?;
+
+        if self.getattr(ino)?.kind == FileType::Directory {
+            for (child, child_name) in self.list_children_names(ino)? {
+                if child_name != "." && child_name != ".." {
+                    self.forget_host_entry(ino, &child_name, child)?;
+                }
+            }
+        }
+
+        for tag in self.list_tags(ino)? {
+            self.remove_tag(ino, &tag)?;
+        }
+        self.remove_directory_entry(parent_ino, name)?;
+        self.free_entry(ino)?;
+        self.notify(ChangeEvent::Deleted {parent: parent_ino, ino: ino, name: name.to_string()});
+
+        Ok(())
+    }
+
+
+    // Removes a file and frees its blocks, its entries in the tag
+    // directories go with it. In a tag directory it only untags the file.
+    // Files still open are freed when their last handle is closed, like
+    // unnamed files.
+    pub fn unlink(&mut self, parent_ino: u64, name: &String) -> Result<(), PtfsError> {
+        debug!("unlink() parent={} name={}", parent_ino, name);
+        self.check_mutation(None)?;
+
+        let ino = self.find_child(parent_ino, name)?.ok_or(PtfsError::NotFound)?;
+        if self.find_filetype(ino)? == FileType::Directory {
+            return Err(PtfsError::IsADirectory);
+        }
+        self.check_removable(parent_ino, ino)?;
+
+        if self.cache.retrieve_entry_block(parent_ino)?.is_tag {
+            let (_, tag) = self.all_tags()?.into_iter().find(|(tag_ino, _)| *tag_ino == parent_ino).ok_or(PtfsError::NotFound)?;
+            return self.remove_tag(ino, &tag);
+        }
+
+        // files of a passthrough directory would come back at the next attach
+        if overlay::host_path(self.cache.retrieve_entry_block(parent_ino)?).is_some() {
+            if let Some(path) = overlay::host_path(self.cache.retrieve_entry_block(ino)?) {
+                overlay::remove_host(&path)?;
+            }
+        }
+
+        self.remove_directory_entry(parent_ino, name)?;
+        self.release_link(parent_ino, name, ino)
+    }
+
+
+    // the part of unlink() after the entry is gone from the directory
+    fn release_link(&mut self, parent_ino: u64, name: &String, ino: u64) -> Result<(), PtfsError> {
+        let now = SystemTime::now();
+        let parent = self.retrieve_entry_block(parent_ino)?;
+        parent.attr.mtime = now;
+        parent.attr.ctime = now;
+
+        self.notify(ChangeEvent::Deleted {parent: parent_ino, ino, name: name.to_string()});
+
+        // the file lives on under its other names
+        let eb = self.retrieve_entry_block(ino)?;
+        eb.attr.nlink = eb.attr.nlink.saturating_sub(1);
+        eb.attr.ctime = now;
+        if eb.attr.nlink > 0 {
+            return Ok(());
+        }
+
+        for tag in self.list_tags(ino)? {
+            self.remove_tag(ino, &tag)?;
+        }
+
+        if self.handles.values().any(|handle| handle.0 == ino) {
+            self.unnamed.insert(ino);
+            return Ok(());
+        }
+        self.free_entry(ino)
+    }
+
+
+    // Removes an empty directory. The directories of the image root, like
+    // /Pathes and /Tags, stay. Removing an empty tag directory drops the tag.
+    pub fn rmdir(&mut self, parent_ino: u64, name: &String) -> Result<(), PtfsError> {
+        debug!("rmdir() parent={} name={}", parent_ino, name);
+        self.check_mutation(None)?;
+
+        match name.as_str() {
+            "." => return Err(PtfsError::InvalidArgument),
+            ".." => return Err(PtfsError::NotEmpty),
+            _ => {}
+        }
+
+        let ino = self.find_child(parent_ino, name)?.ok_or(PtfsError::NotFound)?;
+        if self.find_filetype(ino)? != FileType::Directory {
+            return Err(PtfsError::NotADirectory);
+        }
+        self.check_rmdir(parent_ino, ino)?;
+
+        if overlay::host_path(self.cache.retrieve_entry_block(parent_ino)?).is_some() {
+            if let Some(path) = overlay::host_path(self.cache.retrieve_entry_block(ino)?) {
+                overlay::remove_host(&path)?;
+            }
+        }
+
+        self.remove_directory_entry(parent_ino, name)?;
+        self.release_directory(parent_ino, name, ino)
+    }
+
+
+    // the checks of rmdir() for the directory ino in parent_ino
+    fn check_rmdir(&mut self, parent_ino: u64, ino: u64) -> Result<(), PtfsError> {
+        if parent_ino == INO_ROOT || parent_ino == self.root {
+            return Err(PtfsError::NotPermitted);
+        }
+        self.check_removable(parent_ino, ino)?;
+
+        if self.list_children_names(ino)?.iter().any(|(_, child)| child != "." && child != "..") {
+            return Err(PtfsError::NotEmpty);
+        }
+        Ok(())
+    }
+
+
+    // the part of rmdir() after the entry is gone from the directory
+    fn release_directory(&mut self, parent_ino: u64, name: &String, ino: u64) -> Result<(), PtfsError> {
+        let now = SystemTime::now();
+        let parent = self.retrieve_entry_block(parent_ino)?;
+        parent.attr.mtime = now;
+        parent.attr.ctime = now;
+
+        self.free_entry(ino)?;
+        self.notify(ChangeEvent::Deleted {parent: parent_ino, ino, name: name.to_string()});
+
+        Ok(())
+    }
+
+
+    // gives the entry block and the chain of an inode without a name back
+    fn free_entry(&mut self, ino: u64) -> Result<(), PtfsError> {
+        let (chain, data) = self.file_blocks(ino)?;
+        for bno in data {
+            self.cache.release_block(bno)?;
+        }
+        for bno in chain {
+            self.cache.release_metadata_block(bno)?;
+        }
+
+        self.attrs.remove(&ino);
+
+        // a preload walk must not list the inode once it is reused
+        if let Some(walk) = self.preloading.as_mut() {
+            walk.pending.retain(|dir| *dir != ino);
+        }
+        self.cache.release_inode(ino)
+    }
+
+
+    pub fn mkdir(&mut self, parent_ino: u64, name: &String) -> Result<FileAttr, PtfsError> {
+        debug!("mkdir() parent={} name={}", parent_ino, name);
+
+        self.check_mutation(None)?;
+        self.check_new_name(parent_ino, name)?;
+        let host = self.create_host_child(parent_ino, name, FileType::Directory)?;
+
+        let (ino, bno) = self.cache.allocate_inode_near(self.cache.entry_block_no(parent_ino))?;
+        self.add_directory_entry(parent_ino, &name.to_string(), ino, FileType::Directory)?;
+        
+        let mut entry = EntryBlock::new(&name, ino, fuser::FileType::Directory, false);
+        if let Some(path) = host {
+            overlay::set_host_path(&mut entry, &path)?;
+        }
+        let attr: FileAttr = entry.attr.into();
+        self.cache.write_block(AnyBlock::EntryBlock(entry), bno)?;
+        
+        self.add_directory_entry(ino, &".".to_string(), ino, FileType::Directory)?;            
+        self.add_directory_entry(ino, &"..".to_string(), parent_ino, FileType::Directory)?;            
+        self.notify(ChangeEvent::Created {parent: parent_ino, ino: ino, name: name.to_string()});
+        
+        Ok(attr)
+    }
+    
+    
+    // The target is kept in the entry block, long ones that don't fit go
+    // to data blocks like the contents of a file.
+    pub fn symlink(&mut self, parent_ino: u64, name: &String, target: &str) -> Result<FileAttr, PtfsError> {
+        debug!("symlink() parent={} name={} target={}", parent_ino, name, target);
+        self.check_mutation(None)?;
+
+        if target.is_empty() {
+            return Err(PtfsError::NotFound);
+        }
+        if target.len() >= MAX_SYMLINK_TARGET {
+            return Err(PtfsError::NameTooLong);
+        }
+
+        let ino = self.mknod(parent_ino, name, FileType::Symlink)?.ino;
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        match eb.set_extension(EXT_SYMLINK_TARGET, target.as_bytes()) {
+            Ok(()) => eb.attr.size = target.len() as u64,
+            Err(PtfsError::NoSpace) => self.write_blocks(ino, 0, target.as_bytes())?,
+            Err(err) => return Err(err),
+        }
+
+        self.getattr(ino)
+    }
+
+
+    // tail is either the last directory block of the chain, or the inode
+    // of the directory itself if it has no directory blocks yet
+    fn extend_directory_chain(&mut self, parent_ino: u64, tail: u64, name: &String, ino: u64, kind: FileType) -> Result<u64, PtfsError> {
+
+        debug!("extend_directory_chain()  Adding new directory node to chain tail {} for name {} (inode {})", tail, name, ino);
+
+        let bno = self.cache.allocate_metadata_block_near(tail)?;
+        let mut db = DirectoryBlock::new();
+        db.entries.push(DirectoryEntry{ino: ino, name: name.to_string(), kind: Some(kind)});
+        
+        let ab = AnyBlock::DirectoryBlock(db);
+        self.cache.write_block(ab, bno)?;
+
+        // block and inode numbers may be the same, so ask the directory itself
+        let entry = self.cache.retrieve_entry_block(parent_ino)?;
+        if entry.more_data == 0 {
+            entry.more_data = bno;
+        }
+        else {
+            let dir = self.cache.retrieve_directory_block(tail)?;
+            dir.next = bno;
+        }
+        
+        Ok(bno)
+    }
+
+    
+    pub fn store_directory_entry(&mut self, parent_ino: u64, name: &String, ino: u64, kind: FileType) -> Result<u64, PtfsError> {
+
+        debug!("store_directory_entry()  Trying to store new directory entry {} (inode {}) in inode {} directory", name, ino, parent_ino);
+        let mut result = 0;
+        let parent = self.cache.retrieve_entry_block(parent_ino)?;
+
+        if parent.more_data == 0 {
+            debug!("  no directory blocks for inode {}", parent_ino);
+            result = parent_ino;
+        }
+        else {
+            // traverse the chain
+            let mut next = parent.more_data;
+            while next != 0 {
+                let db = self.cache.retrieve_directory_block(next)?;
+
+                result = next;
+
+                //  check if there are free entries
+                if db.entries.len() < MAX_ENTRIES {
+                    debug!("  storing entry in block {}", result);
+                    db.entries.push(DirectoryEntry{ino: ino, name: name.to_string(), kind: Some(kind)});
+                    result = 0;
+                    next = 0;
+                } else {
+                    // blocks to check
+                    next = db.next;
+                }  
+            }
+        }
+        
+        Ok(result)
+    }    
+
+
+    pub fn add_directory_entry(&mut self, parent_ino: u64, name: &String, ino: u64, kind: FileType) -> Result<(), PtfsError> {
+        debug!("add_directory_entry()  Add new directory entry {} (inode {}) in inode {} directory", name, ino, parent_ino);
+        self.check_mutation(None)?;
+        
+        // try to store the new entry in one of the existing directrory blocks of this inode 
+        let tail = self.store_directory_entry(parent_ino, name, ino, kind)?;
+        
+        if tail != 0 {
+            // there were no free entries, but we got the tail of the chain
+            self.extend_directory_chain(parent_ino, tail, name, ino, kind)?;
+        }
+        
+        Ok(())
+    }
+
+
+    // removes the entry of the named child from the directory parent_ino,
+    // the child itself is left untouched
+    pub fn remove_directory_entry(&mut self, parent_ino: u64, name: &String) -> Result<u64, PtfsError> {
+        debug!("remove_directory_entry()  Removing directory entry {} from inode {} directory", name, parent_ino);
+        self.check_mutation(None)?;
+
+        let eb = self.retrieve_directory_entry(parent_ino)?;
+        let mut next = eb.more_data;
+
+        while next != 0 {
+            let db = self.cache.retrieve_directory_block(next)?;
+
+            if let Some(pos) = db.entries.iter().position(|entry| comp(name, &entry.name)) {
+                // emptied blocks stay in the chain, new entries will fill them again
+                let entry = db.entries.remove(pos);
+                return Ok(entry.ino);
+            }
+
+            next = db.next;
+        }
+
+        Err(PtfsError::NotFound)
+    }
+
+
+    // A tag is a directory below /Tags, tagging a file adds an entry for it
+    // to that directory under the name of the file. Unknown tags are created
+    // on first use.
+    pub fn add_tag(&mut self, ino: u64, tag: &str) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+
+        let tag_ino = match self.find_tag(tag)? {
+            Some(tag_ino) => tag_ino,
+            None => self.create_tag(tag)?,
+        };
+
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        let name = eb.name.to_string();
+        let kind = eb.attr.kind;
+
+        let name = self.free_tag_entry_name(tag_ino, &name)?;
+        self.add_tag_entry(ino, tag_ino, tag, &name, kind)
+    }
+
+
+    fn add_tag_entry(&mut self, ino: u64, tag_ino: u64, tag: &str, name: &String, kind: FileType) -> Result<(), PtfsError> {
+        // a file can carry a tag only once
+        if self.list_children_names(tag_ino)?.iter().any(|child| child.0 == ino) {
+            return Err(PtfsError::Exists);
+        }
+
+        self.add_directory_entry(tag_ino, name, ino, kind)?;
+        self.notify(ChangeEvent::Tagged {ino: ino, tag: tag.to_string()});
+
+        Ok(())
+    }
+
+
+    // Files from different directories may have the same name, the later
+    // ones show up as "report (2).pdf" and so on in the tag directory. The
+    // name is stored with the entry, so it stays the same across mounts.
+    fn free_tag_entry_name(&mut self, tag_ino: u64, name: &String) -> Result<String, PtfsError> {
+        let mut candidate = name.to_string();
+        let mut n = 2;
+
+        loop {
+            match self.check_new_name(tag_ino, &candidate) {
+                Err(PtfsError::Exists) => {},
+                result => return result.map(|_| candidate),
+            }
+
+            candidate = numbered_name(name, n);
+            n += 1;
+        }
+    }
+
+
+    pub fn remove_tag(&mut self, ino: u64, tag: &str) -> Result<(), PtfsError> {
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        let entry = self.list_children_names(tag_ino)?.into_iter().find(|child| child.0 == ino);
+
+        match entry {
+            None => Err(PtfsError::NotFound),
+            Some((_, name)) => {
+                self.remove_directory_entry(tag_ino, &name)?;
+                self.untagged(ino, tag_ino, tag)
+            }
+        }
+    }
+
+
+    // the part of remove_tag() after the entry is gone from the tag directory
+    fn untagged(&mut self, ino: u64, tag_ino: u64, tag: &str) -> Result<(), PtfsError> {
+        let mut order = self.tag_order_of(tag_ino)?;
+        if order.pinned.contains(&ino) || order.order.contains(&ino) {
+            order.pinned.retain(|pinned| *pinned != ino);
+            order.order.retain(|ordered| *ordered != ino);
+            self.store_tag_order(tag_ino, &order)?;
+        }
+
+        self.notify(ChangeEvent::Untagged {ino: ino, tag: tag.to_string()});
+
+        if self.remove_empty_tags && self.is_unused_tag(tag_ino)? {
+            match self.delete_tag(tag_ino) {
+                Err(PtfsError::NotPermitted) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+
+    // Adds or removes the tag for many files at once, returns the number of
+    // files that changed. All inodes are checked before the first change.
+    // In sync mode the blocks are written once at the end, not per file.
+    pub fn tag_many(&mut self, inodes: &[u64], tag: &str, add: bool) -> Result<usize, PtfsError> {
+        self.check_mutation(None)?;
+
+        for ino in inodes {
+            if self.cache.retrieve_entry_block(*ino)?.is_tag {
+                return Err(PtfsError::InvalidArgument);
+            }
+        }
+
+        let sync_mode = self.cache.sync_mode();
+        self.cache.set_sync_mode(SyncMode::Async);
+        let result = if add {self.add_tags(inodes, tag)} else {self.remove_tags(inodes, tag)};
+        self.cache.set_sync_mode(sync_mode);
+
+        if sync_mode == SyncMode::Sync {
+            self.cache.sync()?;
+        }
+        result
+    }
+
+
+    fn add_tags(&mut self, inodes: &[u64], tag: &str) -> Result<usize, PtfsError> {
+        let tag_ino = match self.find_tag(tag)? {
+            Some(tag_ino) => tag_ino,
+            None => self.create_tag(tag)?,
+        };
+
+        let mut members = self.list_children_names(tag_ino)?.into_iter().map(|child| child.0).collect::<HashSet<_>>();
+        let mut changed = 0;
+
+        for ino in inodes {
+            if !members.insert(*ino) {
+                continue;
+            }
+
+            let eb = self.cache.retrieve_entry_block(*ino)?;
+            let name = eb.name.to_string();
+            let kind = eb.attr.kind;
+
+            let name = self.free_tag_entry_name(tag_ino, &name)?;
+            self.add_directory_entry(tag_ino, &name, *ino, kind)?;
+            self.notify(ChangeEvent::Tagged {ino: *ino, tag: tag.to_string()});
+            changed += 1;
+        }
+
+        Ok(changed)
+    }
+
+
+    fn remove_tags(&mut self, inodes: &[u64], tag: &str) -> Result<usize, PtfsError> {
+        self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        let mut changed = 0;
+
+        for ino in inodes {
+            match self.remove_tag(*ino, tag) {
+                Ok(()) => changed += 1,
+                Err(PtfsError::NotFound) => {},
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(changed)
+    }
+
+
+    // tags without files, except the ones marked keep
+    pub fn empty_tags(&mut self) -> Result<Vec<String>, PtfsError> {
+        let mut empty = Vec::new();
+
+        for (tag_ino, name) in self.all_tags()? {
+            if self.is_unused_tag(tag_ino)? {
+                empty.push(name);
+            }
+        }
+
+        Ok(empty)
+    }
+
+
+    // Removes the tags found by empty_tags() and returns their names.
+    // Immutable ones stay, their namespaces stay too.
+    pub fn gc_tags(&mut self) -> Result<Vec<String>, PtfsError> {
+        self.check_mutation(None)?;
+        let mut removed = Vec::new();
+
+        for tag in self.empty_tags()? {
+            let tag_ino = self.find_tag(&tag)?.ok_or(PtfsError::NotFound)?;
+            match self.delete_tag(tag_ino) {
+                Ok(()) => removed.push(tag),
+                Err(PtfsError::NotPermitted) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(removed)
+    }
+
+
+    fn is_unused_tag(&mut self, tag_ino: u64) -> Result<bool, PtfsError> {
+        let used = self.list_children_names(tag_ino)?.iter().any(|child| child.1 != "." && child.1 != "..");
+        Ok(!used && !self.tag_rules_of(tag_ino)?.keep)
+    }
+
+
+    // frees the directory of a tag without members
+    fn delete_tag(&mut self, tag_ino: u64) -> Result<(), PtfsError> {
+        let parent = self.find_child(tag_ino, &"..".to_string())?
+            .ok_or_else(|| PtfsError::Corrupt(format!("tag directory {} has no parent", tag_ino)))?;
+        self.check_removable(parent, tag_ino)?;
+
+        let name = self.cache.retrieve_entry_block(tag_ino)?.name.to_string();
+        self.remove_directory_entry(parent, &name)?;
+        self.free_entry(tag_ino)?;
+        self.notify(ChangeEvent::Deleted {parent, ino: tag_ino, name});
+
+        Ok(())
+    }
+
+
+    pub fn tag_order(&mut self, tag: &str) -> Result<TagOrder, PtfsError> {
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        self.tag_order_of(tag_ino)
+    }
+
+
+    // the listed files must carry the tag, see the tag_order module
+    pub fn set_tag_order(&mut self, tag: &str, order: &TagOrder) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        let members = self.list_children_names(tag_ino)?;
+        let is_member = |ino: &u64| members.iter().any(|member| member.0 == *ino && member.1 != "." && member.1 != "..");
+
+        if !order.pinned.iter().chain(order.order.iter()).all(is_member) {
+            return Err(PtfsError::NotFound);
+        }
+
+        self.store_tag_order(tag_ino, order)
+    }
+
+
+    pub fn tag_rules(&mut self, tag: &str) -> Result<TagRules, PtfsError> {
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        self.tag_rules_of(tag_ino)
+    }
+
+
+    // queries with the tag may change, so the cached ones are dropped
+    pub fn set_tag_rules(&mut self, tag: &str, rules: TagRules) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        self.store_tag_rules(tag_ino, rules)
+    }
+
+
+    fn tag_rules_of(&mut self, tag_ino: u64) -> Result<TagRules, PtfsError> {
+        match self.cache.retrieve_entry_block(tag_ino)?.extension(EXT_TAG_RULES) {
+            None => Ok(TagRules::default()),
+            Some(data) => TagRules::decode(data),
+        }
+    }
+
+
+    fn store_tag_rules(&mut self, tag_ino: u64, rules: TagRules) -> Result<(), PtfsError> {
+        let eb = self.cache.retrieve_entry_block(tag_ino)?;
+
+        if rules == TagRules::default() {
+            eb.remove_extension(EXT_TAG_RULES);
+        }
+        else {
+            eb.set_extension(EXT_TAG_RULES, &rules.encode())?;
+        }
+
+        self.queries.clear();
+        Ok(())
+    }
+
+
+    fn tag_order_of(&mut self, tag_ino: u64) -> Result<TagOrder, PtfsError> {
+        let eb = self.cache.retrieve_entry_block(tag_ino)?;
+
+        match eb.extension(EXT_TAG_ORDER) {
+            None => Ok(TagOrder::default()),
+            Some(data) => TagOrder::decode(data),
+        }
+    }
+
+
+    fn store_tag_order(&mut self, tag_ino: u64, order: &TagOrder) -> Result<(), PtfsError> {
+        let eb = self.cache.retrieve_entry_block(tag_ino)?;
+
+        if order.is_empty() {
+            eb.remove_extension(EXT_TAG_ORDER);
+        }
+        else {
+            eb.set_extension(EXT_TAG_ORDER, &order.encode())?;
+        }
+
+        Ok(())
+    }
+
+
+    // Extended attributes, so far only the order of tag directories. Their
+    // values are the entry names, one per line.
+    pub fn getxattr(&mut self, ino: u64, name: &str) -> Result<Vec<u8>, PtfsError> {
+        if name == XATTR_COMMENT {
+            return self.comment(ino)?.map(String::into_bytes).ok_or(PtfsError::NoAttribute);
+        }
+
+        if name == XATTR_RATING {
+            return match self.rating(ino)? {
+                0 => Err(PtfsError::NoAttribute),
+                rating => Ok(rating.to_string().into_bytes()),
+            };
+        }
+
+        if name == XATTR_SHA256 {
+            if !self.has_content_hash(ino)? {
+                return Err(PtfsError::NoAttribute);
+            }
+            return Ok(sha256::to_hex(&self.content_hash(ino)?).into_bytes());
+        }
+
+        if RULE_XATTRS.contains(&name) {
+            let mut rules = self.xattr_tag_rules(ino)?;
+            return Ok(tag_rules::switch_value(*rules.switch_mut(name).unwrap()));
+        }
+
+        if name == XATTR_TAGS || name == XATTR_INHERITED_TAGS {
+            let tags = if name == XATTR_TAGS {self.list_tags(ino)?} else {self.inherited_tags(ino)?};
+            if tags.is_empty() {
+                return Err(PtfsError::NoAttribute);
+            }
+            return Ok(tags.join("\n").into_bytes());
+        }
+
+        if name == XATTR_CRTIME {
+            let crtime = self.cache.retrieve_entry_block(ino)?.attr.crtime;
+            let since = crtime.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            return Ok(format!("{}.{:09}", since.as_secs(), since.subsec_nanos()).into_bytes());
+        }
+
+        let order = self.xattr_tag_order(ino)?;
+        let inodes = match name {
+            XATTR_ORDER if !order.order.is_empty() => order.order,
+            XATTR_PINNED if !order.pinned.is_empty() => order.pinned,
+            _ => return Err(PtfsError::NoAttribute),
+        };
+
+        let members = self.list_children_names(ino)?;
+        let names = inodes.iter()
+            .filter_map(|ino| members.iter().find(|member| member.0 == *ino))
+            .map(|member| member.1.as_str())
+            .collect::<Vec<_>>();
+
+        Ok(names.join("\n").into_bytes())
+    }
+
+
+    pub fn setxattr(&mut self, ino: u64, name: &str, value: &[u8]) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        self.check_unchanged(ino)?;
+
+        if RULE_XATTRS.contains(&name) {
+            let mut rules = self.xattr_tag_rules(ino)?;
+            *rules.switch_mut(name).unwrap() = tag_rules::parse_switch(value)?;
+            return self.store_tag_rules(ino, rules);
+        }
+
+        if name == XATTR_COMMENT {
+            let comment = std::str::from_utf8(value).map_err(|_| PtfsError::InvalidArgument)?;
+            return self.set_comment(ino, comment);
+        }
+
+        if name == XATTR_RATING {
+            let text = std::str::from_utf8(value).map_err(|_| PtfsError::InvalidArgument)?;
+            let rating = text.trim_end_matches('\n').parse().map_err(|_| PtfsError::InvalidArgument)?;
+            return self.set_rating(ino, rating);
+        }
+
+        // the hash, the creation time and the tags are kept by the file system
+        if name != XATTR_ORDER && name != XATTR_PINNED {
+            return Err(PtfsError::NotPermitted);
+        }
+
+        let mut order = self.xattr_tag_order(ino)?;
+        let value = std::str::from_utf8(value).map_err(|_| PtfsError::InvalidArgument)?;
+        let mut inodes = Vec::new();
+
+        for line in value.lines().filter(|line| !line.is_empty()) {
+            if line == "." || line == ".." {
+                return Err(PtfsError::InvalidArgument);
+            }
+            inodes.push(self.find_child(ino, &line.to_string())?.ok_or(PtfsError::NotFound)?);
+        }
+
+        match name {
+            XATTR_ORDER => order.order = inodes,
+            _ => order.pinned = inodes,
+        }
+
+        self.store_tag_order(ino, &order)
+    }
+
+
+    pub fn listxattr(&mut self, ino: u64) -> Result<Vec<String>, PtfsError> {
+        let mut names = vec![XATTR_CRTIME.to_string()];
+
+        if self.cache.retrieve_entry_block(ino)?.extension(EXT_COMMENT).is_some() {
+            names.push(XATTR_COMMENT.to_string());
+        }
+        if self.rating(ino)? != 0 {
+            names.push(XATTR_RATING.to_string());
+        }
+
+        if !self.list_tags(ino)?.is_empty() {
+            names.push(XATTR_TAGS.to_string());
+        }
+        if !self.inherited_tags(ino)?.is_empty() {
+            names.push(XATTR_INHERITED_TAGS.to_string());
+        }
+
+        if self.has_content_hash(ino)? {
+            names.push(XATTR_SHA256.to_string());
+        }
+
+        if self.cache.retrieve_entry_block(ino)?.is_tag {
+            let order = self.tag_order_of(ino)?;
+            if !order.order.is_empty() {
+                names.push(XATTR_ORDER.to_string());
+            }
+            if !order.pinned.is_empty() {
+                names.push(XATTR_PINNED.to_string());
+            }
+
+            // the defaults are not listed
+            if self.cache.retrieve_entry_block(ino)?.extension(EXT_TAG_RULES).is_some() {
+                names.extend(RULE_XATTRS.iter().map(|name| name.to_string()));
+            }
+        }
+
+        Ok(names)
+    }
+
+
+    pub fn removexattr(&mut self, ino: u64, name: &str) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        self.check_unchanged(ino)?;
+
+        if name == XATTR_SHA256 || name == XATTR_CRTIME || name == XATTR_TAGS || name == XATTR_INHERITED_TAGS {
+            return Err(PtfsError::NotPermitted);
+        }
+
+        if name == XATTR_COMMENT {
+            if self.comment(ino)?.is_none() {
+                return Err(PtfsError::NoAttribute);
+            }
+            return self.set_comment(ino, "");
+        }
+
+        if name == XATTR_RATING {
+            if self.rating(ino)? == 0 {
+                return Err(PtfsError::NoAttribute);
+            }
+            return self.set_rating(ino, 0);
+        }
+
+        // back to the default
+        if RULE_XATTRS.contains(&name) {
+            let mut rules = self.xattr_tag_rules(ino)?;
+            let default = *TagRules::default().switch_mut(name).unwrap();
+            *rules.switch_mut(name).unwrap() = default;
+            return self.store_tag_rules(ino, rules);
+        }
+
+        let mut order = self.xattr_tag_order(ino)?;
+        let inodes = match name {
+            XATTR_ORDER => &mut order.order,
+            XATTR_PINNED => &mut order.pinned,
+            _ => return Err(PtfsError::NoAttribute),
+        };
+
+        if inodes.is_empty() {
+            return Err(PtfsError::NoAttribute);
+        }
+
+        inodes.clear();
+        self.store_tag_order(ino, &order)
+    }
 
 
-pub struct PathTagFs {
-    cache: BlockCache,
-}
+    pub fn comment(&mut self, ino: u64) -> Result<Option<String>, PtfsError> {
+        match self.cache.retrieve_entry_block(ino)?.extension(EXT_COMMENT) {
+            None => Ok(None),
+            Some(text) => Ok(Some(String::from_utf8_lossy(text).into_owned())),
+        }
+    }
 
 
-impl PathTagFs {
-    
-    pub fn new(backingstore: &str) -> PathTagFs {
-        PathTagFs {
-            cache: BlockCache::new(backingstore),
+    // An empty comment removes it. The comment goes before the contents of
+    // small files, they move to data blocks to make room for it.
+    pub fn set_comment(&mut self, ino: u64, comment: &str) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        self.check_unchanged(ino)?;
+
+        if comment.len() > MAX_COMMENT_LENGTH {
+            return Err(PtfsError::NoSpace);
+        }
+
+        let eb = self.retrieve_entry_block(ino)?;
+        eb.attr.ctime = SystemTime::now();
+
+        if comment.is_empty() {
+            eb.remove_extension(EXT_COMMENT);
+            return Ok(());
+        }
+
+        match eb.set_extension(EXT_COMMENT, comment.as_bytes()) {
+            Err(PtfsError::NoSpace) if eb.extension(EXT_INLINE_DATA).is_some() => {
+                let content = eb.extension(EXT_INLINE_DATA).unwrap().to_vec();
+                eb.remove_extension(EXT_INLINE_DATA);
+                eb.set_extension(EXT_COMMENT, comment.as_bytes())?;
+                self.write_blocks(ino, 0, &content)
+            }
+            result => result,
         }
     }
-    
-    
-    pub fn open(& mut self, ino_root: u64) {
-        self.cache.open();
-        self.list_fs(ino_root);
-    }
-    
 
-    pub fn destroy(& mut self) {
-        self.cache.flush();
+
+    // from 1 to MAX_RATING stars, 0 if the file is not rated
+    pub fn rating(&mut self, ino: u64) -> Result<u8, PtfsError> {
+        match self.cache.retrieve_entry_block(ino)?.extension(EXT_RATING) {
+            None => Ok(0),
+            Some([rating]) if *rating <= MAX_RATING => Ok(*rating),
+            Some(_) => Err(PtfsError::Corrupt(format!("rating of inode {} is damaged", ino))),
+        }
     }
 
-    
-    pub fn mkfs(& mut self, ino_root: u64, size: u64) {
-        
-        self.cache.size_filesystem(size);
-        
-        // take special blocks (reserved, fs info block, root inode)
-        self.cache.take_block(0);
-        self.cache.take_block(1);
-        self.cache.take_block(2);
-        
-        let root = EntryBlock::new("Root", ino_root, FileType::Directory, false);
 
-        self.cache.write_block(AnyBlock::EntryBlock(root), ino_root).unwrap();
+    // a rating of 0 removes it
+    pub fn set_rating(&mut self, ino: u64, rating: u8) -> Result<(), PtfsError> {
+        self.check_mutation(None)?;
+        self.check_unchanged(ino)?;
 
-        self.mkdir(ino_root, &"Pathes".to_string());
-        self.mkdir(ino_root, &"Tags".to_string());
-        
-        // persist data
-        self.cache.flush();
-        
-        self.list_fs(ino_root);
-    }
+        if rating > MAX_RATING {
+            return Err(PtfsError::InvalidArgument);
+        }
 
+        let eb = self.retrieve_entry_block(ino)?;
+        eb.attr.ctime = SystemTime::now();
 
-    // list file system structure for debugging
-    fn list_fs(&mut self, ino: u64) {
-       
-        let mut subdirs = Vec::new();
-        let children = self.list_children(ino);
-        
-        println!("Inode {}", ino);
-        
-        for child in children {
-            println!("  child ino={} type={:?} name={}", child.0, child.1, child.2);
-            if child.1 == FileType::Directory && child.2.starts_with(".") ==false {
-                subdirs.push(child.0);
-            }            
+        if rating == 0 {
+            eb.remove_extension(EXT_RATING);
         }
-        
-        for subdir in subdirs {
-            self.list_fs(subdir);
+        else {
+            eb.set_extension(EXT_RATING, &[rating])?;
+        }
+
+        self.index_file(ino)
+    }
+
+
+    // regular files show their hash if it is kept up to date or known
+    fn has_content_hash(&mut self, ino: u64) -> Result<bool, PtfsError> {
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        Ok(eb.attr.kind == FileType::RegularFile && (self.content_hashes || eb.extension(EXT_SHA256).is_some()))
+    }
+
+
+    // other files have no rules
+    fn xattr_tag_rules(&mut self, ino: u64) -> Result<TagRules, PtfsError> {
+        if !self.cache.retrieve_entry_block(ino)?.is_tag {
+            return Err(PtfsError::NoAttribute);
         }
+
+        self.tag_rules_of(ino)
     }
-    
 
-    pub fn retrieve_entry_block(&mut self, bno: u64) -> Option<&mut EntryBlock> {
-        self.cache.retrieve_entry_block(bno)
+
+    // other files have no order attributes
+    fn xattr_tag_order(&mut self, ino: u64) -> Result<TagOrder, PtfsError> {
+        if !self.cache.retrieve_entry_block(ino)?.is_tag {
+            return Err(PtfsError::NoAttribute);
+        }
+
+        self.tag_order_of(ino)
     }
-    
-    
-    pub fn find_child(&mut self, parent_ino: u64, name: &String) -> Option<u64> {
 
-        println!("find_child()  finding {} from inode {}", name, parent_ino);                
 
-        let eb_opt = self.cache.retrieve_entry_block(parent_ino);
+    // returns the names of all tags carried by ino
+    pub fn list_tags(&mut self, ino: u64) -> Result<Vec<String>, PtfsError> {
+        let mut tags = Vec::new();
 
-        match eb_opt {
-            None => {
-                println!("  find_child(): error:  {} is no entry block", parent_ino);                
+        for (tag_ino, name) in self.all_tags()? {
+            // "." of a tag directory is no member
+            if self.list_children_names(tag_ino)?.iter().any(|child| child.0 == ino && child.1 != "." && child.1 != "..") {
+                tags.push(name);
             }
-            Some(eb) => {
-                let mut next = eb.more_data;
+        }
+
+        Ok(tags)
+    }
 
-                println!("  find_child(): next directory block is {}", next);                
 
-                while next != 0 {
-                    let option = self.cache.retrieve_directory_block(next);
-                    let db = option.unwrap();
-                    for entry in &db.entries {
-                        
-                        // println!("  find_child(): comparing search='{}' entry='{}'", name, entry.name);                
-                        if comp(name, &entry.name) {
-                            return Option::Some(entry.ino);   
-                        }
-                    }
-                                    
-                    next = db.next;
+    // Returns all tags with their full names, sorted by name. Namespaces
+    // are the plain directories below /Tags, tags the ones marked is_tag.
+    pub fn all_tags(&mut self) -> Result<Vec<(u64, String)>, PtfsError> {
+        let tags_ino = self.lookup(self.root, &TAGS_DIR.to_string())?.ino;
+        let mut tags = Vec::new();
+        let mut pending = vec![(tags_ino, String::new())];
+
+        while let Some((dir, prefix)) = pending.pop() {
+            for (ino, name) in self.list_children_names(dir)? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                let full = format!("{}{}", prefix, name);
+                let eb = self.cache.retrieve_entry_block(ino)?;
+
+                if eb.is_tag {
+                    tags.push((ino, full));
+                }
+                else if eb.attr.kind == FileType::Directory {
+                    pending.push((ino, full + "/"));
                 }
             }
         }
 
-        return None;        
+        tags.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(tags)
     }
 
 
-    pub fn list_children_names(&mut self, parent_ino: u64) -> Vec<(u64, String)> {
-        let mut result = Vec::new();
+    // returns the files that carry tag
+    pub fn list_tagged(&mut self, tag: &str) -> Result<Vec<(u64, String)>, PtfsError> {
+        let tag_ino = self.find_tag(tag)?.ok_or(PtfsError::NotFound)?;
+        let mut members = self.list_children_names(tag_ino)?;
+        members.retain(|member| member.1 != "." && member.1 != "..");
+
+        Ok(members)
+    }
 
-        println!("list_children_names()  listing from inode {}", parent_ino);                
 
-        let eb_opt = self.cache.retrieve_entry_block(parent_ino);
+    // The files that carry tag, directly or from a tagged directory above
+    // them. Those below a directory are found at each call, so files added
+    // to it later carry the tag too.
+    pub fn tag_members(&mut self, tag: &str) -> Result<BTreeSet<u64>, PtfsError> {
+        let mut members = self.list_tagged(tag)?.into_iter().map(|member| member.0).collect::<BTreeSet<_>>();
+        let dirs = self.tagged_dirs(tag)?;
 
-        match eb_opt {
-            None => {
-                println!("  error:  {} is no entry block", parent_ino);                
+        for ino in self.subtree(dirs)? {
+            if self.find_filetype(ino)? != FileType::Directory {
+                members.insert(ino);
             }
-            Some(eb) => {
-                let mut next = eb.more_data;
+        }
 
-                println!("  next directory block is {}", next);                
+        Ok(members)
+    }
 
-                while next != 0 {
-                    let option = self.cache.retrieve_directory_block(next);
-                    
-                    match option {
-                        None => {
-                            println!("  error:  {} is no directory block", next);                
-                        }
-                        Some(db) => {
-                            for entry in &db.entries {
-                                let name = entry.name.to_string();
-                                let ino = entry.ino;
-                                result.push((ino, name));                
-                            }
-                            next = db.next;
-                            println!("  next directory block is {}", next);                
-                        }
-                    }
-                }
+
+    // the tags ino carries from the tagged directories above it
+    pub fn inherited_tags(&mut self, ino: u64) -> Result<Vec<String>, PtfsError> {
+        let mut tags = Vec::new();
+
+        let own = self.list_tags(ino)?;
+
+        for (_, tag) in self.all_tags()? {
+            if own.contains(&tag) {
+                continue;
+            }
+
+            let dirs = self.tagged_dirs(&tag)?;
+            if !dirs.is_empty() && self.subtree(dirs)?.contains(&ino) {
+                tags.push(tag);
             }
         }
 
-        return result;
+        Ok(tags)
     }
 
 
-    fn find_filetype(&mut self, ino: u64) -> Option<FileType> {
-        println!("find_filetype()  finding type of inode {}", ino);                
+    // the directories that pass tag on, none for unknown tags
+    fn tagged_dirs(&mut self, tag: &str) -> Result<Vec<u64>, PtfsError> {
+        let tag_ino = match self.find_tag(tag)? {
+            Some(tag_ino) => tag_ino,
+            None => return Ok(Vec::new()),
+        };
 
-        let inode = self.cache.retrieve_entry_block(ino);
-        match inode {
-            None => {
-                println!("  error:  {} is no entry block", ino);                
+        if !self.tag_rules_of(tag_ino)?.inherit {
+            return Ok(Vec::new());
+        }
+
+        let members = self.list_tagged(tag)?;
+
+        let mut dirs = Vec::new();
+        for (ino, _) in members {
+            if self.find_filetype(ino)? == FileType::Directory {
+                dirs.push(ino);
             }
-            Some(entry) => {
-                return Some(entry.attr.kind);
+        }
+
+        Ok(dirs)
+    }
+
+
+    // everything below the directories, subdirectories included
+    fn subtree(&mut self, dirs: Vec<u64>) -> Result<BTreeSet<u64>, PtfsError> {
+        let mut found = BTreeSet::new();
+        let mut visited = dirs.iter().cloned().collect::<HashSet<_>>();
+        let mut pending = dirs;
+
+        while let Some(dir) = pending.pop() {
+            for (ino, name) in self.list_children_names(dir)? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                found.insert(ino);
+                if self.find_filetype(ino)? == FileType::Directory && visited.insert(ino) {
+                    pending.push(ino);
+                }
             }
         }
-        
-        None 
+
+        Ok(found)
     }
 
 
-    pub fn list_children(&mut self, parent_ino: u64) -> Vec<(u64, fuser::FileType, String)> {
-        let names = self.list_children_names(parent_ino);
-        let mut result = Vec::new();
+    // returns the files matching a query expression, see the query module
+    pub fn query(&mut self, expression: &str) -> Result<BTreeSet<u64>, PtfsError> {
+        self.cached_query(None, expression)
+    }
 
-        for (ino, name) in names {
-            let kind_opt = self.find_filetype(ino);
-            result.push((ino, kind_opt.unwrap(), name));
+
+    // like query(), but the tags of the expression are names in namespace
+    pub fn query_in(&mut self, namespace: &str, expression: &str) -> Result<BTreeSet<u64>, PtfsError> {
+        split_tag(namespace)?;
+        self.cached_query(Some(namespace), expression)
+    }
+
+
+    fn cached_query(&mut self, namespace: Option<&str>, expression: &str) -> Result<BTreeSet<u64>, PtfsError> {
+        if let Some(result) = self.queries.get(namespace, expression) {
+            return Ok(result);
         }
 
-        result    
+        let query = query::parse(expression)?;
+        let result = match namespace {
+            Some(namespace) => query.evaluate_in(self, namespace)?,
+            None => query.evaluate(self)?,
+        };
+
+        let mut tags = Vec::new();
+        query.tags(namespace, &mut tags);
+        let mut subtrees = false;
+        for tag in tags {
+            subtrees |= !self.tagged_dirs(&tag)?.is_empty();
+        }
+
+        self.queries.insert(namespace, expression, &query, result.clone(), subtrees);
+        Ok(result)
     }
 
-    
-    pub fn read(&mut self, index_block: u64, offset: i64, size: u64) -> Vec<u8> {
-        println!("read() reading data");
-        let mut result = Vec::new();
 
-        if offset < 0 {
-            println!("  error: data offset is negative, cannot read there.");
-            return result;
+    // Files whose size, mtime or rating is in range, from the attribute index. None
+    // if the index doesn't cover the mounted files, they are scanned then.
+    pub fn indexed_files(&mut self, attr: IndexedAttr, range: RangeInclusive<u64>) -> Result<Option<BTreeSet<u64>>, PtfsError> {
+        // the index has the files of all sub-volumes
+        if self.root != INO_ROOT || self.find_child(INO_ROOT, &SUBVOLS_DIR.to_string())?.is_some() {
+            return Ok(None);
         }
 
-        let mut list = Vec::new();
-        let mut ib_no = index_block;
-        
-        while ib_no != 0 {
-            let ib_opt = self.cache.retrieve_index_block(ib_no);
-            
-            match ib_opt {
-                None => {
-                    println!("  error: Block {} is not an index block.", ib_no);
-                    ib_no = 0;
-                }
-                Some(ib) => {
-                    if ib.block[0] != 0 {
-                        
-                        let start = offset as usize / BLOCK_SIZE;
-                        let end = (offset + size as i64) as usize / BLOCK_SIZE;    
-        
-                        for n in start..=end {
-                            let dbno = ib.block[n];
-                            list.push(dbno);
-                        }
-                    }
-                    else {
-                        println!("  error: No data blocks for file.");                
-                    }
-                    ib_no = ib.next;
-                }
-            }
+        if self.attr_index.is_none() {
+            self.build_attr_index()?;
         }
 
-        for bno in list {
-            println!("  reading data block {}.", bno);                
+        match &self.attr_index {
+            Some(index) if !index.partial => Ok(Some(index.range(attr, range))),
+            _ => Ok(None),
+        }
+    }
 
-            let db_opt = self.cache.retrieve_data_block(bno);
-            match db_opt {
-                None => {
-                    println!("  error: block {} is no data block.", bno);                
+
+    // Builds the derived data again from the directory tree, after damage
+    // or a change of its format. Returns the number of files found.
+    pub fn reindex(&mut self) -> Result<usize, PtfsError> {
+        self.check_mutation(None)?;
+        self.queries.clear();
+        self.build_attr_index()
+    }
+
+
+    // walks the whole image, everything that is not a directory goes in
+    fn build_attr_index(&mut self) -> Result<usize, PtfsError> {
+        debug!("build_attr_index()");
+        self.attr_index = Some(AttrIndex::default());
+
+        let mut visited = HashSet::from([INO_ROOT]);
+        let mut pending = vec![INO_ROOT];
+        let mut files = 0;
+
+        while let Some(dir) = pending.pop() {
+            for (ino, kind, name) in self.list_children(dir)? {
+                if name == "." || name == ".." || !visited.insert(ino) {
+                    continue;
+                }
+
+                if kind == FileType::Directory {
+                    pending.push(ino);
                 }
-                Some(db) => {
-                    println!("  copy data");                
-                    result.extend_from_slice(&db.data);
+                else {
+                    self.index_file(ino)?;
+                    files += 1;
                 }
             }
         }
-            
-        return result;
+
+        Ok(files)
     }
 
 
-    pub fn write(&mut self, inode: u64, offset: i64, data: &[u8]) {
+    // Walks the image to fill the caches, so the first listings and
+    // queries don't wait for the disk. It stops early when the block cache
+    // is full, the blocks read last would only push out the first ones.
+    // Returns the number of inodes visited.
+    pub fn preload(&mut self, preload: Preload) -> Result<usize, PtfsError> {
+        debug!("preload() {:?}", preload);
+        self.start_preload(preload)?;
 
-        if offset < 0 {
-            println!("  data offset is negative, cannot write there.");
+        loop {
+            if let Some(visited) = self.preload_step(usize::MAX)? {
+                return Ok(visited);
+            }
         }
+    }
 
-        let list = self.write_data_blocks(offset as usize, data);
 
-        let ib_no = self.cache.allocate_block() as u64;
-        let mut ib = IndexBlock::new();            
+    // Starts a preload() walk that goes on in preload_step(), so a mount
+    // can serve requests before the walk is done.
+    pub fn start_preload(&mut self, preload: Preload) -> Result<(), PtfsError> {
+        if preload == Preload::None {
+            self.preloading = None;
+            return Ok(());
+        }
 
-        for i in 0..list.len() {
-            ib.block[i] = list[i];            
+        if self.attr_index.is_none() {
+            self.build_attr_index()?;
         }
 
-        self.cache.write_block(AnyBlock::IndexBlock(ib), ib_no).unwrap();
-        
-        let eb_opt = self.cache.retrieve_entry_block(inode);
-        let eb = eb_opt.unwrap();
-        
-        eb.more_data = ib_no;
-        eb.attr.size = data.len() as u64;
+        self.preloading = Some(PreloadWalk {
+            preload,
+            visited: HashSet::from([INO_ROOT]),
+            pending: vec![INO_ROOT],
+            blocks: Vec::new(),
+        });
+        Ok(())
     }
 
-    
-    fn write_data_blocks(&mut self, offset: usize, data: &[u8]) -> Vec<u64> {
-        let mut result = Vec::new();
 
-        let start = offset / BLOCK_SIZE as usize;
-        let end = (offset + data.len()) / BLOCK_SIZE as usize;    
+    pub fn is_preloading(&self) -> bool {
+        self.preloading.is_some()
+    }
 
-        for n in start..=end {
 
-            let data_start = (n - start) * BLOCK_SIZE as usize;
+    // Goes on with the walk for about budget inodes and data blocks, a
+    // directory is always listed as a whole. Returns the number of inodes
+    // visited once the walk is done, an error ends it too. Files may have
+    // changed since the last step, freed directories and blocks are skipped.
+    pub fn preload_step(&mut self, budget: usize) -> Result<Option<usize>, PtfsError> {
+        let mut walk = match self.preloading.take() {
+            Some(walk) => walk,
+            None => return Ok(Some(0)),
+        };
 
-            let db_no = self.cache.allocate_block() as u64;
-            let mut db = DataBlock::new();
+        if self.walk_preload(&mut walk, budget)? {
+            return Ok(Some(walk.visited.len()));
+        }
+        self.preloading = Some(walk);
+        Ok(None)
+    }
 
-            let data_size = std::cmp::min(BLOCK_SIZE as usize, data.len() - data_start);
 
-            println!("  writing {} bytes to data block {} chain={}", data_size, db_no, n);
-            
-            // db.data.copy_from_slice(src)
-            db.data[0..data_size].copy_from_slice(&data[data_start..data_start+data_size]);
-            result.push(db_no);
-            self.cache.write_block(AnyBlock::DataBlock(db), db_no).unwrap();
-        }        
-        
-        result
-    }
+    // one step of preload_step(), true when the walk is done
+    fn walk_preload(&mut self, walk: &mut PreloadWalk, mut budget: usize) -> Result<bool, PtfsError> {
+        while budget > 0 {
+            if self.cache.is_full() {
+                return Ok(true);
+            }
 
+            if let Some(bno) = walk.blocks.pop() {
+                if self.cache.is_block_used(bno) {
+                    self.cache.retrieve_data_block(bno)?;
+                }
+                budget -= 1;
+                continue;
+            }
 
-    pub fn mknod(&mut self, parent_ino: u64, name: &String, kind: FileType) -> Option<FileAttr> {
-        println!("mknod() parent={} name={} kind={:?}", parent_ino, name, kind);
+            let dir = match walk.pending.pop() {
+                Some(dir) => dir,
+                None => return Ok(true),
+            };
 
-        let parent_opt = self.cache.retrieve_entry_block(parent_ino);
+            for (ino, kind, name) in self.list_children(dir)? {
+                if name == "." || name == ".." || !walk.visited.insert(ino) {
+                    continue;
+                }
 
-        match parent_opt {
-            None => {
-                println!("  error: {} is no allocated block.", parent_ino);
-            }
-            Some(_parent) => {
-                let bno = self.cache.allocate_block() as u64;
-                self.add_directory_entry(parent_ino, &name.to_string(), bno);
-                
-                let entry = EntryBlock::new(&name, bno, kind, false);
-                let attr: FileAttr = entry.attr.into();
-                
-                self.cache.write_block(AnyBlock::EntryBlock(entry), bno).unwrap();
-                
-                return Some(attr);
+                self.getattr(ino)?;
+                if kind == FileType::Directory {
+                    walk.pending.push(ino);
+                }
+                else if walk.preload == Preload::All {
+                    walk.blocks.extend(self.file_blocks(ino)?.1.into_iter().rev());
+                }
+                budget = budget.saturating_sub(1);
             }
         }
-        
-        return None;
+
+        Ok(false)
     }
 
 
-    pub fn mkdir(&mut self, parent_ino: u64, name: &String) -> Option<FileAttr> {
-        println!("mkdir() parent={} name={}", parent_ino, name);
+    // Brings the index up to date with the entry block of a file. Host
+    // files change behind our back, they make the index partial.
+    fn index_file(&mut self, ino: u64) -> Result<(), PtfsError> {
+        if self.attr_index.is_none() {
+            return Ok(());
+        }
 
-        let parent_opt = self.cache.retrieve_entry_block(parent_ino);
+        let eb = self.cache.retrieve_entry_block(ino)?;
+        let host = overlay::host_path(eb).is_some();
+        let attr = eb.attr;
+        let rating = self.rating(ino)?;
 
-        match parent_opt {
-            None => {
-                println!("  error: {} is no allocated block.", parent_ino);
-            }
-            Some(_parent) => {
-                let bno = self.cache.allocate_block() as u64;
-                self.add_directory_entry(parent_ino, &name.to_string(), bno);
-                
-                let entry = EntryBlock::new(&name, bno, fuser::FileType::Directory, false);
-                let attr: FileAttr = entry.attr.into();
-                self.cache.write_block(AnyBlock::EntryBlock(entry), bno).unwrap();
-                
-                self.add_directory_entry(bno, &".".to_string(), bno);            
-                self.add_directory_entry(bno, &"..".to_string(), parent_ino);            
-                
-                return Some(attr);
-            }
+        let index = self.attr_index.as_mut().unwrap();
+        if host {
+            index.partial = true;
         }
-        
-        return None;
+        else if attr.kind != FileType::Directory {
+            let mtime = attr.mtime.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+            index.update(ino, attr.size, mtime, rating as u64);
+        }
+
+        Ok(())
     }
-    
-    
-    fn extend_directory_chain(&mut self, tail: u64, name: &String, ino: u64) -> u64 {
 
-        println!("extend_directory_chain()  Adding new directory node to chain tail {} for name {} (inode {})", tail, name, ino);
 
-        let bno = self.cache.allocate_block() as u64;
-        let mut db = DirectoryBlock::new();
-        db.entries.push(DirectoryEntry{ino: ino, name: name.to_string(),});
-        
-        let ab = AnyBlock::DirectoryBlock(db);
-        self.cache.write_block(ab, bno).unwrap();
-
-        // tail can either be an entry block or an directory block
-        // directory block is more common so we check that first
-        let dir_opt = self.cache.retrieve_directory_block(tail);
-        match dir_opt {
-            None => {
-                // ok, this should be an entry node then ...
-                
-                let entry_opt = self.cache.retrieve_entry_block(tail);
-                let entry = entry_opt.unwrap();
+    fn find_tag(&mut self, tag: &str) -> Result<Option<u64>, PtfsError> {
+        let mut dir = self.lookup(self.root, &TAGS_DIR.to_string())?.ino;
 
-                // add new block here  
-                entry.more_data = bno;
-            }
-            Some(dir) => {
-                // just add new block here  
-                dir.next = bno;
+        for name in split_tag(tag)? {
+            match self.find_child(dir, &name.to_string())? {
+                Some(ino) => dir = ino,
+                None => return Ok(None),
             }
         }
-        
-        bno
+
+        // a namespace of that name is no tag
+        if !self.cache.retrieve_entry_block(dir)?.is_tag {
+            return Ok(None);
+        }
+
+        Ok(Some(dir))
     }
 
-    
-    pub fn store_directory_entry(&mut self, parent_ino: u64, name: &String, ino: u64) -> u64 {
 
-        println!("store_directory_entry()  Trying to store new directory entry {} (inode {}) in inode {} directory", name, ino, parent_ino);
-        let mut result = 0;
-        let parent_opt = self.cache.retrieve_entry_block(parent_ino);
+    // creates the missing namespaces of the tag too
+    fn create_tag(&mut self, tag: &str) -> Result<u64, PtfsError> {
+        let mut dir = self.lookup(self.root, &TAGS_DIR.to_string())?.ino;
+        let names = split_tag(tag)?;
+        let (last, namespaces) = names.split_last().ok_or(PtfsError::InvalidArgument)?;
 
-        match parent_opt {
-            None => {
-                println!("  error: block {} is no entry block", parent_ino);
-            }
-            Some(parent) => {
-                if parent.more_data == 0 {
-                    println!("  no directory blocks for inode {}", parent_ino);
-                    result = parent_ino;
-                }
-                else {
-                    // traverse the chain
-                    let mut next = parent.more_data;
-                    while next != 0 {
-                        let option = self.cache.retrieve_directory_block(next);
-                        let db = option.unwrap();
-  
-                        result = next;
-  
-                        //  check if there are free entries
-                        if db.entries.len() < MAX_ENTRIES {
-                            println!("  storing entry in block {}", result);
-                            db.entries.push(DirectoryEntry{ino: ino, name: name.to_string(),});
-                            result = 0;
-                            next = 0;
-                        } else {
-                            // blocks to check
-                            next = db.next;
-                        }  
+        for name in namespaces {
+            dir = match self.find_child(dir, &name.to_string())? {
+                // tags hold files, not other tags
+                Some(ino) if self.cache.retrieve_entry_block(ino)?.is_tag => return Err(PtfsError::NotADirectory),
+                Some(ino) => ino,
+                None => self.mkdir(dir, &name.to_string())?.ino,
+            };
+        }
+
+        let ino = self.mkdir(dir, &last.to_string())?.ino;
+        self.cache.retrieve_entry_block(ino)?.is_tag = true;
+
+        Ok(ino)
+    }
+}
+
+
+// Windows has no names with <>:"/\|?* or control characters, none that
+// end in a dot or a space, and none of the device names, with or without
+// an extension
+fn windows_safe_name(name: &str) -> bool {
+    const DEVICES:[&str; 22] = ["CON", "PRN", "AUX", "NUL",
+        "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+    if name.chars().any(|c| c.is_ascii_control() || "<>:\"/\\|?*".contains(c)) {
+        return false;
+    }
+
+    if name.ends_with('.') || name.ends_with(' ') {
+        return false;
+    }
+
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    !DEVICES.iter().any(|device| stem.eq_ignore_ascii_case(device))
+}
+
+
+// "work/urgent" is the tag urgent in the namespace work
+fn split_tag(tag: &str) -> Result<Vec<&str>, PtfsError> {
+    let names: Vec<&str> = tag.split('/').collect();
+
+    if names.iter().any(|name| name.is_empty() || *name == "." || *name == "..") {
+        return Err(PtfsError::InvalidArgument);
+    }
+
+    Ok(names)
+}
+
+
+// Iterator over the children of a directory, see PathTagFs::children().
+pub struct Children<'a> {
+    fs: &'a mut PathTagFs,
+    position: Position,
+
+    // the rest of the current directory block
+    entries: std::vec::IntoIter<(u64, Option<FileType>, String)>,
+
+    // the tag directory if its members are listed as symlinks
+    tag: Option<u64>,
+}
+
+
+// where Children is in the chain
+struct Position {
+    // next directory block of the chain
+    next: u64,
+
+    // the current directory block and the slot of the next entry in it
+    block: u64,
+    slot: usize,
+
+    // the index of the next entry in an ordered tag directory
+    index: Option<u64>,
+}
+
+
+impl Children<'_> {
+
+    // the readdir offset of the next entry, for PathTagFs::children_at()
+    pub fn cookie(&self) -> u64 {
+        match self.position.index {
+            Some(index) => ORDERED_COOKIE | index,
+            None if self.position.block == 0 => END_COOKIE,
+            None => self.position.block << SLOT_BITS | self.position.slot as u64,
+        }
+    }
+}
+
+
+impl Iterator for Children<'_> {
+    type Item = Result<(u64, FileType, String), PtfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ino, kind, name)) = self.entries.next() {
+                self.position.slot += 1;
+                self.position.index = self.position.index.map(|index| index + 1);
+
+                // older images don't know the type without the entry block
+                let kind = match kind.map_or_else(|| self.fs.find_filetype(ino), Ok) {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        // in rescue mode still list the name, accessing it will report the damage
+                        if let Err(err) = self.fs.truncate_chain(err) {
+                            return Some(Err(err));
+                        }
+                        FileType::RegularFile
                     }
+                };
+
+                if let Some(link) = self.tag.filter(|_| name != "." && name != "..").and_then(|tag| tag_link_ino(tag, ino)) {
+                    return Some(Ok((link, FileType::Symlink, name)));
                 }
+
+                return Some(Ok((ino, kind, name)));
             }
-        }
-        
-        result
-    }    
 
+            if self.position.next == 0 {
+                return None;
+            }
 
-    pub fn add_directory_entry(&mut self, parent_ino: u64, name: &String, ino: u64) {
-        println!("add_directory_entry()  Add new directory entry {} (inode {}) in inode {} directory", name, ino, parent_ino);
-        
-        // try to store the new entry in one of the existing directrory blocks of this inode 
-        let tail = self.store_directory_entry(parent_ino, name, ino);
-        
-        if tail != 0 {
-            // there were no free entries, but we got the tail of the chain
-            self.extend_directory_chain(tail, name, ino);
+            match self.fs.cache.retrieve_directory_block(self.position.next) {
+                Err(err) => {
+                    self.position = Position {next: 0, block: 0, slot: 0, index: None};
+                    if let Err(err) = self.fs.truncate_chain(err) {
+                        return Some(Err(err));
+                    }
+                }
+                Ok(db) => {
+                    self.entries = db.entries.iter().map(|entry| (entry.ino, entry.kind, entry.name.to_string())).collect::<Vec<_>>().into_iter();
+                    self.position.block = self.position.next;
+                    self.position.slot = 0;
+                    self.position.next = db.next;
+                }
+            }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,246 @@
+//
+// C interface to PtfsHandle, see include/ptfs.h
+//
+// All functions return 0 or a positive count on success, and a negated
+// errno value on failure.
+//
+// ptfs_create() and ptfs_open() return NULL on failure instead, and the
+// negated errno through their err argument.
+//
+// Strings must be NUL terminated UTF-8. Handles come from ptfs_create()
+// or ptfs_open() and must not be used after ptfs_close().
+//
+
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::{c_char, c_int};
+
+use log::warn;
+
+use crate::error::PtfsError;
+use crate::handle::PtfsHandle;
+use crate::path_tag_fs::MountMode;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    extern "C" fn collect(ino: u64, name: *const c_char, user_data: *mut c_void) {
+        let found = unsafe { &mut *(user_data as *mut Vec<(u64, String)>) };
+        let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap().to_string();
+        found.push((ino, name));
+    }
+
+    #[test]
+    fn test_ffi_roundtrip() {
+        let image = CString::new("/tmp/ptfs_test_ffi").unwrap();
+        let path = CString::new("/Pathes/note").unwrap();
+        let tag = CString::new("todo").unwrap();
+        let text = b"buy milk";
+        let _ = std::fs::remove_file("/tmp/ptfs_test_ffi");
+
+        unsafe {
+            let mut err = -1;
+            let handle = ptfs_create(image.as_ptr(), 64, &mut err);
+            assert!(!handle.is_null());
+            assert_eq!(err, 0);
+            assert_eq!(ptfs_write_file(handle, path.as_ptr(), text.as_ptr(), text.len()), 0);
+            assert_eq!(ptfs_tag(handle, path.as_ptr(), tag.as_ptr()), 0);
+            assert_eq!(ptfs_tag(handle, path.as_ptr(), tag.as_ptr()), -libc::EEXIST);
+            assert_eq!(ptfs_close(handle), 0);
+
+            // a failed open tells why through err
+            let missing = CString::new("/tmp/ptfs_test_ffi_missing").unwrap();
+            assert!(ptfs_open(missing.as_ptr(), 1, &mut err).is_null());
+            assert_eq!(err, -libc::EIO);
+            assert!(ptfs_open(ptr::null(), 1, ptr::null_mut()).is_null());
+
+            let handle = ptfs_open(image.as_ptr(), 1, ptr::null_mut());
+            assert!(!handle.is_null());
+
+            // a short buffer still reports the full size
+            let mut buf = [0u8; 3];
+            assert_eq!(ptfs_read_file(handle, path.as_ptr(), buf.as_mut_ptr(), buf.len()), text.len() as i64);
+            assert_eq!(&buf, b"buy");
+
+            let mut found: Vec<(u64, String)> = Vec::new();
+            let tags = [tag.as_ptr()];
+            let count = ptfs_query(handle, tags.as_ptr(), tags.len(), collect, &mut found as *mut _ as *mut c_void);
+            assert_eq!(count, 1);
+            assert_eq!(found[0].1, "note");
+
+            assert_eq!(ptfs_untag(handle, path.as_ptr(), tag.as_ptr()), -libc::EROFS);
+            assert_eq!(ptfs_read_file(handle, ptr::null(), buf.as_mut_ptr(), buf.len()), -libc::EINVAL as i64);
+            assert_eq!(ptfs_close(handle), 0);
+        }
+    }
+}
+
+
+// called once per query result, name is only valid during the call
+pub type PtfsQueryCallback = extern "C" fn(ino: u64, name: *const c_char, user_data: *mut c_void);
+
+
+fn error_code(err: &PtfsError) -> c_int {
+    -err.to_errno()
+}
+
+
+unsafe fn to_str<'a>(text: *const c_char) -> Result<&'a str, PtfsError> {
+    if text.is_null() {
+        return Err(PtfsError::InvalidArgument);
+    }
+
+    CStr::from_ptr(text).to_str().map_err(|_| PtfsError::InvalidArgument)
+}
+
+
+unsafe fn to_handle<'a>(handle: *mut PtfsHandle) -> Result<&'a mut PtfsHandle, PtfsError> {
+    handle.as_mut().ok_or(PtfsError::InvalidArgument)
+}
+
+
+// stores the negated errno in err, unless it is NULL
+unsafe fn into_raw(result: Result<PtfsHandle, PtfsError>, err: *mut c_int) -> *mut PtfsHandle {
+    let (handle, code) = match result {
+        Err(error) => {
+            warn!("ptfs_ffi: {}", error);
+            (std::ptr::null_mut(), error_code(&error))
+        }
+        Ok(handle) => (Box::into_raw(Box::new(handle)), 0),
+    };
+
+    if let Some(err) = err.as_mut() {
+        *err = code;
+    }
+    handle
+}
+
+
+// Formats a new image of size blocks and opens it for writing.
+// Returns NULL on failure, with the negated errno in err if not NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_create(image: *const c_char, size: u64, err: *mut c_int) -> *mut PtfsHandle {
+    into_raw(to_str(image).and_then(|image| PtfsHandle::create_image(image, size)), err)
+}
+
+
+// Opens an existing image. Returns NULL on failure, with the negated
+// errno in err if not NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_open(image: *const c_char, read_only: c_int, err: *mut c_int) -> *mut PtfsHandle {
+    let mode = if read_only != 0 {MountMode::ReadOnly} else {MountMode::ReadWrite};
+    into_raw(to_str(image).and_then(|image| PtfsHandle::open_image(image, mode)), err)
+}
+
+
+// Writes pending changes and frees the handle, also if writing fails.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_close(handle: *mut PtfsHandle) -> c_int {
+    if handle.is_null() {
+        return error_code(&PtfsError::InvalidArgument);
+    }
+
+    match Box::from_raw(handle).close() {
+        Err(err) => error_code(&err),
+        Ok(()) => 0,
+    }
+}
+
+
+// Copies up to len bytes of the file into buf and returns the file size,
+// which may be larger than len.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_read_file(handle: *mut PtfsHandle, path: *const c_char, buf: *mut u8, len: usize) -> i64 {
+    let result = to_handle(handle).and_then(|handle| handle.read_file(to_str(path)?));
+
+    match result {
+        Err(err) => error_code(&err) as i64,
+        Ok(data) => {
+            let count = std::cmp::min(len, data.len());
+            if count > 0 {
+                if buf.is_null() {
+                    return error_code(&PtfsError::InvalidArgument) as i64;
+                }
+                std::ptr::copy_nonoverlapping(data.as_ptr(), buf, count);
+            }
+            data.len() as i64
+        }
+    }
+}
+
+
+// Replaces the contents of the file, creates it if needed.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_write_file(handle: *mut PtfsHandle, path: *const c_char, data: *const u8, len: usize) -> c_int {
+    if data.is_null() && len > 0 {
+        return error_code(&PtfsError::InvalidArgument);
+    }
+
+    let data = if len > 0 {std::slice::from_raw_parts(data, len)} else {&[]};
+    let result = to_handle(handle).and_then(|handle| handle.write_file(to_str(path)?, data));
+
+    match result {
+        Err(err) => error_code(&err),
+        Ok(()) => 0,
+    }
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_tag(handle: *mut PtfsHandle, path: *const c_char, tag: *const c_char) -> c_int {
+    let result = to_handle(handle).and_then(|handle| handle.tag(to_str(path)?, to_str(tag)?));
+
+    match result {
+        Err(err) => error_code(&err),
+        Ok(()) => 0,
+    }
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_untag(handle: *mut PtfsHandle, path: *const c_char, tag: *const c_char) -> c_int {
+    let result = to_handle(handle).and_then(|handle| handle.untag(to_str(path)?, to_str(tag)?));
+
+    match result {
+        Err(err) => error_code(&err),
+        Ok(()) => 0,
+    }
+}
+
+
+// Calls callback for each file that carries all count tags and returns
+// the number of files found.
+#[no_mangle]
+pub unsafe extern "C" fn ptfs_query(handle: *mut PtfsHandle, tags: *const *const c_char, count: usize,
+                                    callback: PtfsQueryCallback, user_data: *mut c_void) -> c_int {
+    if tags.is_null() && count > 0 {
+        return error_code(&PtfsError::InvalidArgument);
+    }
+
+    let mut names = Vec::new();
+    for i in 0..count {
+        match to_str(*tags.add(i)) {
+            Err(err) => return error_code(&err),
+            Ok(name) => names.push(name),
+        }
+    }
+
+    let result = to_handle(handle).and_then(|handle| handle.query(&names));
+
+    match result {
+        Err(err) => error_code(&err),
+        Ok(files) => {
+            for (ino, name) in &files {
+                // names with inner NUL bytes cannot be handed to C
+                if let Ok(name) = CString::new(name.as_str()) {
+                    callback(*ino, name.as_ptr(), user_data);
+                }
+            }
+            files.len() as c_int
+        }
+    }
+}
@@ -0,0 +1,871 @@
+//
+// Tag queries as set algebra over inodes
+//
+// Expressions combine tag names with AND, OR, NOT and parentheses. Terms
+// next to each other are joined by AND, so "rock loud" is the same as
+// "rock AND loud" or "rock & loud". A term can also compare an attribute,
+// "size>1M", "rating>=4" or "mtime<30d" (modified within the last 30 days), or test
+// the file itself, "kind=symlink", "name=*.mp3" or "comment=*live*".
+//
+// Tag names and the values of name and comment are percent-encoded where
+// they would clash with the syntax, "%26" is '&', "%20" a space and "%25"
+// the percent sign itself, see escape().
+//
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::CString;
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
+use fuser::FileType;
+
+use crate::attr_index::IndexedAttr;
+use crate::error::PtfsError;
+use crate::events::ChangeEvent;
+use crate::path_tag_fs::{PathTagFs, PATHS_DIR};
+use crate::stats::QueryCacheStats;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_tag_fs::{MountMode, INO_ROOT};
+
+    fn tag(name: &str) -> Box<Query> {
+        Box::new(Query::Tag(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("rock").unwrap(), *tag("rock"));
+        assert_eq!(parse("rock loud").unwrap(), Query::And(tag("rock"), tag("loud")));
+        assert_eq!(parse("a OR b c").unwrap(), Query::Or(tag("a"), Box::new(Query::And(tag("b"), tag("c")))));
+        assert_eq!(parse("(a OR b) AND NOT c").unwrap(),
+                   Query::And(Box::new(Query::Or(tag("a"), tag("b"))), Box::new(Query::Not(tag("c")))));
+        assert_eq!(parse("size>1M").unwrap(), Query::Attr(Attr::Size, Cmp::Greater, 1024*1024));
+        assert_eq!(parse("mtime<30d").unwrap(), Query::Attr(Attr::Mtime, Cmp::Less, 30*24*60*60));
+        assert_eq!(parse("size=10").unwrap(), Query::Attr(Attr::Size, Cmp::Equal, 10));
+        assert_eq!(parse("size>=10").unwrap(), Query::Attr(Attr::Size, Cmp::AtLeast, 10));
+        assert_eq!(parse("music & size>50M").unwrap(),
+                   Query::And(tag("music"), Box::new(Query::Attr(Attr::Size, Cmp::Greater, 50*1024*1024))));
+        assert_eq!(parse("a&b").unwrap(), Query::And(tag("a"), tag("b")));
+        assert_eq!(parse("mtime<2023-01-01").unwrap(), Query::Attr(Attr::MtimeDate, Cmp::Less, 1672531200));
+        assert_eq!(parse("crtime>=1970-01-02").unwrap(), Query::Attr(Attr::CrtimeDate, Cmp::AtLeast, 86400));
+        assert_eq!(parse("crtime<2h").unwrap(), Query::Attr(Attr::Crtime, Cmp::Less, 2*60*60));
+        assert_eq!(parse("owner=1000").unwrap(), Query::Attr(Attr::Owner, Cmp::Equal, 1000));
+        assert_eq!(parse("owner=root").unwrap(), Query::Attr(Attr::Owner, Cmp::Equal, 0));
+        assert_eq!(parse("kind=dir").unwrap(), Query::Kind(FileType::Directory));
+        assert_eq!(parse("name=*.mp3").unwrap(), Query::Name("*.mp3".to_string()));
+        assert_eq!(parse("rating>=4").unwrap(), Query::Attr(Attr::Rating, Cmp::AtLeast, 4));
+        assert_eq!(parse("comment=*Live*").unwrap(), Query::Comment("*live*".to_string()));
+        assert_eq!(parse("a<b").unwrap(), *tag("a<b"));
+        assert_eq!(parse("rock%26roll%20%28live%29").unwrap(), *tag("rock&roll (live)"));
+        assert_eq!(parse("size%3E1M 100%25").unwrap(), Query::And(tag("size>1M"), tag("100%")));
+        assert_eq!(parse("%4FR OR b").unwrap(), Query::Or(tag("OR"), tag("b")));
+        assert_eq!(parse("work/to-do%2Fnow").unwrap(), *tag("work/to-do/now"));
+        assert_eq!(parse("name=my%20song.mp3").unwrap(), Query::Name("my song.mp3".to_string()));
+
+        for bad in ["", "(a", "a)", "a OR", "NOT", "size>", "size>1X", "mtime<3M", "a &", "& a",
+                    "mtime<2023-13-01", "mtime<1969-12-31", "owner=nobody-here", "kind=disk", "kind>file", "name<a",
+                    "100%", "a%2", "a%zz", "%FF"] {
+            assert!(matches!(parse(bad), Err(PtfsError::InvalidArgument)), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        for name in ["rock&roll (live)", "a+b-c", "100%", "size>1M", "OR", "tab\there", "Grüße", "plain"] {
+            let escaped = escape(name);
+            assert!(!escaped.contains([' ', '&', '(', '+', '-', '>']), "{}", escaped);
+            assert_eq!(parse(&escaped).unwrap(), *tag(name));
+        }
+        assert_eq!(escape("a b"), "a%20b");
+        assert_eq!(escape("Grüße"), "Grüße");
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_query", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let music = fs.mkdir(paths, &"music".to_string()).unwrap().ino;
+        let song = fs.mknod(music, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        let tune = fs.mknod(music, &"tune".to_string(), FileType::RegularFile).unwrap().ino;
+        let note = fs.mknod(paths, &"note".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.write(tune, None, 0, &[0; 3000]).unwrap();
+        let link = fs.mknod(music, &"link.mp3".to_string(), FileType::Symlink).unwrap().ino;
+
+        fs.add_tag(song, "rock").unwrap();
+        fs.add_tag(song, "loud").unwrap();
+        fs.add_tag(tune, "rock").unwrap();
+
+        let set = |inos: &[u64]| inos.iter().copied().collect::<BTreeSet<u64>>();
+        assert_eq!(fs.query("rock").unwrap(), set(&[song, tune]));
+        assert_eq!(fs.query("rock loud").unwrap(), set(&[song]));
+        assert_eq!(fs.query("loud OR NOT rock").unwrap(), set(&[song, note, link]));
+        assert_eq!(fs.query("NOT (rock OR loud)").unwrap(), set(&[note, link]));
+        assert_eq!(fs.query("rock size>2K").unwrap(), set(&[tune]));
+        assert_eq!(fs.query("rock & size<=3000").unwrap(), set(&[song, tune]));
+        assert_eq!(fs.query("mtime<1d").unwrap(), set(&[song, tune, note, link]));
+        assert_eq!(fs.query("mtime>1d").unwrap(), set(&[]));
+        assert_eq!(fs.query("rock & mtime>2023-01-01").unwrap(), set(&[song, tune]));
+        assert_eq!(fs.query("crtime<2023-01-01").unwrap(), set(&[]));
+        assert_eq!(fs.query("kind=symlink").unwrap(), set(&[link]));
+        assert_eq!(fs.query("kind=dir").unwrap(), set(&[music]));
+        assert_eq!(fs.query("name=*.mp3 OR name=s?ng").unwrap(), set(&[song, link]));
+        fs.set_comment(tune, "Recorded live").unwrap();
+        assert_eq!(fs.query("comment=*LIVE*").unwrap(), set(&[tune]));
+        let uid = fs.getattr(song).unwrap().uid;
+        assert_eq!(fs.query(&format!("rock owner={}", uid)).unwrap(), set(&[song, tune]));
+        assert_eq!(fs.query("unknown").unwrap(), set(&[]));
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let mut cache = QueryCache::default();
+        let set = BTreeSet::from([7]);
+        cache.insert(None, "rock", &parse("rock").unwrap(), set.clone(), false);
+        cache.insert(Some("work"), "rock", &parse("rock").unwrap(), set.clone(), false);
+        cache.insert(None, "NOT loud", &parse("NOT loud").unwrap(), set.clone(), false);
+        cache.insert(None, "size>1K", &parse("size>1K").unwrap(), set.clone(), false);
+        cache.insert(None, "trip", &parse("trip").unwrap(), set.clone(), true);
+
+        assert_eq!(cache.get(None, "rock"), Some(set.clone()));
+        assert_eq!(cache.get(None, "size>1K"), None);
+        assert_eq!(cache.stats().cached, 4);
+
+        cache.invalidate(&ChangeEvent::Tagged {ino: 1, tag: "work/rock".to_string()});
+        assert_eq!(cache.get(Some("work"), "rock"), None);
+        assert!(cache.get(None, "rock").is_some());
+
+        cache.invalidate(&ChangeEvent::Created {parent: 1, ino: 8, name: "new".to_string()});
+        assert_eq!(cache.get(None, "NOT loud"), None);
+        assert_eq!(cache.get(None, "trip"), None);
+        assert!(cache.get(None, "rock").is_some());
+
+        cache.invalidate(&ChangeEvent::Deleted {parent: 1, ino: 7, name: "old".to_string()});
+        assert_eq!(cache.get(None, "rock"), None);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.invalidations, stats.cached), (3, 5, 4, 0));
+    }
+
+    #[test]
+    fn test_cache_limit() {
+        let mut cache = QueryCache::default();
+        cache.set_max_inodes(5);
+        let query = parse("rock").unwrap();
+
+        cache.insert(None, "a", &query, BTreeSet::from([1, 2]), false);
+        cache.insert(None, "b", &query, BTreeSet::from([3, 4]), false);
+        cache.get(None, "a");
+        cache.insert(None, "c", &query, BTreeSet::from([5, 6]), false);
+        cache.insert(None, "d", &query, BTreeSet::from([1, 2, 3, 4, 5, 6]), false);
+
+        // the least recently used result made room, too large ones are not kept
+        assert!(cache.get(None, "a").is_some());
+        assert!(cache.get(None, "b").is_none());
+        assert!(cache.get(None, "c").is_some());
+        assert!(cache.get(None, "d").is_none());
+    }
+
+    #[test]
+    fn test_cached_queries() {
+        let mut fs = PathTagFs::new("/tmp/ptfs_test_query_cache", MountMode::ReadWrite).unwrap();
+        fs.mkfs(INO_ROOT, 64).unwrap();
+        let paths = fs.lookup(INO_ROOT, &PATHS_DIR.to_string()).unwrap().ino;
+        let song = fs.mknod(paths, &"song".to_string(), FileType::RegularFile).unwrap().ino;
+        fs.add_tag(song, "rock").unwrap();
+
+        assert_eq!(fs.query("rock").unwrap(), BTreeSet::from([song]));
+        assert_eq!(fs.query("NOT rock").unwrap(), BTreeSet::new());
+        assert_eq!(fs.query("rock").unwrap(), BTreeSet::from([song]));
+        assert_eq!(fs.stats().queries.hits, 1);
+
+        // changes show up in the next result
+        let tune = fs.mknod(paths, &"tune".to_string(), FileType::RegularFile).unwrap().ino;
+        assert_eq!(fs.query("NOT rock").unwrap(), BTreeSet::from([tune]));
+        fs.add_tag(tune, "rock").unwrap();
+        assert_eq!(fs.query("rock").unwrap(), BTreeSet::from([song, tune]));
+        fs.remove_tag(song, "rock").unwrap();
+        assert_eq!(fs.query("rock").unwrap(), BTreeSet::from([tune]));
+        assert_eq!(fs.stats().queries.hits, 1);
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    Tag(String),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Attr(Attr, Cmp, u64),
+    Kind(FileType),
+
+    // a glob pattern for the entry name, with * and ?
+    Name(String),
+
+    // a glob pattern for the comment, upper and lower case are the same
+    Comment(String),
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Attr {
+    // in bytes
+    Size,
+
+    // age of the last modification or the creation in seconds
+    Mtime,
+    Crtime,
+
+    // seconds since 1970, for dates like "mtime<2023-01-01"
+    MtimeDate,
+    CrtimeDate,
+
+    // user id
+    Owner,
+
+    // stars, 0 for files without a rating
+    Rating,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cmp {
+    Less,
+    Greater,
+    Equal,
+    AtMost,
+    AtLeast,
+}
+
+
+impl Cmp {
+
+    fn holds(&self, actual: u64, value: u64) -> bool {
+        match self {
+            Cmp::Less => actual < value,
+            Cmp::Greater => actual > value,
+            Cmp::Equal => actual == value,
+            Cmp::AtMost => actual <= value,
+            Cmp::AtLeast => actual >= value,
+        }
+    }
+}
+
+
+pub fn parse(text: &str) -> Result<Query, PtfsError> {
+    let tokens = tokenize(text);
+    let mut pos = 0;
+    let query = parse_or(&tokens, &mut pos)?;
+
+    if pos != tokens.len() {
+        return Err(PtfsError::InvalidArgument);
+    }
+
+    Ok(query)
+}
+
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+
+    for c in text.chars() {
+        if c.is_whitespace() || c == '(' || c == ')' || c == '&' {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            if !c.is_whitespace() {
+                tokens.push(c.to_string());
+            }
+        }
+        else {
+            word.push(c);
+        }
+    }
+
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+
+    tokens
+}
+
+
+fn parse_or(tokens: &[String], pos: &mut usize) -> Result<Query, PtfsError> {
+    let mut query = parse_and(tokens, pos)?;
+
+    while tokens.get(*pos).is_some_and(|token| token == "OR") {
+        *pos += 1;
+        query = Query::Or(Box::new(query), Box::new(parse_and(tokens, pos)?));
+    }
+
+    Ok(query)
+}
+
+
+fn parse_and(tokens: &[String], pos: &mut usize) -> Result<Query, PtfsError> {
+    let mut query = parse_not(tokens, pos)?;
+
+    loop {
+        match tokens.get(*pos).map(|token| token.as_str()) {
+            None | Some("OR") | Some(")") => return Ok(query),
+            Some("AND") | Some("&") => *pos += 1,
+            Some(_) => {}
+        }
+
+        query = Query::And(Box::new(query), Box::new(parse_not(tokens, pos)?));
+    }
+}
+
+
+fn parse_not(tokens: &[String], pos: &mut usize) -> Result<Query, PtfsError> {
+    let token = tokens.get(*pos).ok_or(PtfsError::InvalidArgument)?;
+    *pos += 1;
+
+    match token.as_str() {
+        "NOT" => Ok(Query::Not(Box::new(parse_not(tokens, pos)?))),
+        "(" => {
+            let query = parse_or(tokens, pos)?;
+            if tokens.get(*pos).is_none_or(|token| token != ")") {
+                return Err(PtfsError::InvalidArgument);
+            }
+            *pos += 1;
+            Ok(query)
+        }
+        ")" | "AND" | "&" | "OR" => Err(PtfsError::InvalidArgument),
+        _ => parse_term(token),
+    }
+}
+
+
+fn parse_term(token: &str) -> Result<Query, PtfsError> {
+    let split = token.find(['<', '>', '=']);
+
+    let (name, rest) = match split {
+        None => return Ok(Query::Tag(unescape(token)?)),
+        Some(i) => (&token[..i], &token[i..]),
+    };
+
+    let (cmp, value) = if let Some(value) = rest.strip_prefix("<=") {
+        (Cmp::AtMost, value)
+    }
+    else if let Some(value) = rest.strip_prefix(">=") {
+        (Cmp::AtLeast, value)
+    }
+    else {
+        let cmp = match &rest[..1] {
+            "<" => Cmp::Less,
+            ">" => Cmp::Greater,
+            _ => Cmp::Equal,
+        };
+        (cmp, &rest[1..])
+    };
+
+    match name {
+        "size" => Ok(Query::Attr(Attr::Size, cmp, parse_value(Attr::Size, value)?)),
+        "mtime" if value.contains('-') => Ok(Query::Attr(Attr::MtimeDate, cmp, parse_date(value)?)),
+        "mtime" => Ok(Query::Attr(Attr::Mtime, cmp, parse_value(Attr::Mtime, value)?)),
+        "crtime" if value.contains('-') => Ok(Query::Attr(Attr::CrtimeDate, cmp, parse_date(value)?)),
+        "crtime" => Ok(Query::Attr(Attr::Crtime, cmp, parse_value(Attr::Crtime, value)?)),
+        "owner" => Ok(Query::Attr(Attr::Owner, cmp, parse_owner(value)?)),
+        "rating" => Ok(Query::Attr(Attr::Rating, cmp, parse_value(Attr::Rating, value)?)),
+        "kind" | "name" | "comment" if cmp != Cmp::Equal || value.is_empty() => Err(PtfsError::InvalidArgument),
+        "kind" => Ok(Query::Kind(parse_kind(value)?)),
+        "name" => Ok(Query::Name(unescape(value)?)),
+        "comment" => Ok(Query::Comment(unescape(value)?.to_lowercase())),
+        // tag names may contain these characters too
+        _ => Ok(Query::Tag(unescape(token)?)),
+    }
+}
+
+
+// Percent-encodes what the parser would take apart or read as an operator,
+// including + and - which are kept for later operators. Keywords like OR
+// get their first letter encoded.
+pub fn escape(name: &str) -> String {
+    if ["AND", "OR", "NOT"].contains(&name) {
+        return format!("%{:02X}{}", name.as_bytes()[0], &name[1..]);
+    }
+
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if c.is_whitespace() || c.is_control() || "()&<>=%+-".contains(c) {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                escaped += &format!("%{:02X}", byte);
+            }
+        }
+        else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+
+fn unescape(text: &str) -> Result<String, PtfsError> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+
+        let hex = tail.get(..2).and_then(|hex| std::str::from_utf8(hex).ok()).ok_or(PtfsError::InvalidArgument)?;
+        bytes.push(u8::from_str_radix(hex, 16).map_err(|_| PtfsError::InvalidArgument)?);
+        rest = &tail[2..];
+    }
+
+    String::from_utf8(bytes).map_err(|_| PtfsError::InvalidArgument)
+}
+
+
+// sizes take K, M, G suffixes, ages s, m, h, d and default to seconds
+fn parse_value(attr: Attr, value: &str) -> Result<u64, PtfsError> {
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let number: u64 = digits.parse().map_err(|_| PtfsError::InvalidArgument)?;
+
+    let unit = match (attr, &value[digits.len()..]) {
+        (_, "") => 1,
+        (Attr::Size, "K") => 1024,
+        (Attr::Size, "M") => 1024*1024,
+        (Attr::Size, "G") => 1024*1024*1024,
+        (Attr::Mtime | Attr::Crtime, "s") => 1,
+        (Attr::Mtime | Attr::Crtime, "m") => 60,
+        (Attr::Mtime | Attr::Crtime, "h") => 60*60,
+        (Attr::Mtime | Attr::Crtime, "d") => 24*60*60,
+        _ => return Err(PtfsError::InvalidArgument),
+    };
+
+    number.checked_mul(unit).ok_or(PtfsError::InvalidArgument)
+}
+
+
+// YYYY-MM-DD as seconds since 1970 at midnight UTC
+fn parse_date(value: &str) -> Result<u64, PtfsError> {
+    let parts = value.split('-').map(|part| part.parse::<u64>()).collect::<Result<Vec<_>, _>>();
+
+    let (year, month, day) = match parts.as_deref() {
+        Ok(&[year, month, day]) if year >= 1970 && (1..=12).contains(&month) && (1..=31).contains(&day) => (year, month, day),
+        _ => return Err(PtfsError::InvalidArgument),
+    };
+
+    // days from the civil calendar, with the year starting in March
+    let year = if month <= 2 {year - 1} else {year};
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Ok(days * 24*60*60)
+}
+
+
+// a user id or a user name
+fn parse_owner(value: &str) -> Result<u64, PtfsError> {
+    if let Ok(uid) = value.parse::<u32>() {
+        return Ok(uid as u64);
+    }
+
+    let name = CString::new(value).map_err(|_| PtfsError::InvalidArgument)?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(PtfsError::InvalidArgument);
+    }
+
+    Ok(unsafe { (*entry).pw_uid } as u64)
+}
+
+
+fn parse_kind(value: &str) -> Result<FileType, PtfsError> {
+    match value {
+        "file" => Ok(FileType::RegularFile),
+        "dir" | "directory" => Ok(FileType::Directory),
+        "symlink" => Ok(FileType::Symlink),
+        "pipe" => Ok(FileType::NamedPipe),
+        "socket" => Ok(FileType::Socket),
+        "char_device" => Ok(FileType::CharDevice),
+        "block_device" => Ok(FileType::BlockDevice),
+        _ => Err(PtfsError::InvalidArgument),
+    }
+}
+
+
+// the values passing a comparison, None if there are none
+fn cmp_range(cmp: Cmp, value: u64) -> Option<RangeInclusive<u64>> {
+    match cmp {
+        Cmp::Less => Some(0..=value.checked_sub(1)?),
+        Cmp::Greater => Some(value.checked_add(1)?..=u64::MAX),
+        Cmp::Equal => Some(value..=value),
+        Cmp::AtMost => Some(0..=value),
+        Cmp::AtLeast => Some(value..=u64::MAX),
+    }
+}
+
+
+// * stands for any number of characters, ? for one
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some(('?', rest)) => !name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+
+impl Query {
+
+    // the names of the tags the query reads
+    pub fn tags(&self, namespace: Option<&str>, tags: &mut Vec<String>) {
+        match self {
+            Query::Tag(tag) => tags.push(match namespace {
+                Some(namespace) => format!("{}/{}", namespace, tag),
+                None => tag.to_string(),
+            }),
+            Query::Not(query) => query.tags(namespace, tags),
+            Query::And(one, two) | Query::Or(one, two) => {
+                one.tags(namespace, tags);
+                two.tags(namespace, tags);
+            }
+            Query::Attr(..) | Query::Kind(_) | Query::Name(_) | Query::Comment(_) => {}
+        }
+    }
+
+
+    // NOT and attribute terms read all files below /Pathes
+    fn reads_all_files(&self) -> bool {
+        match self {
+            Query::Tag(_) => false,
+            Query::Not(_) | Query::Attr(..) | Query::Kind(_) | Query::Name(_) | Query::Comment(_) => true,
+            Query::And(one, two) | Query::Or(one, two) => one.reads_all_files() || two.reads_all_files(),
+        }
+    }
+
+
+    fn reads_attributes(&self) -> bool {
+        match self {
+            Query::Tag(_) => false,
+            Query::Not(query) => query.reads_attributes(),
+            Query::And(one, two) | Query::Or(one, two) => one.reads_attributes() || two.reads_attributes(),
+            Query::Attr(..) | Query::Kind(_) | Query::Name(_) | Query::Comment(_) => true,
+        }
+    }
+
+
+    // Returns the inodes matching the query. NOT and attribute terms work
+    // on all files below /Pathes, "kind=dir" on the directories there.
+    // Unknown tags match nothing.
+    pub fn evaluate(&self, fs: &mut PathTagFs) -> Result<BTreeSet<u64>, PtfsError> {
+        let mut all = None;
+        self.evaluate_with(fs, None, &mut all)
+    }
+
+
+    // tags are looked up in namespace, "urgent" means "work/urgent" then
+    pub fn evaluate_in(&self, fs: &mut PathTagFs, namespace: &str) -> Result<BTreeSet<u64>, PtfsError> {
+        let mut all = None;
+        self.evaluate_with(fs, Some(namespace), &mut all)
+    }
+
+
+    fn evaluate_with(&self, fs: &mut PathTagFs, namespace: Option<&str>, all: &mut Option<BTreeSet<u64>>) -> Result<BTreeSet<u64>, PtfsError> {
+        match self {
+            Query::Tag(tag) => {
+                let tag = match namespace {
+                    Some(namespace) => format!("{}/{}", namespace, tag),
+                    None => tag.to_string(),
+                };
+
+                match fs.tag_members(&tag) {
+                    Ok(members) => Ok(members),
+                    Err(PtfsError::NotFound) => Ok(BTreeSet::new()),
+                    Err(err) => Err(err),
+                }
+            }
+            Query::Not(query) => {
+                let excluded = query.evaluate_with(fs, namespace, all)?;
+                Ok(all_files(fs, all)?.difference(&excluded).copied().collect())
+            }
+            Query::And(one, two) => {
+                let one = one.evaluate_with(fs, namespace, all)?;
+                Ok(one.intersection(&two.evaluate_with(fs, namespace, all)?).copied().collect())
+            }
+            Query::Or(one, two) => {
+                let one = one.evaluate_with(fs, namespace, all)?;
+                Ok(one.union(&two.evaluate_with(fs, namespace, all)?).copied().collect())
+            }
+            Query::Attr(..) | Query::Kind(_) | Query::Name(_) | Query::Comment(_) => {
+                let now = SystemTime::now();
+                let candidates = match self {
+                    Query::Kind(FileType::Directory) => all_dirs(fs)?,
+                    _ => match self.indexed_candidates(fs, now)? {
+                        Some(candidates) => candidates,
+                        None => all_files(fs, all)?.clone(),
+                    },
+                };
+
+                let mut matches = BTreeSet::new();
+                for ino in candidates {
+                    if self.term_matches(fs, ino, now)? {
+                        matches.insert(ino);
+                    }
+                }
+
+                Ok(matches)
+            }
+        }
+    }
+
+
+    // Size, mtime and rating terms take the files from the attribute index. It
+    // narrows them down, term_matches() decides.
+    fn indexed_candidates(&self, fs: &mut PathTagFs, now: SystemTime) -> Result<Option<BTreeSet<u64>>, PtfsError> {
+        let (attr, range) = match self {
+            Query::Attr(attr, cmp, value) => match cmp_range(*cmp, *value) {
+                Some(range) => (*attr, range),
+                None => return Ok(Some(BTreeSet::new())),
+            },
+            _ => return Ok(None),
+        };
+
+        match attr {
+            Attr::Size => fs.indexed_files(IndexedAttr::Size, range),
+            Attr::MtimeDate => fs.indexed_files(IndexedAttr::Mtime, range),
+            Attr::Rating => fs.indexed_files(IndexedAttr::Rating, range),
+            Attr::Mtime => {
+                // the index has whole seconds, files from the future are of age 0
+                let now = now.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+                let oldest = now.saturating_sub(*range.end()).saturating_sub(1);
+                let newest = if *range.start() == 0 {u64::MAX} else {now.saturating_sub(*range.start()) + 1};
+                fs.indexed_files(IndexedAttr::Mtime, oldest..=newest)
+            }
+            _ => Ok(None),
+        }
+    }
+
+
+    fn term_matches(&self, fs: &mut PathTagFs, ino: u64, now: SystemTime) -> Result<bool, PtfsError> {
+        let attrs = fs.getattr(ino)?;
+        let age = |time: SystemTime| now.duration_since(time).map_or(0, |age| age.as_secs());
+        let since_epoch = |time: SystemTime| time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+
+        match self {
+            Query::Attr(attr, cmp, value) => {
+                let actual = match attr {
+                    Attr::Size => attrs.size,
+                    Attr::Mtime => age(attrs.mtime),
+                    Attr::Crtime => age(attrs.crtime),
+                    Attr::MtimeDate => since_epoch(attrs.mtime),
+                    Attr::CrtimeDate => since_epoch(attrs.crtime),
+                    Attr::Owner => attrs.uid as u64,
+                    Attr::Rating => fs.rating(ino)? as u64,
+                };
+                Ok(cmp.holds(actual, *value))
+            }
+            Query::Kind(kind) => Ok(attrs.kind == *kind),
+            Query::Name(pattern) => {
+                let pattern = pattern.chars().collect::<Vec<_>>();
+                let name = fs.retrieve_entry_block(ino)?.name.chars().collect::<Vec<_>>();
+                Ok(glob_matches(&pattern, &name))
+            }
+            Query::Comment(pattern) => {
+                let pattern = pattern.chars().collect::<Vec<_>>();
+                match fs.comment(ino)? {
+                    Some(comment) => Ok(glob_matches(&pattern, &comment.to_lowercase().chars().collect::<Vec<_>>())),
+                    None => Ok(false),
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+
+// collects all files below /Pathes once per evaluation
+fn all_files<'a>(fs: &mut PathTagFs, all: &'a mut Option<BTreeSet<u64>>) -> Result<&'a BTreeSet<u64>, PtfsError> {
+    if all.is_none() {
+        let paths = fs.lookup(fs.root(), &PATHS_DIR.to_string())?.ino;
+        let mut files = BTreeSet::new();
+        let mut visited = HashSet::from([paths]);
+        let mut dirs = vec![paths];
+
+        while let Some(dir) = dirs.pop() {
+            for (ino, kind, name) in fs.list_children(dir)? {
+                if name == "." || name == ".." {
+                    continue;
+                }
+
+                if kind == FileType::Directory {
+                    if visited.insert(ino) {
+                        dirs.push(ino);
+                    }
+                }
+                else {
+                    files.insert(ino);
+                }
+            }
+        }
+
+        *all = Some(files);
+    }
+
+    Ok(all.as_ref().unwrap())
+}
+
+
+// the directories below /Pathes, without /Pathes itself
+fn all_dirs(fs: &mut PathTagFs) -> Result<BTreeSet<u64>, PtfsError> {
+    let paths = fs.lookup(fs.root(), &PATHS_DIR.to_string())?.ino;
+    let mut found = BTreeSet::new();
+    let mut dirs = vec![paths];
+
+    while let Some(dir) = dirs.pop() {
+        for (ino, kind, name) in fs.list_children(dir)? {
+            if kind == FileType::Directory && name != "." && name != ".." && ino != paths && found.insert(ino) {
+                dirs.push(ino);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+
+// this many results are kept, the least recently used goes first
+const MAX_CACHED_QUERIES:usize = 64;
+
+
+struct CachedQuery {
+    result: BTreeSet<u64>,
+    tags: Vec<String>,
+    all_files: bool,
+    last_used: u64,
+}
+
+
+// Results by namespace and expression, dropped when a change event says
+// that they may be stale. Writes send no events, so queries comparing
+// attributes are not kept.
+pub struct QueryCache {
+    entries: HashMap<(Option<String>, String), CachedQuery>,
+    clock: u64,
+    stats: QueryCacheStats,
+
+    // inodes of all results together, see set_max_inodes()
+    max_inodes: usize,
+}
+
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        QueryCache {
+            entries: HashMap::new(),
+            clock: 0,
+            stats: QueryCacheStats::default(),
+            max_inodes: usize::MAX,
+        }
+    }
+}
+
+
+impl QueryCache {
+
+    pub fn get(&mut self, namespace: Option<&str>, expression: &str) -> Option<BTreeSet<u64>> {
+        self.clock += 1;
+
+        match self.entries.get_mut(&(namespace.map(str::to_string), expression.to_string())) {
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+            Some(entry) => {
+                self.stats.hits += 1;
+                entry.last_used = self.clock;
+                Some(entry.result.clone())
+            }
+        }
+    }
+
+
+    // subtrees tells that a tag of the query is on a directory, so new files
+    // below it change the result like they change NOT queries
+    pub fn insert(&mut self, namespace: Option<&str>, expression: &str, query: &Query, inodes: BTreeSet<u64>, subtrees: bool) {
+        if query.reads_attributes() || inodes.len() > self.max_inodes {
+            return;
+        }
+
+        while self.entries.len() >= MAX_CACHED_QUERIES || self.cached_inodes() + inodes.len() > self.max_inodes {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let mut read_tags = Vec::new();
+        query.tags(namespace, &mut read_tags);
+
+        self.entries.insert((namespace.map(str::to_string), expression.to_string()), CachedQuery {
+            result: inodes,
+            tags: read_tags,
+            all_files: query.reads_all_files() || subtrees,
+            last_used: self.clock,
+        });
+    }
+
+
+    // New files only change queries with NOT, deleted and moved ones may
+    // be in any result.
+    pub fn invalidate(&mut self, event: &ChangeEvent) {
+        let count = self.entries.len();
+
+        match event {
+            ChangeEvent::Tagged {tag, ..} | ChangeEvent::Untagged {tag, ..} => {
+                self.entries.retain(|_, entry| !entry.tags.contains(tag));
+            }
+            ChangeEvent::Created {..} => {
+                self.entries.retain(|_, entry| !entry.all_files);
+            }
+            ChangeEvent::Deleted {..} | ChangeEvent::Renamed {..} => {
+                self.entries.clear();
+            }
+            // queries reading attributes are not cached
+            ChangeEvent::Modified {..} => {}
+        }
+
+        self.stats.invalidations += (count - self.entries.len()) as u64;
+    }
+
+
+    // bounds the memory of the results, larger ones are not kept at all
+    pub fn set_max_inodes(&mut self, max_inodes: usize) {
+        self.max_inodes = max_inodes;
+        self.entries.clear();
+    }
+
+
+    fn cached_inodes(&self) -> usize {
+        self.entries.values().map(|entry| entry.result.len()).sum()
+    }
+
+
+    pub fn clear(&mut self) {
+        self.stats.invalidations += self.entries.len() as u64;
+        self.entries.clear();
+    }
+
+
+    pub fn stats(&self) -> QueryCacheStats {
+        QueryCacheStats {
+            cached: self.entries.len() as u64,
+            ..self.stats
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//
+// Counters of the block cache and of file system operations, for tuning
+// and for finding out where the time goes
+//
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::diagnostics::Warning;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_stats() {
+        let mut stats = OpStats::default();
+        stats.record("read", Duration::from_millis(3), true);
+        stats.record("read", Duration::from_millis(1), false);
+        stats.record("write", Duration::from_millis(2), true);
+
+        let read = &stats.ops()["read"];
+        assert_eq!((read.count, read.errors), (2, 1));
+        assert_eq!(read.total, Duration::from_millis(4));
+        assert_eq!(read.max, Duration::from_millis(3));
+        assert_eq!(read.average(), Duration::from_millis(2));
+        assert_eq!(stats.ops().keys().collect::<Vec<_>>(), vec![&"read", &"write"]);
+        assert_eq!(OpCounter::default().average(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_hit_rate() {
+        let stats = CacheStats {hits: 3, misses: 1, ..Default::default()};
+        assert_eq!(stats.hit_rate(), 0.75);
+        assert_eq!(CacheStats::default().hit_rate(), 0.0);
+    }
+}
+
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CacheStats {
+    // block lookups that found the block in the cache
+    pub hits: u64,
+
+    // block lookups that had to read the backing store
+    pub misses: u64,
+
+    // blocks dropped from the cache
+    pub evictions: u64,
+
+    // blocks in the cache right now, and those not yet written
+    pub cached: u64,
+    pub dirty: u64,
+
+    // dirty blocks that make a write wait for a flush, and how often one did
+    pub dirty_limit: u64,
+    pub throttled: u64,
+
+    // the backing store ran into the I/O timeout, see BlockIo::set_timeout()
+    pub degraded: bool,
+}
+
+
+impl CacheStats {
+
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / lookups as f64
+    }
+}
+
+
+// see query::QueryCache
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+
+    // results dropped because a change made them stale
+    pub invalidations: u64,
+
+    pub cached: u64,
+}
+
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OpCounter {
+    pub count: u64,
+    pub errors: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+
+impl OpCounter {
+
+    pub fn average(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+
+        self.total / self.count as u32
+    }
+}
+
+
+// calls and latencies by operation name
+#[derive(Clone, Default, Debug)]
+pub struct OpStats {
+    ops: BTreeMap<&'static str, OpCounter>,
+}
+
+
+impl OpStats {
+
+    pub fn record(&mut self, op: &'static str, elapsed: Duration, ok: bool) {
+        let counter = self.ops.entry(op).or_default();
+        counter.count += 1;
+        counter.total += elapsed;
+        counter.max = std::cmp::max(counter.max, elapsed);
+        if !ok {
+            counter.errors += 1;
+        }
+    }
+
+
+    pub fn ops(&self) -> &BTreeMap<&'static str, OpCounter> {
+        &self.ops
+    }
+}
+
+
+// see PathTagFs::stats()
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    pub cache: CacheStats,
+    pub queries: QueryCacheStats,
+    pub ops: OpStats,
+
+    // the recent warnings about damaged blocks and failed requests
+    pub warnings: Vec<Warning>,
+}
@@ -0,0 +1,106 @@
+//
+// Custom order of the files in a tag directory, for playlist like tags.
+// Pinned files come first, then the ordered ones, then the rest as they
+// are stored. The lists hold inodes, so they survive renamed entries.
+//
+
+use fuser::FileType;
+
+use crate::error::PtfsError;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(ino: u64, name: &str) -> (u64, Option<FileType>, String) {
+        (ino, None, name.to_string())
+    }
+
+    #[test]
+    fn test_sort() {
+        let order = TagOrder {pinned: vec![9], order: vec![7, 9, 5]};
+        let mut entries = vec![entry(2, "."), entry(1, ".."), entry(5, "e"), entry(6, "f"), entry(7, "g"), entry(8, "h"), entry(9, "i")];
+        order.sort(&mut entries);
+
+        let names = entries.iter().map(|entry| entry.2.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec![".", "..", "i", "g", "e", "f", "h"]);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let order = TagOrder {pinned: vec![3], order: vec![1, 2]};
+        assert_eq!(TagOrder::decode(&order.encode()).unwrap(), order);
+        assert_eq!(TagOrder::decode(&TagOrder::default().encode()).unwrap(), TagOrder::default());
+        assert!(TagOrder::decode(&order.encode()[1..]).is_err());
+        assert!(TagOrder::decode(&[5, 0, 0, 0]).is_err());
+    }
+}
+
+
+// the extended attributes of a tag directory, entry names one per line
+pub const XATTR_ORDER:&str = "user.ptfs.order";
+pub const XATTR_PINNED:&str = "user.ptfs.pinned";
+
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct TagOrder {
+    pub pinned: Vec<u64>,
+    pub order: Vec<u64>,
+}
+
+
+impl TagOrder {
+
+    pub fn is_empty(&self) -> bool {
+        self.pinned.is_empty() && self.order.is_empty()
+    }
+
+
+    // the number of pinned inodes, then the pinned and the ordered inodes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + 8 * (self.pinned.len() + self.order.len()));
+        data.extend_from_slice(&(self.pinned.len() as u32).to_le_bytes());
+        for ino in self.pinned.iter().chain(self.order.iter()) {
+            data.extend_from_slice(&ino.to_le_bytes());
+        }
+        data
+    }
+
+
+    pub fn decode(data: &[u8]) -> Result<TagOrder, PtfsError> {
+        let damaged = || PtfsError::Corrupt("tag order has a bad length".to_string());
+
+        if data.len() < 4 || !(data.len() - 4).is_multiple_of(8) {
+            return Err(damaged());
+        }
+
+        let pinned = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        let mut inodes = data[4..].chunks(8).map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap())).collect::<Vec<_>>();
+        if pinned > inodes.len() {
+            return Err(damaged());
+        }
+
+        let ordered = inodes.split_off(pinned);
+        Ok(TagOrder {pinned: inodes, order: ordered})
+    }
+
+
+    // "." and ".." stay in front, the rest keeps its order if not listed
+    pub fn sort(&self, entries: &mut [(u64, Option<FileType>, String)]) {
+        entries.sort_by_key(|(ino, _, name)| {
+            if name == "." || name == ".." {
+                return (0, 0);
+            }
+
+            if let Some(i) = self.pinned.iter().position(|pinned| pinned == ino) {
+                return (1, i);
+            }
+
+            match self.order.iter().position(|ordered| ordered == ino) {
+                Some(i) => (2, i),
+                None => (3, 0),
+            }
+        });
+    }
+}
@@ -0,0 +1,123 @@
+//
+// How a tag on a directory spreads. By default everything below the
+// directory carries the tag while it is there. A tag may also keep to
+// the directory itself, or stay with files moved out of the directory.
+// A tag marked keep stays when it has no members anymore.
+//
+
+use crate::error::PtfsError;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        for rules in [TagRules::default(), TagRules {inherit: false, propagate: true, keep: true}] {
+            assert_eq!(TagRules::decode(&rules.encode()).unwrap(), rules);
+        }
+        assert!(TagRules::decode(&[]).is_err());
+        assert!(TagRules::decode(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn test_switches() {
+        assert!(parse_switch(b"yes").unwrap());
+        assert!(!parse_switch(b"no\n").unwrap());
+        assert!(parse_switch(b"maybe").is_err());
+        assert_eq!(switch_value(true), b"yes");
+
+        let mut rules = TagRules::default();
+        *rules.switch_mut(XATTR_KEEP).unwrap() = true;
+        assert!(rules.keep);
+        assert!(rules.switch_mut("user.ptfs.order").is_none());
+    }
+}
+
+
+// the extended attributes of a tag directory, "yes" or "no"
+pub const XATTR_INHERIT:&str = "user.ptfs.inherit";
+pub const XATTR_PROPAGATE:&str = "user.ptfs.propagate";
+pub const XATTR_KEEP:&str = "user.ptfs.keep";
+
+pub const RULE_XATTRS:[&str; 3] = [XATTR_INHERIT, XATTR_PROPAGATE, XATTR_KEEP];
+
+const INHERIT:u8 = 1;
+const PROPAGATE:u8 = 2;
+const KEEP:u8 = 4;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TagRules {
+    // files below a tagged directory carry the tag too
+    pub inherit: bool,
+
+    // files moved out of a tagged directory keep the tag as their own
+    pub propagate: bool,
+
+    // the tag is not removed when it has no members, see PathTagFs::gc_tags()
+    pub keep: bool,
+}
+
+
+impl Default for TagRules {
+    fn default() -> Self {
+        TagRules {inherit: true, propagate: false, keep: false}
+    }
+}
+
+
+impl TagRules {
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.inherit {
+            flags |= INHERIT;
+        }
+        if self.propagate {
+            flags |= PROPAGATE;
+        }
+        if self.keep {
+            flags |= KEEP;
+        }
+        vec![flags]
+    }
+
+
+    pub fn decode(data: &[u8]) -> Result<TagRules, PtfsError> {
+        match data {
+            [flags] if flags & !(INHERIT | PROPAGATE | KEEP) == 0 => Ok(TagRules {
+                inherit: flags & INHERIT != 0,
+                propagate: flags & PROPAGATE != 0,
+                keep: flags & KEEP != 0,
+            }),
+            _ => Err(PtfsError::Corrupt("tag rules are damaged".to_string())),
+        }
+    }
+
+
+    // the switch behind one of RULE_XATTRS
+    pub fn switch_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            XATTR_INHERIT => Some(&mut self.inherit),
+            XATTR_PROPAGATE => Some(&mut self.propagate),
+            XATTR_KEEP => Some(&mut self.keep),
+            _ => None,
+        }
+    }
+}
+
+
+pub fn parse_switch(value: &[u8]) -> Result<bool, PtfsError> {
+    match value.strip_suffix(b"\n").unwrap_or(value) {
+        b"yes" => Ok(true),
+        b"no" => Ok(false),
+        _ => Err(PtfsError::InvalidArgument),
+    }
+}
+
+
+pub fn switch_value(on: bool) -> Vec<u8> {
+    if on {b"yes".to_vec()} else {b"no".to_vec()}
+}
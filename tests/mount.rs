@@ -0,0 +1,291 @@
+//
+// End-to-end tests, these mount a fresh image through the path_tag_fs binary
+// and work on it with std::fs. They are skipped if FUSE is not available.
+//
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+
+const BINARY: &str = env!("CARGO_BIN_EXE_path_tag_fs");
+
+
+struct Mount {
+    image: PathBuf,
+    mountpoint: PathBuf,
+    child: Option<Child>,
+}
+
+
+impl Mount {
+
+    // formats a new image for the test called name
+    fn format(name: &str, size: u64) -> Mount {
+        let dir = std::env::temp_dir().join(format!("ptfs_it_{}_{}", name, std::process::id()));
+        let image = dir.join("image");
+        let mountpoint = dir.join("mnt");
+
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&mountpoint).unwrap();
+
+        let status = Command::new(BINARY)
+            .arg("-d").arg(&image)
+            .arg("-m").arg(size.to_string())
+            .status()
+            .unwrap();
+        assert!(status.success(), "mkfs failed");
+
+        Mount {
+            image: image,
+            mountpoint: mountpoint,
+            child: None,
+        }
+    }
+
+
+    fn mount(&mut self) {
+        let child = Command::new(BINARY)
+            .arg("-d").arg(&self.image)
+            .arg(&self.mountpoint)
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+
+        // the root of a fresh mount always has the Pathes directory
+        let start = Instant::now();
+        while !self.path("Pathes").is_dir() {
+            assert!(start.elapsed() < Duration::from_secs(10), "mount did not appear");
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+
+    fn unmount(&mut self) {
+        let mountpoint = self.mountpoint.as_os_str();
+        let unmounted = ["fusermount3", "fusermount"].iter()
+            .any(|tool| Command::new(tool).arg("-u").arg(mountpoint).status().map_or(false, |s| s.success()))
+            || Command::new("umount").arg(mountpoint).status().map_or(false, |s| s.success());
+        assert!(unmounted, "could not unmount {:?}", self.mountpoint);
+
+        // the image is only complete after the file system process is done
+        let status = self.child.take().unwrap().wait().unwrap();
+        assert!(status.success(), "file system process failed");
+    }
+
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.mountpoint.join(relative)
+    }
+}
+
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = Command::new("umount").arg("-l").arg(&self.mountpoint).status();
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+
+fn fuse_available() -> bool {
+    if Path::new("/dev/fuse").exists() {
+        return true;
+    }
+
+    println!("skipping, /dev/fuse is not available");
+    false
+}
+
+
+#[test]
+fn test_files_survive_remount() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("files", 256);
+    mount.mount();
+
+    fs::create_dir(mount.path("Pathes/docs")).unwrap();
+    fs::write(mount.path("Pathes/docs/short.txt"), b"hello").unwrap();
+    fs::write(mount.path("Pathes/docs/other.txt"), b"world").unwrap();
+
+    assert_eq!(fs::read(mount.path("Pathes/docs/short.txt")).unwrap(), b"hello");
+    assert!(fs::create_dir(mount.path("Pathes/docs")).is_err());
+
+    mount.unmount();
+    mount.mount();
+
+    assert_eq!(fs::read(mount.path("Pathes/docs/short.txt")).unwrap(), b"hello");
+    assert_eq!(fs::read(mount.path("Pathes/docs/other.txt")).unwrap(), b"world");
+
+    let mut names: Vec<String> = fs::read_dir(mount.path("Pathes/docs")).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["other.txt", "short.txt"]);
+
+    mount.unmount();
+}
+
+
+#[test]
+fn test_large_file() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("large", 256);
+    mount.mount();
+
+    let long: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(mount.path("Pathes/long.bin"), &long).unwrap();
+
+    mount.unmount();
+    mount.mount();
+
+    assert_eq!(fs::read(mount.path("Pathes/long.bin")).unwrap(), long);
+    mount.unmount();
+}
+
+
+#[test]
+fn test_append() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("append", 256);
+    mount.mount();
+
+    let path = mount.path("Pathes/log");
+    let mut expected: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, &expected).unwrap();
+
+    for line in [&b"first line\n"[..], b"second line\n"] {
+        let mut file = fs::File::options().append(true).open(&path).unwrap();
+        std::io::Write::write_all(&mut file, line).unwrap();
+        expected.extend_from_slice(line);
+    }
+
+    mount.unmount();
+    mount.mount();
+
+    assert_eq!(fs::read(&path).unwrap(), expected);
+    mount.unmount();
+}
+
+
+#[test]
+fn test_admin_ioctls() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("ioctls", 256);
+    mount.mount();
+    fs::write(mount.path("Pathes/note"), b"before sync").unwrap();
+
+    for action in ["sync", "drop-caches", "check"] {
+        let output = Command::new(BINARY).arg("ctl").arg(&mount.mountpoint).arg(action).output().unwrap();
+        assert!(output.status.success(), "{} failed: {}", action, String::from_utf8_lossy(&output.stdout));
+
+        if action == "check" {
+            assert!(String::from_utf8_lossy(&output.stdout).contains("0 problems"));
+        }
+    }
+
+    // the dropped cache is read back from the image
+    assert_eq!(fs::read(mount.path("Pathes/note")).unwrap(), b"before sync");
+    mount.unmount();
+}
+
+
+#[test]
+fn test_tag_directories() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("tags", 256);
+    mount.mount();
+
+    fs::create_dir(mount.path("Tags/holiday")).unwrap();
+    assert!(mount.path("Tags/holiday").is_dir());
+
+    mount.unmount();
+    mount.mount();
+
+    assert!(mount.path("Tags/holiday").is_dir());
+    mount.unmount();
+}
+
+
+#[test]
+fn test_rename() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("rename", 256);
+    mount.mount();
+
+    fs::write(mount.path("Pathes/old"), b"data").unwrap();
+    fs::rename(mount.path("Pathes/old"), mount.path("Pathes/new")).unwrap();
+
+    mount.unmount();
+    mount.mount();
+
+    assert!(!mount.path("Pathes/old").exists());
+    assert_eq!(fs::read(mount.path("Pathes/new")).unwrap(), b"data");
+    mount.unmount();
+}
+
+
+#[test]
+fn test_tag_file() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("tagfile", 256);
+    mount.mount();
+
+    fs::write(mount.path("Pathes/photo"), b"jpeg").unwrap();
+    fs::create_dir(mount.path("Tags/holiday")).unwrap();
+    fs::hard_link(mount.path("Pathes/photo"), mount.path("Tags/holiday/photo")).unwrap();
+
+    mount.unmount();
+    mount.mount();
+
+    assert_eq!(fs::read(mount.path("Tags/holiday/photo")).unwrap(), b"jpeg");
+    mount.unmount();
+}
+
+
+#[test]
+fn test_nanosecond_mtime() {
+    if !fuse_available() {
+        return;
+    }
+
+    let mut mount = Mount::format("mtime", 256);
+    mount.mount();
+
+    let path = mount.path("Pathes/stamped");
+    let mtime = std::time::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+    fs::write(&path, b"x").unwrap();
+    fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+
+    mount.unmount();
+    mount.mount();
+
+    assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+    mount.unmount();
+}